@@ -0,0 +1,64 @@
+// Compares write throughput of the WAL sync modes, run with
+// cargo bench --bench wal_sync_modes
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lokikv::{Config, LokiKV, ValueObject, WALSyncMode, DEFAULT_COLLECTION};
+use tempfile::TempDir;
+
+const THREADS: usize = 8;
+const RECORDS_PER_THREAD: usize = 500;
+
+// Every writer takes the database lock for its put and waits for the WAL
+// after releasing it, like the server does for a request
+fn writer_threads(mode: WALSyncMode) -> Duration {
+    let dir = TempDir::new().unwrap();
+    let db = LokiKV::open(Config::new(dir.path()).with_wal_sync_mode(mode)).unwrap();
+    let sync = db.get_wal_sync();
+    let db = Arc::new(RwLock::new(db));
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let db = db.clone();
+            let sync = sync.clone();
+            thread::spawn(move || {
+                for i in 0..RECORDS_PER_THREAD {
+                    db.write()
+                        .unwrap()
+                        .put(
+                            DEFAULT_COLLECTION,
+                            &format!("key{}_{}", t, i),
+                            ValueObject::IntData(i as isize),
+                        )
+                        .unwrap();
+                    sync.wait_durable(sync.get_written_lsn()).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    for mode in [
+        WALSyncMode::Always,
+        WALSyncMode::GroupCommit,
+        WALSyncMode::Periodic,
+        WALSyncMode::Disabled,
+    ] {
+        let elapsed = writer_threads(mode);
+        let total = THREADS * RECORDS_PER_THREAD;
+        println!(
+            "{:<12} {:>6} records in {:>8.2?} -> {:>10.0} records/s",
+            format!("{:?}", mode),
+            total,
+            elapsed,
+            total as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
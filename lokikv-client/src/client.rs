@@ -0,0 +1,340 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::connection::Connection;
+use crate::error::{ClientError, Result, ServerError};
+use crate::value::Value;
+
+const DEFAULT_COLLECTION: &str = "default";
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    // host:port of the server's TCP listener
+    pub addr: String,
+    // Connections kept open, requests are spread over them
+    pub pool_size: usize,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    // Attempts per reconnect before giving up with the last error
+    pub max_connect_attempts: u32,
+    // Wait before the second attempt, doubled after every failed one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(addr: impl Into<String>) -> Self {
+        ClientConfig {
+            addr: addr.into(),
+            pool_size: 4,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_connect_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollectionKind {
+    HashMap,
+    BTree,
+    CustomBTree,
+}
+
+impl CollectionKind {
+    fn command(&self) -> &'static str {
+        match self {
+            CollectionKind::HashMap => "/c_hcol",
+            CollectionKind::BTree => "/c_bcol",
+            CollectionKind::CustomBTree => "/c_bcust",
+        }
+    }
+}
+
+// Async client for a LokiKV server. Cheap to share between tasks through an
+// Arc, every method takes &self. Connections that break are opened again on
+// their next use.
+pub struct LokiClient {
+    config: ClientConfig,
+    pool: Vec<Mutex<Option<Arc<Connection>>>>,
+    next_slot: AtomicUsize,
+    collection: RwLock<String>,
+}
+
+impl LokiClient {
+    // Opens the first connection right away so a wrong address fails here,
+    // the others are opened when first needed
+    pub async fn connect(config: ClientConfig) -> Result<Self> {
+        if config.pool_size == 0 {
+            return Err(ClientError::InvalidArgument(String::from(
+                "pool_size must be at least 1",
+            )));
+        }
+        let pool = (0..config.pool_size).map(|_| Mutex::new(None)).collect();
+        let client = LokiClient {
+            config,
+            pool,
+            next_slot: AtomicUsize::new(0),
+            collection: RwLock::new(String::from(DEFAULT_COLLECTION)),
+        };
+        client.connection(0).await?;
+        Ok(client)
+    }
+
+    pub fn get_config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn get_current_collection(&self) -> String {
+        self.collection.read().unwrap().clone()
+    }
+
+    async fn connection(&self, slot: usize) -> Result<Arc<Connection>> {
+        let mut conn = self.pool[slot].lock().await;
+        if let Some(c) = conn.as_ref().filter(|c| !c.is_closed()) {
+            return Ok(c.clone());
+        }
+        *conn = None;
+        let c = Arc::new(self.reconnect().await?);
+        *conn = Some(c.clone());
+        Ok(c)
+    }
+
+    async fn reconnect(&self) -> Result<Connection> {
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match Connection::connect(&self.config.addr, self.config.connect_timeout).await {
+                Ok(conn) => return Ok(conn),
+                Err(e @ ClientError::Protocol(_)) => return Err(e),
+                Err(e) if attempt >= self.config.max_connect_attempts => return Err(e),
+                Err(_) => {
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Runs LokiQL query in the selected collection and returns the result of
+    // every command in it
+    pub async fn query(&self, query: &str) -> Result<Vec<std::result::Result<Value, ServerError>>> {
+        let slot = self.next_slot.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        let conn = self.connection(slot).await?;
+        let collection = self.get_current_collection();
+        let commands = split_commands(query);
+        let switches = commands.iter().any(|c| selected_collection(c).is_some());
+        let responses = conn
+            .request(&collection, query, switches, self.config.request_timeout)
+            .await?;
+        // A `/selectcol` in the query switches the whole client, like select
+        for (command, response) in commands.iter().zip(&responses) {
+            if let (Some(name), Ok(_)) = (selected_collection(command), response) {
+                *self.collection.write().unwrap() = name.to_string();
+            }
+        }
+        Ok(responses)
+    }
+
+    // Runs a single command and returns its result
+    async fn command(&self, command: &str) -> Result<Value> {
+        let mut responses = self.query(command).await?;
+        if responses.len() != 1 {
+            return Err(ClientError::Protocol(format!(
+                "expected 1 result, got {}",
+                responses.len()
+            )));
+        }
+        Ok(responses.pop().unwrap()?)
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<Value>> {
+        check_key(key)?;
+        match self.command(&format!("GET {}", key)).await {
+            Ok(value) => Ok(Some(value)),
+            Err(ClientError::Server(ServerError::KeyNotFound(_))) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: impl Into<Value>) -> Result<()> {
+        check_key(key)?;
+        let value = value.into().to_literal()?;
+        self.command(&format!("SET {} {}", key, value)).await?;
+        Ok(())
+    }
+
+    // Returns how many of the keys existed
+    pub async fn del(&self, keys: &[&str]) -> Result<i64> {
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        for key in keys {
+            check_key(key)?;
+        }
+        let value = self.command(&format!("DEL {}", keys.join(" "))).await?;
+        expect_int(value)
+    }
+
+    // Returns the new value, the key has to hold an integer already
+    pub async fn incr(&self, key: &str) -> Result<i64> {
+        check_key(key)?;
+        expect_int(self.command(&format!("INCR {}", key)).await?)
+    }
+
+    pub async fn decr(&self, key: &str) -> Result<i64> {
+        check_key(key)?;
+        expect_int(self.command(&format!("DECR {}", key)).await?)
+    }
+
+    pub async fn hll_add(&self, key: &str, items: &[Value]) -> Result<()> {
+        check_key(key)?;
+        if items.is_empty() {
+            return Ok(());
+        }
+        let commands = items
+            .iter()
+            .map(|item| Ok(format!("ADDHLL {} {}", key, item.to_literal()?)))
+            .collect::<Result<Vec<_>>>()?;
+        for response in self.query(&commands.join(" ; ")).await? {
+            response?;
+        }
+        Ok(())
+    }
+
+    // Estimated number of distinct items in the union of the HLLs at keys
+    pub async fn hll_count(&self, keys: &[&str]) -> Result<u64> {
+        if keys.is_empty() {
+            return Err(ClientError::InvalidArgument(String::from(
+                "hll_count needs at least one key",
+            )));
+        }
+        for key in keys {
+            check_key(key)?;
+        }
+        match self
+            .command(&format!("HLLCOUNT {}", keys.join(" ")))
+            .await?
+        {
+            Value::Float(count) => Ok(count.round() as u64),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    pub async fn create_collection(&self, name: &str, kind: CollectionKind) -> Result<()> {
+        check_key(name)?;
+        self.command(&format!("{} {}", kind.command(), name))
+            .await?;
+        Ok(())
+    }
+
+    // Later requests of this client run in collection name
+    pub async fn select(&self, name: &str) -> Result<()> {
+        check_key(name)?;
+        let slot = self.next_slot.fetch_add(1, Ordering::Relaxed) % self.pool.len();
+        let conn = self.connection(slot).await?;
+        // Switching one connection checks that the collection exists
+        conn.request(name, "CURCOLNAME", false, self.config.request_timeout)
+            .await?;
+        *self.collection.write().unwrap() = name.to_string();
+        Ok(())
+    }
+}
+
+// Keys and collection names are written into the query as they are
+fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() || key.contains(|c: char| c.is_whitespace() || c == ';') {
+        return Err(ClientError::InvalidArgument(format!(
+            "{:?} is not a valid key, keys can't be empty or hold whitespace or ';'",
+            key
+        )));
+    }
+    Ok(())
+}
+
+// Commands of a query without the separators, the server answers each of
+// them once
+fn split_commands(query: &str) -> Vec<&str> {
+    let mut commands = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in query.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '\'' => in_string = !in_string,
+            ';' if !in_string => {
+                commands.push(query[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    commands.push(query[start..].trim());
+    commands.retain(|command| !command.is_empty());
+    commands
+}
+
+// Collection a `/selectcol` command switches to
+fn selected_collection(command: &str) -> Option<&str> {
+    let mut words = command.split_whitespace();
+    match words.next() {
+        Some("/selectcol") => words.next(),
+        _ => None,
+    }
+}
+
+fn expect_int(value: Value) -> Result<i64> {
+    value.as_int().ok_or_else(|| unexpected(value))
+}
+
+fn unexpected(value: Value) -> ClientError {
+    ClientError::Protocol(format!("unexpected result {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_split_commands() {
+        assert_eq!(split_commands("GET a"), ["GET a"]);
+        assert_eq!(
+            split_commands("SET a 'x;y' ; GET a ;"),
+            ["SET a 'x;y'", "GET a"]
+        );
+        assert_eq!(split_commands("SET a 'it\\'s;' ; ; GET a").len(), 2);
+        assert!(split_commands("  ").is_empty());
+        assert_eq!(selected_collection("/selectcol users"), Some("users"));
+        assert_eq!(selected_collection("SET users 1"), None);
+        assert!(check_key("user:1").is_ok());
+        assert!(check_key("a b").is_err());
+        assert!(check_key("a;").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_gives_up_after_backoff() {
+        // Nothing listens on the port once the listener is gone
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let mut config = ClientConfig::new(addr);
+        config.max_connect_attempts = 3;
+        config.initial_backoff = Duration::from_millis(10);
+        let started = std::time::Instant::now();
+        let result = LokiClient::connect(config).await;
+        assert!(matches!(result, Err(ClientError::Io(_))));
+        // Waited 10ms and then 20ms between the attempts
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::error::{ClientError, Result};
+use crate::protocol::{self, Responses};
+
+type Pending = Arc<StdMutex<HashMap<u64, oneshot::Sender<Responses>>>>;
+
+struct Writer {
+    stream: OwnedWriteHalf,
+    next_id: u64,
+    // Collection the server session of this connection has selected
+    collection: String,
+}
+
+// A single binary protocol connection. Requests from several tasks are
+// pipelined over it, a reader task hands every response to the request with
+// the same ID.
+pub struct Connection {
+    writer: Mutex<Writer>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+impl Connection {
+    pub async fn connect<A: ToSocketAddrs>(addr: A, connect_timeout: Duration) -> Result<Self> {
+        let stream = timeout(connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+        stream.set_nodelay(true)?;
+        let (rd, mut wr) = stream.into_split();
+        let mut rd = BufReader::new(rd);
+
+        let hello = async {
+            wr.write_all(protocol::hello_line().as_bytes()).await?;
+            let mut line = String::new();
+            rd.read_line(&mut line).await?;
+            Ok::<_, ClientError>(line)
+        };
+        let line = timeout(connect_timeout, hello)
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+        if !protocol::is_accepted(&line) {
+            return Err(ClientError::Protocol(format!(
+                "server refused binary protocol {}: {}",
+                protocol::BINARY_VERSION,
+                line.trim_end()
+            )));
+        }
+
+        let pending = Pending::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn(read_responses(rd, pending.clone(), closed.clone()));
+        Ok(Connection {
+            writer: Mutex::new(Writer {
+                stream: wr,
+                next_id: 0,
+                collection: String::from("default"),
+            }),
+            pending,
+            closed,
+            reader,
+        })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // Runs query in collection and returns the results of its commands.
+    // switches is set when the query selects another collection itself.
+    pub async fn request(
+        &self,
+        collection: &str,
+        query: &str,
+        switches: bool,
+        request_timeout: Duration,
+    ) -> Result<Responses> {
+        let (id, rx) = {
+            let mut writer = self.writer.lock().await;
+            if writer.collection != collection {
+                // Other requests wait for the switch so none of them runs
+                // in the wrong collection. Unknown until the answer arrives.
+                writer.collection.clear();
+                let select = format!("/selectcol {}", collection);
+                let (id, rx) = self.send(&mut writer, &select).await?;
+                let mut responses = self.wait(id, rx, request_timeout).await?;
+                match responses.pop() {
+                    Some(Ok(_)) => writer.collection = collection.to_string(),
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err(ClientError::Protocol(String::from("empty response"))),
+                }
+            }
+            let sent = self.send(&mut writer, query).await?;
+            if switches {
+                // Requests after this one select their collection again
+                writer.collection.clear();
+            }
+            sent
+        };
+        self.wait(id, rx, request_timeout).await
+    }
+
+    async fn send(
+        &self,
+        writer: &mut Writer,
+        query: &str,
+    ) -> Result<(u64, oneshot::Receiver<Responses>)> {
+        if self.is_closed() {
+            return Err(ClientError::ConnectionClosed);
+        }
+        let (tx, rx) = oneshot::channel();
+        let id = writer.next_id;
+        writer.next_id += 1;
+        self.pending.lock().unwrap().insert(id, tx);
+        if let Err(e) = protocol::write_request(&mut writer.stream, id, query).await {
+            self.pending.lock().unwrap().remove(&id);
+            self.closed.store(true, Ordering::Release);
+            return Err(e.into());
+        }
+        Ok((id, rx))
+    }
+
+    async fn wait(
+        &self,
+        id: u64,
+        rx: oneshot::Receiver<Responses>,
+        request_timeout: Duration,
+    ) -> Result<Responses> {
+        match timeout(request_timeout, rx).await {
+            Ok(Ok(responses)) => Ok(responses),
+            Ok(Err(_)) => Err(ClientError::ConnectionClosed),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(ClientError::Timeout)
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_responses<R: AsyncRead + Unpin>(
+    mut reader: R,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+) {
+    while let Ok(payload) = protocol::read_frame(&mut reader).await {
+        let Ok((id, responses)) = protocol::decode_response(&payload) else {
+            break;
+        };
+        // Nobody waits for it anymore if the request timed out
+        if let Some(tx) = pending.lock().unwrap().remove(&id) {
+            let _ = tx.send(responses);
+        }
+    }
+    closed.store(true, Ordering::Release);
+    // Dropping the senders wakes up every request still waiting
+    pending.lock().unwrap().clear();
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+
+use serde::Deserialize;
+
+// An error returned by the server for a single command. Mirrors the server's
+// `LokiError`, the variants have to stay in the same order (see tests/wire.rs).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum ServerError {
+    KeyNotFound(String),
+    CollectionNotFound(String),
+    WrongType(String),
+    OutOfRange(String),
+    ParseError(String),
+    IoError(String),
+}
+
+impl ServerError {
+    // Same codes the text protocol sends in front of the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::KeyNotFound(_) => "KEY_NOT_FOUND",
+            ServerError::CollectionNotFound(_) => "COLLECTION_NOT_FOUND",
+            ServerError::WrongType(_) => "WRONG_TYPE",
+            ServerError::OutOfRange(_) => "OUT_OF_RANGE",
+            ServerError::ParseError(_) => "PARSE_ERROR",
+            ServerError::IoError(_) => "IO_ERROR",
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::KeyNotFound(key) => write!(f, "key {} does not exist", key),
+            ServerError::CollectionNotFound(name) => {
+                write!(f, "collection {} does not exist", name)
+            }
+            ServerError::WrongType(msg)
+            | ServerError::OutOfRange(msg)
+            | ServerError::ParseError(msg)
+            | ServerError::IoError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+#[derive(Debug)]
+pub enum ClientError {
+    // Connecting, reading or writing failed
+    Io(io::Error),
+    // No answer within the request timeout
+    Timeout,
+    // The connection went away before the answer arrived
+    ConnectionClosed,
+    // The server sent something this client doesn't understand
+    Protocol(String),
+    // A key or value that can't be written as LokiQL
+    InvalidArgument(String),
+    Server(ServerError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Timeout => write!(f, "request timed out"),
+            ClientError::ConnectionClosed => write!(f, "connection closed"),
+            ClientError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            ClientError::InvalidArgument(msg) => write!(f, "{}", msg),
+            ClientError::Server(e) => write!(f, "{}: {}", e.code(), e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        ClientError::Io(e)
+    }
+}
+
+impl From<ServerError> for ClientError {
+    fn from(e: ServerError) -> Self {
+        ClientError::Server(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
@@ -0,0 +1,17 @@
+// Async client for LokiKV. Talks the server's binary protocol so values keep
+// their type, pipelines requests over a small pool of connections and opens
+// broken connections again with backoff.
+//
+//     let client = LokiClient::connect(ClientConfig::new("127.0.0.1:8765")).await?;
+//     client.set("visits", 1).await?;
+//     client.incr("visits").await?;
+//     assert_eq!(client.get("visits").await?, Some(Value::Int(2)));
+mod client;
+mod connection;
+mod error;
+mod protocol;
+mod value;
+
+pub use client::{ClientConfig, CollectionKind, LokiClient};
+pub use error::{ClientError, Result, ServerError};
+pub use value::{BloomFilter, CountMinSketch, Hll, Value};
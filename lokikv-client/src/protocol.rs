@@ -0,0 +1,91 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::ServerError;
+use crate::value::Value;
+
+// Client side of the server's binary protocol, see
+// src/db/server_multithread/protocol.rs. Only version 2 is spoken since the
+// connection relies on request IDs to pipeline requests.
+pub const BINARY_VERSION: u32 = 2;
+const MAX_FRAME_LEN: u32 = 512 << 20;
+
+pub type Responses = Vec<Result<Value, ServerError>>;
+
+pub fn hello_line() -> String {
+    format!("HELLO BINARY {}\n", BINARY_VERSION)
+}
+
+pub fn is_accepted(line: &str) -> bool {
+    line.trim_end() == format!("OK BINARY {}", BINARY_VERSION)
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is larger than {}", len, MAX_FRAME_LEN),
+        ));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+pub async fn write_request<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    id: u64,
+    query: &str,
+) -> io::Result<()> {
+    let len = u32::try_from(8 + query.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("query of {} bytes is too large", query.len()),
+            )
+        })?;
+    let mut frame = Vec::with_capacity(4 + len as usize);
+    frame.extend(len.to_be_bytes());
+    frame.extend(id.to_be_bytes());
+    frame.extend(query.as_bytes());
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+// Request ID and decoded results of a response frame
+pub fn decode_response(payload: &[u8]) -> Result<(u64, Responses), String> {
+    let (id, body) = payload
+        .split_first_chunk::<8>()
+        .ok_or_else(|| format!("response of {} bytes has no request ID", payload.len()))?;
+    let responses: Responses =
+        bincode::deserialize(body).map_err(|e| format!("undecodable response: {}", e))?;
+    let responses = responses
+        .into_iter()
+        .map(|response| response.map(Value::unquoted))
+        .collect();
+    Ok((u64::from_be_bytes(*id), responses))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_frames() {
+        let mut buf = Vec::new();
+        write_request(&mut buf, 7, "GET key;").await.unwrap();
+        let mut reader = &buf[..];
+        let payload = read_frame(&mut reader).await.unwrap();
+        assert_eq!(&payload[..8], &7u64.to_be_bytes());
+        assert_eq!(&payload[8..], b"GET key;");
+        assert!(read_frame(&mut reader).await.is_err());
+
+        assert!(decode_response(&[0; 4]).is_err());
+        assert!(is_accepted("OK BINARY 2\n"));
+        assert!(!is_accepted("ERROR PARSE_ERROR: unsupported\n"));
+    }
+}
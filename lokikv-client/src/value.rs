@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::error::{ClientError, Result};
+
+// A value sent by the server. Mirrors the server's `ValueObject`, the
+// variants have to stay in the same order for bincode to decode them,
+// tests/wire.rs checks both against each other.
+// Strings come without the quotes LokiQL stores them with.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Null,
+    Float(f64),
+    // Status text of commands that return no value, like `SET`
+    Output(String),
+    Blob(Vec<u8>),
+    List(Vec<Value>),
+    Hll(Hll),
+    Map(BTreeMap<String, Value>),
+    Set(BTreeSet<String>),
+    // Members with their scores, lowest score first
+    SortedSet(Vec<(String, f64)>),
+    BloomFilter(BloomFilter),
+    CountMinSketch(CountMinSketch),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Hll {
+    registers: HllRegisters,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+enum HllRegisters {
+    Sparse(Vec<(u16, u8)>),
+    Dense(Vec<u8>),
+}
+
+impl Hll {
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.registers, HllRegisters::Sparse(_))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    error_rate: f64,
+    capacity: u64,
+}
+
+impl BloomFilter {
+    pub fn get_error_rate(&self) -> f64 {
+        self.error_rate
+    }
+
+    pub fn get_capacity(&self) -> u64 {
+        self.capacity
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CountMinSketch {
+    width: u64,
+    depth: u64,
+    counters: Vec<u64>,
+}
+
+impl CountMinSketch {
+    pub fn get_width(&self) -> u64 {
+        self.width
+    }
+
+    pub fn get_depth(&self) -> u64 {
+        self.depth
+    }
+}
+
+// Undoes the quoting and escapes of a LokiQL string literal, other text is
+// returned as is
+fn unquote(s: String) -> String {
+    let Some(inner) = s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) else {
+        return s;
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+                match u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    Some(c) => out.push(c),
+                    None => {
+                        out.push_str("\\u");
+                        out.push_str(&code);
+                    }
+                }
+            }
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('\'');
+    for c in s.chars() {
+        match c {
+            '\'' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+impl Value {
+    pub(crate) fn unquoted(self) -> Value {
+        match self {
+            Value::String(s) => Value::String(unquote(s)),
+            Value::List(items) => Value::List(items.into_iter().map(Value::unquoted).collect()),
+            Value::Map(fields) => Value::Map(
+                fields
+                    .into_iter()
+                    .map(|(field, value)| (field, value.unquoted()))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    // The value written as a LokiQL literal. Only values that can be stored
+    // with SET have one.
+    pub fn to_literal(&self) -> Result<String> {
+        match self {
+            Value::String(s) => Ok(quote(s)),
+            Value::Int(n) => Ok(n.to_string()),
+            Value::Bool(b) => Ok(b.to_string()),
+            Value::Float(f) if f.is_finite() => {
+                let f = f.to_string();
+                // LokiQL floats always have a decimal point
+                Ok(if f.contains('.') { f } else { f + ".0" })
+            }
+            Value::List(items) => {
+                let items = items
+                    .iter()
+                    .map(Value::to_literal)
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("[{}]", items.join(", ")))
+            }
+            Value::Map(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(field, value)| Ok(format!("{}: {}", quote(field), value.to_literal()?)))
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(format!("{{{}}}", fields.join(", ")))
+            }
+            other => Err(ClientError::InvalidArgument(format!(
+                "{} can not be written as a LokiQL literal",
+                other
+            ))),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::Output(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+// LokiQL literals where there is one
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", quote(s)),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Null => write!(f, "null"),
+            Value::Float(n) => write!(f, "{:?}", n),
+            Value::Output(s) => write!(f, "{}", s),
+            Value::Blob(bytes) => write!(f, "<blob of {} bytes>", bytes.len()),
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(fields) => {
+                write!(f, "{{")?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", quote(field), value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Set(members) => write!(f, "{:?}", members),
+            Value::SortedSet(members) => write!(f, "{:?}", members),
+            Value::Hll(_) => write!(f, "<hyperloglog>"),
+            Value::BloomFilter(filter) => write!(
+                f,
+                "<bloom filter for {} items at {}>",
+                filter.capacity, filter.error_rate
+            ),
+            Value::CountMinSketch(sketch) => {
+                write!(f, "<count-min sketch {}x{}>", sketch.width, sketch.depth)
+            }
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Self {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literals_round_trip_through_quoting() {
+        let value = Value::from("it's a \\ test");
+        let literal = value.to_literal().unwrap();
+        assert_eq!(literal, "'it\\'s a \\\\ test'");
+        assert_eq!(Value::String(literal).unquoted(), value);
+
+        assert_eq!(
+            unquote("'line\\nbreak \\u00e9'".to_string()),
+            "line\nbreak é"
+        );
+        assert_eq!(Value::Float(3.0).to_literal().unwrap(), "3.0");
+        assert_eq!(Value::Float(-0.25).to_literal().unwrap(), "-0.25");
+        assert!(Value::Float(f64::NAN).to_literal().is_err());
+        assert_eq!(Value::from(vec![1i64, 2]).to_literal().unwrap(), "[1, 2]");
+        let map = Value::Map(BTreeMap::from([("a".to_string(), Value::Bool(true))]));
+        assert_eq!(map.to_literal().unwrap(), "{'a': true}");
+        assert!(Value::Blob(vec![1]).to_literal().is_err());
+    }
+}
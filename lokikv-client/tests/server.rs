@@ -0,0 +1,105 @@
+// Runs the client against a server started in-process
+use std::sync::{Arc, RwLock};
+
+use lokikv::server_multithread::server::handle_connection;
+use lokikv::{Config, LokiKV};
+use lokikv_client::{ClientConfig, ClientError, CollectionKind, LokiClient, ServerError, Value};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+// Accepts any number of connections, returns the address to connect to
+async fn serve(dir: &TempDir) -> String {
+    let db = LokiKV::open(Config::new(dir.path())).unwrap();
+    let db = Arc::new(RwLock::new(db));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(handle_connection(socket, db.clone()));
+        }
+    });
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn test_client_library() {
+    let dir = TempDir::new().unwrap();
+    let mut config = ClientConfig::new(serve(&dir).await);
+    config.pool_size = 3;
+    let client = LokiClient::connect(config).await.unwrap();
+
+    client.set("greeting", "it's 'quoted'\n").await.unwrap();
+    client.set("pi", 3.25).await.unwrap();
+    client.set("tags", vec!["a", "b"]).await.unwrap();
+    client.set("visits", 41).await.unwrap();
+    assert_eq!(client.incr("visits").await.unwrap(), 42);
+    assert_eq!(client.decr("visits").await.unwrap(), 41);
+    assert_eq!(
+        client.get("greeting").await.unwrap(),
+        Some(Value::from("it's 'quoted'\n"))
+    );
+    assert_eq!(client.get("pi").await.unwrap(), Some(Value::Float(3.25)));
+    assert_eq!(
+        client.get("tags").await.unwrap(),
+        Some(Value::from(vec!["a", "b"]))
+    );
+    assert_eq!(client.get("missing").await.unwrap(), None);
+    assert_eq!(client.del(&["pi", "missing"]).await.unwrap(), 1);
+    assert!(client.set("bad key", 1).await.is_err());
+
+    let items: Vec<Value> = (0..100).map(Value::from).collect();
+    client.hll_add("seen", &items).await.unwrap();
+    client.hll_add("seen", &items[..10]).await.unwrap();
+    let count = client.hll_count(&["seen"]).await.unwrap();
+    assert!((95..=105).contains(&count), "estimated {}", count);
+
+    // Every pooled connection has to follow the selected collection
+    client
+        .create_collection("users", CollectionKind::BTree)
+        .await
+        .unwrap();
+    client.select("users").await.unwrap();
+    for i in 0..6 {
+        client.set(&format!("user:{}", i), i).await.unwrap();
+    }
+    for i in 0..6 {
+        assert_eq!(
+            client.get(&format!("user:{}", i)).await.unwrap(),
+            Some(Value::Int(i))
+        );
+        assert_eq!(client.get("visits").await.unwrap(), None);
+    }
+    assert!(matches!(
+        client.select("nowhere").await,
+        Err(ClientError::Server(ServerError::CollectionNotFound(_)))
+    ));
+    assert_eq!(client.get_current_collection(), "users");
+
+    // The client's types decode every kind of value
+    let responses = client
+        .query(
+            "HSET h name 'loki' ; HGETALL h ; SADD s 'x' ; SMEMBERS s ; ZADD z 1 a ; \
+             ZRANGE z 0 -1 WITHSCORES ; BFRESERVE bf 0.01 100 ; GET bf ; CMSINCR cms 'x' ; \
+             GET cms ; ADDHLL hll 'x' ; GET hll ; INCR user:0",
+        )
+        .await
+        .unwrap();
+    assert_eq!(responses.len(), 13);
+    assert!(responses.iter().take(12).all(Result::is_ok));
+    assert!(matches!(responses[1], Ok(Value::Map(_))));
+    assert!(matches!(responses[7], Ok(Value::BloomFilter(_))));
+    assert!(matches!(responses[9], Ok(Value::CountMinSketch(_))));
+    assert!(matches!(responses[11], Ok(Value::Hll(_))));
+    assert_eq!(responses[12], Ok(Value::Int(1)));
+
+    // A /selectcol sent as plain LokiQL switches every pooled connection
+    client
+        .query("/selectcol default ; SET moved 1")
+        .await
+        .unwrap();
+    assert_eq!(client.get_current_collection(), "default");
+    for _ in 0..3 {
+        assert_eq!(client.get("moved").await.unwrap(), Some(Value::Int(1)));
+        assert_eq!(client.get("user:0").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,113 @@
+// The client decodes the server's responses with its own copies of
+// `ValueObject` and `LokiError`, these have to keep the same layout
+use std::collections::{BTreeMap, BTreeSet};
+
+use lokikv::loki_kv::data_structures::bloom::BloomFilter as ServerBloomFilter;
+use lokikv::loki_kv::data_structures::count_min_sketch::CountMinSketch as ServerSketch;
+use lokikv::loki_kv::data_structures::hyperloglog::HLL;
+use lokikv::loki_kv::data_structures::sorted_set::SortedSet;
+use lokikv::server_multithread::protocol::Responses as ServerResponses;
+use lokikv::{LokiError, ValueObject};
+use lokikv_client::{ServerError, Value};
+
+fn round_trip(responses: ServerResponses) -> Vec<Result<Value, ServerError>> {
+    bincode::deserialize(&bincode::serialize(&responses).unwrap()).unwrap()
+}
+
+#[test]
+fn test_every_value_decodes_as_the_same_value() {
+    let mut sparse = HLL::new();
+    sparse.add_item(b"x");
+    let mut dense = HLL::new();
+    for item in 0..10_000u32 {
+        dense.add_item(&item.to_le_bytes());
+    }
+    let mut sorted_set = SortedSet::new();
+    sorted_set.insert("b".to_string(), 2.0);
+    sorted_set.insert("a".to_string(), 1.5);
+
+    let decoded = round_trip(vec![
+        Ok(ValueObject::StringData("loki".to_string())),
+        Ok(ValueObject::IntData(-7)),
+        Ok(ValueObject::BoolData(true)),
+        Ok(ValueObject::Phantom),
+        Ok(ValueObject::DecimalData(2.5)),
+        Ok(ValueObject::OutputString("SET".to_string())),
+        Ok(ValueObject::BlobData(vec![0, 255])),
+        Ok(ValueObject::ListData(vec![ValueObject::IntData(1)])),
+        Ok(ValueObject::HLLPointer(sparse)),
+        Ok(ValueObject::HLLPointer(dense)),
+        Ok(ValueObject::MapData(BTreeMap::from([(
+            "field".to_string(),
+            ValueObject::BoolData(false),
+        )]))),
+        Ok(ValueObject::SetData(BTreeSet::from(["m".to_string()]))),
+        Ok(ValueObject::SortedSetData(sorted_set)),
+        Ok(ValueObject::BloomFilterData(
+            ServerBloomFilter::new(0.01, 100).unwrap(),
+        )),
+        Ok(ValueObject::CountMinSketchData(ServerSketch::new(64, 3))),
+    ]);
+
+    let expected = [
+        Value::String("loki".to_string()),
+        Value::Int(-7),
+        Value::Bool(true),
+        Value::Null,
+        Value::Float(2.5),
+        Value::Output("SET".to_string()),
+        Value::Blob(vec![0, 255]),
+        Value::List(vec![Value::Int(1)]),
+    ];
+    assert_eq!(decoded.len(), 15);
+    for (decoded, expected) in decoded.iter().zip(expected) {
+        assert_eq!(decoded, &Ok(expected));
+    }
+    assert!(matches!(&decoded[8], Ok(Value::Hll(hll)) if hll.is_sparse()));
+    assert!(matches!(&decoded[9], Ok(Value::Hll(hll)) if !hll.is_sparse()));
+    assert_eq!(
+        decoded[10],
+        Ok(Value::Map(BTreeMap::from([(
+            "field".to_string(),
+            Value::Bool(false)
+        )])))
+    );
+    assert_eq!(
+        decoded[11],
+        Ok(Value::Set(BTreeSet::from(["m".to_string()])))
+    );
+    assert_eq!(
+        decoded[12],
+        Ok(Value::SortedSet(vec![
+            ("a".to_string(), 1.5),
+            ("b".to_string(), 2.0)
+        ]))
+    );
+    assert!(matches!(&decoded[13], Ok(Value::BloomFilter(filter))
+        if filter.get_error_rate() == 0.01 && filter.get_capacity() == 100));
+    assert!(matches!(&decoded[14], Ok(Value::CountMinSketch(sketch))
+        if sketch.get_width() == 64 && sketch.get_depth() == 3));
+}
+
+#[test]
+fn test_every_error_decodes_as_the_same_error() {
+    let decoded = round_trip(vec![
+        Err(LokiError::KeyNotFound("a".to_string())),
+        Err(LokiError::CollectionNotFound("b".to_string())),
+        Err(LokiError::WrongType("c".to_string())),
+        Err(LokiError::OutOfRange("d".to_string())),
+        Err(LokiError::ParseError("e".to_string())),
+        Err(LokiError::IoError("f".to_string())),
+    ]);
+    assert_eq!(
+        decoded,
+        [
+            Err(ServerError::KeyNotFound("a".to_string())),
+            Err(ServerError::CollectionNotFound("b".to_string())),
+            Err(ServerError::WrongType("c".to_string())),
+            Err(ServerError::OutOfRange("d".to_string())),
+            Err(ServerError::ParseError("e".to_string())),
+            Err(ServerError::IoError("f".to_string())),
+        ]
+    );
+}
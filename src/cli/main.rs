@@ -1,10 +1,7 @@
-use std::{
-    error::Error,
-    io::{self, BufRead, BufReader, Read, Write},
-    net::TcpStream,
-};
+use std::io::{self, Write};
 
 use clap::Parser;
+use lokikv_client::{ClientConfig, LokiClient};
 use paris::Logger;
 
 #[derive(Parser)]
@@ -16,23 +13,24 @@ struct Args {
     port: usize,
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     let mut logger = Logger::new();
     let args = Args::parse();
     let s = format!("Connecting to {}:{}.....", args.host, args.port);
     logger.loading(s.as_str());
-    let mut stream = match TcpStream::connect(format!("{}:{}", args.host, args.port)) {
-        Ok(strm) => {
+    // A single connection keeps the commands of the prompt in order
+    let mut config = ClientConfig::new(format!("{}:{}", args.host, args.port));
+    config.pool_size = 1;
+    let client = match LokiClient::connect(config).await {
+        Ok(client) => {
             logger.done();
             logger.success("Connected to LokiKV instance!");
-            strm
+            client
         }
         Err(err) => panic!("Unable to connect! Error: {}", err),
     };
 
-    let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone stream"));
-    let mut writer = stream;
-
     // Prints welcome message
     println!(
         "\n\
@@ -68,46 +66,33 @@ fn main() {
         io::stdout().flush().expect("Failed to flush stdout");
 
         let mut buf = String::new();
-        if io::stdin().read_line(&mut buf).is_err() {
-            logger.error("Couldn't read command");
-            continue;
-        }
-
-        if !buf.ends_with("\n") {
-            buf.push('\n');
+        match io::stdin().read_line(&mut buf) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(_) => {
+                logger.error("Couldn't read command");
+                continue;
+            }
         }
-
-        // println!("Writing to stream: {}", buf);
-        if let Err(e) = writer.write_all(buf.as_bytes()) {
-            let e = format!("Failed to send command: {}", e);
-            logger.error(e.as_str());
-            break;
+        if buf.trim().is_empty() {
+            continue;
         }
-        // println!("Written to stream!");
 
-        // println!("Checking response....");
+        let responses = match client.query(buf.trim()).await {
+            Ok(responses) => responses,
+            Err(e) => {
+                let e = format!("Request failed: {}", e);
+                logger.error(e.as_str());
+                continue;
+            }
+        };
         let mut response = String::new();
-        loop {
-            let mut line = String::new();
-            if let Ok(bytes) = reader.read_line(&mut line) {
-                if bytes == 0 {
-                    logger.error("Seems like there was an error! Please try again..");
-                    return;
-                }
-
-                if line.trim() == "<END_OF_RESPONSE>" {
-                    break; // Stop reading when marker is received
-                }
-
-                response.push_str(line.trim());
-                response += "\n";
-            } else {
-                logger.error("Failed to read response.");
-                break;
+        for result in responses {
+            match result {
+                Ok(value) => response += &format!("{}\n", value),
+                Err(e) => response += &format!("ERROR {}: {}\n", e.code(), e),
             }
         }
-
-        let t = format!("{}", response);
-        logger.info(t.as_str());
+        logger.info(response.as_str());
     }
 }
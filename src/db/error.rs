@@ -0,0 +1,55 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+// Errors surfaced to clients by the query path. Anything that goes wrong
+// while serving a request ends up here instead of panicking while holding
+// the database lock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LokiError {
+    KeyNotFound(String),
+    CollectionNotFound(String),
+    // The value or collection does not support the requested operation
+    WrongType(String),
+    // An index outside of a list
+    OutOfRange(String),
+    ParseError(String),
+    IoError(String),
+}
+
+impl LokiError {
+    // Stable identifier sent to clients in front of the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            LokiError::KeyNotFound(_) => "KEY_NOT_FOUND",
+            LokiError::CollectionNotFound(_) => "COLLECTION_NOT_FOUND",
+            LokiError::WrongType(_) => "WRONG_TYPE",
+            LokiError::OutOfRange(_) => "OUT_OF_RANGE",
+            LokiError::ParseError(_) => "PARSE_ERROR",
+            LokiError::IoError(_) => "IO_ERROR",
+        }
+    }
+}
+
+impl fmt::Display for LokiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LokiError::KeyNotFound(key) => write!(f, "key {} does not exist", key),
+            LokiError::CollectionNotFound(name) => {
+                write!(f, "collection {} does not exist", name)
+            }
+            LokiError::WrongType(msg)
+            | LokiError::OutOfRange(msg)
+            | LokiError::ParseError(msg)
+            | LokiError::IoError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for LokiError {}
+
+impl From<std::io::Error> for LokiError {
+    fn from(e: std::io::Error) -> Self {
+        LokiError::IoError(e.to_string())
+    }
+}
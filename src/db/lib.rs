@@ -1 +1,18 @@
+// LokiKV as a library. `LokiKV::open` runs the storage engine, WAL and
+// persistence in-process, the `server-db` binary puts the TCP server in
+// front of the same modules.
+//
+//     let mut db = LokiKV::open(Config::new("data/lokikv"))?;
+//     db.create_collection("users", CollectionKind::BTreeMap)?;
+//     db.put("users", "ann", ValueObject::IntData(7))?;
+//     db.sync()?;
+pub mod error;
+pub mod loki_kv;
+pub mod parser;
+pub mod server_multithread;
+pub mod utils;
 
+pub use error::LokiError;
+pub use loki_kv::config::Config;
+pub use loki_kv::loki_kv::{CollectionKind, LokiKV, ValueObject, DEFAULT_COLLECTION};
+pub use loki_kv::wal::WALSyncMode;
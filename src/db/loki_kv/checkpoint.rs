@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::loki_kv::format::{decode_page, encode_pairs};
+use crate::loki_kv::loki_kv::{get_current_timestamp_as_u64, CollectionKind, ValueObject};
+use crate::utils::{info_string, warning_string};
+
+// A checkpoint lives in `<checkpoint dir>/<checkpoint id>/` and is made of
+// chunk files plus a MANIFEST describing them. It is built in a staging
+// directory and renamed into place, so a published checkpoint is always
+// complete. The control file only points at it after that rename.
+pub const MANIFEST_FILE: &str = "MANIFEST";
+const STAGING_SUFFIX: &str = ".tmp";
+const CHUNK_RECORDS: usize = 8000;
+
+// Contents of one collection at the time of the checkpoint
+pub struct CollectionSnapshot {
+    pub name: String,
+    pub kind: CollectionKind,
+    pub pairs: Vec<(String, ValueObject)>,
+    // Deadlines of expiring keys, in milliseconds since the epoch
+    pub expiries: Vec<(String, u64)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestChunk {
+    file: String,
+    records: u64,
+    checksum: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestCollection {
+    name: String,
+    kind: CollectionKind,
+    record_count: u64,
+    chunks: Vec<ManifestChunk>,
+    // Key deadlines stored as integer values, missing when no key expires
+    #[serde(default)]
+    expiry_chunk: Option<ManifestChunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckpointManifest {
+    checkpoint_id: u64,
+    // Last WAL timeline and LSN whose records are contained in the checkpoint
+    sealed_timeline: u64,
+    checkpoint_lsn: u64,
+    created_at: u64,
+    collections: Vec<ManifestCollection>,
+}
+
+impl CheckpointManifest {
+    pub fn get_checkpoint_lsn(&self) -> u64 {
+        self.checkpoint_lsn
+    }
+
+    pub fn get_record_count(&self) -> u64 {
+        self.collections.iter().map(|col| col.record_count).sum()
+    }
+}
+
+pub fn checkpoint_path(checkpoint_dir: &str, checkpoint_id: u64) -> PathBuf {
+    Path::new(checkpoint_dir).join(checkpoint_id.to_string())
+}
+
+fn staging_path(checkpoint_dir: &str, checkpoint_id: u64) -> PathBuf {
+    Path::new(checkpoint_dir).join(format!("{}{}", checkpoint_id, STAGING_SUFFIX))
+}
+
+pub fn write_synced(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let mut file =
+        File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// Makes renames and newly created files inside `path` durable
+pub fn sync_dir(path: &Path) -> Result<(), String> {
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| format!("Failed to sync directory {}: {}", path.display(), e))
+}
+
+fn write_chunk(
+    dir: &Path,
+    file: String,
+    pairs: &[(String, ValueObject)],
+) -> Result<ManifestChunk, String> {
+    let bytes = encode_pairs(pairs).map_err(|e| format!("Failed to encode {}: {}", file, e))?;
+    write_synced(&dir.join(&file), &bytes)?;
+    Ok(ManifestChunk {
+        file,
+        records: pairs.len() as u64,
+        checksum: crc32fast::hash(&bytes),
+    })
+}
+
+// Reads a chunk file and verifies it against its manifest entry
+fn read_chunk(dir: &Path, chunk: &ManifestChunk) -> Result<Vec<(String, ValueObject)>, String> {
+    let path = dir.join(&chunk.file);
+    let bytes = fs::read(&path)
+        .map_err(|e| format!("Failed to read checkpoint chunk {}: {}", path.display(), e))?;
+    if crc32fast::hash(&bytes) != chunk.checksum {
+        return Err(format!(
+            "Checksum mismatch in checkpoint chunk {}",
+            path.display()
+        ));
+    }
+    let (_, pairs) = decode_page(&bytes)
+        .map_err(|e| format!("Corrupt checkpoint chunk {}: {}", path.display(), e))?;
+    if pairs.len() as u64 != chunk.records {
+        return Err(format!(
+            "Checkpoint chunk {} holds {} records, manifest expects {}",
+            path.display(),
+            pairs.len(),
+            chunk.records
+        ));
+    }
+    Ok(pairs)
+}
+
+fn remove_dir_if_exists(path: &Path) -> Result<(), String> {
+    match fs::remove_dir_all(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to remove {}: {}", path.display(), e)),
+    }
+}
+
+// Writes every collection as a new checkpoint and publishes it atomically
+pub fn write_checkpoint(
+    checkpoint_dir: &str,
+    checkpoint_id: u64,
+    sealed_timeline: u64,
+    checkpoint_lsn: u64,
+    collections: &[CollectionSnapshot],
+) -> Result<CheckpointManifest, String> {
+    let staging = staging_path(checkpoint_dir, checkpoint_id);
+    remove_dir_if_exists(&staging)?;
+    fs::create_dir_all(&staging)
+        .map_err(|e| format!("Failed to create {}: {}", staging.display(), e))?;
+
+    let mut manifest = CheckpointManifest {
+        checkpoint_id,
+        sealed_timeline,
+        checkpoint_lsn,
+        created_at: get_current_timestamp_as_u64(),
+        collections: Vec::new(),
+    };
+    for (col_idx, collection) in collections.iter().enumerate() {
+        let mut chunks = Vec::new();
+        // Chunk files are named by position, collection names can hold any character
+        for (chunk_idx, chunk) in collection.pairs.chunks(CHUNK_RECORDS).enumerate() {
+            let file = format!("{}_{}.lqlpage", col_idx, chunk_idx);
+            chunks.push(write_chunk(&staging, file, chunk)?);
+        }
+        let expiry_chunk = if collection.expiries.is_empty() {
+            None
+        } else {
+            let deadlines: Vec<(String, ValueObject)> = collection
+                .expiries
+                .iter()
+                .map(|(key, expire_at)| (key.clone(), ValueObject::IntData(*expire_at as isize)))
+                .collect();
+            let file = format!("{}_expiries.lqlpage", col_idx);
+            Some(write_chunk(&staging, file, &deadlines)?)
+        };
+        manifest.collections.push(ManifestCollection {
+            name: collection.name.clone(),
+            kind: collection.kind,
+            record_count: collection.pairs.len() as u64,
+            chunks,
+            expiry_chunk,
+        });
+    }
+
+    let manifest_string = toml::to_string(&manifest)
+        .map_err(|e| format!("Failed to encode checkpoint manifest: {}", e))?;
+    let manifest_tmp = staging.join(format!("{}{}", MANIFEST_FILE, STAGING_SUFFIX));
+    write_synced(&manifest_tmp, manifest_string.as_bytes())?;
+    fs::rename(&manifest_tmp, staging.join(MANIFEST_FILE))
+        .map_err(|e| format!("Failed to publish checkpoint manifest: {}", e))?;
+    sync_dir(&staging)?;
+
+    // The control file never points at an id it has not handed out yet, so
+    // anything already here is left over from an unfinished attempt
+    let target = checkpoint_path(checkpoint_dir, checkpoint_id);
+    remove_dir_if_exists(&target)?;
+    fs::rename(&staging, &target)
+        .map_err(|e| format!("Failed to publish checkpoint {}: {}", checkpoint_id, e))?;
+    sync_dir(Path::new(checkpoint_dir))?;
+    Ok(manifest)
+}
+
+pub fn read_manifest(
+    checkpoint_dir: &str,
+    checkpoint_id: u64,
+) -> Result<CheckpointManifest, String> {
+    let path = checkpoint_path(checkpoint_dir, checkpoint_id).join(MANIFEST_FILE);
+    let manifest_string = fs::read_to_string(&path).map_err(|e| {
+        format!(
+            "Failed to read checkpoint manifest {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let manifest: CheckpointManifest = toml::from_str(&manifest_string).map_err(|e| {
+        format!(
+            "Failed to parse checkpoint manifest {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    if manifest.checkpoint_id != checkpoint_id {
+        return Err(format!(
+            "Checkpoint manifest {} belongs to checkpoint {}",
+            path.display(),
+            manifest.checkpoint_id
+        ));
+    }
+    Ok(manifest)
+}
+
+// Records new checksums for chunk files that were rewritten in place by
+// the offline upgrade
+pub fn update_chunk_checksums(
+    checkpoint_path: &Path,
+    checksums: &HashMap<String, u32>,
+) -> Result<(), String> {
+    let path = checkpoint_path.join(MANIFEST_FILE);
+    let manifest_string = fs::read_to_string(&path).map_err(|e| {
+        format!(
+            "Failed to read checkpoint manifest {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    let mut manifest: CheckpointManifest = toml::from_str(&manifest_string).map_err(|e| {
+        format!(
+            "Failed to parse checkpoint manifest {}: {}",
+            path.display(),
+            e
+        )
+    })?;
+    for chunk in manifest
+        .collections
+        .iter_mut()
+        .flat_map(|col| col.chunks.iter_mut().chain(col.expiry_chunk.iter_mut()))
+    {
+        if let Some(checksum) = checksums.get(&chunk.file) {
+            chunk.checksum = *checksum;
+        }
+    }
+
+    let manifest_string = toml::to_string(&manifest)
+        .map_err(|e| format!("Failed to encode checkpoint manifest: {}", e))?;
+    let manifest_tmp = checkpoint_path.join(format!("{}{}", MANIFEST_FILE, STAGING_SUFFIX));
+    write_synced(&manifest_tmp, manifest_string.as_bytes())?;
+    fs::rename(&manifest_tmp, &path)
+        .map_err(|e| format!("Failed to publish checkpoint manifest: {}", e))?;
+    sync_dir(checkpoint_path)
+}
+
+// Loads every collection of a checkpoint, verifying each chunk against the manifest
+pub fn read_checkpoint(
+    checkpoint_dir: &str,
+    checkpoint_id: u64,
+) -> Result<Vec<CollectionSnapshot>, String> {
+    let manifest = read_manifest(checkpoint_dir, checkpoint_id)?;
+    let dir = checkpoint_path(checkpoint_dir, checkpoint_id);
+
+    let mut collections = Vec::new();
+    for collection in manifest.collections {
+        let mut pairs: Vec<(String, ValueObject)> = Vec::new();
+        for chunk in collection.chunks.iter() {
+            pairs.extend(read_chunk(&dir, chunk)?);
+        }
+        if pairs.len() as u64 != collection.record_count {
+            return Err(format!(
+                "Collection {} in checkpoint {} holds {} records, manifest expects {}",
+                collection.name,
+                checkpoint_id,
+                pairs.len(),
+                collection.record_count
+            ));
+        }
+        let mut expiries = Vec::new();
+        if let Some(chunk) = collection.expiry_chunk.as_ref() {
+            for (key, deadline) in read_chunk(&dir, chunk)? {
+                match deadline {
+                    ValueObject::IntData(expire_at) if expire_at >= 0 => {
+                        expiries.push((key, expire_at as u64))
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Invalid deadline for key {} in checkpoint chunk {}",
+                            key, chunk.file
+                        ))
+                    }
+                }
+            }
+        }
+        collections.push(CollectionSnapshot {
+            name: collection.name,
+            kind: collection.kind,
+            pairs,
+            expiries,
+        });
+    }
+    Ok(collections)
+}
+
+// Removes checkpoints older than `current_id` along with abandoned staging
+// directories. Failures are only logged, they are retried on the next run.
+pub fn remove_old_checkpoints(checkpoint_dir: &str, current_id: u64) {
+    let entries = match fs::read_dir(checkpoint_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warning_string(format!("Failed to list {}: {}", checkpoint_dir, e));
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let stale = match name.strip_suffix(STAGING_SUFFIX) {
+            Some(id) => id.parse::<u64>().is_ok(),
+            // Only touch directories that are checkpoints, PERSIST output
+            // lives under collections/
+            None => match name.parse::<u64>() {
+                Ok(id) => id < current_id && path.join(MANIFEST_FILE).exists(),
+                Err(_) => false,
+            },
+        };
+        if !stale {
+            continue;
+        }
+        match fs::remove_dir_all(&path) {
+            Ok(_) => info_string(format!("Removed old checkpoint {}", path.display())),
+            Err(e) => warning_string(format!("Failed to remove {}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn snapshot(name: &str, len: usize) -> CollectionSnapshot {
+        CollectionSnapshot {
+            name: name.to_string(),
+            kind: CollectionKind::BTreeMap,
+            pairs: (0..len)
+                .map(|i| (format!("{:06}", i), ValueObject::IntData(i as isize)))
+                .collect(),
+            expiries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_roundtrip_through_manifest() {
+        let dir = TempDir::new().unwrap();
+        let checkpoint_dir = dir.path().display().to_string();
+        let collections = vec![snapshot("big/one", CHUNK_RECORDS + 5), snapshot("empty", 0)];
+
+        let manifest = write_checkpoint(&checkpoint_dir, 3, 2, 40, &collections).unwrap();
+        assert_eq!(manifest.get_record_count(), (CHUNK_RECORDS + 5) as u64);
+        assert!(!staging_path(&checkpoint_dir, 3).exists());
+
+        let restored = read_checkpoint(&checkpoint_dir, 3).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].name, "big/one");
+        assert_eq!(restored[0].kind, CollectionKind::BTreeMap);
+        assert_eq!(restored[0].pairs.len(), CHUNK_RECORDS + 5);
+        assert_eq!(restored[1].name, "empty");
+        assert!(restored[1].pairs.is_empty());
+        assert_eq!(
+            read_manifest(&checkpoint_dir, 3)
+                .unwrap()
+                .get_checkpoint_lsn(),
+            40
+        );
+    }
+
+    #[test]
+    fn test_corrupt_chunk_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let checkpoint_dir = dir.path().display().to_string();
+        write_checkpoint(&checkpoint_dir, 1, 1, 10, &[snapshot("col", 10)]).unwrap();
+
+        let chunk = checkpoint_path(&checkpoint_dir, 1).join("0_0.lqlpage");
+        let mut bytes = fs::read(&chunk).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&chunk, bytes).unwrap();
+
+        let err = read_checkpoint(&checkpoint_dir, 1).err().unwrap();
+        assert!(err.contains("Checksum mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn test_unfinished_checkpoint_is_ignored_and_cleaned_up() {
+        let dir = TempDir::new().unwrap();
+        let checkpoint_dir = dir.path().display().to_string();
+        write_checkpoint(&checkpoint_dir, 1, 1, 10, &[snapshot("col", 10)]).unwrap();
+        write_checkpoint(&checkpoint_dir, 2, 2, 20, &[snapshot("col", 20)]).unwrap();
+        // A crash while writing checkpoint 3 leaves only its staging directory
+        fs::create_dir_all(staging_path(&checkpoint_dir, 3)).unwrap();
+        fs::create_dir_all(dir.path().join("persisted_collection")).unwrap();
+
+        remove_old_checkpoints(&checkpoint_dir, 2);
+        assert!(!checkpoint_path(&checkpoint_dir, 1).exists());
+        assert!(!staging_path(&checkpoint_dir, 3).exists());
+        assert!(dir.path().join("persisted_collection").exists());
+        assert_eq!(
+            read_checkpoint(&checkpoint_dir, 2).unwrap()[0].pairs.len(),
+            20
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+use crate::error::LokiError;
+use crate::loki_kv::control::{ControlFile, ControlFileOptions};
+use crate::loki_kv::wal::WALSyncMode;
+
+// Data directory used when none is given, relative to the working directory
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+// Where and how `LokiKV::open` keeps a database. The control file, WAL and
+// checkpoints all live inside data_dir, which is created on first open.
+#[derive(Debug, Clone)]
+pub struct Config {
+    data_dir: PathBuf,
+    // An existing control file to use instead of the one in data_dir, its
+    // WAL and checkpoint directories can be anywhere
+    control_file: Option<PathBuf>,
+    // Keeps whatever the control file says when not set
+    wal_sync_mode: Option<WALSyncMode>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new(DEFAULT_DATA_DIR)
+    }
+}
+
+impl Config {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Config {
+            data_dir: data_dir.into(),
+            control_file: None,
+            wal_sync_mode: None,
+        }
+    }
+
+    pub fn with_control_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control_file = Some(path.into());
+        self
+    }
+
+    pub fn with_wal_sync_mode(mut self, mode: WALSyncMode) -> Self {
+        self.wal_sync_mode = Some(mode);
+        self
+    }
+
+    pub fn get_data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn get_control_file_path(&self) -> PathBuf {
+        match &self.control_file {
+            Some(path) => path.clone(),
+            None => self.data_dir.join("control.toml"),
+        }
+    }
+
+    // Path of the control file, the one in data_dir is written with the
+    // defaults the first time. A control file given explicitly has to exist.
+    pub(crate) fn prepare_control_file(&self) -> Result<String, LokiError> {
+        if let Some(path) = &self.control_file {
+            let path = path.display().to_string();
+            let control_file =
+                ControlFile::read_from_file_path(path.clone()).map_err(LokiError::IoError)?;
+            return self.apply_wal_sync_mode(control_file).map(|_| path);
+        }
+        create_dir_all(&self.data_dir).map_err(|e| {
+            LokiError::IoError(format!(
+                "Failed to create data directory {}: {}",
+                self.data_dir.display(),
+                e
+            ))
+        })?;
+        let path = self.get_control_file_path().display().to_string();
+        let control_file = match self.get_control_file_path().exists() {
+            true => ControlFile::read_from_file_path(path.clone()),
+            false => ControlFile::write(ControlFileOptions::new(
+                path.clone(),
+                self.data_dir.join("checkpoints").display().to_string(),
+                self.data_dir.join("wal").display().to_string(),
+            )),
+        }
+        .map_err(LokiError::IoError)?;
+        self.apply_wal_sync_mode(control_file)?;
+        Ok(path)
+    }
+
+    fn apply_wal_sync_mode(&self, mut control_file: ControlFile) -> Result<(), LokiError> {
+        match self.wal_sync_mode {
+            Some(mode) if control_file.get_wal_sync_mode() != mode => control_file
+                .set_wal_sync_mode(mode)
+                .map_err(LokiError::IoError),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::control::write_test_control_file;
+    use crate::loki_kv::loki_kv::{CollectionKind, LokiKV, ValueObject, DEFAULT_COLLECTION};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_open_creates_and_reopens_data_dir() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::new(dir.path().join("db")).with_wal_sync_mode(WALSyncMode::Always);
+        {
+            let mut db = LokiKV::open(config.clone()).unwrap();
+            db.create_collection("users", CollectionKind::BTreeMap)
+                .unwrap();
+            db.put("users", "ann", ValueObject::IntData(7)).unwrap();
+            db.sync().unwrap();
+        }
+
+        let db = LokiKV::open(config).unwrap();
+        assert_eq!(
+            db.get_collection_kind("users"),
+            Some(CollectionKind::BTreeMap)
+        );
+        assert!(matches!(
+            db.get("users", "ann"),
+            Ok(ValueObject::IntData(7))
+        ));
+        assert_eq!(
+            db.get_control_file().get_wal_sync_mode(),
+            WALSyncMode::Always
+        );
+    }
+
+    #[test]
+    fn test_explicit_control_file_must_exist() {
+        let dir = TempDir::new().unwrap();
+        let missing = Config::default().with_control_file(dir.path().join("missing.toml"));
+        assert!(LokiKV::open(missing).is_err());
+        assert!(!dir.path().join("data").exists());
+
+        let control_file_path = write_test_control_file(dir.path());
+        let config = Config::new(dir.path().join("unused")).with_control_file(&control_file_path);
+        let mut db = LokiKV::open(config).unwrap();
+        db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+            .unwrap();
+        assert!(dir.path().join("wal").exists());
+        assert!(!dir.path().join("unused").exists());
+    }
+}
@@ -1,11 +1,11 @@
-use bincode;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{create_dir_all, File},
+    fs::{create_dir_all, rename, File},
     io::{Read, Write},
     path::Path,
 };
 
+use crate::loki_kv::wal::WALSyncMode;
 use crate::utils::info_string;
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,6 +23,58 @@ pub struct ControlFile {
     checkpoint_timer_interval: Option<u64>,
     paxos_timer_interval: Option<u64>,
     gossip_timeout: Option<u64>,
+    wal_sync_mode: Option<WALSyncMode>,
+    wal_sync_interval_ms: Option<u64>,
+    // How often expired keys are removed in the background
+    expiry_sweep_interval_ms: Option<u64>,
+    // LSN of the last WAL record covered by the last checkpoint
+    last_checkpoint_lsn: Option<u64>,
+    // Address of the RESP listener for Redis clients, off when not set
+    resp_addr: Option<String>,
+    // Location this control file was read from / written to, so that
+    // updates land back in the same file
+    #[serde(skip)]
+    file_path: String,
+}
+
+// Settings of a new control file, see `ControlFile::write`
+pub struct ControlFileOptions {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub last_wal_timeline: u64,
+    pub last_checkpoint_id: u64,
+    pub checkpoint_directory_path: String,
+    pub wal_directory_path: String,
+    pub send_addr: Option<String>,
+    pub consume_addr: Option<String>,
+    pub checkpoint_timer_interval: Option<u64>,
+    pub paxos_timer_interval: Option<u64>,
+    pub gossip_timeout: Option<u64>,
+}
+
+impl ControlFileOptions {
+    // A control file at path for a fresh database, listening on localhost:8765
+    pub fn new(
+        path: String,
+        checkpoint_directory_path: String,
+        wal_directory_path: String,
+    ) -> Self {
+        ControlFileOptions {
+            host: "localhost".to_string(),
+            port: 8765,
+            path,
+            last_wal_timeline: 0,
+            last_checkpoint_id: 0,
+            checkpoint_directory_path,
+            wal_directory_path,
+            send_addr: None,
+            consume_addr: None,
+            checkpoint_timer_interval: None,
+            paxos_timer_interval: None,
+            gossip_timeout: None,
+        }
+    }
 }
 
 impl ControlFile {
@@ -39,6 +91,18 @@ impl ControlFile {
         self.last_wal_timeline + 1
     }
 
+    pub fn get_last_checkpoint_id(&self) -> u64 {
+        self.last_checkpoint_id
+    }
+
+    pub fn get_last_wal_timeline(&self) -> u64 {
+        self.last_wal_timeline
+    }
+
+    pub fn get_last_checkpoint_lsn(&self) -> u64 {
+        self.last_checkpoint_lsn.unwrap_or(0)
+    }
+
     pub fn get_send_addr(&self) -> &str {
         &self.send_addr
     }
@@ -84,6 +148,27 @@ impl ControlFile {
         }
     }
 
+    pub fn get_wal_sync_mode(&self) -> WALSyncMode {
+        self.wal_sync_mode.unwrap_or(WALSyncMode::GroupCommit)
+    }
+
+    pub fn get_wal_sync_interval_ms(&self) -> u64 {
+        self.wal_sync_interval_ms.unwrap_or(1000)
+    }
+
+    pub fn get_expiry_sweep_interval_ms(&self) -> u64 {
+        self.expiry_sweep_interval_ms.unwrap_or(1000)
+    }
+
+    pub fn get_resp_addr(&self) -> Option<&str> {
+        self.resp_addr.as_deref()
+    }
+
+    pub fn set_wal_sync_mode(&mut self, mode: WALSyncMode) -> Result<(), String> {
+        self.wal_sync_mode = Some(mode);
+        self.update()
+    }
+
     pub fn set_current_leader_identifier(&mut self, current_leader_value: u64) {
         self.current_leader_value = Some(current_leader_value.clone());
     }
@@ -93,27 +178,29 @@ impl ControlFile {
     }
 
     pub fn is_leader(&self) -> bool {
-        if self.self_identifier.is_some() && self.current_leader_value.is_some() {
-            if self.self_identifier.unwrap() == self.current_leader_value.unwrap() {
-                return true;
-            }
+        if self.self_identifier.is_some()
+            && self.current_leader_value.is_some()
+            && self.self_identifier.unwrap() == self.current_leader_value.unwrap()
+        {
+            return true;
         }
         return false;
     }
-    pub fn write(
-        host: String,
-        port: u16,
-        path: String,
-        last_wal_timeline: u64,
-        last_checkpoint_id: u64,
-        checkpoint_directory_path: String,
-        wal_directory_path: String,
-        send_addr: Option<String>,
-        consume_addr: Option<String>,
-        checkpoint_timer_interval: Option<u64>,
-        paxos_timer_interval: Option<u64>,
-        gossip_timeout: Option<u64>,
-    ) -> Result<ControlFile, String> {
+    pub fn write(options: ControlFileOptions) -> Result<ControlFile, String> {
+        let ControlFileOptions {
+            host,
+            port,
+            path,
+            last_wal_timeline,
+            last_checkpoint_id,
+            checkpoint_directory_path,
+            wal_directory_path,
+            send_addr,
+            consume_addr,
+            checkpoint_timer_interval,
+            paxos_timer_interval,
+            gossip_timeout,
+        } = options;
         // Create the WAL and checkpoint directories
         let wal_dir = Path::new(&wal_directory_path);
         let checkpoint_dir = Path::new(&checkpoint_directory_path);
@@ -160,6 +247,12 @@ impl ControlFile {
             checkpoint_timer_interval,
             paxos_timer_interval,
             gossip_timeout,
+            wal_sync_mode: None,
+            wal_sync_interval_ms: None,
+            expiry_sweep_interval_ms: None,
+            last_checkpoint_lsn: None,
+            resp_addr: None,
+            file_path: path.clone(),
         };
 
         // Take lock on control file
@@ -168,7 +261,6 @@ impl ControlFile {
         match File::create(&path) {
             Ok(mut file) => {
                 let toml_string = toml::to_string(&ctrl_file).unwrap();
-                println!("{}", toml_string);
                 file.write_all(toml_string.as_bytes()).unwrap();
                 Ok(ctrl_file)
             }
@@ -178,35 +270,58 @@ impl ControlFile {
 
     pub fn read_from_file_path(path_string: String) -> Result<ControlFile, String> {
         let path = Path::new(path_string.as_str());
-        println!("{:?}", path);
         match File::open(&path) {
             Ok(mut file) => {
                 // take a lock on the file
                 let mut buffer = String::new();
-                file.read_to_string(&mut buffer).unwrap();
-                let op = toml::from_str::<ControlFile>(&buffer).unwrap();
+                file.read_to_string(&mut buffer)
+                    .map_err(|e| format!("Failed to read control file: {}", e))?;
+                let mut op = toml::from_str::<ControlFile>(&buffer)
+                    .map_err(|e| format!("Failed to parse control file: {}", e))?;
+                op.file_path = path_string.clone();
                 return Ok(op);
             }
             Err(err) => Err(format!("Failed to open file: {}", err)),
         }
     }
 
-    pub fn set_new_params(&mut self, checkpoint_id: u64) {
-        self.last_wal_timeline += 1;
+    // Records that `sealed_timeline` (and everything before it, up to
+    // `checkpoint_lsn`) is covered by checkpoint `checkpoint_id`
+    pub fn set_new_params(
+        &mut self,
+        checkpoint_id: u64,
+        sealed_timeline: u64,
+        checkpoint_lsn: u64,
+    ) -> Result<(), String> {
+        self.last_wal_timeline = sealed_timeline;
         self.last_checkpoint_id = checkpoint_id;
-        self.update();
+        self.last_checkpoint_lsn = Some(checkpoint_lsn);
+        self.update()
     }
 
+    // Rewrites the control file through a temporary file and a rename so a
+    // crash never leaves a half written control file behind
     fn update(&mut self) -> Result<(), String> {
-        let path = Path::new(self.get_wal_directory_path());
-        match File::create(&path) {
-            Ok(mut file) => {
-                let toml_string = toml::to_string(&self).unwrap();
-                println!("{}", toml_string);
-                file.write_all(toml_string.as_bytes()).unwrap();
-                Ok(())
-            }
-            Err(err) => return Err(err.to_string()),
-        }
+        let toml_string = toml::to_string(&self).map_err(|e| e.to_string())?;
+        let tmp_path = format!("{}.tmp", self.file_path);
+        let mut file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+        file.write_all(toml_string.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| e.to_string())?;
+        rename(&tmp_path, &self.file_path).map_err(|e| e.to_string())?;
+        Ok(())
     }
 }
+
+// Writes a control file whose WAL and checkpoint directories live inside `dir`
+#[cfg(test)]
+pub fn write_test_control_file(dir: &Path) -> String {
+    let control_file_path = dir.join("control.toml").display().to_string();
+    ControlFile::write(ControlFileOptions::new(
+        control_file_path.clone(),
+        dir.join("checkpoints").display().to_string(),
+        dir.join("wal").display().to_string(),
+    ))
+    .unwrap();
+    control_file_path
+}
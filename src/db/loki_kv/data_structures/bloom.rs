@@ -0,0 +1,139 @@
+use std::f64::consts::LN_2;
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use super::xxhash::hash_pair;
+use crate::error::LokiError;
+
+// Used when BFADD creates the filter
+const DEFAULT_ERROR_RATE: f64 = 0.01;
+const DEFAULT_CAPACITY: u64 = 1000;
+// 128 MB of bits
+const MAX_BITS: u64 = 1 << 30;
+
+// Answers whether an item may have been added, never with a false negative.
+// The false positive rate stays below `error_rate` until more than
+// `capacity` items are added.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+    error_rate: f64,
+    capacity: u64,
+}
+
+impl BloomFilter {
+    pub fn new(error_rate: f64, capacity: u64) -> Result<Self, LokiError> {
+        let num_bits = BloomFilter::bits_needed(error_rate, capacity)?;
+        // k = m / n ln(2) hashes
+        let num_hashes = (num_bits as f64 / capacity as f64 * LN_2)
+            .round()
+            .clamp(1.0, 32.0) as u32;
+        Ok(BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+            error_rate,
+            capacity,
+        })
+    }
+
+    // Size of a filter for `capacity` items, fails for parameters `new`
+    // would reject
+    pub fn bits_needed(error_rate: f64, capacity: u64) -> Result<u64, LokiError> {
+        if !(error_rate > 0.0 && error_rate < 1.0) {
+            return Err(LokiError::OutOfRange(format!(
+                "error rate {} is not between 0 and 1",
+                error_rate
+            )));
+        }
+        if capacity == 0 {
+            return Err(LokiError::OutOfRange(
+                "capacity must be positive".to_string(),
+            ));
+        }
+        // m = -n ln(p) / ln(2)^2 bits
+        let num_bits = (-(capacity as f64) * error_rate.ln() / (LN_2 * LN_2)).ceil();
+        if num_bits > MAX_BITS as f64 {
+            return Err(LokiError::OutOfRange(format!(
+                "a filter for {} items at error rate {} needs more than {} bits",
+                capacity, error_rate, MAX_BITS
+            )));
+        }
+        Ok((num_bits as u64).max(64))
+    }
+
+    // Word and bit mask of every bit the item maps to
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = (usize, u64)> {
+        let (h1, h2) = hash_pair(item);
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| {
+            let position = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            ((position / 64) as usize, 1 << (position % 64))
+        })
+    }
+
+    // Returns false if the item may have been added before
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let mut added = false;
+        for (word, mask) in self.positions(item) {
+            added |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        added
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|(word, mask)| self.bits[word] & mask != 0)
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        BloomFilter::new(DEFAULT_ERROR_RATE, DEFAULT_CAPACITY).unwrap()
+    }
+}
+
+impl Debug for BloomFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BloomFilter")
+            .field("error_rate", &self.error_rate)
+            .field("capacity", &self.capacity)
+            .field("bits", &self.num_bits)
+            .field("hashes", &self.num_hashes)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_false_negatives_and_bounded_false_positives() {
+        let mut filter = BloomFilter::new(0.01, 10_000).unwrap();
+        let added = (0..10_000)
+            .filter(|i| filter.insert(format!("item_{}", i).as_bytes()))
+            .count();
+        // An item only counts as added if some of its bits were still unset
+        assert!(added > 9_900, "{}", added);
+        assert!(!filter.insert(b"item_5"));
+        assert!((0..10_000).all(|i| filter.contains(format!("item_{}", i).as_bytes())));
+
+        let false_positives = (0..10_000)
+            .filter(|i| filter.contains(format!("other_{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 200, "{}", false_positives);
+    }
+
+    #[test]
+    fn test_invalid_sizes_are_rejected() {
+        assert!(BloomFilter::new(0.0, 10).is_err());
+        assert!(BloomFilter::new(1.0, 10).is_err());
+        assert!(BloomFilter::new(0.01, 0).is_err());
+        assert!(BloomFilter::new(0.000001, u64::MAX / 2).is_err());
+    }
+}
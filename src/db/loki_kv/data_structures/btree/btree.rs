@@ -1,53 +1,67 @@
-use crate::loki_kv::{self, loki_kv::ValueObject};
-use std::boxed::Box;
+use crate::loki_kv::loki_kv::ValueObject;
+use imbl::Vector;
+use std::ops::Bound;
 
+// Maximum number of children of a node. Every node other than the root keeps
+// at least MIN_KEYS keys, a node holding MAX_KEYS is full and gets split
+// before an insert goes through it.
 const CAP: usize = 4;
+const MAX_KEYS: usize = CAP - 1;
+const MIN_KEYS: usize = CAP / 2 - 1;
 
-#[derive(Debug)]
+// Values only live in the leaves, inner nodes hold copies of keys to route
+// lookups. Keys below keys[i] are in children[i], the others further right.
+#[derive(Debug, Clone)]
 struct BTreeNode {
     keys: Vec<String>,
+    // Empty for inner nodes
     values: Vec<ValueObject>,
-    num_keys: usize,
-    children: Vec<Option<usize>>,
-    is_leaf: bool,
+    // Empty for leaves, otherwise one more than the number of keys
+    children: Vec<usize>,
+    // Neighbouring leaves in key order, scans follow these instead of going
+    // back up the tree. Always None for inner nodes.
+    to_left: Option<usize>,
     to_right: Option<usize>,
 }
 
-impl Clone for BTreeNode {
-    fn clone(&self) -> Self {
-        BTreeNode {
-            keys: self.keys.clone(),
-            values: self.values.clone(),
-            num_keys: self.num_keys,
-            children: self.children.clone(),
-            is_leaf: self.is_leaf,
-            to_right: self.to_right,
-        }
-    }
-}
-
+// Nodes live in a persistent vector so cloning a tree is cheap and only the
+// nodes touched afterwards get copied
 #[derive(Clone)]
 pub struct BTree {
     root_index: usize,
-    vals: Vec<ValueObject>,
-    nodes: Vec<BTreeNode>,
+    nodes: Vector<BTreeNode>,
+    // Slots of merged away nodes, reused by the next split
+    free_nodes: Vec<usize>,
+    len: usize,
 }
 
 impl BTreeNode {
     pub fn new() -> Self {
         BTreeNode {
-            keys: vec!["".to_string(); CAP - 1],
-            values: vec![ValueObject::Phantom; CAP - 1],
-            num_keys: 0,
-            children: vec![None; CAP],
-            is_leaf: true,
+            keys: Vec::with_capacity(MAX_KEYS),
+            values: Vec::new(),
+            children: Vec::new(),
+            to_left: None,
             to_right: None,
         }
     }
 
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+
     pub fn is_node_full(&self) -> bool {
-        // println!("{} --> {}", self.num_keys, CAP-1);
-        self.num_keys == CAP - 1
+        self.keys.len() == MAX_KEYS
+    }
+
+    // Ok with the position of the key, or Err where it would be inserted
+    fn find(&self, key: &str) -> Result<usize, usize> {
+        self.keys.binary_search_by(|k| k.as_str().cmp(key))
+    }
+
+    // Position of the child of an inner node that leads to key
+    fn child_pos(&self, key: &str) -> usize {
+        self.keys.partition_point(|k| k.as_str() <= key)
     }
 }
 
@@ -55,205 +69,474 @@ impl BTree {
     pub fn new() -> Self {
         let mut tree = BTree {
             root_index: 0,
-            vals: Vec::new(),
-            nodes: Vec::new(),
+            nodes: Vector::new(),
+            free_nodes: Vec::new(),
+            len: 0,
         };
-        tree.root_index = tree.add_node(BTreeNode::new()).unwrap();
+        tree.root_index = tree.add_node(BTreeNode::new());
         tree
     }
 
-    pub fn get_node(&self, idx: usize) -> BTreeNode {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn get_node(&self, idx: usize) -> BTreeNode {
         self.nodes[idx].clone()
     }
 
-    pub fn get_node_ref(&self, idx: usize) -> &BTreeNode {
+    fn get_node_ref(&self, idx: usize) -> &BTreeNode {
         &self.nodes[idx]
     }
 
-    pub fn add_node(&mut self, node: BTreeNode) -> Option<usize> {
-        self.nodes.push(node);
-        Some(self.nodes.len() - 1)
+    fn add_node(&mut self, node: BTreeNode) -> usize {
+        match self.free_nodes.pop() {
+            Some(idx) => {
+                self.replace_node(node, idx);
+                idx
+            }
+            None => {
+                self.nodes.push_back(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn free_node(&mut self, idx: usize) {
+        self.replace_node(BTreeNode::new(), idx);
+        self.free_nodes.push(idx);
     }
 
-    pub fn is_node_full(&mut self, node_idx: usize) -> bool {
-        self.get_node(node_idx).is_node_full()
+    fn replace_node(&mut self, node: BTreeNode, idx: usize) {
+        self.nodes.set(idx, node);
     }
 
-    pub fn replace_node(&mut self, node: BTreeNode, idx: usize) {
-        self.nodes[idx] = node;
+    // Makes two leaves neighbours, either side can be the end of the list
+    fn link_leaves(&mut self, left: Option<usize>, right: Option<usize>) {
+        if let Some(node) = left.and_then(|idx| self.nodes.get_mut(idx)) {
+            node.to_right = right;
+        }
+        if let Some(node) = right.and_then(|idx| self.nodes.get_mut(idx)) {
+            node.to_left = left;
+        }
     }
 
-    pub fn split_child(&mut self, node_idx: usize, child_idx: usize) {
+    // Splits the full child at `child_pos`. A leaf copies the first key of
+    // its right half into the parent, an inner node moves its middle key up.
+    fn split_child(&mut self, node_idx: usize, child_pos: usize) {
         let mut parent = self.get_node(node_idx);
-        let child_node_idx = parent.children[child_idx].unwrap();
-        let mut child = self.get_node(child_node_idx);
+        let child_idx = parent.children[child_pos];
+        let mut child = self.get_node(child_idx);
 
+        let mid = MAX_KEYS / 2;
         let mut new_node = BTreeNode::new();
-        new_node.is_leaf = child.is_leaf;
+        let is_leaf = child.is_leaf();
+        let separator = if is_leaf {
+            new_node.keys = child.keys.split_off(mid);
+            new_node.values = child.values.split_off(mid);
+            new_node.keys[0].clone()
+        } else {
+            new_node.keys = child.keys.split_off(mid + 1);
+            new_node.children = child.children.split_off(mid + 1);
+            child.keys.pop().unwrap()
+        };
+        let next_leaf = child.to_right;
 
-        let mid = CAP / 2;
+        let new_node_idx = self.add_node(new_node);
+        parent.keys.insert(child_pos, separator);
+        parent.children.insert(child_pos + 1, new_node_idx);
 
-        // Move keys to new node
-        for i in 0..(mid - 1) {
-            new_node.keys[i] = child.keys[i + mid].clone();
-            new_node.values[i] = child.values[mid + i].clone();
-            child.keys[i + mid] = "".to_string();
+        self.replace_node(child, child_idx);
+        self.replace_node(parent, node_idx);
+        if is_leaf {
+            self.link_leaves(Some(child_idx), Some(new_node_idx));
+            self.link_leaves(Some(new_node_idx), next_leaf);
         }
+    }
 
-        // Move children if internal node
-        if !child.is_leaf {
-            for i in 0..mid {
-                new_node.children[i] = child.children[i + mid];
-                child.children[i + mid] = None;
+    fn insert_nonfull(&mut self, mut node_idx: usize, key: String, value: ValueObject) {
+        loop {
+            let node = self.get_node_ref(node_idx);
+            if node.is_leaf() {
+                let pos = match node.find(&key) {
+                    Ok(_) => unreachable!("insert_nonfull is only called for new keys"),
+                    Err(pos) => pos,
+                };
+                let mut node = self.get_node(node_idx);
+                node.keys.insert(pos, key);
+                node.values.insert(pos, value);
+                self.replace_node(node, node_idx);
+                return;
             }
-        }
-
-        new_node.num_keys = mid - 1;
-        child.num_keys = mid;
 
-        // Insert new node
-        let new_node_idx = self.add_node(new_node).unwrap();
-        child.to_right = Some(new_node_idx);
+            let mut pos = node.child_pos(&key);
+            if self.get_node_ref(node.children[pos]).is_node_full() {
+                self.split_child(node_idx, pos);
+                if self.get_node_ref(node_idx).keys[pos] <= key {
+                    pos += 1;
+                }
+            }
+            node_idx = self.get_node_ref(node_idx).children[pos];
+        }
+    }
 
-        // Update parent
-        for i in (child_idx..parent.num_keys).rev() {
-            parent.keys[i + 1] = parent.keys[i].clone();
-            parent.values[i + 1] = parent.values[i].clone();
-            parent.children[i + 2] = parent.children[i + 1];
+    // Inserts or replaces the value at key, returns the previous value
+    pub fn insert(&mut self, key: String, value: ValueObject) -> Option<ValueObject> {
+        if let Some((node_idx, pos)) = self.locate(&key) {
+            let mut node = self.get_node(node_idx);
+            let old = std::mem::replace(&mut node.values[pos], value);
+            self.replace_node(node, node_idx);
+            return Some(old);
         }
 
-        parent.keys[child_idx] = child.keys[mid].clone();
-        parent.values[child_idx] = child.values[mid].clone();
-        parent.children[child_idx + 1] = Some(new_node_idx);
-        parent.num_keys += 1;
+        if self.get_node_ref(self.root_index).is_node_full() {
+            let mut new_root = BTreeNode::new();
+            new_root.children.push(self.root_index);
+            self.root_index = self.add_node(new_root);
+            self.split_child(self.root_index, 0);
+        }
+        self.insert_nonfull(self.root_index, key, value);
+        self.len += 1;
+        None
+    }
 
-        child.keys[mid] = "".to_string();
-        child.values[mid] = ValueObject::Phantom;
+    // Removes the key, returns the value it held
+    pub fn remove(&mut self, key: &str) -> Option<ValueObject> {
+        let removed = self.remove_from(self.root_index, key);
 
-        self.replace_node(child, child_node_idx);
-        self.replace_node(parent, node_idx);
+        // The root lost its last key to a merge, its only child takes over
+        let root = self.get_node_ref(self.root_index);
+        if root.keys.is_empty() && !root.is_leaf() {
+            let old_root = self.root_index;
+            self.root_index = root.children[0];
+            self.free_node(old_root);
+        }
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
     }
 
-    pub fn insert_nonfull(&mut self, node_idx: usize, key: String, value: ValueObject) {
-        let mut node = self.get_node(node_idx);
-
-        if node.is_leaf {
-            let mut pos = node.num_keys;
-            while pos > 0 && node.keys[pos - 1] > key {
-                node.keys[pos] = node.keys[pos - 1].clone();
-                node.values[pos] = node.values[pos - 1].clone();
-                pos -= 1;
-            }
-            // println!("{:?}", node.values);
-            node.keys[pos] = key;
-            node.values[pos] = value;
-            node.num_keys += 1;
-            self.replace_node(node, node_idx);
-        } else {
-            let mut child_idx = node.num_keys;
-            while child_idx > 0 && node.keys[child_idx - 1] > key {
-                child_idx -= 1;
+    // Every node on the way down holds more than MIN_KEYS keys when we step
+    // into it, so removing from a leaf never leaves it underfull. Keys left
+    // behind in inner nodes still route correctly and are not touched.
+    fn remove_from(&mut self, mut node_idx: usize, key: &str) -> Option<ValueObject> {
+        loop {
+            let node = self.get_node_ref(node_idx);
+            if node.is_leaf() {
+                let pos = node.find(key).ok()?;
+                let mut node = self.get_node(node_idx);
+                node.keys.remove(pos);
+                let value = node.values.remove(pos);
+                self.replace_node(node, node_idx);
+                return Some(value);
             }
 
-            let next_child_idx = node.children[child_idx].unwrap();
-            if self.is_node_full(next_child_idx) {
-                self.split_child(node_idx, child_idx);
-                node = self.get_node(node_idx);
-                if node.keys[child_idx] < key {
-                    child_idx += 1;
-                }
+            let mut pos = node.child_pos(key);
+            if self.get_node_ref(node.children[pos]).keys.len() == MIN_KEYS {
+                pos = self.fill_child(node_idx, pos);
             }
-            self.insert_nonfull(node.children[child_idx].unwrap(), key, value);
+            node_idx = self.get_node_ref(node_idx).children[pos];
         }
     }
 
-    pub fn insert(&mut self, key: String, value: ValueObject) {
-        if self.is_node_full(self.root_index) {
-            let new_root = BTreeNode {
-                keys: vec!["".to_string(); CAP - 1],
-                values: vec![ValueObject::Phantom; CAP - 1],
-                num_keys: 0,
-                children: {
-                    let mut v = vec![None; CAP];
-                    v[0] = Some(self.root_index);
-                    v
-                },
-                is_leaf: false,
-                to_right: None,
-            };
-            let new_root_idx = self.add_node(new_root).unwrap();
-            self.root_index = new_root_idx;
-            self.split_child(new_root_idx, 0);
+    // Tops up a child holding MIN_KEYS keys by borrowing from a sibling or
+    // merging with one. Returns the position of the child afterwards.
+    fn fill_child(&mut self, node_idx: usize, pos: usize) -> usize {
+        let node = self.get_node_ref(node_idx);
+        let num_keys = node.keys.len();
+        if pos > 0 && self.get_node_ref(node.children[pos - 1]).keys.len() > MIN_KEYS {
+            self.borrow_from_prev(node_idx, pos);
+            pos
+        } else if pos < num_keys && self.get_node_ref(node.children[pos + 1]).keys.len() > MIN_KEYS
+        {
+            self.borrow_from_next(node_idx, pos);
+            pos
+        } else if pos < num_keys {
+            self.merge_children(node_idx, pos);
+            pos
+        } else {
+            self.merge_children(node_idx, pos - 1);
+            pos - 1
         }
-        self.insert_nonfull(self.root_index, key, value);
     }
 
-    pub fn print_tree(&self) -> String {
-        let mut result = String::new();
-        self.display_tree(self.root_index, &mut result);
-        result
+    fn borrow_from_prev(&mut self, node_idx: usize, pos: usize) {
+        let mut parent = self.get_node(node_idx);
+        let mut child = self.get_node(parent.children[pos]);
+        let mut sibling = self.get_node(parent.children[pos - 1]);
+
+        if child.is_leaf() {
+            child.keys.insert(0, sibling.keys.pop().unwrap());
+            child.values.insert(0, sibling.values.pop().unwrap());
+            parent.keys[pos - 1] = child.keys[0].clone();
+        } else {
+            let separator =
+                std::mem::replace(&mut parent.keys[pos - 1], sibling.keys.pop().unwrap());
+            child.keys.insert(0, separator);
+            child.children.insert(0, sibling.children.pop().unwrap());
+        }
+
+        self.replace_node(child, parent.children[pos]);
+        self.replace_node(sibling, parent.children[pos - 1]);
+        self.replace_node(parent, node_idx);
     }
 
-    pub fn display_tree(&self, node_idx: usize, result: &mut String) {
-        let node = self.get_node(node_idx);
+    fn borrow_from_next(&mut self, node_idx: usize, pos: usize) {
+        let mut parent = self.get_node(node_idx);
+        let mut child = self.get_node(parent.children[pos]);
+        let mut sibling = self.get_node(parent.children[pos + 1]);
 
-        // Append key -> value pairs
-        for i in 0..node.num_keys {
-            result.push_str(&format!("{} -> {:?}\n", node.keys[i], node.values[i]));
+        if child.is_leaf() {
+            child.keys.push(sibling.keys.remove(0));
+            child.values.push(sibling.values.remove(0));
+            parent.keys[pos] = sibling.keys[0].clone();
+        } else {
+            let separator = std::mem::replace(&mut parent.keys[pos], sibling.keys.remove(0));
+            child.keys.push(separator);
+            child.children.push(sibling.children.remove(0));
         }
 
-        // Recursively traverse child nodes if not a leaf
-        if !node.is_leaf {
-            for i in 0..=node.num_keys {
-                if let Some(child_idx) = node.children[i] {
-                    self.display_tree(child_idx, result);
-                }
-            }
-        }
+        self.replace_node(child, parent.children[pos]);
+        self.replace_node(sibling, parent.children[pos + 1]);
+        self.replace_node(parent, node_idx);
     }
 
-    pub fn generate_pairs(&self, node_idx: usize, result: &mut Vec<(String, ValueObject)>) {
-        let node = self.get_node(node_idx);
+    // Appends the child on the right of the key at `pos` to the one on its
+    // left. Inner nodes pull the key down, leaves just drop it.
+    fn merge_children(&mut self, node_idx: usize, pos: usize) {
+        let mut parent = self.get_node(node_idx);
+        let left_idx = parent.children[pos];
+        let right_idx = parent.children.remove(pos + 1);
+        let separator = parent.keys.remove(pos);
+        let mut left = self.get_node(left_idx);
+        let right = self.get_node(right_idx);
+
+        let is_leaf = left.is_leaf();
+        if !is_leaf {
+            left.keys.push(separator);
+        }
+        left.keys.extend(right.keys);
+        left.values.extend(right.values);
+        left.children.extend(right.children);
 
-        // Append key -> value pairs
-        for i in 0..node.num_keys {
-            result.push((node.keys[i].clone(), node.values[i].clone()));
+        self.replace_node(left, left_idx);
+        self.replace_node(parent, node_idx);
+        self.free_node(right_idx);
+        if is_leaf {
+            self.link_leaves(Some(left_idx), right.to_right);
         }
+    }
 
-        // Recursively traverse child nodes if not a leaf
-        if !node.is_leaf {
-            for i in 0..=node.num_keys {
-                if let Some(child_idx) = node.children[i] {
-                    self.generate_pairs(child_idx, result);
-                }
+    fn locate(&self, key: &str) -> Option<(usize, usize)> {
+        let mut node_idx = self.root_index;
+        loop {
+            let node = self.get_node_ref(node_idx);
+            if node.is_leaf() {
+                return node.find(key).ok().map(|pos| (node_idx, pos));
             }
+            node_idx = node.children[node.child_pos(key)];
         }
     }
 
-    fn search_at_idx(&self, idx: usize, key: String) -> Option<&ValueObject> {
-        let mut i = 0;
-        let root = self.get_node_ref(idx);
-        while i < root.num_keys && key > root.keys[i] {
-            i += 1;
+    pub fn search(&self, key: &str) -> Option<&ValueObject> {
+        self.locate(key)
+            .map(|(node_idx, pos)| &self.get_node_ref(node_idx).values[pos])
+    }
+
+    pub fn search_mut(&mut self, key: &str) -> Option<&mut ValueObject> {
+        let (node_idx, pos) = self.locate(key)?;
+        self.nodes
+            .get_mut(node_idx)
+            .map(|node| &mut node.values[pos])
+    }
+
+    pub fn print_tree(&self) -> String {
+        let mut result = String::new();
+        for (key, value) in self.generate_pairs() {
+            result.push_str(&format!("{} -> {:?}\n", key, value));
         }
+        result
+    }
 
-        if i <= root.num_keys && key == root.keys[i] {
-            return Some(&root.values[i]);
-        } else if root.is_leaf {
-            return None;
-        } else {
-            return self.search_at_idx(root.children[i].unwrap(), key);
+    // Every key-value pair in key order
+    pub fn generate_pairs(&self) -> Vec<(String, ValueObject)> {
+        self.range(Bound::Unbounded, Bound::Unbounded, false)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    // Pairs between the bounds, in descending order when `reverse` is set.
+    // Only the path to the leaf with the first key is walked, the iterator
+    // moves along the leaf links from there.
+    pub fn range(&self, start: Bound<&str>, end: Bound<&str>, reverse: bool) -> Range<'_> {
+        let start = start.map(str::to_string);
+        let end = end.map(str::to_string);
+        let mut node_idx = self.root_index;
+        loop {
+            let node = self.get_node_ref(node_idx);
+            // Keys before this position are below the range going forward,
+            // or the ones still inside it going backward
+            let pos = if reverse {
+                node.keys.partition_point(|k| !past_end(k, &end))
+            } else {
+                node.keys.partition_point(|k| before_start(k, &start))
+            };
+            if node.is_leaf() {
+                return Range {
+                    tree: self,
+                    leaf: Some(node_idx),
+                    pos,
+                    start,
+                    end,
+                    reverse,
+                };
+            }
+            node_idx = node.children[pos];
         }
     }
+}
+
+impl Default for BTree {
+    fn default() -> Self {
+        BTree::new()
+    }
+}
+
+fn before_start(key: &str, start: &Bound<String>) -> bool {
+    match start {
+        Bound::Included(s) => key < s.as_str(),
+        Bound::Excluded(s) => key <= s.as_str(),
+        Bound::Unbounded => false,
+    }
+}
+
+fn past_end(key: &str, end: &Bound<String>) -> bool {
+    match end {
+        Bound::Included(e) => key > e.as_str(),
+        Bound::Excluded(e) => key >= e.as_str(),
+        Bound::Unbounded => false,
+    }
+}
+
+// Walk over the leaves between two bounds. pos is the next key to visit in
+// the current leaf going forward, or the one after it going backward.
+pub struct Range<'a> {
+    tree: &'a BTree,
+    leaf: Option<usize>,
+    pos: usize,
+    start: Bound<String>,
+    end: Bound<String>,
+    reverse: bool,
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = (&'a String, &'a ValueObject);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = self.tree;
+        loop {
+            let node = tree.get_node_ref(self.leaf?);
+            let key_pos = if self.reverse {
+                match self.pos.checked_sub(1) {
+                    Some(key_pos) => key_pos,
+                    None => {
+                        self.leaf = node.to_left;
+                        self.pos = self.leaf.map_or(0, |idx| tree.get_node_ref(idx).keys.len());
+                        continue;
+                    }
+                }
+            } else if self.pos < node.keys.len() {
+                self.pos
+            } else {
+                self.leaf = node.to_right;
+                self.pos = 0;
+                continue;
+            };
 
-    pub fn search(&self, key: String) -> Option<&ValueObject> {
-        return self.search_at_idx(self.root_index, key);
+            let key = &node.keys[key_pos];
+            let done = if self.reverse {
+                before_start(key, &self.start)
+            } else {
+                past_end(key, &self.end)
+            };
+            if done {
+                self.leaf = None;
+                return None;
+            }
+            self.pos = if self.reverse { key_pos } else { key_pos + 1 };
+            return Some((key, &node.values[key_pos]));
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    // Checks key order, node fill, that the keys of every subtree sit between
+    // the keys routing to it and that every leaf is at the same depth. Leaves
+    // are collected left to right. Returns the depth of the leaves below
+    // node_idx.
+    fn check_node(
+        tree: &BTree,
+        node_idx: usize,
+        bounds: (Option<&String>, Option<&String>),
+        leaves: &mut Vec<usize>,
+    ) -> usize {
+        let node = tree.get_node_ref(node_idx);
+        assert!(node.keys.len() <= MAX_KEYS);
+        assert!(node_idx == tree.root_index || node.keys.len() >= MIN_KEYS);
+        assert!(node.keys.windows(2).all(|w| w[0] < w[1]));
+        assert!(node
+            .keys
+            .iter()
+            .all(|k| bounds.0.map_or(true, |low| k >= low)));
+        assert!(node
+            .keys
+            .iter()
+            .all(|k| bounds.1.map_or(true, |high| k < high)));
+        if node.is_leaf() {
+            assert_eq!(node.keys.len(), node.values.len());
+            leaves.push(node_idx);
+            return 0;
+        }
+        assert!(node.values.is_empty());
+        assert!(node.to_left.is_none() && node.to_right.is_none());
+        assert_eq!(node.children.len(), node.keys.len() + 1);
+        let depths: Vec<usize> = (0..node.children.len())
+            .map(|i| {
+                let low = if i == 0 {
+                    bounds.0
+                } else {
+                    node.keys.get(i - 1)
+                };
+                let high = node.keys.get(i).or(bounds.1);
+                check_node(tree, node.children[i], (low, high), leaves)
+            })
+            .collect();
+        assert!(depths.windows(2).all(|w| w[0] == w[1]));
+        depths[0] + 1
+    }
+
+    // Also checks that the leaf links follow the leaves in key order
+    fn check_tree(tree: &BTree) {
+        let mut leaves = Vec::new();
+        check_node(tree, tree.root_index, (None, None), &mut leaves);
+        for (i, leaf) in leaves.iter().enumerate() {
+            let node = tree.get_node_ref(*leaf);
+            let prev = i.checked_sub(1).map(|i| leaves[i]);
+            assert_eq!(node.to_left, prev);
+            assert_eq!(node.to_right, leaves.get(i + 1).copied());
+        }
+    }
 
     #[test]
     fn try_btree() {
@@ -263,12 +546,132 @@ mod tests {
             "test-1",
         ];
 
-        let mut k = String::new();
-        for item in data {
+        for item in data.iter() {
             tree.insert(item.to_string(), ValueObject::StringData(item.to_string()));
-            let a = tree.print_tree();
-            k += &a;
-            println!("{}", a);
+        }
+        check_tree(&tree);
+        assert_eq!(tree.len(), data.len());
+        let keys: Vec<String> = tree.generate_pairs().into_iter().map(|p| p.0).collect();
+        let mut expected: Vec<String> = data.iter().map(|k| k.to_string()).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+        // Keys past the largest one used to index out of bounds
+        assert!(tree.search("zzz").is_none());
+    }
+
+    #[test]
+    fn test_clone_is_isolated_from_later_inserts() {
+        let mut tree = BTree::new();
+        for i in 1..4 {
+            tree.insert(format!("key{}", i), ValueObject::IntData(i));
+        }
+        let snapshot = tree.clone();
+        tree.insert("key0".to_string(), ValueObject::IntData(0));
+        tree.remove("key2");
+
+        assert!(tree.search("key0").is_some());
+        assert!(snapshot.search("key0").is_none());
+        assert!(matches!(
+            snapshot.search("key2"),
+            Some(ValueObject::IntData(2))
+        ));
+    }
+
+    #[test]
+    fn test_random_operations_match_std_btreemap() {
+        let mut rng = StdRng::seed_from_u64(0x10c1);
+        for _ in 0..20 {
+            let mut tree = BTree::new();
+            let mut model: BTreeMap<String, isize> = BTreeMap::new();
+            for step in 0..2000 {
+                let key = format!("k{:03}", rng.gen_range(0..300));
+                if rng.gen_bool(0.55) {
+                    let old = tree.insert(key.clone(), ValueObject::IntData(step));
+                    assert_eq!(old.is_some(), model.insert(key, step).is_some());
+                } else {
+                    let removed = tree.remove(&key);
+                    match (removed, model.remove(&key)) {
+                        (Some(ValueObject::IntData(a)), Some(b)) => assert_eq!(a, b),
+                        (None, None) => {}
+                        (a, b) => panic!("{} removed {:?}, expected {:?}", key, a, b),
+                    }
+                }
+            }
+
+            check_tree(&tree);
+            assert_eq!(tree.len(), model.len());
+            let pairs: Vec<(String, isize)> = tree
+                .generate_pairs()
+                .into_iter()
+                .map(|(k, v)| match v {
+                    ValueObject::IntData(v) => (k, v),
+                    other => panic!("unexpected value {:?}", other),
+                })
+                .collect();
+            assert_eq!(pairs, model.into_iter().collect::<Vec<_>>());
+
+            // Drain the tree completely, the root has to shrink back to a leaf
+            for (key, _) in pairs {
+                assert!(tree.remove(&key).is_some());
+                assert!(tree.search(&key).is_none());
+            }
+            assert!(tree.is_empty());
+            assert!(tree.get_node_ref(tree.root_index).is_leaf());
+        }
+    }
+
+    #[test]
+    fn test_range_matches_std_btreemap() {
+        let mut rng = StdRng::seed_from_u64(0x5ca9);
+        let mut tree = BTree::new();
+        let mut model: BTreeMap<String, isize> = BTreeMap::new();
+        for step in 0..800 {
+            let key = format!("k{:03}", rng.gen_range(0..400));
+            // Removals merge and rebalance leaves, the links have to follow
+            if rng.gen_bool(0.7) {
+                tree.insert(key.clone(), ValueObject::IntData(step));
+                model.insert(key, step);
+            } else {
+                tree.remove(&key);
+                model.remove(&key);
+            }
+        }
+        check_tree(&tree);
+
+        let random_bound = |rng: &mut StdRng| {
+            let key = format!("k{:03}", rng.gen_range(0..410));
+            match rng.gen_range(0..3) {
+                0 => Bound::Included(key),
+                1 => Bound::Excluded(key),
+                _ => Bound::Unbounded,
+            }
+        };
+        for _ in 0..300 {
+            let start = random_bound(&mut rng);
+            let end = random_bound(&mut rng);
+            let expected: Vec<&String> = model
+                .keys()
+                .filter(|k| !before_start(k, &start) && !past_end(k, &end))
+                .collect();
+            let forward: Vec<&String> = tree
+                .range(
+                    start.as_ref().map(String::as_str),
+                    end.as_ref().map(String::as_str),
+                    false,
+                )
+                .map(|(k, _)| k)
+                .collect();
+            let mut backward: Vec<&String> = tree
+                .range(
+                    start.as_ref().map(String::as_str),
+                    end.as_ref().map(String::as_str),
+                    true,
+                )
+                .map(|(k, _)| k)
+                .collect();
+            backward.reverse();
+            assert_eq!(forward, expected);
+            assert_eq!(backward, expected);
         }
     }
 }
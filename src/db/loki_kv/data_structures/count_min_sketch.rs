@@ -0,0 +1,133 @@
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use super::xxhash::hash_pair;
+use crate::error::LokiError;
+
+// Estimates are at most e / WIDTH of the total count too high, with
+// probability 1 - e^-DEPTH
+const DEFAULT_WIDTH: usize = 1024;
+const DEFAULT_DEPTH: usize = 5;
+
+// Approximate item frequencies. Each of the `depth` rows counts every item in
+// one of `width` counters, an item's estimate is the smallest of its counters
+// so it can only be too high.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CountMinSketch {
+    width: usize,
+    depth: usize,
+    // Row after row
+    counters: Vec<u64>,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> Self {
+        CountMinSketch {
+            width,
+            depth,
+            counters: vec![0; width * depth],
+        }
+    }
+
+    // Index of the item's counter in every row
+    fn cells(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let (h1, h2) = hash_pair(item);
+        let width = self.width;
+        (0..self.depth).map(move |row| {
+            let column = h1.wrapping_add((row as u64).wrapping_mul(h2)) % width as u64;
+            row * width + column as usize
+        })
+    }
+
+    // Adds count to the item and returns its new estimate
+    pub fn increment(&mut self, item: &[u8], count: u64) -> u64 {
+        let mut estimate = u64::MAX;
+        for cell in self.cells(item) {
+            self.counters[cell] = self.counters[cell].saturating_add(count);
+            estimate = estimate.min(self.counters[cell]);
+        }
+        estimate
+    }
+
+    pub fn estimate(&self, item: &[u8]) -> u64 {
+        self.cells(item)
+            .map(|cell| self.counters[cell])
+            .min()
+            .unwrap_or(0)
+    }
+
+    // Adds the counts of other, which must have the same dimensions
+    pub fn merge(&mut self, other: &CountMinSketch) -> Result<(), LokiError> {
+        if (self.width, self.depth) != (other.width, other.depth) {
+            return Err(LokiError::WrongType(format!(
+                "cannot merge a {}x{} sketch into a {}x{} one",
+                other.width, other.depth, self.width, self.depth
+            )));
+        }
+        for (counter, other) in self.counters.iter_mut().zip(&other.counters) {
+            *counter = counter.saturating_add(*other);
+        }
+        Ok(())
+    }
+}
+
+impl Default for CountMinSketch {
+    fn default() -> Self {
+        CountMinSketch::new(DEFAULT_WIDTH, DEFAULT_DEPTH)
+    }
+}
+
+impl Debug for CountMinSketch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CountMinSketch")
+            .field("width", &self.width)
+            .field("depth", &self.depth)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimates_never_undercount() {
+        let mut sketch = CountMinSketch::default();
+        let mut total = 0;
+        for i in 0..5_000u64 {
+            let count = i % 7 + 1;
+            sketch.increment(format!("item_{}", i).as_bytes(), count);
+            total += count;
+        }
+        let hot = sketch.increment(b"hot", 10_000);
+        assert!(hot >= 10_000);
+        assert_eq!(sketch.estimate(b"hot"), hot);
+        total += 10_000;
+
+        let slack = (std::f64::consts::E / DEFAULT_WIDTH as f64 * total as f64) as u64;
+        let mut within = 0;
+        for i in 0..5_000u64 {
+            let count = i % 7 + 1;
+            let estimate = sketch.estimate(format!("item_{}", i).as_bytes());
+            assert!(estimate >= count);
+            if estimate <= count + slack {
+                within += 1;
+            }
+        }
+        assert!(within > 4_900, "{}", within);
+    }
+
+    #[test]
+    fn test_merge_adds_counts() {
+        let mut a = CountMinSketch::default();
+        let mut b = CountMinSketch::default();
+        a.increment(b"x", 3);
+        b.increment(b"x", 4);
+        b.increment(b"y", 1);
+        a.merge(&b).unwrap();
+        assert!(a.estimate(b"x") >= 7);
+        assert!(a.estimate(b"y") >= 1);
+        assert!(a.merge(&CountMinSketch::new(10, 2)).is_err());
+    }
+}
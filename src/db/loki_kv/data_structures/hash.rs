@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::LokiError;
+use crate::loki_kv::loki_kv::ValueObject;
+
+// Field level mutations of a MapData value, logged to the WAL as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HashOp {
+    Set { fields: Vec<(String, ValueObject)> },
+    Delete { fields: Vec<String> },
+    // A missing field counts as 0
+    IncrBy { field: String, delta: isize },
+}
+
+impl HashOp {
+    // Fails exactly when `apply` would, without touching the map
+    pub fn check(&self, map: &BTreeMap<String, ValueObject>) -> Result<(), LokiError> {
+        if let HashOp::IncrBy { field, delta } = self {
+            field_value(map, field)?
+                .checked_add(*delta)
+                .ok_or_else(|| LokiError::OutOfRange(format!("field {} would overflow", field)))?;
+        }
+        Ok(())
+    }
+
+    pub fn apply(self, map: &mut BTreeMap<String, ValueObject>) -> Result<(), LokiError> {
+        self.check(map)?;
+        match self {
+            HashOp::Set { fields } => map.extend(fields),
+            HashOp::Delete { fields } => {
+                for field in fields {
+                    map.remove(&field);
+                }
+            }
+            HashOp::IncrBy { field, delta } => {
+                let value = field_value(map, &field)? + delta;
+                map.insert(field, ValueObject::IntData(value));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Integer held by a field, a missing field counts as 0
+fn field_value(map: &BTreeMap<String, ValueObject>, field: &str) -> Result<isize, LokiError> {
+    match map.get(field) {
+        Some(ValueObject::IntData(current)) => Ok(*current),
+        Some(_) => Err(LokiError::WrongType(format!(
+            "field {} is not an integer",
+            field
+        ))),
+        None => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_ops() {
+        let mut map = BTreeMap::new();
+        HashOp::Set {
+            fields: vec![
+                (
+                    "name".to_string(),
+                    ValueObject::StringData("loki".to_string()),
+                ),
+                ("age".to_string(), ValueObject::IntData(1)),
+            ],
+        }
+        .apply(&mut map)
+        .unwrap();
+        HashOp::IncrBy {
+            field: "age".to_string(),
+            delta: 2,
+        }
+        .apply(&mut map)
+        .unwrap();
+        HashOp::IncrBy {
+            field: "visits".to_string(),
+            delta: -1,
+        }
+        .apply(&mut map)
+        .unwrap();
+        assert!(matches!(map.get("age"), Some(ValueObject::IntData(3))));
+        assert!(matches!(map.get("visits"), Some(ValueObject::IntData(-1))));
+
+        assert!(HashOp::IncrBy {
+            field: "name".to_string(),
+            delta: 1,
+        }
+        .apply(&mut map)
+        .is_err());
+        assert!(HashOp::IncrBy {
+            field: "age".to_string(),
+            delta: isize::MAX,
+        }
+        .apply(&mut map)
+        .is_err());
+
+        HashOp::Delete {
+            fields: vec!["name".to_string(), "missing".to_string()],
+        }
+        .apply(&mut map)
+        .unwrap();
+        assert_eq!(map.len(), 2);
+    }
+}
@@ -1,96 +1,189 @@
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap,
-    fmt::Debug,
-    hash::{DefaultHasher, Hash, Hasher},
-};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{cell::Cell, fmt::Debug};
 
-use crate::loki_kv::loki_kv::ValueObject;
+use super::xxhash::xxh64;
 
 const P_BITS: u32 = 16;
-const M: usize = 2_i32.pow(P_BITS) as usize;
+const M: usize = 1 << P_BITS;
+// Highest rank a register can hold, the hash has 64 - P_BITS bits left
+const MAX_RANK: u8 = (64 - P_BITS + 1) as u8;
+// Sparse HLLs switch to dense registers past this many entries, at which
+// point the sparse form would take about half the M bytes of the dense one
+const SPARSE_LIMIT: usize = M / 8;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize)]
 pub struct HLL {
-    // leading zeros -> Count of elements
-    streams: Vec<usize>,
+    registers: Registers,
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
+#[derive(Clone, Serialize, Deserialize)]
+enum Registers {
+    // (index, rank) of the registers that are not 0, sorted by index
+    Sparse(Vec<(u16, u8)>),
+    Dense(Vec<u8>),
 }
 
-fn get_first_pbits(hashed_val: u64) -> u64 {
-    let mask = (1 << P_BITS) - 1;
-    hashed_val & mask
+thread_local! {
+    static LEGACY_LAYOUT: Cell<bool> = const { Cell::new(false) };
+}
+
+// Runs `decode` with HLLs read in the layout of format versions 0 and 1
+pub fn with_legacy_layout<T>(decode: impl FnOnce() -> T) -> T {
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            LEGACY_LAYOUT.with(|legacy| legacy.set(false));
+        }
+    }
+    LEGACY_LAYOUT.with(|legacy| legacy.set(true));
+    let _reset = Reset;
+    decode()
+}
+
+// Format versions 0 and 1 stored M usize registers
+#[derive(Deserialize)]
+struct LegacyHLL {
+    streams: Vec<usize>,
+}
+
+impl<'de> Deserialize<'de> for HLL {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if LEGACY_LAYOUT.with(Cell::get) {
+            let legacy = LegacyHLL::deserialize(deserializer)?;
+            return Ok(HLL::from_legacy_registers(&legacy.streams));
+        }
+        Ok(HLL {
+            registers: Registers::deserialize(deserializer)?,
+        })
+    }
 }
 
 impl HLL {
     pub fn new() -> Self {
-        let streams = vec![0; M];
-        HLL { streams }
+        HLL {
+            registers: Registers::Sparse(Vec::new()),
+        }
     }
 
-    pub fn add_item<T: Debug + std::fmt::Display + Hash>(&mut self, entry: T) {
-        let hashed_value = calculate_hash(&entry);
-        let remaining_bits = hashed_value >> P_BITS;
-        let leading_zeros = remaining_bits.leading_zeros() as usize + 1;
-        let first_p_bits = get_first_pbits(hashed_value) as usize;
-
-        if leading_zeros > self.streams[first_p_bits] {
-            self.streams[first_p_bits] = leading_zeros;
+    // Legacy registers held the leading zeros + 1 of the whole 64 bit word
+    // left after the index, which are P_BITS above the ranks used now. The
+    // estimate carries over, but the old hash was not stable so items added
+    // again after the upgrade can be counted twice.
+    fn from_legacy_registers(streams: &[usize]) -> Self {
+        let mut hll = HLL::new();
+        for (index, value) in streams.iter().enumerate().take(M) {
+            if *value != 0 {
+                let rank = value
+                    .saturating_sub(P_BITS as usize)
+                    .clamp(1, MAX_RANK as usize);
+                hll.set_register(index as u16, rank as u8);
+            }
         }
+        hll
     }
 
-    fn get_empty_registers(&self) -> usize {
-        self.streams.iter().filter(|&&x| x == 0).count()
+    pub fn add_item(&mut self, item: &[u8]) {
+        let hash = xxh64(item, 0);
+        let index = (hash & (M as u64 - 1)) as u16;
+        // Position of the first set bit in what is left of the hash
+        let rank = ((hash >> P_BITS).leading_zeros() - P_BITS + 1) as u8;
+        self.set_register(index, rank);
     }
 
-    pub fn calculate_cardinality(&self) -> f64 {
-        let mut sum = 0.0;
+    fn set_register(&mut self, index: u16, rank: u8) {
+        match &mut self.registers {
+            Registers::Dense(registers) => {
+                let register = &mut registers[index as usize];
+                *register = (*register).max(rank);
+            }
+            Registers::Sparse(entries) => match entries.binary_search_by_key(&index, |e| e.0) {
+                Ok(pos) => entries[pos].1 = entries[pos].1.max(rank),
+                Err(pos) => {
+                    entries.insert(pos, (index, rank));
+                    if entries.len() > SPARSE_LIMIT {
+                        self.make_dense();
+                    }
+                }
+            },
+        }
+    }
 
-        for reg in 0..M {
-            let reg_val = self.streams[reg];
-            sum += 1.0 / (2_f64.powf(reg_val as f64));
+    fn make_dense(&mut self) {
+        if let Registers::Sparse(entries) = &self.registers {
+            let mut registers = vec![0; M];
+            for (index, rank) in entries {
+                registers[*index as usize] = *rank;
+            }
+            self.registers = Registers::Dense(registers);
         }
+    }
 
-        // println!("indicator -> {}", indicator);
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.registers, Registers::Sparse(_))
+    }
 
-        let alpha = match M {
-            16 => 0.673,
-            32 => 0.697,
-            64 => 0.709,
-            _ => 0.7213 / (1.0 + 1.079 / M as f64),
-        };
+    // Folds other into self, afterwards self counts the union of both
+    pub fn merge(&mut self, other: &HLL) {
+        match &other.registers {
+            Registers::Sparse(entries) => {
+                for (index, rank) in entries {
+                    self.set_register(*index, *rank);
+                }
+            }
+            Registers::Dense(other_registers) => {
+                self.make_dense();
+                if let Registers::Dense(registers) = &mut self.registers {
+                    for (register, other) in registers.iter_mut().zip(other_registers) {
+                        *register = (*register).max(*other);
+                    }
+                }
+            }
+        }
+    }
 
-        let raw_estimate = (alpha * M as f64) / sum;
-
-        if raw_estimate <= 2.5 * M as f64 {
-            // Correction for small range
-            println!("min threshold hit!");
-            let v = self.get_empty_registers();
-            if v != 0 {
-                println!("started linear counting! {} {}", M, v);
-                M as f64 * f64::ln(M as f64 / v as f64)
-            } else {
-                raw_estimate
+    pub fn calculate_cardinality(&self) -> f64 {
+        let (sum, empty) = match &self.registers {
+            Registers::Sparse(entries) => {
+                let empty = M - entries.len();
+                let sum: f64 = entries
+                    .iter()
+                    .map(|(_, rank)| 2_f64.powi(-(*rank as i32)))
+                    .sum();
+                (sum + empty as f64, empty)
             }
-        } else if raw_estimate > (1.0 / 30.0) * 2_f64.powf(32.0) {
-            // correction for large range
-            println!("upper threshld..");
-            -(2f64.powf(32.0)) * f64::ln(1.0 - raw_estimate / 2_f64.powf(32.0))
+            Registers::Dense(registers) => (
+                registers
+                    .iter()
+                    .map(|rank| 2_f64.powi(-(*rank as i32)))
+                    .sum(),
+                registers.iter().filter(|rank| **rank == 0).count(),
+            ),
+        };
+
+        let alpha = 0.7213 / (1.0 + 1.079 / M as f64);
+        let raw_estimate = alpha * (M as f64) * (M as f64) / sum;
+        // Linear counting is more precise while many registers are empty. With
+        // 64 bit hashes there are no collisions to correct for at the top end.
+        if raw_estimate <= 2.5 * M as f64 && empty != 0 {
+            M as f64 * f64::ln(M as f64 / empty as f64)
         } else {
-            println!("no stuff");
             raw_estimate
         }
     }
+}
 
-    pub fn display_streams(&self) {
-        for v in self.streams.iter() {
-            println!("{}", v);
-        }
+impl Default for HLL {
+    fn default() -> Self {
+        HLL::new()
+    }
+}
+
+impl Debug for HLL {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HLL")
+            .field("sparse", &self.is_sparse())
+            .field("cardinality", &self.calculate_cardinality())
+            .finish()
     }
 }
 
@@ -104,15 +197,12 @@ mod tests {
         let count = 800957;
         let low_lim = count as f64 - count as f64 * 0.05;
         let high_lim = count as f64 + count as f64 * 0.05;
-        let mut large_test: Vec<String> = (0..count)
-            .map(|i| String::from(format!("user_{}", i)))
-            .collect();
+        let large_test: Vec<String> = (0..count).map(|i| format!("user_{}", i)).collect();
         for entry in large_test.iter() {
-            hll.add_item(entry);
+            hll.add_item(entry.as_bytes());
         }
-        // hll.display_streams();
+        assert!(!hll.is_sparse());
         let cardinality = hll.calculate_cardinality();
-        // println!("Result -> {} {}", cardinality, count);
         assert!(
             cardinality > low_lim && cardinality < high_lim,
             "{}",
@@ -129,14 +219,11 @@ mod tests {
         let large_test: Vec<String> = (0..count).map(|i| format!("user_{}", i % 1000)).collect();
 
         for entry in large_test.iter() {
-            hll.add_item(entry);
+            hll.add_item(entry.as_bytes());
         }
 
+        assert!(hll.is_sparse());
         let cardinality = hll.calculate_cardinality();
-        println!(
-            "Result -> {} {} {} {}",
-            cardinality, count, low_lim, high_lim
-        );
         assert!(
             cardinality > low_lim && cardinality < high_lim,
             "{} {} {} {} {}",
@@ -147,4 +234,49 @@ mod tests {
             count / 1000
         );
     }
+
+    #[test]
+    fn test_merge_sparse_and_dense() {
+        let mut small = HLL::new();
+        let mut large = HLL::new();
+        for i in 0..100 {
+            small.add_item(format!("small_{}", i).as_bytes());
+        }
+        for i in 0..50_000 {
+            large.add_item(format!("large_{}", i).as_bytes());
+        }
+        assert!(small.is_sparse() && !large.is_sparse());
+
+        let mut merged = small.clone();
+        merged.merge(&large);
+        let estimate = merged.calculate_cardinality();
+        assert!(
+            (estimate - 50_100.0).abs() < 50_100.0 * 0.02,
+            "{}",
+            estimate
+        );
+
+        // Merging the other way around gives the same registers
+        large.merge(&small);
+        assert_eq!(large.calculate_cardinality(), estimate);
+    }
+
+    #[test]
+    fn test_legacy_registers_keep_their_estimate() {
+        // 1000 registers at rank 2 in the old layout
+        let mut streams = vec![0usize; M];
+        for register in streams.iter_mut().take(1000) {
+            *register = P_BITS as usize + 2;
+        }
+        let bytes = bincode::serialize(&streams).unwrap();
+        let hll: HLL = with_legacy_layout(|| bincode::deserialize(&bytes)).unwrap();
+        let expected = M as f64 * f64::ln(M as f64 / (M - 1000) as f64);
+        assert_eq!(hll.calculate_cardinality(), expected);
+        assert!(!LEGACY_LAYOUT.with(Cell::get));
+
+        let bytes = bincode::serialize(&hll).unwrap();
+        let decoded: HLL = bincode::deserialize(&bytes).unwrap();
+        assert!(decoded.is_sparse());
+        assert_eq!(decoded.calculate_cardinality(), expected);
+    }
 }
@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::LokiError;
+use crate::loki_kv::loki_kv::ValueObject;
+
+// Mutations of a ListData value. They are logged to the WAL as they are, so
+// applying one must only depend on the list it is applied to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ListOp {
+    // Values pushed to the front end up in reverse order, like LPUSH
+    Push {
+        front: bool,
+        values: Vec<ValueObject>,
+    },
+    Pop {
+        front: bool,
+        count: usize,
+    },
+    Set {
+        index: isize,
+        value: ValueObject,
+    },
+    // Keeps the inclusive range start..=stop
+    Trim {
+        start: isize,
+        stop: isize,
+    },
+}
+
+impl ListOp {
+    // Fails exactly when `apply` would, without touching the list
+    pub fn check(&self, list: &[ValueObject]) -> Result<(), LokiError> {
+        match self {
+            ListOp::Set { index, .. } if resolve_index(list.len(), *index).is_none() => Err(
+                LokiError::OutOfRange(format!("index {} out of range", index)),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply(self, list: &mut Vec<ValueObject>) -> Result<(), LokiError> {
+        self.check(list)?;
+        match self {
+            ListOp::Push {
+                front: true,
+                values,
+            } => {
+                list.splice(0..0, values.into_iter().rev());
+            }
+            ListOp::Push {
+                front: false,
+                values,
+            } => list.extend(values),
+            ListOp::Pop { front, count } => {
+                let count = count.min(list.len());
+                if front {
+                    list.drain(..count);
+                } else {
+                    list.truncate(list.len() - count);
+                }
+            }
+            ListOp::Set { index, value } => {
+                if let Some(pos) = resolve_index(list.len(), index) {
+                    list[pos] = value;
+                }
+            }
+            ListOp::Trim { start, stop } => match resolve_range(list.len(), start, stop) {
+                Some((start, stop)) => {
+                    list.truncate(stop + 1);
+                    list.drain(..start);
+                }
+                None => list.clear(),
+            },
+        }
+        Ok(())
+    }
+}
+
+// Position of an index into a list of `len` values, negative indexes count
+// from the end
+pub fn resolve_index(len: usize, index: isize) -> Option<usize> {
+    let len = len as isize;
+    let index = if index < 0 { len + index } else { index };
+    (0..len).contains(&index).then_some(index as usize)
+}
+
+// Inclusive range clamped to a list of `len` values, None when it selects
+// nothing
+pub fn resolve_range(len: usize, start: isize, stop: isize) -> Option<(usize, usize)> {
+    let len = len as isize;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    (start <= stop && start < len).then_some((start as usize, stop as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(list: &[ValueObject]) -> Vec<isize> {
+        list.iter()
+            .map(|v| match v {
+                ValueObject::IntData(i) => *i,
+                other => panic!("unexpected value {:?}", other),
+            })
+            .collect()
+    }
+
+    fn int_list(values: &[isize]) -> Vec<ValueObject> {
+        values.iter().map(|i| ValueObject::IntData(*i)).collect()
+    }
+
+    #[test]
+    fn test_list_ops() {
+        let mut list = Vec::new();
+        ListOp::Push {
+            front: false,
+            values: int_list(&[3, 4]),
+        }
+        .apply(&mut list)
+        .unwrap();
+        ListOp::Push {
+            front: true,
+            values: int_list(&[2, 1]),
+        }
+        .apply(&mut list)
+        .unwrap();
+        assert_eq!(ints(&list), [1, 2, 3, 4]);
+
+        ListOp::Set {
+            index: -1,
+            value: ValueObject::IntData(40),
+        }
+        .apply(&mut list)
+        .unwrap();
+        assert!(ListOp::Set {
+            index: 4,
+            value: ValueObject::IntData(0),
+        }
+        .apply(&mut list)
+        .is_err());
+
+        ListOp::Trim { start: 1, stop: -1 }
+            .apply(&mut list)
+            .unwrap();
+        assert_eq!(ints(&list), [2, 3, 40]);
+        ListOp::Pop {
+            front: false,
+            count: 1,
+        }
+        .apply(&mut list)
+        .unwrap();
+        ListOp::Pop {
+            front: true,
+            count: 5,
+        }
+        .apply(&mut list)
+        .unwrap();
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_range() {
+        assert_eq!(resolve_range(5, 0, -1), Some((0, 4)));
+        assert_eq!(resolve_range(5, -100, 100), Some((0, 4)));
+        assert_eq!(resolve_range(5, 3, 1), None);
+        assert_eq!(resolve_range(5, 5, 10), None);
+        assert_eq!(resolve_range(5, 0, -6), None);
+        assert_eq!(resolve_range(0, 0, -1), None);
+        assert_eq!(resolve_index(3, -3), Some(0));
+        assert_eq!(resolve_index(3, 3), None);
+    }
+}
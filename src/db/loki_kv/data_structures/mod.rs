@@ -1,2 +1,9 @@
+pub mod bloom;
 pub mod btree;
+pub mod count_min_sketch;
+pub mod hash;
 pub mod hyperloglog;
+pub mod list;
+pub mod set;
+pub mod sorted_set;
+pub mod xxhash;
@@ -0,0 +1,78 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+// Member level mutations of a SetData value, logged to the WAL as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SetOp {
+    Add { members: Vec<String> },
+    Remove { members: Vec<String> },
+}
+
+impl SetOp {
+    pub fn apply(self, set: &mut BTreeSet<String>) {
+        match self {
+            SetOp::Add { members } => set.extend(members),
+            SetOp::Remove { members } => {
+                for member in members {
+                    set.remove(&member);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetAlgebra {
+    Union,
+    Inter,
+    // Members of the first set that are in none of the others
+    Diff,
+}
+
+// Combines the sets left to right, missing keys are passed as empty sets
+pub fn combine(algebra: SetAlgebra, sets: &[&BTreeSet<String>]) -> BTreeSet<String> {
+    let Some((first, rest)) = sets.split_first() else {
+        return BTreeSet::new();
+    };
+    let mut result = (*first).clone();
+    for set in rest {
+        match algebra {
+            SetAlgebra::Union => result.extend(set.iter().cloned()),
+            SetAlgebra::Inter => result.retain(|member| set.contains(member)),
+            SetAlgebra::Diff => result.retain(|member| !set.contains(member)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: &[&str]) -> BTreeSet<String> {
+        members.iter().map(|m| m.to_string()).collect()
+    }
+
+    #[test]
+    fn test_set_ops_and_algebra() {
+        let mut a = set(&["x"]);
+        SetOp::Add {
+            members: vec!["y".to_string(), "z".to_string()],
+        }
+        .apply(&mut a);
+        SetOp::Remove {
+            members: vec!["x".to_string(), "missing".to_string()],
+        }
+        .apply(&mut a);
+        assert_eq!(a, set(&["y", "z"]));
+
+        let b = set(&["z", "w"]);
+        let empty = BTreeSet::new();
+        assert_eq!(combine(SetAlgebra::Union, &[&a, &b]), set(&["w", "y", "z"]));
+        assert_eq!(combine(SetAlgebra::Inter, &[&a, &b]), set(&["z"]));
+        assert_eq!(combine(SetAlgebra::Diff, &[&a, &b]), set(&["y"]));
+        assert!(combine(SetAlgebra::Inter, &[&a, &empty]).is_empty());
+        assert_eq!(combine(SetAlgebra::Diff, &[&a]), a);
+    }
+}
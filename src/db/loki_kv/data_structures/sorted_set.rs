@@ -0,0 +1,478 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::LokiError;
+
+// Members ordered by (score, member). The order is kept in an AVL tree whose
+// nodes count the entries below them, which makes rank lookups O(log n). A
+// map from member to score finds the tree entry of a member.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(String, f64)>", into = "Vec<(String, f64)>")]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    root: Link,
+}
+
+type Link = Option<Box<Node>>;
+
+#[derive(Clone)]
+struct Node {
+    score: f64,
+    member: String,
+    height: u8,
+    size: usize,
+    left: Link,
+    right: Link,
+}
+
+impl Node {
+    fn new(score: f64, member: String) -> Box<Node> {
+        Box::new(Node {
+            score,
+            member,
+            height: 1,
+            size: 1,
+            left: None,
+            right: None,
+        })
+    }
+
+    fn update(&mut self) {
+        self.height = 1 + height(&self.left).max(height(&self.right));
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+fn height(link: &Link) -> u8 {
+    link.as_ref().map_or(0, |node| node.height)
+}
+
+fn size(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+fn cmp_entry(score: f64, member: &str, node: &Node) -> Ordering {
+    score
+        .total_cmp(&node.score)
+        .then_with(|| member.cmp(&node.member))
+}
+
+fn rotate_right(mut node: Box<Node>) -> Box<Node> {
+    let Some(mut left) = node.left.take() else {
+        return node;
+    };
+    node.left = left.right.take();
+    node.update();
+    left.right = Some(node);
+    left.update();
+    left
+}
+
+fn rotate_left(mut node: Box<Node>) -> Box<Node> {
+    let Some(mut right) = node.right.take() else {
+        return node;
+    };
+    node.right = right.left.take();
+    node.update();
+    right.left = Some(node);
+    right.update();
+    right
+}
+
+fn rebalance(mut node: Box<Node>) -> Box<Node> {
+    node.update();
+    let balance = height(&node.left) as i16 - height(&node.right) as i16;
+    if balance > 1 {
+        if node
+            .left
+            .as_ref()
+            .is_some_and(|left| height(&left.left) < height(&left.right))
+        {
+            node.left = node.left.take().map(rotate_left);
+        }
+        return rotate_right(node);
+    }
+    if balance < -1 {
+        if node
+            .right
+            .as_ref()
+            .is_some_and(|right| height(&right.right) < height(&right.left))
+        {
+            node.right = node.right.take().map(rotate_right);
+        }
+        return rotate_left(node);
+    }
+    node
+}
+
+// The entry must not be in the tree yet
+fn insert(link: Link, score: f64, member: String) -> Box<Node> {
+    let Some(mut node) = link else {
+        return Node::new(score, member);
+    };
+    if cmp_entry(score, &member, &node) == Ordering::Less {
+        node.left = Some(insert(node.left.take(), score, member));
+    } else {
+        node.right = Some(insert(node.right.take(), score, member));
+    }
+    rebalance(node)
+}
+
+fn remove(link: Link, score: f64, member: &str) -> Link {
+    let mut node = link?;
+    match cmp_entry(score, member, &node) {
+        Ordering::Less => node.left = remove(node.left.take(), score, member),
+        Ordering::Greater => node.right = remove(node.right.take(), score, member),
+        Ordering::Equal => match (node.left.take(), node.right.take()) {
+            (None, right) => return right,
+            (left, None) => return left,
+            (left, Some(right)) => {
+                let (rest, mut successor) = remove_min(right);
+                successor.left = left;
+                successor.right = rest;
+                return Some(rebalance(successor));
+            }
+        },
+    }
+    Some(rebalance(node))
+}
+
+// Splits the smallest entry off a subtree
+fn remove_min(mut node: Box<Node>) -> (Link, Box<Node>) {
+    match node.left.take() {
+        None => (node.right.take(), node),
+        Some(left) => {
+            let (rest, min) = remove_min(left);
+            node.left = rest;
+            (Some(rebalance(node)), min)
+        }
+    }
+}
+
+fn above(min: Bound<f64>, score: f64) -> bool {
+    match min {
+        Bound::Included(min) => score.total_cmp(&min) != Ordering::Less,
+        Bound::Excluded(min) => score.total_cmp(&min) == Ordering::Greater,
+        Bound::Unbounded => true,
+    }
+}
+
+fn below(max: Bound<f64>, score: f64) -> bool {
+    match max {
+        Bound::Included(max) => score.total_cmp(&max) != Ordering::Greater,
+        Bound::Excluded(max) => score.total_cmp(&max) == Ordering::Less,
+        Bound::Unbounded => true,
+    }
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn get_score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Sets the score of member, returns true if it was not in the set
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        // -0.0 and 0.0 would otherwise sort as different scores
+        let score = score + 0.0;
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.root = remove(self.root.take(), previous, &member);
+        }
+        self.root = Some(insert(self.root.take(), score, member));
+        previous.is_none()
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.root = remove(self.root.take(), score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Position of member counting from the lowest score
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.get_score(member)?;
+        let mut rank = 0;
+        let mut link = &self.root;
+        while let Some(node) = link {
+            match cmp_entry(score, member, node) {
+                Ordering::Less => link = &node.left,
+                Ordering::Greater => {
+                    rank += size(&node.left) + 1;
+                    link = &node.right;
+                }
+                Ordering::Equal => return Some(rank + size(&node.left)),
+            }
+        }
+        None
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        self.iter_from_rank(0)
+    }
+
+    // Entries in order, starting with the one at rank
+    pub fn iter_from_rank(&self, mut rank: usize) -> Iter<'_> {
+        let mut stack = Vec::new();
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let left = size(&node.left);
+            match rank.cmp(&left) {
+                Ordering::Less => {
+                    stack.push(&**node);
+                    link = &node.left;
+                }
+                Ordering::Equal => {
+                    stack.push(&**node);
+                    break;
+                }
+                Ordering::Greater => {
+                    rank -= left + 1;
+                    link = &node.right;
+                }
+            }
+        }
+        Iter { stack }
+    }
+
+    // Entries whose score lies between min and max, in order
+    pub fn range_by_score(
+        &self,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> impl Iterator<Item = (&String, f64)> + '_ {
+        let mut stack = Vec::new();
+        let mut link = &self.root;
+        while let Some(node) = link {
+            if above(min, node.score) {
+                stack.push(&**node);
+                link = &node.left;
+            } else {
+                link = &node.right;
+            }
+        }
+        Iter { stack }.take_while(move |(_, score)| below(max, *score))
+    }
+}
+
+pub struct Iter<'a> {
+    // Nodes still to visit, the next one on top
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a String, f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        let mut link = &node.right;
+        while let Some(next) = link {
+            self.stack.push(next);
+            link = &next.left;
+        }
+        Some((&node.member, node.score))
+    }
+}
+
+impl Debug for SortedSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// Stored as (member, score) pairs, the tree is rebuilt when loading
+impl From<Vec<(String, f64)>> for SortedSet {
+    fn from(entries: Vec<(String, f64)>) -> Self {
+        let mut set = SortedSet::new();
+        for (member, score) in entries {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl From<SortedSet> for Vec<(String, f64)> {
+    fn from(set: SortedSet) -> Self {
+        set.iter()
+            .map(|(member, score)| (member.clone(), score))
+            .collect()
+    }
+}
+
+// Member level mutations of a SortedSetData value, logged to the WAL as they are
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SortedSetOp {
+    Add { members: Vec<(String, f64)> },
+    Remove { members: Vec<String> },
+    // A missing member starts at 0
+    IncrBy { member: String, delta: f64 },
+}
+
+impl SortedSetOp {
+    // Fails exactly when `apply` would, without touching the set
+    pub fn check(&self, set: &SortedSet) -> Result<(), LokiError> {
+        match self {
+            SortedSetOp::Add { members } => {
+                if let Some((member, _)) = members.iter().find(|(_, score)| score.is_nan()) {
+                    return Err(LokiError::OutOfRange(format!(
+                        "score of {} is not a number",
+                        member
+                    )));
+                }
+            }
+            SortedSetOp::Remove { .. } => {}
+            SortedSetOp::IncrBy { member, delta } => {
+                if (set.get_score(member).unwrap_or(0.0) + delta).is_nan() {
+                    return Err(LokiError::OutOfRange(format!(
+                        "score of {} would not be a number",
+                        member
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(self, set: &mut SortedSet) -> Result<(), LokiError> {
+        self.check(set)?;
+        match self {
+            SortedSetOp::Add { members } => {
+                for (member, score) in members {
+                    set.insert(member, score);
+                }
+            }
+            SortedSetOp::Remove { members } => {
+                for member in members {
+                    set.remove(&member);
+                }
+            }
+            SortedSetOp::IncrBy { member, delta } => {
+                let score = set.get_score(&member).unwrap_or(0.0) + delta;
+                set.insert(member, score);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn members(set: &SortedSet) -> Vec<&str> {
+        set.iter().map(|(member, _)| member.as_str()).collect()
+    }
+
+    // Checks balance, subtree sizes and order of every node
+    fn check(link: &Link) -> (u8, usize) {
+        let Some(node) = link else {
+            return (0, 0);
+        };
+        let (left_height, left_size) = check(&node.left);
+        let (right_height, right_size) = check(&node.right);
+        assert!((left_height as i16 - right_height as i16).abs() <= 1);
+        assert_eq!(node.height, 1 + left_height.max(right_height));
+        assert_eq!(node.size, 1 + left_size + right_size);
+        if let Some(left) = &node.left {
+            assert_eq!(cmp_entry(left.score, &left.member, node), Ordering::Less);
+        }
+        if let Some(right) = &node.right {
+            assert_eq!(
+                cmp_entry(right.score, &right.member, node),
+                Ordering::Greater
+            );
+        }
+        (node.height, node.size)
+    }
+
+    #[test]
+    fn test_ranks_and_ranges() {
+        let mut set = SortedSet::new();
+        assert!(set.insert("carol".to_string(), 30.0));
+        assert!(set.insert("alice".to_string(), 10.0));
+        assert!(set.insert("bob".to_string(), 20.0));
+        assert!(set.insert("dave".to_string(), 20.0));
+        assert!(!set.insert("alice".to_string(), 25.0));
+
+        assert_eq!(members(&set), ["bob", "dave", "alice", "carol"]);
+        assert_eq!(set.rank("bob"), Some(0));
+        assert_eq!(set.rank("alice"), Some(2));
+        assert_eq!(set.rank("missing"), None);
+        assert_eq!(
+            set.iter_from_rank(1)
+                .take(2)
+                .map(|(m, _)| m.as_str())
+                .collect::<Vec<_>>(),
+            ["dave", "alice"]
+        );
+        let scores: Vec<&str> = set
+            .range_by_score(Bound::Excluded(20.0), Bound::Included(30.0))
+            .map(|(m, _)| m.as_str())
+            .collect();
+        assert_eq!(scores, ["alice", "carol"]);
+
+        SortedSetOp::IncrBy {
+            member: "bob".to_string(),
+            delta: 100.0,
+        }
+        .apply(&mut set)
+        .unwrap();
+        assert_eq!(set.get_score("bob"), Some(120.0));
+        assert_eq!(set.rank("bob"), Some(3));
+        assert!(SortedSetOp::IncrBy {
+            member: "bob".to_string(),
+            delta: f64::NAN,
+        }
+        .apply(&mut set)
+        .is_err());
+        assert_eq!(set.get_score("bob"), Some(120.0));
+
+        let restored: SortedSet = Vec::from(set.clone()).into();
+        assert_eq!(members(&restored), members(&set));
+    }
+
+    #[test]
+    fn test_tree_stays_balanced() {
+        let mut rng = StdRng::seed_from_u64(18);
+        let mut set = SortedSet::new();
+        let mut expected = std::collections::BTreeMap::new();
+        for _ in 0..2000 {
+            let member = format!("m{}", rng.gen_range(0..300));
+            if rng.gen_bool(0.3) {
+                assert_eq!(set.remove(&member), expected.remove(&member).is_some());
+            } else {
+                let score = rng.gen_range(0..50) as f64;
+                set.insert(member.clone(), score);
+                expected.insert(member, score);
+            }
+        }
+        assert_eq!(check(&set.root).1, expected.len());
+
+        let mut sorted: Vec<(f64, &String)> = expected.iter().map(|(m, s)| (*s, m)).collect();
+        sorted.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+        for (rank, (_, member)) in sorted.iter().enumerate() {
+            assert_eq!(set.rank(member), Some(rank));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use crate::loki_kv::data_structures::hyperloglog::with_legacy_layout;
+use crate::loki_kv::loki_kv::ValueObject;
+use crate::loki_kv::wal::WALRecord;
+
+// Every page and WAL file starts with
+// | magic (4 bytes) | format version (u32) |
+// Files written before this header existed have no magic and are read as
+// version 0. Version 0 shares the value layout of version 1. Version 2
+// changed how HLLs are stored, older HLLs are converted while decoding.
+//
+// Whenever the encoding of `ValueObject` or `WALRecord` changes in a way old
+// readers can't follow, bump `CURRENT_FORMAT_VERSION` and keep decoding the
+// older versions in `decode_pairs` / `decode_wal_record`. New enum variants
+// go at the end so that older files keep decoding.
+pub const FILE_HEADER_LEN: usize = 8;
+pub const CURRENT_FORMAT_VERSION: u32 = 2;
+const LEGACY_FORMAT_VERSION: u32 = 0;
+const DENSE_HLL_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Page,
+    Wal,
+}
+
+impl FileKind {
+    fn magic(&self) -> &'static [u8; 4] {
+        match self {
+            FileKind::Page => b"LQPG",
+            FileKind::Wal => b"LWAL",
+        }
+    }
+}
+
+pub fn encode_file_header(kind: FileKind) -> Vec<u8> {
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN);
+    header.extend_from_slice(kind.magic());
+    header.extend_from_slice(&CURRENT_FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+// Reads the file header and returns the format version along with the offset
+// at which the body starts
+pub fn decode_file_header(kind: FileKind, bytes: &[u8]) -> Result<(u32, usize), String> {
+    let magic = kind.magic();
+    let prefix = &bytes[..bytes.len().min(magic.len())];
+    if bytes.is_empty() || prefix != &magic[..prefix.len()] {
+        return Ok((LEGACY_FORMAT_VERSION, 0));
+    }
+    if bytes.len() < FILE_HEADER_LEN {
+        return Err("truncated file header".to_string());
+    }
+
+    let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    if version > CURRENT_FORMAT_VERSION {
+        return Err(format!(
+            "format version {} is newer than the supported version {}",
+            version, CURRENT_FORMAT_VERSION
+        ));
+    }
+    Ok((version, FILE_HEADER_LEN))
+}
+
+pub fn encode_pairs(pairs: &[(String, ValueObject)]) -> Result<Vec<u8>, String> {
+    let mut bytes = encode_file_header(FileKind::Page);
+    let body = bincode::serialize(pairs).map_err(|e| format!("Failed to encode page: {}", e))?;
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+// Decodes a whole page file, with or without a header
+pub fn decode_page(bytes: &[u8]) -> Result<(u32, Vec<(String, ValueObject)>), String> {
+    let (version, offset) = decode_file_header(FileKind::Page, bytes)?;
+    Ok((version, decode_pairs(version, &bytes[offset..])?))
+}
+
+pub fn decode_pairs(version: u32, body: &[u8]) -> Result<Vec<(String, ValueObject)>, String> {
+    match version {
+        LEGACY_FORMAT_VERSION | DENSE_HLL_FORMAT_VERSION => {
+            with_legacy_layout(|| bincode::deserialize(body)).map_err(|e| e.to_string())
+        }
+        CURRENT_FORMAT_VERSION => bincode::deserialize(body).map_err(|e| e.to_string()),
+        _ => Err(format!("unsupported page format version {}", version)),
+    }
+}
+
+pub fn decode_wal_record(version: u32, payload: &[u8]) -> Result<WALRecord, String> {
+    match version {
+        LEGACY_FORMAT_VERSION | DENSE_HLL_FORMAT_VERSION => {
+            with_legacy_layout(|| bincode::deserialize(payload)).map_err(|e| e.to_string())
+        }
+        CURRENT_FORMAT_VERSION => bincode::deserialize(payload).map_err(|e| e.to_string()),
+        _ => Err(format!("unsupported WAL format version {}", version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pages_with_and_without_header_decode() {
+        let pairs = vec![("k".to_string(), ValueObject::IntData(4))];
+        let (version, decoded) = decode_page(&encode_pairs(&pairs).unwrap()).unwrap();
+        assert_eq!(version, CURRENT_FORMAT_VERSION);
+        assert!(matches!(decoded[0].1, ValueObject::IntData(4)));
+
+        let legacy = bincode::serialize(&pairs).unwrap();
+        let (version, decoded) = decode_page(&legacy).unwrap();
+        assert_eq!(version, LEGACY_FORMAT_VERSION);
+        assert_eq!(decoded[0].0, "k");
+    }
+
+    #[test]
+    fn test_version_1_hlls_are_converted() {
+        // One pair holding an HLLPointer, the ninth variant, with a register
+        // at 17 in the old layout
+        let mut streams = vec![0usize; 1 << 16];
+        streams[7] = 17;
+        let mut bytes = encode_file_header(FileKind::Page);
+        bytes[4..8].copy_from_slice(&DENSE_HLL_FORMAT_VERSION.to_le_bytes());
+        bytes.extend(bincode::serialize(&(1u64, "h", 8u32, streams)).unwrap());
+
+        let (version, pairs) = decode_page(&bytes).unwrap();
+        assert_eq!(version, DENSE_HLL_FORMAT_VERSION);
+        match &pairs[0].1 {
+            ValueObject::HLLPointer(hll) => {
+                assert!(hll.is_sparse());
+                assert!((hll.calculate_cardinality() - 1.0).abs() < 0.01);
+            }
+            other => panic!("unexpected value {:?}", other),
+        }
+
+        let (version, pairs) = decode_page(&encode_pairs(&pairs).unwrap()).unwrap();
+        assert_eq!(version, CURRENT_FORMAT_VERSION);
+        assert!(matches!(pairs[0].1, ValueObject::HLLPointer(_)));
+    }
+
+    #[test]
+    fn test_newer_format_is_rejected() {
+        let mut bytes = encode_file_header(FileKind::Wal);
+        bytes[4..8].copy_from_slice(&(CURRENT_FORMAT_VERSION + 1).to_le_bytes());
+        let err = decode_file_header(FileKind::Wal, &bytes).unwrap_err();
+        assert!(err.contains("newer"), "{}", err);
+        assert!(decode_file_header(FileKind::Wal, &bytes[..5]).is_err());
+    }
+}
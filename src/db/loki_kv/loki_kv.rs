@@ -1,22 +1,33 @@
-use core::{f32, panic};
 use std::any::{Any, TypeId};
-use std::collections::{self, BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env::VarError;
 use std::fmt::Debug;
+use std::ops::Bound;
 use std::ptr::null;
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, mem};
 
 use clap::builder::StringValueParser;
+use imbl::OrdMap;
 use serde::{Deserialize, Serialize};
 use tokio::fs::File;
 
-use crate::loki_kv::wal::WALManager;
-use crate::utils::{error_string, info_string};
+use crate::error::LokiError;
+use crate::loki_kv::config::Config;
+use crate::loki_kv::control::ControlFile;
+use crate::loki_kv::wal::{WALManager, WALRecord, WALSync};
+use crate::utils::info_string;
+use std::sync::Arc;
 
+use super::checkpoint::{self, CollectionSnapshot};
+use super::data_structures::bloom::BloomFilter;
 use super::data_structures::btree::btree::BTree;
+use super::data_structures::count_min_sketch::CountMinSketch;
+use super::data_structures::hash::HashOp;
 use super::data_structures::hyperloglog::HLL;
-use super::persist::Persistor;
+use super::data_structures::list::{resolve_index, resolve_range, ListOp};
+use super::data_structures::set::{combine, SetAlgebra, SetOp};
+use super::data_structures::sorted_set::{SortedSet, SortedSetOp};
 use paris::Logger;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,32 +41,107 @@ pub enum ValueObject {
     BlobData(Vec<u8>),
     ListData(Vec<ValueObject>),
     HLLPointer(HLL),
+    // Field name to value, new variants go last to keep stored data readable
+    MapData(BTreeMap<String, ValueObject>),
+    SetData(BTreeSet<String>),
+    SortedSetData(SortedSet),
+    BloomFilterData(BloomFilter),
+    CountMinSketchData(CountMinSketch),
 }
 
+// Backing structure of a collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CollectionKind {
+    HashMap,
+    BTreeMap,
+    CustomBTree,
+}
+
+// Pairs of an ordered collection in the order they are scanned
+pub type PairIter<'a> = Box<dyn Iterator<Item = (&'a String, &'a ValueObject)> + 'a>;
+
+// Keys of a collection snapshot, owned so a cursor can keep it between commands
+pub type KeyIter = Box<dyn Iterator<Item = String> + Send>;
+
 pub trait CollectionProps {
     fn new() -> Self
     where
         Self: Sized; // Move Sized to this method only
     fn put(&mut self, key: &str, value: ValueObject) -> bool;
     fn get(&self, key: &str) -> Option<&ValueObject>;
+    fn get_mut(&mut self, key: &str) -> Option<&mut ValueObject>;
+    // Removes the key, returns the value it held
+    fn remove(&mut self, key: &str) -> Option<ValueObject>;
     fn key_exists(&self, key: &str) -> bool;
-    fn incr(&mut self, key: &str) -> Result<(), &str>;
-    fn decr(&mut self, key: &str) -> Result<(), &str>;
+    fn incr(&mut self, key: &str) -> Result<(), LokiError>;
+    fn decr(&mut self, key: &str) -> Result<(), LokiError>;
     fn display_collection(&self) -> String;
     fn generate_pairs(&self) -> Vec<(String, ValueObject)>;
     fn bulk_put(&mut self, pairs: Vec<(String, ValueObject)>);
+
+    // Pairs between the bounds in key order, None for collections that don't
+    // keep their keys sorted
+    fn range<'a>(
+        &'a self,
+        _start: Bound<&str>,
+        _end: Bound<&str>,
+        _reverse: bool,
+    ) -> Option<PairIter<'a>> {
+        None
+    }
+
+    // Iterator over the keys as they are now, later writes don't affect it
+    fn snapshot_keys(&self) -> KeyIter;
+
+    // Deadlines of expiring keys in milliseconds since the epoch
+    fn get_expiries(&self) -> &imbl::HashMap<String, u64>;
+    fn get_expiries_mut(&mut self) -> &mut imbl::HashMap<String, u64>;
+
+    fn get_expiry(&self, key: &str) -> Option<u64> {
+        self.get_expiries().get(key).copied()
+    }
+
+    // Sets or clears the deadline of a key
+    fn set_expiry(&mut self, key: &str, expire_at: Option<u64>) {
+        match expire_at {
+            Some(expire_at) => {
+                self.get_expiries_mut().insert(key.to_string(), expire_at);
+            }
+            None => {
+                self.get_expiries_mut().remove(key);
+            }
+        }
+    }
+
+    fn is_expired(&self, key: &str, now: u64) -> bool {
+        self.get_expiry(key)
+            .is_some_and(|expire_at| expire_at <= now)
+    }
+
+    fn expired_keys(&self, now: u64) -> Vec<String> {
+        self.get_expiries()
+            .iter()
+            .filter(|(_, expire_at)| **expire_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
 }
 
-// Table structure with btree as internal store
+// Table structure with btree as internal store. Collections use persistent
+// maps so a checkpoint can snapshot them with a cheap clone.
 #[derive(Clone)]
 pub struct CollectionBTree {
-    store: BTreeMap<String, ValueObject>,
+    store: OrdMap<String, ValueObject>,
+    expiries: imbl::HashMap<String, u64>,
 }
 
 impl CollectionProps for CollectionBTree {
     fn new() -> Self {
-        let store: BTreeMap<String, ValueObject> = BTreeMap::new();
-        CollectionBTree { store }
+        let store: OrdMap<String, ValueObject> = OrdMap::new();
+        CollectionBTree {
+            store,
+            expiries: imbl::HashMap::new(),
+        }
     }
 
     fn put(&mut self, key: &str, value: ValueObject) -> bool {
@@ -77,12 +163,39 @@ impl CollectionProps for CollectionBTree {
         self.store.get(key)
     }
 
+    fn get_mut(&mut self, key: &str) -> Option<&mut ValueObject> {
+        self.store.get_mut(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<ValueObject> {
+        self.expiries.remove(key);
+        self.store.remove(key)
+    }
+
+    fn get_expiries(&self) -> &imbl::HashMap<String, u64> {
+        &self.expiries
+    }
+
+    fn get_expiries_mut(&mut self) -> &mut imbl::HashMap<String, u64> {
+        &mut self.expiries
+    }
+
+    fn snapshot_keys(&self) -> KeyIter {
+        Box::new(OrderedKeys {
+            snapshot: self.clone(),
+            last: None,
+        })
+    }
+
     fn key_exists(&self, key: &str) -> bool {
         self.store.contains_key(key)
     }
 
-    fn incr(&mut self, key: &str) -> Result<(), &str> {
-        let val = self.store.get(key).unwrap();
+    fn incr(&mut self, key: &str) -> Result<(), LokiError> {
+        let val = self
+            .store
+            .get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
 
         match val {
             ValueObject::IntData(data) => {
@@ -95,12 +208,15 @@ impl CollectionProps for CollectionBTree {
                     .insert(key.to_string(), ValueObject::DecimalData(data + 1.0));
                 Ok(())
             }
-            _ => Err("incr not supported for data type"),
+            _ => Err(LokiError::WrongType(format!("{} is not a number", key))),
         }
     }
 
-    fn decr(&mut self, key: &str) -> Result<(), &str> {
-        let val = self.store.get(key).unwrap();
+    fn decr(&mut self, key: &str) -> Result<(), LokiError> {
+        let val = self
+            .store
+            .get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
 
         match val {
             ValueObject::IntData(data) => {
@@ -113,7 +229,7 @@ impl CollectionProps for CollectionBTree {
                     .insert(key.to_string(), ValueObject::DecimalData(data - 1.0));
                 Ok(())
             }
-            _ => Err("incr not supported for data type"),
+            _ => Err(LokiError::WrongType(format!("{} is not a number", key))),
         }
     }
 
@@ -134,6 +250,23 @@ impl CollectionProps for CollectionBTree {
         }
         return data;
     }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+    ) -> Option<PairIter<'a>> {
+        if is_empty_range(start, end) {
+            return Some(Box::new(std::iter::empty()));
+        }
+        let pairs = self.store.range::<_, str>((start, end));
+        if reverse {
+            Some(Box::new(pairs.rev()))
+        } else {
+            Some(Box::new(pairs))
+        }
+    }
 }
 
 // Custom BTree Implementation
@@ -141,6 +274,7 @@ impl CollectionProps for CollectionBTree {
 pub struct CollectionBTreeCustom {
     store: BTree,
     option_val: Option<ValueObject>,
+    expiries: imbl::HashMap<String, u64>,
 }
 
 impl CollectionProps for CollectionBTreeCustom {
@@ -149,20 +283,12 @@ impl CollectionProps for CollectionBTreeCustom {
         CollectionBTreeCustom {
             store,
             option_val: None,
+            expiries: imbl::HashMap::new(),
         }
     }
 
     fn put(&mut self, key: &str, value: ValueObject) -> bool {
-        self.store.insert(key.to_string(), value);
-        return true;
-        // let stat = self.store.insert(key, value);
-        // match stat {
-        //     Some(stat) => {
-        //         println!("{:?}", stat);
-        //         true
-        //     }
-        //     None => false,
-        // }
+        self.store.insert(key.to_string(), value).is_some()
     }
 
     fn bulk_put(&mut self, pairs: Vec<(String, ValueObject)>) {
@@ -172,24 +298,42 @@ impl CollectionProps for CollectionBTreeCustom {
     }
 
     fn key_exists(&self, key: &str) -> bool {
-        match self.store.search(key.to_string()) {
-            None => false,
-            Some(_) => true,
-        }
+        self.store.search(key).is_some()
     }
 
     // Gets data
     fn get(&self, key: &str) -> Option<&ValueObject> {
-        let data = match self.store.search(key.to_string()) {
-            Some(value) => Some(value), // This won't work! See explanation below
-            None => None,
-        };
+        self.store.search(key)
+    }
 
-        data
+    fn get_mut(&mut self, key: &str) -> Option<&mut ValueObject> {
+        self.store.search_mut(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<ValueObject> {
+        self.expiries.remove(key);
+        self.store.remove(key)
     }
 
-    fn incr(&mut self, key: &str) -> Result<(), &str> {
-        let val = self.get(key.clone()).unwrap();
+    fn get_expiries(&self) -> &imbl::HashMap<String, u64> {
+        &self.expiries
+    }
+
+    fn get_expiries_mut(&mut self) -> &mut imbl::HashMap<String, u64> {
+        &mut self.expiries
+    }
+
+    fn snapshot_keys(&self) -> KeyIter {
+        Box::new(OrderedKeys {
+            snapshot: self.clone(),
+            last: None,
+        })
+    }
+
+    fn incr(&mut self, key: &str) -> Result<(), LokiError> {
+        let val = self
+            .get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
 
         match val {
             ValueObject::IntData(data) => {
@@ -202,12 +346,14 @@ impl CollectionProps for CollectionBTreeCustom {
                     .insert(key.to_string(), ValueObject::DecimalData(data + 1.0));
                 Ok(())
             }
-            _ => Err("incr not supported for data type"),
+            _ => Err(LokiError::WrongType(format!("{} is not a number", key))),
         }
     }
 
-    fn decr(&mut self, key: &str) -> Result<(), &str> {
-        let val = self.get(key.clone()).unwrap();
+    fn decr(&mut self, key: &str) -> Result<(), LokiError> {
+        let val = self
+            .get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
 
         match val {
             ValueObject::IntData(data) => {
@@ -220,7 +366,7 @@ impl CollectionProps for CollectionBTreeCustom {
                     .insert(key.to_string(), ValueObject::DecimalData(data - 1.0));
                 Ok(())
             }
-            _ => Err("incr not supported for data type"),
+            _ => Err(LokiError::WrongType(format!("{} is not a number", key))),
         }
     }
 
@@ -230,22 +376,33 @@ impl CollectionProps for CollectionBTreeCustom {
     }
 
     fn generate_pairs(&self) -> Vec<(String, ValueObject)> {
-        let mut data: Vec<(String, ValueObject)> = vec![];
-        self.store.generate_pairs(0, data.as_mut());
-        return data;
+        self.store.generate_pairs()
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+    ) -> Option<PairIter<'a>> {
+        Some(Box::new(self.store.range(start, end, reverse)))
     }
 }
 
 // Equivalent to a table
 #[derive(Clone)]
 pub struct Collection {
-    store: HashMap<String, ValueObject>,
+    store: imbl::HashMap<String, ValueObject>,
+    expiries: imbl::HashMap<String, u64>,
 }
 
 impl CollectionProps for Collection {
     fn new() -> Self {
-        let store: HashMap<String, ValueObject> = HashMap::new();
-        Collection { store }
+        let store: imbl::HashMap<String, ValueObject> = imbl::HashMap::new();
+        Collection {
+            store,
+            expiries: imbl::HashMap::new(),
+        }
     }
     fn put(&mut self, key: &str, value: ValueObject) -> bool {
         let stat = self.store.insert(key.to_string(), value);
@@ -271,8 +428,32 @@ impl CollectionProps for Collection {
         data
     }
 
-    fn incr(&mut self, key: &str) -> Result<(), &str> {
-        let val = self.store.get(key).unwrap();
+    fn get_mut(&mut self, key: &str) -> Option<&mut ValueObject> {
+        self.store.get_mut(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<ValueObject> {
+        self.expiries.remove(key);
+        self.store.remove(key)
+    }
+
+    fn get_expiries(&self) -> &imbl::HashMap<String, u64> {
+        &self.expiries
+    }
+
+    fn get_expiries_mut(&mut self) -> &mut imbl::HashMap<String, u64> {
+        &mut self.expiries
+    }
+
+    fn snapshot_keys(&self) -> KeyIter {
+        Box::new(self.store.clone().into_iter().map(|(key, _)| key))
+    }
+
+    fn incr(&mut self, key: &str) -> Result<(), LokiError> {
+        let val = self
+            .store
+            .get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
 
         match val {
             ValueObject::IntData(data) => {
@@ -285,12 +466,15 @@ impl CollectionProps for Collection {
                     .insert(key.to_string(), ValueObject::DecimalData(data + 1.0));
                 Ok(())
             }
-            _ => Err("incr not supported for data type"),
+            _ => Err(LokiError::WrongType(format!("{} is not a number", key))),
         }
     }
 
-    fn decr(&mut self, key: &str) -> Result<(), &str> {
-        let val = self.store.get(key).unwrap();
+    fn decr(&mut self, key: &str) -> Result<(), LokiError> {
+        let val = self
+            .store
+            .get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
 
         match val {
             ValueObject::IntData(data) => {
@@ -303,7 +487,7 @@ impl CollectionProps for Collection {
                     .insert(key.to_string(), ValueObject::DecimalData(data - 1.0));
                 Ok(())
             }
-            _ => Err("incr not supported for data type"),
+            _ => Err(LokiError::WrongType(format!("{} is not a number", key))),
         }
     }
 
@@ -326,17 +510,60 @@ impl CollectionProps for Collection {
     }
 }
 
-pub fn get_data_directory() -> String {
-    match env::var("PERSIST_DIR") {
-        Ok(s) => s,
-        _ => "data".to_string(),
+// Walks the keys of an ordered collection snapshot, each step resumes the
+// range after the key returned last
+struct OrderedKeys<C: CollectionProps> {
+    snapshot: C,
+    last: Option<String>,
+}
+
+impl<C: CollectionProps> Iterator for OrderedKeys<C> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let start = match self.last.as_deref() {
+            Some(last) => Bound::Excluded(last),
+            None => Bound::Unbounded,
+        };
+        let key = self
+            .snapshot
+            .range(start, Bound::Unbounded, false)?
+            .next()
+            .map(|(key, _)| key.clone())?;
+        self.last = Some(key.clone());
+        Some(key)
+    }
+}
+
+// True when no key can lie between the bounds
+fn is_empty_range(start: Bound<&str>, end: Bound<&str>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+            s >= e
+        }
+        _ => false,
     }
 }
 
-pub fn get_control_file_path() -> String {
-    match env::var("CONTROL_FILE_PATH") {
+// Smallest string greater than every string starting with `prefix`, None
+// when there is none
+fn prefix_upper_bound(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+pub fn get_data_directory() -> String {
+    match env::var("PERSIST_DIR") {
         Ok(s) => s,
-        _ => "/home/akshat/control.toml".to_string(),
+        _ => "data".to_string(),
     }
 }
 
@@ -355,6 +582,24 @@ pub fn get_current_timestamp() -> String {
     return timestamp_str;
 }
 
+pub fn get_current_timestamp_as_millis() -> u64 {
+    let duration_since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    duration_since_epoch.as_millis() as u64
+}
+
+// HLLs, bloom filters and sketches hash items by their text, which keeps
+// persisted hashes valid across releases
+fn item_bytes(item: &ValueObject) -> Result<Vec<u8>, LokiError> {
+    match item {
+        ValueObject::IntData(val) => Ok(val.to_string().into_bytes()),
+        ValueObject::BoolData(val) => Ok(val.to_string().into_bytes()),
+        ValueObject::StringData(val) => Ok(val.as_bytes().to_vec()),
+        _ => Err(LokiError::WrongType(
+            "items must be strings, integers or booleans".to_string(),
+        )),
+    }
+}
+
 pub fn get_current_timestamp_as_u64() -> u64 {
     let now = SystemTime::now();
     let duration_since_epoch = now.duration_since(UNIX_EPOCH).unwrap();
@@ -362,154 +607,1687 @@ pub fn get_current_timestamp_as_u64() -> u64 {
     return timestamp;
 }
 
+// Collection every session starts in, it always exists
+pub const DEFAULT_COLLECTION: &str = "default";
+
 pub struct LokiKV {
     collections_hmap: HashMap<String, Collection>,
     collections_bmap: HashMap<String, CollectionBTree>,
     collections_bmap_cust: HashMap<String, CollectionBTreeCustom>,
-    current_collection: String,
     wal_manager: WALManager,
+    checkpoint_in_progress: bool,
 }
 
-impl LokiKV {
-    pub fn new() -> Self {
-        let mut collections_hmap: HashMap<String, Collection> = HashMap::new();
-        let mut collections_bmap: HashMap<String, CollectionBTree> = HashMap::new();
-        let mut collections_bmap_cust: HashMap<String, CollectionBTreeCustom> = HashMap::new();
-        let control_file_path = "/home/akshat/lokikv/control.toml";
-        collections_hmap.insert("default".to_string(), Collection::new());
-        LokiKV {
-            collections_hmap,
-            collections_bmap,
-            collections_bmap_cust,
-            current_collection: "default".to_string(),
-            wal_manager: WALManager::new(get_control_file_path()),
+// Snapshot taken by `LokiKV::begin_checkpoint`, written to disk without
+// holding the database lock
+pub struct PendingCheckpoint {
+    checkpoint_id: u64,
+    checkpoint_dir: String,
+    sealed_timeline: u64,
+    checkpoint_lsn: u64,
+    collections_hmap: HashMap<String, Collection>,
+    collections_bmap: HashMap<String, CollectionBTree>,
+    collections_bmap_cust: HashMap<String, CollectionBTreeCustom>,
+}
+
+fn snapshot_expiries(expiries: &imbl::HashMap<String, u64>) -> Vec<(String, u64)> {
+    expiries
+        .iter()
+        .map(|(key, expire_at)| (key.clone(), *expire_at))
+        .collect()
+}
+
+impl PendingCheckpoint {
+    fn snapshot_collections(&self) -> Vec<CollectionSnapshot> {
+        let mut snapshots = Vec::new();
+        for (name, col) in self.collections_hmap.iter() {
+            snapshots.push(CollectionSnapshot {
+                name: name.clone(),
+                kind: CollectionKind::HashMap,
+                pairs: col.generate_pairs(),
+                expiries: snapshot_expiries(col.get_expiries()),
+            });
+        }
+        for (name, col) in self.collections_bmap.iter() {
+            snapshots.push(CollectionSnapshot {
+                name: name.clone(),
+                kind: CollectionKind::BTreeMap,
+                pairs: col.generate_pairs(),
+                expiries: snapshot_expiries(col.get_expiries()),
+            });
+        }
+        for (name, col) in self.collections_bmap_cust.iter() {
+            snapshots.push(CollectionSnapshot {
+                name: name.clone(),
+                kind: CollectionKind::CustomBTree,
+                pairs: col.generate_pairs(),
+                expiries: snapshot_expiries(col.get_expiries()),
+            });
         }
+        snapshots
     }
 
-    pub fn create_hmap_collection(&mut self, collection_name: String) {
-        self.collections_hmap
-            .insert(collection_name, Collection::new());
+    // Serialises the snapshot into a new checkpoint directory and returns
+    // the number of records written
+    pub fn write(&self) -> Result<u64, String> {
+        let manifest = checkpoint::write_checkpoint(
+            &self.checkpoint_dir,
+            self.checkpoint_id,
+            self.sealed_timeline,
+            self.checkpoint_lsn,
+            &self.snapshot_collections(),
+        )?;
+        Ok(manifest.get_record_count())
     }
+}
 
-    pub fn create_bmap_collection(&mut self, collection_name: String) {
-        self.collections_bmap
-            .insert(collection_name, CollectionBTree::new());
+impl LokiKV {
+    // Opens the database in config's data directory, creating it if needed.
+    // Used to embed LokiKV without running the server.
+    pub fn open(config: Config) -> Result<Self, LokiError> {
+        let control_file_path = config.prepare_control_file()?;
+        Self::with_control_file(control_file_path).map_err(LokiError::IoError)
     }
 
-    pub fn create_custom_bcol(&mut self, collection_name: String) {
-        self.collections_bmap_cust
-            .insert(collection_name, CollectionBTreeCustom::new());
+    // Opens the database described by the control file and rebuilds its
+    // state from the last checkpoint and the WAL written after it
+    pub fn with_control_file(control_file_path: String) -> Result<Self, String> {
+        let mut db = LokiKV {
+            collections_hmap: HashMap::new(),
+            collections_bmap: HashMap::new(),
+            collections_bmap_cust: HashMap::new(),
+            wal_manager: WALManager::new(control_file_path)?,
+            checkpoint_in_progress: false,
+        };
+
+        let applied = db.recover()?;
+        for (collection_name, count) in applied.iter() {
+            info_string(format!(
+                "Recovered {} records into collection {}",
+                count, collection_name
+            ));
+        }
+
+        if !db.collection_exists(DEFAULT_COLLECTION) {
+            db.collections_hmap
+                .insert(DEFAULT_COLLECTION.to_string(), Collection::new());
+        }
+        Ok(db)
     }
 
-    pub fn append_custom_bcol(&mut self, collection_name: String, col: CollectionBTreeCustom) {
-        self.collections_bmap_cust.insert(collection_name, col);
+    // Loads the latest checkpoint and replays every WAL timeline written since,
+    // returning the number of records applied per collection
+    fn recover(&mut self) -> Result<BTreeMap<String, usize>, String> {
+        let mut applied: BTreeMap<String, usize> = BTreeMap::new();
+        let control_file = self.wal_manager.get_control_file().clone();
+
+        let checkpoint_id = control_file.get_last_checkpoint_id();
+        if checkpoint_id > 0 {
+            info_string(format!("Loading checkpoint {}", checkpoint_id));
+            let snapshots = checkpoint::read_checkpoint(
+                control_file.get_checkpoint_directory_path(),
+                checkpoint_id,
+            )?;
+            for snapshot in snapshots {
+                *applied.entry(snapshot.name.clone()).or_insert(0) += snapshot.pairs.len();
+                self.apply_record(WALRecord::LoadCollection {
+                    collection: snapshot.name.clone(),
+                    kind: snapshot.kind,
+                    pairs: snapshot.pairs,
+                })
+                .map_err(|e| e.to_string())?;
+                for (key, expire_at) in snapshot.expiries {
+                    self.apply_record(WALRecord::Expire {
+                        collection: snapshot.name.clone(),
+                        key,
+                        expire_at: Some(expire_at),
+                    })
+                    .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+
+        for (lsn, record) in self.wal_manager.replay_records()? {
+            let collection_name = record.get_collection_name().to_string();
+            if record.needs_existing_collection() && !self.collection_exists(&collection_name) {
+                // The default collection is never logged, every other one
+                // has to come from a checkpoint or a CreateCollection record
+                if collection_name != DEFAULT_COLLECTION {
+                    return Err(format!(
+                        "WAL record {} refers to collection {} which was never created",
+                        lsn, collection_name
+                    ));
+                }
+                self.collections_hmap
+                    .insert(collection_name.clone(), Collection::new());
+            }
+            self.apply_record(record)
+                .map_err(|e| format!("Failed to replay WAL record {}: {}", lsn, e))?;
+            *applied.entry(collection_name).or_insert(0) += 1;
+        }
+        Ok(applied)
     }
 
-    pub fn append_bcol(&mut self, collection_name: String, col: CollectionBTree) {
-        self.collections_bmap.insert(collection_name, col);
+    pub fn get_collection_kind(&self, collection_name: &str) -> Option<CollectionKind> {
+        if self.collections_hmap.contains_key(collection_name) {
+            Some(CollectionKind::HashMap)
+        } else if self.collections_bmap.contains_key(collection_name) {
+            Some(CollectionKind::BTreeMap)
+        } else if self.collections_bmap_cust.contains_key(collection_name) {
+            Some(CollectionKind::CustomBTree)
+        } else {
+            None
+        }
     }
 
-    pub fn append_hmap(&mut self, collection_name: String, col: Collection) {
-        self.collections_hmap.insert(collection_name, col);
+    pub fn collection_exists(&self, collection_name: &str) -> bool {
+        self.collections_hmap.contains_key(collection_name)
+            || self.collections_bmap.contains_key(collection_name)
+            || self.collections_bmap_cust.contains_key(collection_name)
     }
 
-    pub fn remove_collection(&mut self, collection_name: String) {
-        self.collections_bmap.remove(collection_name.as_str());
-        self.collections_bmap_cust.remove(collection_name.as_str());
-        self.collections_hmap.remove(collection_name.as_str());
+    // Applies a mutation to the in-memory collections without logging it,
+    // shared by the public write methods and WAL replay
+    fn apply_record(&mut self, record: WALRecord) -> Result<(), LokiError> {
+        match record {
+            WALRecord::Put {
+                collection,
+                key,
+                value,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                col.put(&key, value);
+                col.set_expiry(&key, None);
+            }
+            WALRecord::PutWithExpiry {
+                collection,
+                key,
+                value,
+                expire_at,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                col.put(&key, value);
+                col.set_expiry(&key, Some(expire_at));
+            }
+            WALRecord::Expire {
+                collection,
+                key,
+                expire_at,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                if !col.key_exists(&key) {
+                    return Err(LokiError::KeyNotFound(key));
+                }
+                col.set_expiry(&key, expire_at);
+            }
+            WALRecord::Incr { collection, key } => {
+                self.get_collection_by_name_mut(&collection)?.incr(&key)?;
+            }
+            WALRecord::Decr { collection, key } => {
+                self.get_collection_by_name_mut(&collection)?.decr(&key)?;
+            }
+            WALRecord::CreateCollection { collection, kind } => {
+                self.remove_collection_unlogged(&collection);
+                match kind {
+                    CollectionKind::HashMap => {
+                        self.collections_hmap.insert(collection, Collection::new());
+                    }
+                    CollectionKind::BTreeMap => {
+                        self.collections_bmap
+                            .insert(collection, CollectionBTree::new());
+                    }
+                    CollectionKind::CustomBTree => {
+                        self.collections_bmap_cust
+                            .insert(collection, CollectionBTreeCustom::new());
+                    }
+                }
+            }
+            WALRecord::DropCollection { collection } => {
+                self.remove_collection_unlogged(&collection);
+            }
+            WALRecord::HllAdd {
+                collection,
+                key,
+                item,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                let mut hll = match col.get(&key) {
+                    Some(ValueObject::HLLPointer(hll)) => hll.clone(),
+                    Some(_) => return Err(LokiError::WrongType(format!("{} is not an HLL", key))),
+                    None => HLL::new(),
+                };
+                hll.add_item(&item_bytes(&item)?);
+                col.put(&key, ValueObject::HLLPointer(hll));
+            }
+            WALRecord::HllMerge {
+                collection,
+                key,
+                source,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::HLLPointer(hll)) => hll.merge(&source),
+                    Some(_) => return Err(LokiError::WrongType(format!("{} is not an HLL", key))),
+                    None => {
+                        col.put(&key, ValueObject::HLLPointer(source));
+                    }
+                }
+            }
+            WALRecord::BloomReserve {
+                collection,
+                key,
+                error_rate,
+                capacity,
+            } => {
+                let filter = BloomFilter::new(error_rate, capacity)?;
+                self.get_collection_by_name_mut(&collection)?
+                    .put(&key, ValueObject::BloomFilterData(filter));
+            }
+            WALRecord::BloomAdd {
+                collection,
+                key,
+                item,
+            } => {
+                let item = item_bytes(&item)?;
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::BloomFilterData(filter)) => {
+                        filter.insert(&item);
+                    }
+                    Some(_) => {
+                        return Err(LokiError::WrongType(format!(
+                            "{} is not a bloom filter",
+                            key
+                        )))
+                    }
+                    None => {
+                        let mut filter = BloomFilter::default();
+                        filter.insert(&item);
+                        col.put(&key, ValueObject::BloomFilterData(filter));
+                    }
+                }
+            }
+            WALRecord::CmsIncr {
+                collection,
+                key,
+                item,
+                count,
+            } => {
+                let item = item_bytes(&item)?;
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::CountMinSketchData(sketch)) => {
+                        sketch.increment(&item, count);
+                    }
+                    Some(_) => {
+                        return Err(LokiError::WrongType(format!(
+                            "{} is not a count-min sketch",
+                            key
+                        )))
+                    }
+                    None => {
+                        let mut sketch = CountMinSketch::default();
+                        sketch.increment(&item, count);
+                        col.put(&key, ValueObject::CountMinSketchData(sketch));
+                    }
+                }
+            }
+            WALRecord::List {
+                collection,
+                key,
+                op,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::ListData(list)) => {
+                        op.apply(list)?;
+                        // Like in Redis a list is gone once it is empty
+                        if list.is_empty() {
+                            col.remove(&key);
+                        }
+                    }
+                    Some(_) => return Err(LokiError::WrongType(format!("{} is not a list", key))),
+                    None => match op {
+                        ListOp::Push { .. } => {
+                            let mut list = Vec::new();
+                            op.apply(&mut list)?;
+                            col.put(&key, ValueObject::ListData(list));
+                        }
+                        _ => return Err(LokiError::KeyNotFound(key)),
+                    },
+                }
+            }
+            WALRecord::Hash {
+                collection,
+                key,
+                op,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::MapData(map)) => {
+                        op.apply(map)?;
+                        if map.is_empty() {
+                            col.remove(&key);
+                        }
+                    }
+                    Some(_) => return Err(LokiError::WrongType(format!("{} is not a map", key))),
+                    None => {
+                        let mut map = BTreeMap::new();
+                        op.apply(&mut map)?;
+                        if !map.is_empty() {
+                            col.put(&key, ValueObject::MapData(map));
+                        }
+                    }
+                }
+            }
+            WALRecord::SetMembers {
+                collection,
+                key,
+                op,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::SetData(set)) => {
+                        op.apply(set);
+                        if set.is_empty() {
+                            col.remove(&key);
+                        }
+                    }
+                    Some(_) => return Err(LokiError::WrongType(format!("{} is not a set", key))),
+                    None => {
+                        let mut set = BTreeSet::new();
+                        op.apply(&mut set);
+                        if !set.is_empty() {
+                            col.put(&key, ValueObject::SetData(set));
+                        }
+                    }
+                }
+            }
+            WALRecord::SortedSet {
+                collection,
+                key,
+                op,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                match col.get_mut(&key) {
+                    Some(ValueObject::SortedSetData(set)) => {
+                        op.apply(set)?;
+                        if set.is_empty() {
+                            col.remove(&key);
+                        }
+                    }
+                    Some(_) => {
+                        return Err(LokiError::WrongType(format!("{} is not a sorted set", key)))
+                    }
+                    None => {
+                        let mut set = SortedSet::new();
+                        op.apply(&mut set)?;
+                        if !set.is_empty() {
+                            col.put(&key, ValueObject::SortedSetData(set));
+                        }
+                    }
+                }
+            }
+            WALRecord::Delete { collection, key } => {
+                self.get_collection_by_name_mut(&collection)?
+                    .remove(&key)
+                    .ok_or(LokiError::KeyNotFound(key))?;
+            }
+            WALRecord::Rename {
+                collection,
+                key,
+                new_key,
+            } => {
+                let col = self.get_collection_by_name_mut(&collection)?;
+                let expire_at = col.get_expiry(&key);
+                let value = col.remove(&key).ok_or(LokiError::KeyNotFound(key))?;
+                col.put(&new_key, value);
+                col.set_expiry(&new_key, expire_at);
+            }
+            WALRecord::LoadCollection {
+                collection,
+                kind,
+                pairs,
+            } => {
+                self.remove_collection_unlogged(&collection);
+                match kind {
+                    CollectionKind::HashMap => {
+                        let mut col = Collection::new();
+                        col.bulk_put(pairs);
+                        self.collections_hmap.insert(collection, col);
+                    }
+                    CollectionKind::BTreeMap => {
+                        let mut col = CollectionBTree::new();
+                        col.bulk_put(pairs);
+                        self.collections_bmap.insert(collection, col);
+                    }
+                    CollectionKind::CustomBTree => {
+                        let mut col = CollectionBTreeCustom::new();
+                        col.bulk_put(pairs);
+                        self.collections_bmap_cust.insert(collection, col);
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn select_collection(&mut self, key: &str) {
-        if self.collections_hmap.contains_key(key) {
-            self.current_collection = key.to_string();
-        } else if self.collections_bmap.contains_key(key) {
-            self.current_collection = key.to_string();
-        } else if self.collections_bmap_cust.contains_key(key) {
-            self.current_collection = key.to_string();
-        } else {
-            panic!("Collection not found!")
+    // Fails exactly when `apply_record` would, without changing anything.
+    // Keep the two in step, a logged record that fails to apply would also
+    // fail recovery.
+    fn check_record(&self, record: &WALRecord) -> Result<(), LokiError> {
+        if !record.needs_existing_collection() {
+            return Ok(());
         }
+        let col = self.get_collection_by_name(record.get_collection_name())?;
+        let wrong_type =
+            |key: &str, kind: &str| Err(LokiError::WrongType(format!("{} is not {}", key, kind)));
+        match record {
+            WALRecord::Expire { key, .. }
+            | WALRecord::Delete { key, .. }
+            | WALRecord::Rename { key, .. }
+                if !col.key_exists(key) =>
+            {
+                Err(LokiError::KeyNotFound(key.clone()))
+            }
+            WALRecord::Incr { key, .. } | WALRecord::Decr { key, .. } => match col.get(key) {
+                Some(ValueObject::IntData(_)) | Some(ValueObject::DecimalData(_)) => Ok(()),
+                Some(_) => wrong_type(key, "a number"),
+                None => Err(LokiError::KeyNotFound(key.clone())),
+            },
+            WALRecord::HllAdd { key, item, .. } => {
+                item_bytes(item)?;
+                match col.get(key) {
+                    Some(ValueObject::HLLPointer(_)) | None => Ok(()),
+                    Some(_) => wrong_type(key, "an HLL"),
+                }
+            }
+            WALRecord::HllMerge { key, .. } => match col.get(key) {
+                Some(ValueObject::HLLPointer(_)) | None => Ok(()),
+                Some(_) => wrong_type(key, "an HLL"),
+            },
+            WALRecord::BloomReserve {
+                error_rate,
+                capacity,
+                ..
+            } => BloomFilter::bits_needed(*error_rate, *capacity).map(|_| ()),
+            WALRecord::BloomAdd { key, item, .. } => {
+                item_bytes(item)?;
+                match col.get(key) {
+                    Some(ValueObject::BloomFilterData(_)) | None => Ok(()),
+                    Some(_) => wrong_type(key, "a bloom filter"),
+                }
+            }
+            WALRecord::CmsIncr { key, item, .. } => {
+                item_bytes(item)?;
+                match col.get(key) {
+                    Some(ValueObject::CountMinSketchData(_)) | None => Ok(()),
+                    Some(_) => wrong_type(key, "a count-min sketch"),
+                }
+            }
+            WALRecord::List { key, op, .. } => match (col.get(key), op) {
+                (Some(ValueObject::ListData(list)), op) => op.check(list),
+                (Some(_), _) => wrong_type(key, "a list"),
+                (None, ListOp::Push { .. }) => Ok(()),
+                (None, _) => Err(LokiError::KeyNotFound(key.clone())),
+            },
+            WALRecord::Hash { key, op, .. } => match col.get(key) {
+                Some(ValueObject::MapData(map)) => op.check(map),
+                Some(_) => wrong_type(key, "a map"),
+                None => op.check(&BTreeMap::new()),
+            },
+            WALRecord::SetMembers { key, .. } => match col.get(key) {
+                Some(ValueObject::SetData(_)) | None => Ok(()),
+                Some(_) => wrong_type(key, "a set"),
+            },
+            WALRecord::SortedSet { key, op, .. } => match col.get(key) {
+                Some(ValueObject::SortedSetData(set)) => op.check(set),
+                Some(_) => wrong_type(key, "a sorted set"),
+                None => op.check(&SortedSet::new()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Logs a mutation and then applies it. Records that would fail are
+    // rejected before they reach the WAL, and memory is only changed once
+    // the append went through, so no reader ever sees a change that a
+    // restart would lose. Values are changed in place, without a copy.
+    fn commit(&mut self, record: WALRecord) -> Result<(), LokiError> {
+        self.check_record(&record)?;
+        self.wal_manager
+            .append_record(record.clone())
+            .map_err(LokiError::IoError)?;
+        self.apply_record(record)
+    }
+
+    // Creates an empty collection, replacing any collection of that name
+    pub fn create_collection(
+        &mut self,
+        collection_name: &str,
+        kind: CollectionKind,
+    ) -> Result<(), LokiError> {
+        self.commit(WALRecord::CreateCollection {
+            collection: collection_name.to_string(),
+            kind,
+        })
+    }
+
+    pub fn create_hmap_collection(&mut self, collection_name: String) -> Result<(), LokiError> {
+        self.create_collection(&collection_name, CollectionKind::HashMap)
+    }
+
+    pub fn create_bmap_collection(&mut self, collection_name: String) -> Result<(), LokiError> {
+        self.create_collection(&collection_name, CollectionKind::BTreeMap)
+    }
+
+    pub fn create_custom_bcol(&mut self, collection_name: String) -> Result<(), LokiError> {
+        self.create_collection(&collection_name, CollectionKind::CustomBTree)
+    }
+
+    // Collections loaded from disk are logged with their full contents so that
+    // replay does not depend on the persisted files staying around
+    pub fn append_custom_bcol(
+        &mut self,
+        collection_name: String,
+        col: CollectionBTreeCustom,
+    ) -> Result<(), LokiError> {
+        self.load_collection(
+            collection_name,
+            CollectionKind::CustomBTree,
+            col.generate_pairs(),
+        )
+    }
+
+    pub fn append_bcol(
+        &mut self,
+        collection_name: String,
+        col: CollectionBTree,
+    ) -> Result<(), LokiError> {
+        self.load_collection(
+            collection_name,
+            CollectionKind::BTreeMap,
+            col.generate_pairs(),
+        )
     }
 
-    pub fn get_current_collection_name(&self) -> String {
-        self.current_collection.clone()
+    // Replaces `collection_name` with `pairs` stored in a collection of `kind`
+    pub fn load_collection(
+        &mut self,
+        collection_name: String,
+        kind: CollectionKind,
+        pairs: Vec<(String, ValueObject)>,
+    ) -> Result<(), LokiError> {
+        self.commit(WALRecord::LoadCollection {
+            collection: collection_name,
+            kind,
+            pairs,
+        })
     }
 
-    pub fn get_current_collection_mut(&mut self) -> &mut dyn CollectionProps {
-        self.get_collection_by_name_mut(&self.current_collection.clone())
+    pub fn append_hmap(
+        &mut self,
+        collection_name: String,
+        col: Collection,
+    ) -> Result<(), LokiError> {
+        self.load_collection(
+            collection_name,
+            CollectionKind::HashMap,
+            col.generate_pairs(),
+        )
     }
 
-    pub fn get_collection_by_name(&self, name: &str) -> &dyn CollectionProps {
+    pub fn remove_collection(&mut self, collection_name: String) -> Result<(), LokiError> {
+        if !self.collection_exists(&collection_name) {
+            return Err(LokiError::CollectionNotFound(collection_name));
+        }
+        self.commit(WALRecord::DropCollection {
+            collection: collection_name,
+        })
+    }
+
+    fn remove_collection_unlogged(&mut self, collection_name: &str) {
+        self.collections_bmap.remove(collection_name);
+        self.collections_bmap_cust.remove(collection_name);
+        self.collections_hmap.remove(collection_name);
+    }
+
+    pub fn get_collection_by_name(&self, name: &str) -> Result<&dyn CollectionProps, LokiError> {
         if let Some(x) = self.collections_hmap.get(name) {
-            return x;
+            return Ok(x);
         }
 
         if let Some(x) = self.collections_bmap.get(name) {
-            return x;
+            return Ok(x);
         }
 
         if let Some(x) = self.collections_bmap_cust.get(name) {
-            return x;
+            return Ok(x);
         }
 
-        panic!("Collection does not exist!")
+        Err(LokiError::CollectionNotFound(name.to_string()))
     }
 
-    pub fn get_collection_by_name_mut(&mut self, name: &str) -> &mut dyn CollectionProps {
+    pub fn get_collection_by_name_mut(
+        &mut self,
+        name: &str,
+    ) -> Result<&mut dyn CollectionProps, LokiError> {
         if let Some(x) = self.collections_hmap.get_mut(name) {
-            return x;
+            return Ok(x);
         }
 
         if let Some(x) = self.collections_bmap.get_mut(name) {
-            return x;
+            return Ok(x);
         }
 
         if let Some(x) = self.collections_bmap_cust.get_mut(name) {
-            return x;
+            return Ok(x);
         }
 
-        error_string("Collection does not exist!".to_string());
-        panic!()
+        Err(LokiError::CollectionNotFound(name.to_string()))
     }
 
-    pub fn get_current_collection(&self) -> &dyn CollectionProps {
-        return self.get_collection_by_name(self.current_collection.clone().as_str());
+    // Inserts Data, returns whether the key already existed
+    pub fn put(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        value: ValueObject,
+    ) -> Result<bool, LokiError> {
+        let existed = self.exists(collection_name, key)?;
+        self.commit(WALRecord::Put {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            value,
+        })?;
+        Ok(existed)
     }
 
-    // Inserts Data
-    pub fn put(&mut self, key: &str, value: ValueObject) -> bool {
-        info_string("Appending to wal!".to_string());
-        self.wal_manager.append_record(
-            self.current_collection.to_string(),
-            key.to_string(),
-            value.clone(),
-        );
-        self.get_current_collection_mut().put(key, value)
+    // Inserts Data that expires at `expire_at` (milliseconds since the epoch)
+    pub fn put_with_expiry(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        value: ValueObject,
+        expire_at: u64,
+    ) -> Result<bool, LokiError> {
+        let existed = self.exists(collection_name, key)?;
+        self.commit(WALRecord::PutWithExpiry {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            value,
+            expire_at,
+        })?;
+        Ok(existed)
     }
 
-    pub fn put_in_collection(&mut self, collection_name: &str, key: &str, value: ValueObject) {
-        self.wal_manager
-            .append_record(collection_name.to_string(), key.to_string(), value.clone());
-        self.get_collection_by_name_mut(collection_name)
-            .put(key, value);
+    // Gets data, keys past their deadline read as missing until they are
+    // swept
+    pub fn get(&self, collection_name: &str, key: &str) -> Result<&ValueObject, LokiError> {
+        let col = self.get_collection_by_name(collection_name)?;
+        if col.is_expired(key, get_current_timestamp_as_millis()) {
+            return Err(LokiError::KeyNotFound(key.to_string()));
+        }
+        col.get(key)
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))
     }
 
-    // Gets data
-    pub fn get(&self, key: &str) -> Option<&ValueObject> {
-        self.get_current_collection().get(key)
+    // Logs the removal of a key whose deadline passed, so that writes never
+    // operate on an expired value
+    fn purge_if_expired(&mut self, collection_name: &str, key: &str) -> Result<(), LokiError> {
+        let now = get_current_timestamp_as_millis();
+        if self
+            .get_collection_by_name(collection_name)?
+            .is_expired(key, now)
+        {
+            self.commit(WALRecord::Delete {
+                collection: collection_name.to_string(),
+                key: key.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+
+    // Removes a key, returns false if there was nothing to remove
+    pub fn delete(&mut self, collection_name: &str, key: &str) -> Result<bool, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        if !self
+            .get_collection_by_name(collection_name)?
+            .key_exists(key)
+        {
+            return Ok(false);
+        }
+        self.commit(WALRecord::Delete {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+        })?;
+        Ok(true)
     }
 
-    pub fn incr(&mut self, key: &str) -> Result<(), &str> {
-        self.get_current_collection_mut().incr(key)
+    pub fn exists(&self, collection_name: &str, key: &str) -> Result<bool, LokiError> {
+        let col = self.get_collection_by_name(collection_name)?;
+        Ok(col.key_exists(key) && !col.is_expired(key, get_current_timestamp_as_millis()))
     }
 
-    pub fn decr(&mut self, key: &str) -> Result<(), &str> {
-        self.get_current_collection_mut().decr(key)
+    // Sets or clears the deadline of a key, returns false if the key is
+    // missing or there was no deadline to clear
+    pub fn expire(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        expire_at: Option<u64>,
+    ) -> Result<bool, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let col = self.get_collection_by_name(collection_name)?;
+        if !col.key_exists(key) || (expire_at.is_none() && col.get_expiry(key).is_none()) {
+            return Ok(false);
+        }
+        self.commit(WALRecord::Expire {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            expire_at,
+        })?;
+        Ok(true)
+    }
+
+    // Milliseconds until the key expires, None if it never does
+    pub fn ttl(&self, collection_name: &str, key: &str) -> Result<Option<u64>, LokiError> {
+        if !self.exists(collection_name, key)? {
+            return Err(LokiError::KeyNotFound(key.to_string()));
+        }
+        let now = get_current_timestamp_as_millis();
+        Ok(self
+            .get_collection_by_name(collection_name)?
+            .get_expiry(key)
+            .map(|expire_at| expire_at.saturating_sub(now)))
+    }
+
+    // Deletes every key whose deadline has passed and returns how many
+    pub fn remove_expired_keys(&mut self) -> Result<usize, LokiError> {
+        let now = get_current_timestamp_as_millis();
+        let mut expired: Vec<(String, String)> = Vec::new();
+        let names: Vec<String> = self
+            .collections_hmap
+            .keys()
+            .chain(self.collections_bmap.keys())
+            .chain(self.collections_bmap_cust.keys())
+            .cloned()
+            .collect();
+        for name in names {
+            for key in self.get_collection_by_name(&name)?.expired_keys(now) {
+                expired.push((name.clone(), key));
+            }
+        }
+
+        for (collection, key) in expired.iter() {
+            self.commit(WALRecord::Delete {
+                collection: collection.clone(),
+                key: key.clone(),
+            })?;
+        }
+        Ok(expired.len())
+    }
+
+    // Moves the value at key to new_key, replacing whatever new_key held
+    pub fn rename(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        new_key: &str,
+    ) -> Result<(), LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit(WALRecord::Rename {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            new_key: new_key.to_string(),
+        })
+    }
+
+    // Returns the value after the increment
+    pub fn incr(&mut self, collection_name: &str, key: &str) -> Result<isize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit(WALRecord::Incr {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+        })?;
+        self.get_counter(collection_name, key)
+    }
+
+    pub fn decr(&mut self, collection_name: &str, key: &str) -> Result<isize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit(WALRecord::Decr {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+        })?;
+        self.get_counter(collection_name, key)
+    }
+
+    fn get_counter(&self, collection_name: &str, key: &str) -> Result<isize, LokiError> {
+        match self.get(collection_name, key)? {
+            ValueObject::IntData(value) => Ok(*value),
+            _ => Err(LokiError::WrongType(format!("{} is not an integer", key))),
+        }
+    }
+
+    // Adds an item to the HyperLogLog stored at key, creating it if needed
+    pub fn hll_add(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        item: ValueObject,
+    ) -> Result<(), LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit(WALRecord::HllAdd {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            item,
+        })
+    }
+
+    // The HLL stored at key, None if the key is not set
+    fn get_hll(&self, collection_name: &str, key: &str) -> Result<Option<&HLL>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::HLLPointer(hll)) => Ok(Some(hll)),
+            Ok(_) => Err(LokiError::WrongType(format!("{} is not an HLL", key))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Estimated number of distinct items added to any of the HLLs at keys,
+    // missing keys count as empty
+    pub fn hll_count(&self, collection_name: &str, keys: &[String]) -> Result<f64, LokiError> {
+        let mut union = HLL::new();
+        for key in keys {
+            match (keys.len(), self.get_hll(collection_name, key)?) {
+                (1, Some(hll)) => return Ok(hll.calculate_cardinality()),
+                (_, Some(hll)) => union.merge(hll),
+                (_, None) => {}
+            }
+        }
+        Ok(union.calculate_cardinality())
+    }
+
+    // Stores the union of destination and the source HLLs at destination
+    pub fn hll_merge(
+        &mut self,
+        collection_name: &str,
+        destination: &str,
+        sources: &[String],
+    ) -> Result<(), LokiError> {
+        self.purge_if_expired(collection_name, destination)?;
+        let mut source = HLL::new();
+        for key in sources {
+            if let Some(hll) = self.get_hll(collection_name, key)? {
+                source.merge(hll);
+            }
+        }
+        // The destination keeps its expiry
+        self.commit(WALRecord::HllMerge {
+            collection: collection_name.to_string(),
+            key: destination.to_string(),
+            source,
+        })
+    }
+
+    // Creates an empty bloom filter sized for capacity items at error_rate,
+    // false if the key is already set
+    pub fn bloom_reserve(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        error_rate: f64,
+        capacity: u64,
+    ) -> Result<bool, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        if self.exists(collection_name, key)? {
+            return Ok(false);
+        }
+        // Checked here so an invalid size never reaches the WAL
+        BloomFilter::new(error_rate, capacity)?;
+        self.commit(WALRecord::BloomReserve {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            error_rate,
+            capacity,
+        })?;
+        Ok(true)
+    }
+
+    fn get_bloom_filter(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<Option<&BloomFilter>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::BloomFilterData(filter)) => Ok(Some(filter)),
+            Ok(_) => Err(LokiError::WrongType(format!(
+                "{} is not a bloom filter",
+                key
+            ))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Adds item to the bloom filter at key, creating a default sized one if
+    // needed. Returns false if the item may have been added before.
+    pub fn bloom_add(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        item: ValueObject,
+    ) -> Result<bool, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let added = !self.bloom_exists(collection_name, key, &item)?;
+        if added {
+            self.commit(WALRecord::BloomAdd {
+                collection: collection_name.to_string(),
+                key: key.to_string(),
+                item,
+            })?;
+        }
+        Ok(added)
+    }
+
+    // False when the item was certainly never added
+    pub fn bloom_exists(
+        &self,
+        collection_name: &str,
+        key: &str,
+        item: &ValueObject,
+    ) -> Result<bool, LokiError> {
+        let item = item_bytes(item)?;
+        Ok(self
+            .get_bloom_filter(collection_name, key)?
+            .is_some_and(|filter| filter.contains(&item)))
+    }
+
+    fn get_sketch(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<Option<&CountMinSketch>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::CountMinSketchData(sketch)) => Ok(Some(sketch)),
+            Ok(_) => Err(LokiError::WrongType(format!(
+                "{} is not a count-min sketch",
+                key
+            ))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Counts item `count` more times, returns its new estimated frequency
+    pub fn sketch_incr(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        item: ValueObject,
+        count: u64,
+    ) -> Result<u64, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let bytes = item_bytes(&item)?;
+        self.commit(WALRecord::CmsIncr {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            item,
+            count,
+        })?;
+        Ok(self
+            .get_sketch(collection_name, key)?
+            .map_or(0, |sketch| sketch.estimate(&bytes)))
+    }
+
+    // Estimated frequency of item, never below the real one
+    pub fn sketch_query(
+        &self,
+        collection_name: &str,
+        key: &str,
+        item: &ValueObject,
+    ) -> Result<u64, LokiError> {
+        let item = item_bytes(item)?;
+        Ok(self
+            .get_sketch(collection_name, key)?
+            .map_or(0, |sketch| sketch.estimate(&item)))
+    }
+
+    // Adds the counts of the source sketches to destination
+    pub fn sketch_merge(
+        &mut self,
+        collection_name: &str,
+        destination: &str,
+        sources: &[String],
+    ) -> Result<(), LokiError> {
+        self.purge_if_expired(collection_name, destination)?;
+        let mut merged = self
+            .get_sketch(collection_name, destination)?
+            .cloned()
+            .unwrap_or_default();
+        for source in sources {
+            if let Some(sketch) = self.get_sketch(collection_name, source)? {
+                merged.merge(sketch)?;
+            }
+        }
+        self.put(
+            collection_name,
+            destination,
+            ValueObject::CountMinSketchData(merged),
+        )?;
+        Ok(())
+    }
+
+    // The list stored at key, None if the key is not set
+    fn get_list(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<Option<&Vec<ValueObject>>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::ListData(list)) => Ok(Some(list)),
+            Ok(_) => Err(LokiError::WrongType(format!("{} is not a list", key))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn commit_list_op(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        op: ListOp,
+    ) -> Result<(), LokiError> {
+        self.commit(WALRecord::List {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            op,
+        })
+    }
+
+    // Pushes values to one end of the list at key, creating it if needed.
+    // Returns the new length.
+    pub fn list_push(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        front: bool,
+        values: Vec<ValueObject>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit_list_op(collection_name, key, ListOp::Push { front, values })?;
+        self.list_len(collection_name, key)
+    }
+
+    // Removes up to `count` values from one end of the list, in the order
+    // they were removed
+    pub fn list_pop(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        front: bool,
+        count: usize,
+    ) -> Result<Vec<ValueObject>, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let list = self
+            .get_list(collection_name, key)?
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
+        let count = count.min(list.len());
+        let popped: Vec<ValueObject> = if front {
+            list[..count].to_vec()
+        } else {
+            list[list.len() - count..].iter().rev().cloned().collect()
+        };
+        if count > 0 {
+            self.commit_list_op(collection_name, key, ListOp::Pop { front, count })?;
+        }
+        Ok(popped)
+    }
+
+    // Values from start to stop inclusive, negative indexes count from the end
+    pub fn list_range(
+        &self,
+        collection_name: &str,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<ValueObject>, LokiError> {
+        let list = match self.get_list(collection_name, key)? {
+            Some(list) => list,
+            None => return Ok(Vec::new()),
+        };
+        Ok(match resolve_range(list.len(), start, stop) {
+            Some((start, stop)) => list[start..=stop].to_vec(),
+            None => Vec::new(),
+        })
+    }
+
+    pub fn list_index(
+        &self,
+        collection_name: &str,
+        key: &str,
+        index: isize,
+    ) -> Result<ValueObject, LokiError> {
+        let list = self
+            .get_list(collection_name, key)?
+            .ok_or_else(|| LokiError::KeyNotFound(key.to_string()))?;
+        resolve_index(list.len(), index)
+            .map(|pos| list[pos].clone())
+            .ok_or_else(|| LokiError::OutOfRange(format!("index {} out of range", index)))
+    }
+
+    pub fn list_set(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        index: isize,
+        value: ValueObject,
+    ) -> Result<(), LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit_list_op(collection_name, key, ListOp::Set { index, value })
+    }
+
+    // Number of values in the list, 0 if the key is not set
+    pub fn list_len(&self, collection_name: &str, key: &str) -> Result<usize, LokiError> {
+        Ok(self
+            .get_list(collection_name, key)?
+            .map_or(0, |list| list.len()))
+    }
+
+    // Keeps only the values from start to stop inclusive
+    pub fn list_trim(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<(), LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        if self.get_list(collection_name, key)?.is_none() {
+            return Ok(());
+        }
+        self.commit_list_op(collection_name, key, ListOp::Trim { start, stop })
+    }
+
+    // The map stored at key, None if the key is not set
+    fn get_map(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<Option<&BTreeMap<String, ValueObject>>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::MapData(map)) => Ok(Some(map)),
+            Ok(_) => Err(LokiError::WrongType(format!("{} is not a map", key))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn commit_hash_op(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        op: HashOp,
+    ) -> Result<(), LokiError> {
+        self.commit(WALRecord::Hash {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            op,
+        })
+    }
+
+    // Sets fields of the map at key, creating it if needed. Returns how many
+    // fields were added rather than updated.
+    pub fn hash_set(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        fields: Vec<(String, ValueObject)>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let added = match self.get_map(collection_name, key)? {
+            Some(map) => {
+                let new_fields: HashSet<&String> = fields
+                    .iter()
+                    .map(|(field, _)| field)
+                    .filter(|field| !map.contains_key(*field))
+                    .collect();
+                new_fields.len()
+            }
+            None => fields
+                .iter()
+                .map(|(field, _)| field)
+                .collect::<HashSet<_>>()
+                .len(),
+        };
+        self.commit_hash_op(collection_name, key, HashOp::Set { fields })?;
+        Ok(added)
+    }
+
+    pub fn hash_get(
+        &self,
+        collection_name: &str,
+        key: &str,
+        field: &str,
+    ) -> Result<ValueObject, LokiError> {
+        self.get_map(collection_name, key)?
+            .and_then(|map| map.get(field))
+            .cloned()
+            .ok_or_else(|| LokiError::KeyNotFound(format!("{} field {}", key, field)))
+    }
+
+    // Removes fields from the map, returns how many existed
+    pub fn hash_delete(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        fields: Vec<String>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let removed = match self.get_map(collection_name, key)? {
+            Some(map) => fields
+                .iter()
+                .filter(|field| map.contains_key(*field))
+                .collect::<HashSet<_>>()
+                .len(),
+            None => 0,
+        };
+        if removed > 0 {
+            self.commit_hash_op(collection_name, key, HashOp::Delete { fields })?;
+        }
+        Ok(removed)
+    }
+
+    // Every field of the map, empty if the key is not set
+    pub fn hash_get_all(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<BTreeMap<String, ValueObject>, LokiError> {
+        Ok(self
+            .get_map(collection_name, key)?
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    // Adds delta to an integer field and returns the new value
+    pub fn hash_incr_by(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        field: &str,
+        delta: isize,
+    ) -> Result<isize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit_hash_op(
+            collection_name,
+            key,
+            HashOp::IncrBy {
+                field: field.to_string(),
+                delta,
+            },
+        )?;
+        match self.hash_get(collection_name, key, field)? {
+            ValueObject::IntData(value) => Ok(value),
+            _ => Err(LokiError::WrongType(format!(
+                "field {} is not an integer",
+                field
+            ))),
+        }
+    }
+
+    pub fn hash_exists(
+        &self,
+        collection_name: &str,
+        key: &str,
+        field: &str,
+    ) -> Result<bool, LokiError> {
+        Ok(self
+            .get_map(collection_name, key)?
+            .is_some_and(|map| map.contains_key(field)))
+    }
+
+    // The set stored at key, None if the key is not set
+    fn get_set(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<Option<&BTreeSet<String>>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::SetData(set)) => Ok(Some(set)),
+            Ok(_) => Err(LokiError::WrongType(format!("{} is not a set", key))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Adds members to the set at key, creating it if needed. Returns how
+    // many were not in it yet.
+    pub fn set_add(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        members: Vec<String>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let added = {
+            let set = self.get_set(collection_name, key)?;
+            members
+                .iter()
+                .filter(|member| !set.is_some_and(|set| set.contains(*member)))
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        if added > 0 {
+            self.commit(WALRecord::SetMembers {
+                collection: collection_name.to_string(),
+                key: key.to_string(),
+                op: SetOp::Add { members },
+            })?;
+        }
+        Ok(added)
+    }
+
+    // Removes members from the set, returns how many were in it
+    pub fn set_remove(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        members: Vec<String>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let removed = match self.get_set(collection_name, key)? {
+            Some(set) => members
+                .iter()
+                .filter(|member| set.contains(*member))
+                .collect::<HashSet<_>>()
+                .len(),
+            None => 0,
+        };
+        if removed > 0 {
+            self.commit(WALRecord::SetMembers {
+                collection: collection_name.to_string(),
+                key: key.to_string(),
+                op: SetOp::Remove { members },
+            })?;
+        }
+        Ok(removed)
+    }
+
+    pub fn set_is_member(
+        &self,
+        collection_name: &str,
+        key: &str,
+        member: &str,
+    ) -> Result<bool, LokiError> {
+        Ok(self
+            .get_set(collection_name, key)?
+            .is_some_and(|set| set.contains(member)))
+    }
+
+    // Members of the set, empty if the key is not set
+    pub fn set_members(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<BTreeSet<String>, LokiError> {
+        Ok(self
+            .get_set(collection_name, key)?
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    // Union, intersection or difference of the sets at keys, in order
+    pub fn set_combine(
+        &self,
+        collection_name: &str,
+        algebra: SetAlgebra,
+        keys: &[String],
+    ) -> Result<BTreeSet<String>, LokiError> {
+        let empty = BTreeSet::new();
+        let sets = keys
+            .iter()
+            .map(|key| Ok(self.get_set(collection_name, key)?.unwrap_or(&empty)))
+            .collect::<Result<Vec<_>, LokiError>>()?;
+        Ok(combine(algebra, &sets))
+    }
+
+    // Like `set_combine`, writing the result to destination. An empty result
+    // removes destination. Returns the size of the result.
+    pub fn set_combine_store(
+        &mut self,
+        collection_name: &str,
+        algebra: SetAlgebra,
+        destination: &str,
+        keys: &[String],
+    ) -> Result<usize, LokiError> {
+        let result = self.set_combine(collection_name, algebra, keys)?;
+        let len = result.len();
+        if result.is_empty() {
+            self.delete(collection_name, destination)?;
+        } else {
+            self.put(collection_name, destination, ValueObject::SetData(result))?;
+        }
+        Ok(len)
+    }
+
+    // The sorted set stored at key, None if the key is not set
+    fn get_sorted_set(
+        &self,
+        collection_name: &str,
+        key: &str,
+    ) -> Result<Option<&SortedSet>, LokiError> {
+        match self.get(collection_name, key) {
+            Ok(ValueObject::SortedSetData(set)) => Ok(Some(set)),
+            Ok(_) => Err(LokiError::WrongType(format!("{} is not a sorted set", key))),
+            Err(LokiError::KeyNotFound(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Sets the scores of members, creating the sorted set if needed. Returns
+    // how many members were not in it yet.
+    pub fn sorted_set_add(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let added = {
+            let set = self.get_sorted_set(collection_name, key)?;
+            members
+                .iter()
+                .filter(|(member, _)| set.and_then(|set| set.get_score(member)).is_none())
+                .map(|(member, _)| member)
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        if !members.is_empty() {
+            self.commit(WALRecord::SortedSet {
+                collection: collection_name.to_string(),
+                key: key.to_string(),
+                op: SortedSetOp::Add { members },
+            })?;
+        }
+        Ok(added)
+    }
+
+    // Removes members from the sorted set, returns how many were in it
+    pub fn sorted_set_remove(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        members: Vec<String>,
+    ) -> Result<usize, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        let removed = match self.get_sorted_set(collection_name, key)? {
+            Some(set) => members
+                .iter()
+                .filter(|member| set.get_score(member).is_some())
+                .collect::<HashSet<_>>()
+                .len(),
+            None => 0,
+        };
+        if removed > 0 {
+            self.commit(WALRecord::SortedSet {
+                collection: collection_name.to_string(),
+                key: key.to_string(),
+                op: SortedSetOp::Remove { members },
+            })?;
+        }
+        Ok(removed)
+    }
+
+    // Adds delta to the score of member, returns the new score
+    pub fn sorted_set_incr_by(
+        &mut self,
+        collection_name: &str,
+        key: &str,
+        member: &str,
+        delta: f64,
+    ) -> Result<f64, LokiError> {
+        self.purge_if_expired(collection_name, key)?;
+        self.commit(WALRecord::SortedSet {
+            collection: collection_name.to_string(),
+            key: key.to_string(),
+            op: SortedSetOp::IncrBy {
+                member: member.to_string(),
+                delta,
+            },
+        })?;
+        self.get_sorted_set(collection_name, key)?
+            .and_then(|set| set.get_score(member))
+            .ok_or_else(|| LokiError::KeyNotFound(format!("{} member {}", key, member)))
+    }
+
+    pub fn sorted_set_score(
+        &self,
+        collection_name: &str,
+        key: &str,
+        member: &str,
+    ) -> Result<f64, LokiError> {
+        self.get_sorted_set(collection_name, key)?
+            .and_then(|set| set.get_score(member))
+            .ok_or_else(|| LokiError::KeyNotFound(format!("{} member {}", key, member)))
+    }
+
+    pub fn sorted_set_rank(
+        &self,
+        collection_name: &str,
+        key: &str,
+        member: &str,
+    ) -> Result<usize, LokiError> {
+        self.get_sorted_set(collection_name, key)?
+            .and_then(|set| set.rank(member))
+            .ok_or_else(|| LokiError::KeyNotFound(format!("{} member {}", key, member)))
+    }
+
+    pub fn sorted_set_len(&self, collection_name: &str, key: &str) -> Result<usize, LokiError> {
+        Ok(self
+            .get_sorted_set(collection_name, key)?
+            .map_or(0, |set| set.len()))
+    }
+
+    // Members from rank start to stop inclusive, negative ranks count from
+    // the highest score
+    pub fn sorted_set_range(
+        &self,
+        collection_name: &str,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<(String, f64)>, LokiError> {
+        let Some(set) = self.get_sorted_set(collection_name, key)? else {
+            return Ok(Vec::new());
+        };
+        Ok(match resolve_range(set.len(), start, stop) {
+            Some((start, stop)) => set
+                .iter_from_rank(start)
+                .take(stop - start + 1)
+                .map(|(member, score)| (member.clone(), score))
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    // Up to `limit` members with a score between min and max, lowest first
+    pub fn sorted_set_range_by_score(
+        &self,
+        collection_name: &str,
+        key: &str,
+        min: Bound<f64>,
+        max: Bound<f64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, f64)>, LokiError> {
+        let Some(set) = self.get_sorted_set(collection_name, key)? else {
+            return Ok(Vec::new());
+        };
+        Ok(set
+            .range_by_score(min, max)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(member, score)| (member.clone(), score))
+            .collect())
+    }
+
+    // Up to `limit` pairs between the bounds of an ordered collection, expired
+    // keys are skipped
+    pub fn scan(
+        &self,
+        collection_name: &str,
+        start: Bound<&str>,
+        end: Bound<&str>,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, ValueObject)>, LokiError> {
+        let col = self.get_collection_by_name(collection_name)?;
+        let pairs = col.range(start, end, reverse).ok_or_else(|| {
+            LokiError::WrongType(format!(
+                "{} is a hashmap collection, its keys are not ordered",
+                collection_name
+            ))
+        })?;
+        let now = get_current_timestamp_as_millis();
+        Ok(pairs
+            .filter(|(key, _)| !col.is_expired(key, now))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    pub fn snapshot_keys(&self, collection_name: &str) -> Result<KeyIter, LokiError> {
+        Ok(self
+            .get_collection_by_name(collection_name)?
+            .snapshot_keys())
+    }
+
+    // Every pair whose key starts with `prefix`, see `scan`
+    pub fn scan_prefix(
+        &self,
+        collection_name: &str,
+        prefix: &str,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, ValueObject)>, LokiError> {
+        let upper = prefix_upper_bound(prefix);
+        let end = match upper.as_deref() {
+            Some(upper) => Bound::Excluded(upper),
+            None => Bound::Unbounded,
+        };
+        self.scan(
+            collection_name,
+            Bound::Included(prefix),
+            end,
+            reverse,
+            limit,
+        )
     }
 
     // Displays all keys and values
-    pub fn display_collection(&self) -> String {
-        self.get_current_collection().display_collection()
+    pub fn display_collection(&self, collection_name: &str) -> Result<String, LokiError> {
+        Ok(self
+            .get_collection_by_name(collection_name)?
+            .display_collection())
     }
 
     pub fn get_all_collection_names(&self) -> String {
@@ -529,44 +2307,709 @@ impl LokiKV {
         res
     }
 
-    pub fn checkpoint(&mut self) {
-        let checkpoint_id = get_current_timestamp_as_u64();
+    // Seals the WAL and takes a snapshot of every collection. This is the only
+    // part of a checkpoint that needs the database lock, the collections are
+    // persistent structures so the snapshot is a cheap clone.
+    pub fn begin_checkpoint(&mut self) -> Result<PendingCheckpoint, String> {
+        if self.checkpoint_in_progress {
+            return Err("Another checkpoint is still running".to_string());
+        }
+        let control_file = self.wal_manager.get_control_file();
+        let checkpoint_id = control_file.get_next_checkpoint_id();
+        let checkpoint_dir = control_file.get_checkpoint_directory_path().to_string();
+
+        // Later writes land in a new timeline that is replayed on top of this checkpoint
+        let (sealed_timeline, checkpoint_lsn) = self.wal_manager.rotate_timeline()?;
+        self.checkpoint_in_progress = true;
+        Ok(PendingCheckpoint {
+            checkpoint_id,
+            checkpoint_dir,
+            sealed_timeline,
+            checkpoint_lsn,
+            collections_hmap: self.collections_hmap.clone(),
+            collections_bmap: self.collections_bmap.clone(),
+            collections_bmap_cust: self.collections_bmap_cust.clone(),
+        })
+    }
+
+    // Points the control file at a checkpoint written by `PendingCheckpoint::write`
+    // and drops the older checkpoints and WAL timelines it replaces
+    pub fn finish_checkpoint(
+        &mut self,
+        pending: &PendingCheckpoint,
+        written: Result<u64, String>,
+    ) -> Result<u64, String> {
+        self.checkpoint_in_progress = false;
+        // On failure the sealed timeline is simply replayed on the next start
+        let record_count = written?;
+        self.wal_manager.mark_checkpointed(
+            pending.checkpoint_id,
+            pending.sealed_timeline,
+            pending.checkpoint_lsn,
+        )?;
+        checkpoint::remove_old_checkpoints(&pending.checkpoint_dir, pending.checkpoint_id);
+
+        info_string(format!(
+            "Checkpoint {} written with {} records up to lsn {}",
+            pending.checkpoint_id, record_count, pending.checkpoint_lsn
+        ));
+        Ok(pending.checkpoint_id)
+    }
 
-        for col in self.collections_bmap_cust.iter() {
-            let pth = format!("{}/{}", get_checkpoint_directory(), checkpoint_id);
-            let pairs = col.1.clone().generate_pairs();
-            if pairs.len() == 0 {
-                continue;
-            }
-            let persistor = Persistor::new(get_control_file_path());
-            persistor.persist(pairs, col.0.clone());
+    // Runs a whole checkpoint while holding on to the database.
+    // Returns the id of the new checkpoint.
+    pub fn checkpoint(&mut self) -> Result<u64, String> {
+        let pending = self.begin_checkpoint()?;
+        let written = pending.write();
+        self.finish_checkpoint(&pending, written)
+    }
+
+    pub fn get_control_file(&self) -> &ControlFile {
+        self.wal_manager.get_control_file()
+    }
+
+    pub fn display_wal(&self) -> String {
+        let res = self.wal_manager.display_wal();
+        res
+    }
+
+    // Handle used to wait for WAL records to become durable without holding
+    // the database lock
+    pub fn get_wal_sync(&self) -> Arc<WALSync> {
+        self.wal_manager.get_sync()
+    }
+
+    // Waits until the writes made so far are as durable as the WAL sync mode
+    // promises. The server does this after every request, embedders call it
+    // before acknowledging their own writes.
+    pub fn sync(&self) -> Result<(), LokiError> {
+        let sync = self.get_wal_sync();
+        sync.wait_durable(sync.get_written_lsn())
+            .map_err(LokiError::IoError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::control::{write_test_control_file, ControlFile};
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_failed_wal_append_leaves_no_change_behind() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+        db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+            .unwrap();
+        db.create_bmap_collection("ordered".to_string()).unwrap();
+
+        // The next append has to open a new timeline file, which fails once
+        // the WAL directory is gone
+        db.wal_manager.rotate_timeline().unwrap();
+        std::fs::remove_dir_all(dir.path().join("wal")).unwrap();
+        assert!(db
+            .put(DEFAULT_COLLECTION, "a", ValueObject::IntData(2))
+            .is_err());
+        assert!(db.incr(DEFAULT_COLLECTION, "a").is_err());
+        assert!(db.create_hmap_collection("ordered".to_string()).is_err());
+        assert!(db.remove_collection("ordered".to_string()).is_err());
+
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(1))
+        ));
+        assert_eq!(
+            db.get_collection_kind("ordered"),
+            Some(CollectionKind::BTreeMap)
+        );
+    }
+
+    #[test]
+    fn test_recovers_from_wal_without_checkpoint() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+                .unwrap();
+            db.put(
+                DEFAULT_COLLECTION,
+                "b",
+                ValueObject::StringData("hello".to_string()),
+            )
+            .unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(2))
+                .unwrap();
+            db.create_hmap_collection("users".to_string()).unwrap();
+            db.put("users", "u1", ValueObject::BoolData(true)).unwrap();
         }
 
-        for col in self.collections_bmap.iter() {
-            let pth = format!("{}/{}", get_checkpoint_directory(), checkpoint_id);
-            let pairs = col.1.clone().generate_pairs();
-            if pairs.len() == 0 {
-                continue;
-            }
-            let persistor = Persistor::new(get_control_file_path());
-            persistor.persist(pairs, col.0.clone());
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(2))
+        ));
+        assert!(
+            matches!(db.get(DEFAULT_COLLECTION, "b"), Ok(ValueObject::StringData(s)) if s == "hello")
+        );
+        assert!(matches!(
+            db.get_collection_by_name("users").unwrap().get("u1"),
+            Some(ValueObject::BoolData(true))
+        ));
+    }
+
+    #[test]
+    fn test_recovers_from_checkpoint_and_newer_timelines() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "before", ValueObject::IntData(1))
+                .unwrap();
+            db.create_bmap_collection("ordered".to_string()).unwrap();
+            db.put("ordered", "k", ValueObject::BoolData(true)).unwrap();
+            db.create_hmap_collection("empty".to_string()).unwrap();
+            assert_eq!(db.checkpoint().unwrap(), 1);
+            db.put(DEFAULT_COLLECTION, "after", ValueObject::IntData(2))
+                .unwrap();
+        }
+
+        let db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "before"),
+            Ok(ValueObject::IntData(1))
+        ));
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "after"),
+            Ok(ValueObject::IntData(2))
+        ));
+        assert_eq!(
+            db.get_collection_kind("ordered"),
+            Some(CollectionKind::BTreeMap)
+        );
+        assert!(matches!(
+            db.get_collection_by_name("ordered").unwrap().get("k"),
+            Some(ValueObject::BoolData(true))
+        ));
+        assert_eq!(
+            db.get_collection_kind("empty"),
+            Some(CollectionKind::HashMap)
+        );
+
+        let control_file = ControlFile::read_from_file_path(control_file_path).unwrap();
+        assert_eq!(control_file.get_last_checkpoint_id(), 1);
+        assert_eq!(control_file.get_last_wal_timeline(), 1);
+        assert_eq!(control_file.get_last_checkpoint_lsn(), 4);
+    }
+
+    #[test]
+    fn test_checkpoint_removes_replaced_checkpoints_and_timelines() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let checkpoint_dir = dir.path().join("checkpoints");
+        let wal_dir = dir.path().join("wal");
+
+        let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+        db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+            .unwrap();
+        db.checkpoint().unwrap();
+        db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(2))
+            .unwrap();
+        db.checkpoint().unwrap();
+        db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(3))
+            .unwrap();
+
+        assert!(!checkpoint_dir.join("1").exists());
+        assert!(checkpoint_dir.join("2").join("MANIFEST").exists());
+        assert!(!wal_dir.join("1.wal").exists());
+        assert!(!wal_dir.join("2.wal").exists());
+        assert!(wal_dir.join("3.wal").exists());
+        drop(db);
+
+        // LSNs keep growing even though the older timelines are gone
+        let mut db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(3))
+        ));
+        let replayed = db.wal_manager.replay_records().unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].0, 3);
+    }
+
+    #[test]
+    fn test_unpublished_checkpoint_is_not_loaded() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+                .unwrap();
+            db.checkpoint().unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(2))
+                .unwrap();
+        }
+        // Leftovers of a checkpoint that crashed before the control file moved on
+        let checkpoint_dir = dir.path().join("checkpoints");
+        std::fs::create_dir_all(checkpoint_dir.join("2.tmp")).unwrap();
+        std::fs::create_dir_all(checkpoint_dir.join("2")).unwrap();
+
+        let mut db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(2))
+        ));
+        assert_eq!(db.checkpoint().unwrap(), 2);
+        assert!(!checkpoint_dir.join("2.tmp").exists());
+        assert!(checkpoint_dir.join("2").join("MANIFEST").exists());
+    }
+
+    #[test]
+    fn test_writes_during_checkpoint_go_to_the_next_timeline() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+                .unwrap();
+            let pending = db.begin_checkpoint().unwrap();
+            assert!(db.begin_checkpoint().is_err());
+
+            // The snapshot is not affected by writes made while it is written out
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(2))
+                .unwrap();
+            db.put(DEFAULT_COLLECTION, "b", ValueObject::IntData(3))
+                .unwrap();
+            let written = pending.write();
+            assert_eq!(db.finish_checkpoint(&pending, written).unwrap(), 1);
+        }
+
+        let snapshot =
+            checkpoint::read_checkpoint(&dir.path().join("checkpoints").display().to_string(), 1)
+                .unwrap();
+        let default = snapshot.iter().find(|col| col.name == "default").unwrap();
+        assert_eq!(default.pairs.len(), 1);
+        assert!(matches!(default.pairs[0].1, ValueObject::IntData(1)));
+
+        let mut db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(2))
+        ));
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "b"),
+            Ok(ValueObject::IntData(3))
+        ));
+        assert!(db.checkpoint().is_ok());
+    }
+
+    #[test]
+    fn test_recovery_truncates_corrupt_tail() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+                .unwrap();
+        }
+
+        let wal_path = dir.path().join("wal").join("1.wal");
+        let intact_len = std::fs::metadata(&wal_path).unwrap().len();
+        let mut wal = OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(&[0xff; 7]).unwrap();
+
+        let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(1))
+        ));
+        assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), intact_len);
+
+        // New records land after the intact prefix and survive another restart
+        db.put(DEFAULT_COLLECTION, "b", ValueObject::IntData(2))
+            .unwrap();
+        drop(db);
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(1))
+        ));
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "b"),
+            Ok(ValueObject::IntData(2))
+        ));
+    }
+
+    #[test]
+    fn test_replays_every_kind_of_mutation() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "counter", ValueObject::IntData(10))
+                .unwrap();
+            db.incr(DEFAULT_COLLECTION, "counter").unwrap();
+            db.incr(DEFAULT_COLLECTION, "counter").unwrap();
+            db.decr(DEFAULT_COLLECTION, "counter").unwrap();
+            db.hll_add(
+                DEFAULT_COLLECTION,
+                "visitors",
+                ValueObject::StringData("a".to_string()),
+            )
+            .unwrap();
+            db.hll_add(DEFAULT_COLLECTION, "visitors", ValueObject::IntData(7))
+                .unwrap();
+
+            db.create_bmap_collection("ordered".to_string()).unwrap();
+            db.put("ordered", "k", ValueObject::BoolData(false))
+                .unwrap();
+            db.create_custom_bcol("custom".to_string()).unwrap();
+            db.put("custom", "old", ValueObject::IntData(1)).unwrap();
+            db.put("custom", "gone", ValueObject::IntData(2)).unwrap();
+            db.rename("custom", "old", "new").unwrap();
+            assert!(db.delete("custom", "gone").unwrap());
+            assert!(!db.delete("custom", "gone").unwrap());
+            db.create_hmap_collection("dropped".to_string()).unwrap();
+            db.remove_collection("dropped".to_string()).unwrap();
+
+            let mut loaded = CollectionBTree::new();
+            loaded.put("x", ValueObject::DecimalData(1.5));
+            db.append_bcol("loaded".to_string(), loaded).unwrap();
+        }
+
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "counter"),
+            Ok(ValueObject::IntData(11))
+        ));
+        assert!(matches!(
+            db.get("custom", "new"),
+            Ok(ValueObject::IntData(1))
+        ));
+        assert!(!db.exists("custom", "old").unwrap());
+        assert!(!db.exists("custom", "gone").unwrap());
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "visitors"),
+            Ok(ValueObject::HLLPointer(_))
+        ));
+        assert_eq!(
+            db.get_collection_kind("ordered"),
+            Some(CollectionKind::BTreeMap)
+        );
+        assert!(matches!(
+            db.get_collection_by_name("ordered").unwrap().get("k"),
+            Some(ValueObject::BoolData(false))
+        ));
+        assert_eq!(
+            db.get_collection_kind("custom"),
+            Some(CollectionKind::CustomBTree)
+        );
+        assert_eq!(db.get_collection_kind("dropped"), None);
+        assert_eq!(
+            db.get_collection_kind("loaded"),
+            Some(CollectionKind::BTreeMap)
+        );
+        assert!(matches!(
+            db.get_collection_by_name("loaded").unwrap().get("x"),
+            Some(ValueObject::DecimalData(v)) if *v == 1.5
+        ));
+    }
+
+    #[test]
+    fn test_failed_mutation_is_not_logged() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(
+                DEFAULT_COLLECTION,
+                "name",
+                ValueObject::StringData("loki".to_string()),
+            )
+            .unwrap();
+            assert!(db.incr(DEFAULT_COLLECTION, "name").is_err());
+            assert!(db
+                .hll_add(DEFAULT_COLLECTION, "name", ValueObject::IntData(1))
+                .is_err());
+            db.list_push(
+                DEFAULT_COLLECTION,
+                "list",
+                false,
+                vec![ValueObject::IntData(1)],
+            )
+            .unwrap();
+            assert!(db
+                .list_set(DEFAULT_COLLECTION, "list", 5, ValueObject::IntData(2))
+                .is_err());
+            assert!(db
+                .hash_incr_by(DEFAULT_COLLECTION, "map", "field", isize::MAX)
+                .is_ok());
+            assert!(db
+                .hash_incr_by(DEFAULT_COLLECTION, "map", "field", 1)
+                .is_err());
         }
 
-        for col in self.collections_hmap.iter() {
-            let pth = format!("{}/{}", get_checkpoint_directory(), checkpoint_id);
-            let pairs = col.1.clone().generate_pairs();
-            if pairs.len() == 0 {
-                continue;
+        let mut db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(
+            matches!(db.get(DEFAULT_COLLECTION, "name"), Ok(ValueObject::StringData(s)) if s == "loki")
+        );
+        assert_eq!(db.wal_manager.replay_records().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_replay_into_unknown_collection_fails() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+                .unwrap();
+        }
+        // Only the default collection is recreated when it is missing
+        let db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(1))
+        ));
+        drop(db);
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.wal_manager
+                .append_record(WALRecord::Put {
+                    collection: "ghost".to_string(),
+                    key: "k".to_string(),
+                    value: ValueObject::IntData(1),
+                })
+                .unwrap();
+            db.sync().unwrap();
+        }
+        assert!(LokiKV::with_control_file(control_file_path).is_err());
+    }
+
+    #[test]
+    fn test_missing_keys_and_collections_are_errors() {
+        let dir = TempDir::new().unwrap();
+        let mut db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+
+        assert_eq!(
+            db.get(DEFAULT_COLLECTION, "missing").err(),
+            Some(LokiError::KeyNotFound("missing".to_string()))
+        );
+        assert_eq!(
+            db.incr(DEFAULT_COLLECTION, "missing"),
+            Err(LokiError::KeyNotFound("missing".to_string()))
+        );
+        assert_eq!(
+            db.get("nope", "k").err(),
+            Some(LokiError::CollectionNotFound("nope".to_string()))
+        );
+        assert!(db.get_collection_by_name("nope").is_err());
+        assert!(matches!(
+            db.remove_collection("nope".to_string()),
+            Err(LokiError::CollectionNotFound(_))
+        ));
+
+        db.put(
+            DEFAULT_COLLECTION,
+            "name",
+            ValueObject::StringData("loki".to_string()),
+        )
+        .unwrap();
+        assert!(matches!(
+            db.decr(DEFAULT_COLLECTION, "name"),
+            Err(LokiError::WrongType(_))
+        ));
+    }
+
+    #[test]
+    fn test_expiries_survive_restart() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let far = get_current_timestamp_as_millis() + 3_600_000;
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.create_custom_bcol("sessions".to_string()).unwrap();
+            db.put_with_expiry("sessions", "checkpointed", ValueObject::IntData(1), far)
+                .unwrap();
+            db.put(DEFAULT_COLLECTION, "cleared", ValueObject::IntData(2))
+                .unwrap();
+            db.expire(DEFAULT_COLLECTION, "cleared", Some(far)).unwrap();
+            db.checkpoint().unwrap();
+            db.put_with_expiry(DEFAULT_COLLECTION, "logged", ValueObject::IntData(3), far)
+                .unwrap();
+            assert!(db.expire(DEFAULT_COLLECTION, "cleared", None).unwrap());
+        }
+
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        let ttl = db.ttl("sessions", "checkpointed").unwrap().unwrap();
+        assert!(ttl > 0 && ttl <= 3_600_000);
+        assert!(db.ttl(DEFAULT_COLLECTION, "logged").unwrap().is_some());
+        assert_eq!(db.ttl(DEFAULT_COLLECTION, "cleared").unwrap(), None);
+    }
+
+    #[test]
+    fn test_hll_merge_keeps_the_expiry_and_replays() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let far = get_current_timestamp_as_millis() + 3_600_000;
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            for item in 0..100 {
+                db.hll_add(DEFAULT_COLLECTION, "a", ValueObject::IntData(item))
+                    .unwrap();
+                db.hll_add(DEFAULT_COLLECTION, "b", ValueObject::IntData(item + 50))
+                    .unwrap();
             }
-            let persistor = Persistor::new(get_control_file_path());
-            persistor.persist(pairs, col.0.clone());
+            db.expire(DEFAULT_COLLECTION, "a", Some(far)).unwrap();
+            db.hll_merge(DEFAULT_COLLECTION, "a", &["b".to_string()])
+                .unwrap();
+            assert!(db.ttl(DEFAULT_COLLECTION, "a").unwrap().is_some());
+            db.put(DEFAULT_COLLECTION, "name", ValueObject::BoolData(true))
+                .unwrap();
+            assert!(db
+                .hll_merge(DEFAULT_COLLECTION, "name", &["a".to_string()])
+                .is_err());
         }
 
-        self.wal_manager.dump_records(checkpoint_id);
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(db.ttl(DEFAULT_COLLECTION, "a").unwrap().is_some());
+        let count = db
+            .hll_count(DEFAULT_COLLECTION, &["a".to_string()])
+            .unwrap();
+        assert!((140.0..160.0).contains(&count), "{}", count);
     }
 
-    pub fn display_wal(&self) -> String {
-        let res = self.wal_manager.display_wal();
-        res
+    #[test]
+    fn test_expired_keys_are_hidden_and_swept() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let past = get_current_timestamp_as_millis() - 1;
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put_with_expiry(DEFAULT_COLLECTION, "lazy", ValueObject::IntData(1), past)
+                .unwrap();
+            db.put_with_expiry(DEFAULT_COLLECTION, "swept", ValueObject::IntData(2), past)
+                .unwrap();
+            db.put(DEFAULT_COLLECTION, "kept", ValueObject::IntData(3))
+                .unwrap();
+
+            // Expired keys read as missing before anything removes them
+            assert!(!db.exists(DEFAULT_COLLECTION, "lazy").unwrap());
+            assert!(db.get(DEFAULT_COLLECTION, "lazy").is_err());
+            assert!(!db.expire(DEFAULT_COLLECTION, "lazy", None).unwrap());
+
+            assert_eq!(db.remove_expired_keys().unwrap(), 1);
+            assert_eq!(db.remove_expired_keys().unwrap(), 0);
+            assert!(db.exists(DEFAULT_COLLECTION, "kept").unwrap());
+        }
+
+        // The removals were logged
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        let col = db.get_collection_by_name(DEFAULT_COLLECTION).unwrap();
+        assert!(!col.key_exists("lazy"));
+        assert!(!col.key_exists("swept"));
+        assert!(col.key_exists("kept"));
+    }
+
+    #[test]
+    fn test_scans_skip_expired_keys_and_respect_prefixes() {
+        let dir = TempDir::new().unwrap();
+        let mut db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        db.create_custom_bcol("ordered".to_string()).unwrap();
+        for key in ["a", "a\u{10FFFF}", "ab", "b"] {
+            db.put("ordered", key, ValueObject::IntData(1)).unwrap();
+        }
+        let past = get_current_timestamp_as_millis() - 1;
+        db.put_with_expiry("ordered", "aa", ValueObject::IntData(2), past)
+            .unwrap();
+
+        let keys = |pairs: Vec<(String, ValueObject)>| -> Vec<String> {
+            pairs.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(db.scan_prefix("ordered", "a", false, None).unwrap()),
+            ["a", "ab", "a\u{10FFFF}"]
+        );
+        assert_eq!(
+            keys(
+                db.scan(
+                    "ordered",
+                    Bound::Excluded("a"),
+                    Bound::Unbounded,
+                    true,
+                    Some(2)
+                )
+                .unwrap()
+            ),
+            ["b", "a\u{10FFFF}"]
+        );
+        assert!(db
+            .scan(
+                "ordered",
+                Bound::Included("b"),
+                Bound::Excluded("a"),
+                false,
+                None
+            )
+            .unwrap()
+            .is_empty());
+        assert_eq!(prefix_upper_bound("a\u{10FFFF}"), Some("b".to_string()));
+        assert_eq!(prefix_upper_bound("\u{10FFFF}"), None);
+    }
+
+    #[test]
+    fn test_list_operations_are_replayed() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.create_custom_bcol("custom".to_string()).unwrap();
+            for collection in [DEFAULT_COLLECTION, "custom"] {
+                let values = (1..=5).map(ValueObject::IntData).collect();
+                db.list_push(collection, "l", false, values).unwrap();
+                db.list_pop(collection, "l", true, 1).unwrap();
+                db.list_set(collection, "l", 0, ValueObject::IntData(20))
+                    .unwrap();
+                db.list_trim(collection, "l", 0, 2).unwrap();
+                db.list_push(collection, "gone", true, vec![ValueObject::IntData(1)])
+                    .unwrap();
+                db.list_pop(collection, "gone", false, 3).unwrap();
+            }
+            // A failed operation is not logged
+            assert!(db
+                .list_set(DEFAULT_COLLECTION, "l", 10, ValueObject::IntData(0))
+                .is_err());
+        }
+
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        for collection in [DEFAULT_COLLECTION, "custom"] {
+            let values: Vec<Option<isize>> = db
+                .list_range(collection, "l", 0, -1)
+                .unwrap()
+                .into_iter()
+                .map(|v| match v {
+                    ValueObject::IntData(i) => Some(i),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(values, [Some(20), Some(3), Some(4)]);
+            assert!(!db.exists(collection, "gone").unwrap());
+        }
     }
 }
@@ -1,5 +1,9 @@
+pub mod checkpoint;
+pub mod config;
 pub mod control;
 pub mod data_structures;
+pub mod format;
 pub mod loki_kv;
 pub mod persist;
+pub mod upgrade;
 pub mod wal;
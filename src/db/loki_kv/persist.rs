@@ -1,19 +1,71 @@
 use tokio::sync::RwLock;
 
+use serde::{Deserialize, Serialize};
+
+use crate::loki_kv::checkpoint::{sync_dir, write_synced, MANIFEST_FILE};
 use crate::loki_kv::control::ControlFile;
+use crate::loki_kv::format::{decode_page, encode_pairs};
 // To persist data on disk
 use crate::loki_kv::loki_kv::{
-    Collection, CollectionBTree, CollectionBTreeCustom, CollectionProps, LokiKV, ValueObject,
+    get_current_timestamp_as_u64, Collection, CollectionBTree, CollectionBTreeCustom,
+    CollectionKind, CollectionProps, ValueObject,
 };
-use crate::utils::{error, info, info_string};
+use crate::utils::{info_string, warning_string};
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::{create_dir_all, File};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 const FILE_EXTENSION: &str = ".lktbl";
 const HARD_END_LIMIT: usize = 8000;
+const HEADER_FILE: &str = "HEADER";
+// Persisted collections live here, apart from the numbered checkpoints
+pub const COLLECTIONS_DIR: &str = "collections";
+// Names the copy of a collection that LOAD reads
+const CURRENT_FILE: &str = "CURRENT";
+const STAGING_SUFFIX: &str = ".persisting";
+pub const PERSIST_FORMAT_VERSION: u32 = 1;
+
+// Stored next to the pages of a persisted collection so LOAD can restore it
+// as the structure it was saved from
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CollectionHeader {
+    format_version: u32,
+    kind: CollectionKind,
+    // When this copy of the collection was persisted, headers written
+    // before the rename call it created_at
+    #[serde(alias = "created_at")]
+    persisted_at: u64,
+    key_count: u64,
+    #[serde(default)]
+    options: BTreeMap<String, String>,
+}
+
+impl CollectionHeader {
+    pub fn new(kind: CollectionKind, key_count: usize) -> Self {
+        CollectionHeader {
+            format_version: PERSIST_FORMAT_VERSION,
+            kind,
+            persisted_at: get_current_timestamp_as_u64(),
+            key_count: key_count as u64,
+            options: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_kind(&self) -> CollectionKind {
+        self.kind
+    }
+
+    pub fn get_key_count(&self) -> u64 {
+        self.key_count
+    }
+
+    pub fn get_persisted_at(&self) -> u64 {
+        self.persisted_at
+    }
+}
 
 struct StoragePage {
     content: Vec<(String, ValueObject)>,
@@ -30,18 +82,16 @@ impl StoragePage {
         }
     }
 
-    fn flush_to_disk(&self) {
+    fn flush_to_disk(&self) -> Result<(), String> {
         let path_disp = format!("{}/{}_{}.lqlpage", self.pwd, "chunk", self.chunk_start_idx);
         let path = Path::new(&path_disp);
         info_string(format!("Persisting to page at {}", path.display()));
 
-        let mut file = match File::create(&path) {
-            Ok(file) => file,
-            Err(e) => panic!("failed to create {}", e),
-        };
-
-        let data = bincode::serialize(&self.content).unwrap();
-        file.write_all(&data);
+        let mut file = File::create(path).map_err(|e| format!("failed to create {}", e))?;
+        let data = encode_pairs(&self.content)?;
+        file.write_all(&data)
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("failed to write {}: {}", path.display(), e))
     }
 }
 
@@ -57,98 +107,199 @@ impl Persistor {
         Persistor { control_file }
     }
 
-    pub fn load_to_btree(&self, collection_name: String) -> (String, CollectionBTreeCustom) {
-        let fin_path = format!(
-            "{}/{}",
-            self.control_file.get_checkpoint_directory_path(),
-            collection_name
-        );
-        let dir = fs::read_dir(&fin_path).expect("Failed to read directory");
+    pub fn from_control_file(control_file: ControlFile) -> Self {
+        Persistor { control_file }
+    }
 
+    pub fn load_to_btree(
+        &self,
+        collection_name: String,
+    ) -> Result<(String, CollectionBTreeCustom), String> {
         let mut col = CollectionBTreeCustom::new();
-        for entry in dir {
-            let entry = entry.expect("Invalid directory entry");
-            let path = entry.path();
-
-            let bytes = fs::read(&path).expect("Unable to read file");
-            let dc: Vec<(String, ValueObject)> =
-                bincode::deserialize(&bytes).expect("Failed to deserialize bincode");
+        col.bulk_put(read_pages(&self.pages_path(&collection_name)?)?);
+        Ok((collection_name, col))
+    }
 
-            col.bulk_put(dc);
-        }
+    pub fn load_to_btree_def(
+        &self,
+        collection_name: String,
+    ) -> Result<(String, CollectionBTree), String> {
+        let mut col = CollectionBTree::new();
+        col.bulk_put(read_pages(&self.pages_path(&collection_name)?)?);
+        Ok((collection_name, col))
+    }
 
-        return (collection_name, col);
+    pub fn load_to_hmap(&self, collection_name: String) -> Result<(String, Collection), String> {
+        let mut col = Collection::new();
+        col.bulk_put(read_pages(&self.pages_path(&collection_name)?)?);
+        Ok((collection_name, col))
     }
 
-    pub fn load_to_btree_def(&self, collection_name: String) -> (String, CollectionBTree) {
-        let fin_path = format!(
-            "{}/{}",
-            self.control_file.get_checkpoint_directory_path(),
-            collection_name
-        );
-        let dir = fs::read_dir(&fin_path).expect("Failed to read directory");
+    fn collection_path(&self, collection_name: &str) -> PathBuf {
+        Path::new(self.control_file.get_checkpoint_directory_path())
+            .join(COLLECTIONS_DIR)
+            .join(collection_name)
+    }
 
-        let mut col = CollectionBTree::new();
-        for entry in dir {
-            let entry = entry.expect("Invalid directory entry");
-            let path = entry.path();
+    // Directory holding the pages of the current copy of a collection.
+    // Collections persisted before they moved under collections/ are read
+    // from their old place, unless a checkpoint took it.
+    fn pages_path(&self, collection_name: &str) -> Result<PathBuf, String> {
+        let collection_path = self.collection_path(collection_name);
+        let current_path = collection_path.join(CURRENT_FILE);
+        match fs::read_to_string(&current_path) {
+            Ok(copy) => Ok(collection_path.join(copy.trim())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let legacy_path = Path::new(self.control_file.get_checkpoint_directory_path())
+                    .join(collection_name);
+                if legacy_path.join(MANIFEST_FILE).exists() {
+                    return Err(format!("{} has not been persisted", collection_name));
+                }
+                Ok(legacy_path)
+            }
+            Err(e) => Err(format!("Failed to read {}: {}", current_path.display(), e)),
+        }
+    }
 
-            let bytes = fs::read(&path).expect("Unable to read file");
-            let dc: Vec<(String, ValueObject)> =
-                bincode::deserialize(&bytes).expect("Failed to deserialize bincode");
+    // Writes the collection pages and its header to a new numbered copy and
+    // points CURRENT at it. CURRENT is replaced by a rename, so a crash
+    // leaves either the old or the new copy in place, never neither.
+    pub fn persist(
+        &self,
+        content: Vec<(String, ValueObject)>,
+        collection_name: String,
+        kind: CollectionKind,
+    ) -> Result<(), String> {
+        let collection_path = self.collection_path(&collection_name);
+        create_dir_all(&collection_path).map_err(|e| e.to_string())?;
+        let copy = next_copy(&collection_path)?;
+        let copy_path = collection_path.join(copy.to_string());
+        create_dir_all(&copy_path).map_err(|e| e.to_string())?;
+        info_string(format!("WRITING {}...", copy_path.display()));
 
-            col.bulk_put(dc);
+        for (cnt, chunk) in content.chunks(HARD_END_LIMIT).enumerate() {
+            let cur_page =
+                StoragePage::new(chunk.to_vec(), cnt, copy_path.to_string_lossy().to_string());
+            cur_page.flush_to_disk()?;
         }
 
-        return (collection_name, col);
-    }
+        let header = CollectionHeader::new(kind, content.len());
+        let header_string = toml::to_string(&header).map_err(|e| e.to_string())?;
+        write_synced(&copy_path.join(HEADER_FILE), header_string.as_bytes())?;
+        sync_dir(&copy_path)?;
 
-    pub fn load_to_hmap(&self, collection_name: String) -> (String, Collection) {
-        let fin_path = format!(
-            "{}/{}",
-            self.control_file.get_checkpoint_directory_path(),
-            collection_name
-        );
-        let dir = fs::read_dir(&fin_path).expect("Failed to read directory");
+        let current_tmp = collection_path.join(format!("{}{}", CURRENT_FILE, STAGING_SUFFIX));
+        write_synced(&current_tmp, copy.to_string().as_bytes())?;
+        fs::rename(&current_tmp, collection_path.join(CURRENT_FILE))
+            .map_err(|e| format!("Failed to publish {}: {}", copy_path.display(), e))?;
+        sync_dir(&collection_path)?;
 
-        let mut col = Collection::new();
-        for entry in dir {
-            let entry = entry.expect("Invalid directory entry");
-            let path = entry.path();
+        remove_old_copies(&collection_path, copy);
+        Ok(())
+    }
 
-            let bytes = fs::read(&path).expect("Unable to read file");
-            let dc: Vec<(String, ValueObject)> =
-                bincode::deserialize(&bytes).expect("Failed to deserialize bincode");
+    pub fn read_header(&self, collection_name: &str) -> Result<CollectionHeader, String> {
+        let header_path = self.pages_path(collection_name)?.join(HEADER_FILE);
+        let header_string = match fs::read_to_string(&header_path) {
+            Ok(header_string) => header_string,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(format!(
+                    "{} was persisted without a header, load it with LOAD_HMAP, LOAD_BDEF or LOAD_BCUST",
+                    collection_name
+                ))
+            }
+            Err(e) => return Err(format!("Failed to read {}: {}", header_path.display(), e)),
+        };
+        let header: CollectionHeader = toml::from_str(&header_string)
+            .map_err(|e| format!("Failed to parse {}: {}", header_path.display(), e))?;
+        if header.format_version > PERSIST_FORMAT_VERSION {
+            return Err(format!(
+                "{} uses format version {}, this server supports up to {}",
+                collection_name, header.format_version, PERSIST_FORMAT_VERSION
+            ));
+        }
+        Ok(header)
+    }
 
-            col.bulk_put(dc);
+    // Reads back a persisted collection along with the header describing it
+    pub fn load(
+        &self,
+        collection_name: &str,
+    ) -> Result<(CollectionHeader, Vec<(String, ValueObject)>), String> {
+        let header = self.read_header(collection_name)?;
+        let pairs = read_pages(&self.pages_path(collection_name)?)?;
+        if pairs.len() as u64 != header.key_count {
+            return Err(format!(
+                "{} holds {} keys on disk, its header expects {}",
+                collection_name,
+                pairs.len(),
+                header.key_count
+            ));
         }
+        Ok((header, pairs))
+    }
+}
+
+// Copies of a collection are numbered, the next one goes after the newest
+fn next_copy(collection_path: &Path) -> Result<u64, String> {
+    let entries = fs::read_dir(collection_path)
+        .map_err(|e| format!("Failed to read {}: {}", collection_path.display(), e))?;
+    let newest = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u64>().ok())
+        .max();
+    Ok(newest.map_or(1, |copy| copy + 1))
+}
 
-        return (collection_name, col);
+// Removes every copy of a collection but `current`, failures are only
+// logged and retried on the next PERSIST
+fn remove_old_copies(collection_path: &Path, current: u64) {
+    let entries = match fs::read_dir(collection_path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warning_string(format!(
+                "Failed to list {}: {}",
+                collection_path.display(),
+                e
+            ));
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() || entry.file_name().to_str() == Some(&current.to_string()) {
+            continue;
+        }
+        if let Err(e) = fs::remove_dir_all(&path) {
+            warning_string(format!("Failed to remove {}: {}", path.display(), e));
+        }
     }
+}
 
-    pub fn persist(&self, content: Vec<(String, ValueObject)>, collection_name: String) {
-        let mut cnt = 0;
-        let dir = self.control_file.get_checkpoint_directory_path();
-        let fin_path = dir.to_string() + "/" + collection_name.as_str();
-        let _ = match create_dir_all(fin_path.clone()) {
-            Ok(_) => info("Created new directory"),
-            Err(..) => error("got some error, lets ignore it for now..."),
-        };
-        for idx in (0..content.len()).step_by(HARD_END_LIMIT) {
-            let mut end_idx = idx + HARD_END_LIMIT;
-            if end_idx >= content.len() {
-                end_idx = content.len();
-            }
-            info_string(format!("WRITING {}...", fin_path));
-            let cur_page = StoragePage::new(content[idx..end_idx].to_vec(), cnt, fin_path.clone());
-            cur_page.flush_to_disk();
-            cnt += 1;
+// Reads every page of a persisted collection, ignoring its header
+fn read_pages(collection_path: &Path) -> Result<Vec<(String, ValueObject)>, String> {
+    let dir = fs::read_dir(collection_path)
+        .map_err(|e| format!("Failed to read {}: {}", collection_path.display(), e))?;
+
+    let mut pairs: Vec<(String, ValueObject)> = Vec::new();
+    for entry in dir {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("lqlpage") {
+            continue;
         }
+        let bytes =
+            fs::read(&path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let (_, page) =
+            decode_page(&bytes).map_err(|e| format!("Corrupt page {}: {}", path.display(), e))?;
+        pairs.extend(page);
     }
+    Ok(pairs)
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::loki_kv::checkpoint::{remove_old_checkpoints, write_checkpoint};
+    use crate::loki_kv::control::write_test_control_file;
     use crate::loki_kv::loki_kv::Collection;
 
     use super::*;
@@ -162,7 +313,104 @@ mod tests {
                 crate::loki_kv::loki_kv::ValueObject::IntData(val.clone()),
             );
         }
-        let my_persistor = Persistor::new("hii".to_string());
-        my_persistor.persist(dc.generate_pairs(), "testCollection".to_string());
+        let dir = tempfile::TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let my_persistor = Persistor::new(control_file_path);
+        my_persistor
+            .persist(
+                dc.generate_pairs(),
+                "testCollection".to_string(),
+                CollectionKind::HashMap,
+            )
+            .unwrap();
+
+        let (header, pairs) = my_persistor.load("testCollection").unwrap();
+        assert_eq!(header.get_kind(), CollectionKind::HashMap);
+        assert_eq!(header.get_key_count(), 999999);
+        assert_eq!(pairs.len(), 999999);
+    }
+
+    #[test]
+    fn test_persist_replaces_earlier_copy() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let my_persistor = Persistor::new(write_test_control_file(dir.path()));
+        let pairs: Vec<(String, ValueObject)> = (0..HARD_END_LIMIT + 1)
+            .map(|i| (i.to_string(), ValueObject::IntData(i as isize)))
+            .collect();
+        my_persistor
+            .persist(pairs, "ordered".to_string(), CollectionKind::BTreeMap)
+            .unwrap();
+        // Shrinking the collection must not leave the second page behind
+        my_persistor
+            .persist(
+                vec![("a".to_string(), ValueObject::BoolData(true))],
+                "ordered".to_string(),
+                CollectionKind::CustomBTree,
+            )
+            .unwrap();
+
+        let (header, pairs) = my_persistor.load("ordered").unwrap();
+        assert_eq!(header.get_kind(), CollectionKind::CustomBTree);
+        assert_eq!(pairs.len(), 1);
+        let copies = fs::read_dir(my_persistor.collection_path("ordered"))
+            .unwrap()
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .count();
+        assert_eq!(copies, 1);
+    }
+
+    #[test]
+    fn test_collection_named_like_a_checkpoint() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let my_persistor = Persistor::new(write_test_control_file(dir.path()));
+        let checkpoint_dir = dir.path().join("checkpoints");
+        let checkpoint_dir = checkpoint_dir.to_str().unwrap();
+        my_persistor
+            .persist(
+                vec![("k".to_string(), ValueObject::IntData(1))],
+                "3".to_string(),
+                CollectionKind::HashMap,
+            )
+            .unwrap();
+
+        write_checkpoint(checkpoint_dir, 3, 0, 0, &[]).unwrap();
+        write_checkpoint(checkpoint_dir, 4, 0, 0, &[]).unwrap();
+        remove_old_checkpoints(checkpoint_dir, 4);
+
+        let (_, pairs) = my_persistor.load("3").unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert!(my_persistor.load("4").is_err());
+    }
+
+    #[test]
+    fn test_header_written_with_created_at() {
+        let header: CollectionHeader = toml::from_str(
+            "format_version = 1\nkind = \"HashMap\"\ncreated_at = 42\nkey_count = 3\n",
+        )
+        .unwrap();
+        assert_eq!(header.get_persisted_at(), 42);
+        assert!(toml::to_string(&header)
+            .unwrap()
+            .contains("persisted_at = 42"));
+    }
+
+    #[test]
+    fn test_collection_without_header_needs_explicit_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let my_persistor = Persistor::new(write_test_control_file(dir.path()));
+        let legacy_path = dir.path().join("checkpoints").join("legacy");
+        create_dir_all(&legacy_path).unwrap();
+        let page = vec![("k".to_string(), ValueObject::IntData(1))];
+        fs::write(
+            legacy_path.join("chunk_0.lqlpage"),
+            bincode::serialize(&page).unwrap(),
+        )
+        .unwrap();
+
+        let err = my_persistor.load("legacy").err().unwrap();
+        assert!(err.contains("LOAD_HMAP"), "{}", err);
+        let (_, col) = my_persistor.load_to_hmap("legacy".to_string()).unwrap();
+        assert!(col.key_exists("k"));
     }
 }
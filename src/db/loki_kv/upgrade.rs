@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use crate::loki_kv::checkpoint::{update_chunk_checksums, MANIFEST_FILE};
+use crate::loki_kv::control::ControlFile;
+use crate::loki_kv::format::{decode_page, encode_pairs, CURRENT_FORMAT_VERSION};
+use crate::loki_kv::persist::COLLECTIONS_DIR;
+use crate::loki_kv::wal::{list_timelines, upgrade_timeline};
+use crate::utils::info_string;
+
+// Offline rewrite of the WAL, checkpoint and persisted collection files of a
+// data directory into the current on-disk format. The server must not be
+// running while this happens.
+
+#[derive(Debug, Default)]
+pub struct UpgradeReport {
+    pub wal_files: usize,
+    pub pages: usize,
+}
+
+// Replaces `path` with `bytes` through a temporary file and a rename
+fn rewrite_file(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let tmp_path = path.with_extension("upgrade.tmp");
+    let mut file = File::create(&tmp_path)
+        .map_err(|e| format!("Failed to create {}: {}", tmp_path.display(), e))?;
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Failed to write {}: {}", tmp_path.display(), e))?;
+    fs::rename(&tmp_path, path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+fn upgrade_wal_directory(wal_dir: &str, report: &mut UpgradeReport) -> Result<(), String> {
+    for timeline in list_timelines(wal_dir)? {
+        let path = Path::new(wal_dir).join(format!("{}.wal", timeline));
+        let bytes =
+            fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let upgraded = upgrade_timeline(&bytes)
+            .map_err(|e| format!("Failed to upgrade {}: {}", path.display(), e))?;
+        if let Some(upgraded) = upgraded {
+            rewrite_file(&path, &upgraded)?;
+            info_string(format!("Upgraded {}", path.display()));
+            report.wal_files += 1;
+        }
+    }
+    Ok(())
+}
+
+// Rewrites the pages of one checkpoint or persisted collection directory
+fn upgrade_page_directory(dir: &Path, report: &mut UpgradeReport) -> Result<(), String> {
+    let mut checksums: HashMap<String, u32> = HashMap::new();
+    for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("lqlpage") {
+            continue;
+        }
+        let bytes =
+            fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let (version, pairs) = decode_page(&bytes)
+            .map_err(|e| format!("Failed to upgrade {}: {}", path.display(), e))?;
+        if version == CURRENT_FORMAT_VERSION {
+            continue;
+        }
+
+        let upgraded = encode_pairs(&pairs)?;
+        rewrite_file(&path, &upgraded)?;
+        if let Some(name) = path.file_name().and_then(|name| name.to_str()) {
+            checksums.insert(name.to_string(), crc32fast::hash(&upgraded));
+        }
+        report.pages += 1;
+    }
+
+    if !checksums.is_empty() && dir.join(MANIFEST_FILE).exists() {
+        update_chunk_checksums(dir, &checksums)?;
+    }
+    if !checksums.is_empty() {
+        info_string(format!(
+            "Upgraded {} pages in {}",
+            checksums.len(),
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+pub fn upgrade_data_directories(control_file: &ControlFile) -> Result<UpgradeReport, String> {
+    let mut report = UpgradeReport::default();
+    upgrade_wal_directory(control_file.get_wal_directory_path(), &mut report)?;
+
+    let checkpoint_dir = control_file.get_checkpoint_directory_path();
+    let entries = match fs::read_dir(checkpoint_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(report),
+        Err(e) => return Err(format!("Failed to read {}: {}", checkpoint_dir, e)),
+    };
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.ends_with(COLLECTIONS_DIR) {
+            upgrade_collections_directory(&path, &mut report)?;
+        } else if path.is_dir() {
+            upgrade_page_directory(&path, &mut report)?;
+        }
+    }
+    Ok(report)
+}
+
+// Persisted collections keep every copy in a directory of its own
+fn upgrade_collections_directory(dir: &Path, report: &mut UpgradeReport) -> Result<(), String> {
+    for collection in fs::read_dir(dir).map_err(|e| e.to_string())? {
+        let collection = collection.map_err(|e| e.to_string())?.path();
+        if !collection.is_dir() {
+            continue;
+        }
+        for copy in fs::read_dir(&collection).map_err(|e| e.to_string())? {
+            let copy = copy.map_err(|e| e.to_string())?.path();
+            if copy.is_dir() {
+                upgrade_page_directory(&copy, report)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::checkpoint::CollectionSnapshot;
+    use crate::loki_kv::checkpoint::{checkpoint_path, read_checkpoint, write_checkpoint};
+    use crate::loki_kv::control::write_test_control_file;
+    use crate::loki_kv::format::FILE_HEADER_LEN;
+    use crate::loki_kv::loki_kv::{CollectionKind, LokiKV, ValueObject, DEFAULT_COLLECTION};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_upgrade_rewrites_legacy_files() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let control_file = ControlFile::read_from_file_path(control_file_path.clone()).unwrap();
+        let checkpoint_dir = control_file.get_checkpoint_directory_path().to_string();
+
+        // Headerless WAL, as written before the format version existed
+        {
+            let mut db = LokiKV::with_control_file(control_file_path.clone()).unwrap();
+            db.put(DEFAULT_COLLECTION, "a", ValueObject::IntData(1))
+                .unwrap();
+        }
+        let wal_path = dir.path().join("wal").join("1.wal");
+        let wal = fs::read(&wal_path).unwrap();
+        fs::write(&wal_path, &wal[FILE_HEADER_LEN..]).unwrap();
+
+        // Checkpoint and persisted collection pages without a header
+        let pairs = vec![("k".to_string(), ValueObject::BoolData(true))];
+        let snapshot = CollectionSnapshot {
+            name: "col".to_string(),
+            kind: CollectionKind::HashMap,
+            pairs: pairs.clone(),
+            expiries: Vec::new(),
+        };
+        write_checkpoint(&checkpoint_dir, 7, 0, 0, &[snapshot]).unwrap();
+        let chunk_dir = checkpoint_path(&checkpoint_dir, 7);
+        let legacy_page = bincode::serialize(&pairs).unwrap();
+        fs::write(chunk_dir.join("0_0.lqlpage"), &legacy_page).unwrap();
+        let checksums = HashMap::from([("0_0.lqlpage".to_string(), crc32fast::hash(&legacy_page))]);
+        update_chunk_checksums(&chunk_dir, &checksums).unwrap();
+        let persisted_dir = Path::new(&checkpoint_dir).join("persisted");
+        fs::create_dir_all(&persisted_dir).unwrap();
+        fs::write(persisted_dir.join("chunk_0.lqlpage"), &legacy_page).unwrap();
+        let copy_dir = Path::new(&checkpoint_dir)
+            .join(COLLECTIONS_DIR)
+            .join("col")
+            .join("1");
+        fs::create_dir_all(&copy_dir).unwrap();
+        fs::write(copy_dir.join("chunk_0.lqlpage"), &legacy_page).unwrap();
+
+        let report = upgrade_data_directories(&control_file).unwrap();
+        assert_eq!(report.wal_files, 1);
+        assert_eq!(report.pages, 3);
+        assert_eq!(fs::read(&wal_path).unwrap(), wal);
+        for page in [
+            chunk_dir.join("0_0.lqlpage"),
+            persisted_dir.join("chunk_0.lqlpage"),
+            copy_dir.join("chunk_0.lqlpage"),
+        ] {
+            let (version, _) = decode_page(&fs::read(page).unwrap()).unwrap();
+            assert_eq!(version, CURRENT_FORMAT_VERSION);
+        }
+        assert_eq!(
+            read_checkpoint(&checkpoint_dir, 7).unwrap()[0].pairs.len(),
+            1
+        );
+
+        // Running it again has nothing left to do
+        let report = upgrade_data_directories(&control_file).unwrap();
+        assert_eq!(report.wal_files + report.pages, 0);
+        let db = LokiKV::with_control_file(control_file_path).unwrap();
+        assert!(matches!(
+            db.get(DEFAULT_COLLECTION, "a"),
+            Ok(ValueObject::IntData(1))
+        ));
+    }
+}
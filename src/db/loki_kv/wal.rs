@@ -1,34 +1,419 @@
 use std::collections::HashSet;
 use std::fmt::format;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
 
-use bincode::ErrorKind;
 use serde::{Deserialize, Serialize};
 
 use crate::loki_kv::control::ControlFile;
-use crate::loki_kv::loki_kv::ValueObject;
-use crate::utils::info_string;
+use crate::loki_kv::data_structures::hash::HashOp;
+use crate::loki_kv::data_structures::hyperloglog::HLL;
+use crate::loki_kv::data_structures::list::ListOp;
+use crate::loki_kv::data_structures::set::SetOp;
+use crate::loki_kv::data_structures::sorted_set::SortedSetOp;
+use crate::loki_kv::format::{
+    decode_file_header, decode_wal_record, encode_file_header, FileKind, CURRENT_FORMAT_VERSION,
+    FILE_HEADER_LEN,
+};
+use crate::loki_kv::loki_kv::{CollectionKind, ValueObject};
+use crate::utils::{error_string, info_string, warning_string};
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct WALRecord {
-    timestamp: u64,
-    collection_name: String,
-    key: String,
-    value: ValueObject,
+// A WAL file starts with the header from `format.rs`, after which every
+// record is framed as
+// | payload length (u32) | crc32 of lsn + payload (u32) | lsn (u64) | payload |
+// so that a torn or corrupted write can be detected and cut off on replay.
+const FRAME_HEADER_LEN: usize = 16;
+
+// A single state change of the database. Replaying every record in LSN
+// order on top of the last checkpoint reproduces the state exactly.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WALRecord {
+    Put {
+        collection: String,
+        key: String,
+        value: ValueObject,
+    },
+    Incr {
+        collection: String,
+        key: String,
+    },
+    Decr {
+        collection: String,
+        key: String,
+    },
+    CreateCollection {
+        collection: String,
+        kind: CollectionKind,
+    },
+    DropCollection {
+        collection: String,
+    },
+    HllAdd {
+        collection: String,
+        key: String,
+        item: ValueObject,
+    },
+    // Collection restored from disk through one of the LOAD_* commands
+    LoadCollection {
+        collection: String,
+        kind: CollectionKind,
+        pairs: Vec<(String, ValueObject)>,
+    },
+    Delete {
+        collection: String,
+        key: String,
+    },
+    Rename {
+        collection: String,
+        key: String,
+        new_key: String,
+    },
+    // Deadlines are absolute, in milliseconds since the epoch, so replaying
+    // them after a restart keeps the original lifetime
+    Expire {
+        collection: String,
+        key: String,
+        expire_at: Option<u64>,
+    },
+    PutWithExpiry {
+        collection: String,
+        key: String,
+        value: ValueObject,
+        expire_at: u64,
+    },
+    List {
+        collection: String,
+        key: String,
+        op: ListOp,
+    },
+    Hash {
+        collection: String,
+        key: String,
+        op: HashOp,
+    },
+    SetMembers {
+        collection: String,
+        key: String,
+        op: SetOp,
+    },
+    SortedSet {
+        collection: String,
+        key: String,
+        op: SortedSetOp,
+    },
+    BloomReserve {
+        collection: String,
+        key: String,
+        error_rate: f64,
+        capacity: u64,
+    },
+    BloomAdd {
+        collection: String,
+        key: String,
+        item: ValueObject,
+    },
+    CmsIncr {
+        collection: String,
+        key: String,
+        item: ValueObject,
+        count: u64,
+    },
+    // Union of the source HLLs as they were when merged into the HLL at key
+    HllMerge {
+        collection: String,
+        key: String,
+        source: HLL,
+    },
 }
 
 impl WALRecord {
-    fn new(timestamp: u64, collection_name: String, key: String, value: ValueObject) -> Self {
-        WALRecord {
-            timestamp: timestamp,
-            collection_name: collection_name,
-            key: key,
-            value: value,
+    pub fn get_collection_name(&self) -> &str {
+        match self {
+            WALRecord::Put { collection, .. }
+            | WALRecord::Incr { collection, .. }
+            | WALRecord::Decr { collection, .. }
+            | WALRecord::CreateCollection { collection, .. }
+            | WALRecord::DropCollection { collection }
+            | WALRecord::HllAdd { collection, .. }
+            | WALRecord::LoadCollection { collection, .. }
+            | WALRecord::Delete { collection, .. }
+            | WALRecord::Rename { collection, .. }
+            | WALRecord::Expire { collection, .. }
+            | WALRecord::PutWithExpiry { collection, .. }
+            | WALRecord::List { collection, .. }
+            | WALRecord::Hash { collection, .. }
+            | WALRecord::SetMembers { collection, .. }
+            | WALRecord::SortedSet { collection, .. }
+            | WALRecord::BloomReserve { collection, .. }
+            | WALRecord::BloomAdd { collection, .. }
+            | WALRecord::CmsIncr { collection, .. }
+            | WALRecord::HllMerge { collection, .. } => collection,
         }
     }
+
+    // Whether the record operates on keys of a collection that must exist
+    pub fn needs_existing_collection(&self) -> bool {
+        matches!(
+            self,
+            WALRecord::Put { .. }
+                | WALRecord::Incr { .. }
+                | WALRecord::Decr { .. }
+                | WALRecord::HllAdd { .. }
+                | WALRecord::Delete { .. }
+                | WALRecord::Rename { .. }
+                | WALRecord::Expire { .. }
+                | WALRecord::PutWithExpiry { .. }
+                | WALRecord::List { .. }
+                | WALRecord::Hash { .. }
+                | WALRecord::SetMembers { .. }
+                | WALRecord::SortedSet { .. }
+                | WALRecord::BloomReserve { .. }
+                | WALRecord::BloomAdd { .. }
+                | WALRecord::CmsIncr { .. }
+                | WALRecord::HllMerge { .. }
+        )
+    }
+}
+
+// How hard the WAL tries to get a record onto stable storage before the
+// write is acknowledged
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WALSyncMode {
+    // fsync after every record, while the database lock is held
+    Always,
+    // Writers wait for durability after releasing the database lock and
+    // whoever gets there first fsyncs on behalf of everyone queued behind it
+    GroupCommit,
+    // A background thread fsyncs every `wal_sync_interval_ms`, writes are
+    // acknowledged straight away
+    Periodic,
+    // Leave it to the OS page cache
+    #[serde(rename = "none")]
+    Disabled,
+}
+
+struct SyncState {
+    synced_lsn: u64,
+    sync_in_progress: bool,
+}
+
+struct ActiveFile {
+    file: Option<Arc<File>>,
+    written_lsn: u64,
+    // Set when a failed append could not be cut off the file again. Records
+    // written after it would be lost on replay, so every append is refused.
+    broken: Option<String>,
+}
+
+// ----------- WAL Sync ---------------------
+// Owns the open handle of the active timeline and tracks which LSNs are
+// durable. Shared between the WALManager (which writes under the database
+// lock) and the connections waiting for their writes to be synced.
+pub struct WALSync {
+    mode: WALSyncMode,
+    active: Mutex<ActiveFile>,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+    // Bytes of the next frame written before the append fails, for tests
+    #[cfg(test)]
+    fail_next_append: Mutex<Option<usize>>,
+    #[cfg(test)]
+    fail_next_sync: Mutex<bool>,
+}
+
+impl WALSync {
+    fn new(mode: WALSyncMode, last_lsn: u64) -> Arc<Self> {
+        Arc::new(WALSync {
+            mode,
+            active: Mutex::new(ActiveFile {
+                file: None,
+                written_lsn: last_lsn,
+                broken: None,
+            }),
+            state: Mutex::new(SyncState {
+                synced_lsn: last_lsn,
+                sync_in_progress: false,
+            }),
+            synced: Condvar::new(),
+            #[cfg(test)]
+            fail_next_append: Mutex::new(None),
+            #[cfg(test)]
+            fail_next_sync: Mutex::new(false),
+        })
+    }
+
+    // Makes the next group commit sync fail, for tests
+    #[cfg(test)]
+    pub fn fail_next_sync(&self) {
+        *self.fail_next_sync.lock().unwrap() = true;
+    }
+
+    pub fn get_mode(&self) -> WALSyncMode {
+        self.mode
+    }
+
+    // LSN of the newest record handed to the OS
+    pub fn get_written_lsn(&self) -> u64 {
+        self.active.lock().unwrap().written_lsn
+    }
+
+    fn write_frame(&self, path: &str, lsn: u64, frame: &[u8]) -> Result<(), String> {
+        let mut active = self.active.lock().unwrap();
+        if let Some(reason) = &active.broken {
+            return Err(format!(
+                "WAL refuses writes after an earlier failure: {}",
+                reason
+            ));
+        }
+        if active.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open WAL file {}: {}", path, e))?;
+            let len = file
+                .metadata()
+                .map_err(|e| format!("Failed to stat WAL file {}: {}", path, e))?
+                .len();
+            if len == 0 {
+                (&file)
+                    .write_all(&encode_file_header(FileKind::Wal))
+                    .map_err(|e| format!("Failed to write WAL header {}: {}", path, e))?;
+            }
+            active.file = Some(Arc::new(file));
+        }
+
+        let file = active.file.clone().unwrap();
+        let len = file
+            .metadata()
+            .map_err(|e| format!("Failed to stat WAL file {}: {}", path, e))?
+            .len();
+        if let Err(e) = self.append(&file, frame) {
+            // A torn frame, or a whole one that was never acknowledged, would
+            // end replay early and hide every record appended after it
+            if let Err(cut) = file.set_len(len).and_then(|_| file.sync_data()) {
+                active.broken = Some(format!(
+                    "failed to cut off a failed append to {}: {}",
+                    path, cut
+                ));
+            }
+            return Err(format!("Failed to append to WAL file {}: {}", path, e));
+        }
+        active.written_lsn = lsn;
+        if self.mode == WALSyncMode::Always {
+            self.mark_synced(lsn);
+        }
+        Ok(())
+    }
+
+    fn append(&self, mut file: &File, frame: &[u8]) -> std::io::Result<()> {
+        #[cfg(test)]
+        if let Some(written) = self.fail_next_append.lock().unwrap().take() {
+            file.write_all(&frame[..written.min(frame.len())])?;
+            return Err(std::io::Error::other("injected failure"));
+        }
+        file.write_all(frame)?;
+        if self.mode == WALSyncMode::Always {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn mark_synced(&self, lsn: u64) {
+        let mut state = self.state.lock().unwrap();
+        if lsn > state.synced_lsn {
+            state.synced_lsn = lsn;
+        }
+        self.synced.notify_all();
+    }
+
+    // Syncs everything written so far. Records from an older timeline were
+    // already synced when that timeline was closed.
+    fn sync_written(&self) -> Result<u64, String> {
+        let (file, target) = {
+            let active = self.active.lock().unwrap();
+            (active.file.clone(), active.written_lsn)
+        };
+        #[cfg(test)]
+        if std::mem::take(&mut *self.fail_next_sync.lock().unwrap()) {
+            return Err("Failed to sync WAL file: injected failure".to_string());
+        }
+        if let Some(file) = file {
+            file.sync_data()
+                .map_err(|e| format!("Failed to sync WAL file: {}", e))?;
+        }
+        Ok(target)
+    }
+
+    // Blocks until `lsn` is durable according to the configured mode.
+    // Must not be called while holding the database lock.
+    pub fn wait_durable(&self, lsn: u64) -> Result<(), String> {
+        if self.mode != WALSyncMode::GroupCommit {
+            return Ok(());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced_lsn >= lsn {
+                return Ok(());
+            }
+            if state.sync_in_progress {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            // Become the leader of this batch
+            state.sync_in_progress = true;
+            drop(state);
+            let result = self.sync_written();
+            state = self.state.lock().unwrap();
+            state.sync_in_progress = false;
+            match result {
+                Ok(target) => {
+                    if target > state.synced_lsn {
+                        state.synced_lsn = target;
+                    }
+                    self.synced.notify_all();
+                }
+                Err(e) => {
+                    self.synced.notify_all();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    // Syncs and closes the active timeline so the next write opens a new one
+    fn close_timeline(&self) -> Result<(), String> {
+        let mut active = self.active.lock().unwrap();
+        if let Some(file) = active.file.take() {
+            file.sync_all()
+                .map_err(|e| format!("Failed to sync WAL file: {}", e))?;
+        }
+        let written = active.written_lsn;
+        drop(active);
+        self.mark_synced(written);
+        Ok(())
+    }
+
+    fn start_periodic_sync(sync: &Arc<WALSync>, interval: Duration) {
+        let weak: Weak<WALSync> = Arc::downgrade(sync);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(sync) = weak.upgrade() else {
+                break;
+            };
+            match sync.sync_written() {
+                Ok(target) => sync.mark_synced(target),
+                Err(e) => error_string(format!("Periodic WAL sync failed: {}", e)),
+            }
+        });
+    }
 }
 
 // ----------- WAL Record Manager ---------------------
@@ -39,148 +424,620 @@ pub struct WALManager {
     control_file: ControlFile,
     wal_records: Vec<WALRecord>,
     cur_timeline: u64,
+    next_lsn: u64,
+    sync: Arc<WALSync>,
 }
 
-impl WALManager {
-    pub fn new(ctrl_file_path: String) -> Self {
-        let control_file: ControlFile = ControlFile::read_from_file_path(ctrl_file_path).unwrap();
-        let timeline = control_file.get_next_timeline_id();
-        WALManager {
-            control_file,
-            wal_records: Vec::new(),
-            cur_timeline: timeline,
+// Result of decoding the frames of a single timeline
+struct TimelineScan {
+    version: u32,
+    records: Vec<(u64, WALRecord)>,
+    // Length of the prefix made of intact frames
+    valid_len: usize,
+    // Why decoding stopped before the end of the file, if it did
+    corruption: Option<String>,
+}
+
+fn frame_checksum(lsn: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&lsn.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn encode_frame(lsn: u64, record: &WALRecord) -> Result<Vec<u8>, String> {
+    let payload =
+        bincode::serialize(record).map_err(|e| format!("Failed to encode WAL record: {}", e))?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&frame_checksum(lsn, &payload).to_le_bytes());
+    frame.extend_from_slice(&lsn.to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Decodes frames until the end of the buffer or the first frame that is
+// truncated, fails its checksum or breaks LSN ordering
+fn scan_frames(bytes: &[u8]) -> Result<TimelineScan, String> {
+    let (version, mut offset) = match decode_file_header(FileKind::Wal, bytes) {
+        Ok(header) => header,
+        // Header cut short by a crash right after the file was created
+        Err(e) if bytes.len() < FILE_HEADER_LEN => {
+            return Ok(TimelineScan {
+                version: CURRENT_FORMAT_VERSION,
+                records: Vec::new(),
+                valid_len: 0,
+                corruption: Some(e),
+            })
+        }
+        Err(e) => return Err(e),
+    };
+    let mut records: Vec<(u64, WALRecord)> = Vec::new();
+    let mut corruption = None;
+
+    while offset < bytes.len() {
+        let remaining = &bytes[offset..];
+        if remaining.len() < FRAME_HEADER_LEN {
+            corruption = Some(format!("truncated frame header at byte offset {}", offset));
+            break;
+        }
+
+        let len = u32::from_le_bytes(remaining[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(remaining[4..8].try_into().unwrap());
+        let lsn = u64::from_le_bytes(remaining[8..16].try_into().unwrap());
+        if remaining.len() - FRAME_HEADER_LEN < len {
+            corruption = Some(format!("truncated frame payload at byte offset {}", offset));
+            break;
+        }
+
+        let payload = &remaining[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+        if frame_checksum(lsn, payload) != crc {
+            corruption = Some(format!("checksum mismatch at byte offset {}", offset));
+            break;
+        }
+        if let Some((prev_lsn, _)) = records.last() {
+            if lsn <= *prev_lsn {
+                corruption = Some(format!(
+                    "out of order lsn {} after {} at byte offset {}",
+                    lsn, prev_lsn, offset
+                ));
+                break;
+            }
+        }
+
+        match decode_wal_record(version, payload) {
+            Ok(record) => records.push((lsn, record)),
+            Err(e) => {
+                corruption = Some(format!(
+                    "undecodable record at byte offset {}: {}",
+                    offset, e
+                ));
+                break;
+            }
         }
+        offset += FRAME_HEADER_LEN + len;
     }
 
-    pub fn new_without_toml() -> Self {
-        let control_file = ControlFile::write(
-            "localhost".to_string(),
-            8765 as u16,
-            "/home/akshat/lokikv/control.toml".to_string(),
-            0 as u64,
-            0 as u64,
-            "/home/akshat/lokikv/checkpoints".to_string(),
-            "/home/akshat/lokikv/wal".to_string(),
-            Some("0.0.0.0:8080".to_string()),
-            Some("0.0.0.0:8081".to_string()),
-            None,
-            None,
-            None,
-        )
-        .unwrap();
-        let timeline = control_file.get_next_timeline_id();
-        WALManager {
+    Ok(TimelineScan {
+        version,
+        records,
+        valid_len: offset,
+        corruption,
+    })
+}
+
+// Re-encodes a timeline written in an older format, returns None when it is
+// already current
+pub fn upgrade_timeline(bytes: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let (version, _) = decode_file_header(FileKind::Wal, bytes)?;
+    if version == CURRENT_FORMAT_VERSION {
+        return Ok(None);
+    }
+    let scan = scan_frames(bytes)?;
+    if let Some(reason) = scan.corruption {
+        return Err(format!(
+            "corrupt record after {} intact records: {}",
+            scan.records.len(),
+            reason
+        ));
+    }
+
+    let mut upgraded = encode_file_header(FileKind::Wal);
+    for (lsn, record) in scan.records.iter() {
+        upgraded.extend_from_slice(&encode_frame(*lsn, record)?);
+    }
+    Ok(Some(upgraded))
+}
+
+impl WALManager {
+    pub fn new(ctrl_file_path: String) -> Result<Self, String> {
+        let control_file: ControlFile = ControlFile::read_from_file_path(ctrl_file_path)?;
+        let mut timeline = control_file.get_next_timeline_id();
+        // Timelines that were never sealed by a checkpoint are still live, keep
+        // appending to the newest one so replay order stays intact
+        let timelines = list_timelines(control_file.get_wal_directory_path())?;
+        if let Some(last) = timelines.last() {
+            if *last > timeline {
+                timeline = *last;
+            }
+        }
+        let sync_mode = control_file.get_wal_sync_mode();
+        let sync_interval = Duration::from_millis(control_file.get_wal_sync_interval_ms());
+        let mut manager = WALManager {
             control_file,
             wal_records: Vec::new(),
             cur_timeline: timeline,
+            next_lsn: 1,
+            sync: WALSync::new(sync_mode, 0),
+        };
+        // LSNs keep increasing across timelines and checkpoints, continue from
+        // the newest record
+        manager.next_lsn = manager.control_file.get_last_checkpoint_lsn() + 1;
+        if let Some(last) = timelines.last() {
+            let scan = manager.scan_timeline(*last)?;
+            if let Some((lsn, _)) = scan.records.last() {
+                manager.next_lsn = manager.next_lsn.max(lsn + 1);
+            }
+            // New records are encoded in the current format, which must not end
+            // up in a file whose header names an older one. An empty file gets
+            // a new header with the first append.
+            if scan.version != CURRENT_FORMAT_VERSION && scan.valid_len > 0 {
+                manager.cur_timeline = last + 1;
+            }
+        }
+        manager.sync = WALSync::new(sync_mode, manager.next_lsn - 1);
+        if sync_mode == WALSyncMode::Periodic {
+            WALSync::start_periodic_sync(&manager.sync, sync_interval);
         }
+        Ok(manager)
     }
 
-    pub fn append_record(&mut self, collection_name: String, key: String, value: ValueObject) {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap();
-        let record = WALRecord::new(now.as_secs(), collection_name, key, value);
+    pub fn get_control_file(&self) -> &ControlFile {
+        &self.control_file
+    }
+
+    pub fn get_sync(&self) -> Arc<WALSync> {
+        self.sync.clone()
+    }
+
+    // Appends a record and returns its LSN. Depending on the sync mode the
+    // caller still has to wait on `WALSync::wait_durable` before acknowledging.
+    pub fn append_record(&mut self, record: WALRecord) -> Result<u64, String> {
         // write record to disk first
-        self.update_wal_file(&record);
+        let lsn = self.update_wal_file(&record)?;
         // After that update in memory
         self.wal_records.push(record);
+        Ok(lsn)
     }
 
-    pub fn update_wal_file(&mut self, record: &WALRecord) {
-        let wal_file_path = format!(
-            "{}/{}.wal",
-            self.control_file.get_wal_directory_path(),
-            self.cur_timeline.to_string()
-        );
-        let mut file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .truncate(false)
-            .open(wal_file_path)
-            .unwrap();
-
-        let data = bincode::serialize(record).unwrap();
-        file.write_all(&data).unwrap();
-        file.flush().unwrap();
+    pub fn update_wal_file(&mut self, record: &WALRecord) -> Result<u64, String> {
+        let wal_file_path = self.timeline_path(self.cur_timeline);
+        let lsn = self.next_lsn;
+        let frame = encode_frame(lsn, record)?;
+        // Never hand out an LSN twice, even if the append below fails after
+        // some of its bytes reached the file
+        self.next_lsn += 1;
+        self.sync.write_frame(&wal_file_path, lsn, &frame)?;
+        Ok(lsn)
     }
-    pub fn dump_records(&mut self, checkpoint_id: u64) {
-        let wal_file_path = format!(
-            "{}/{}.wal",
-            self.control_file.get_wal_directory_path(),
-            self.cur_timeline.to_string()
-        );
-        info_string(format!("Getting the following WAL path: {}", wal_file_path));
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(false)
-            .open(wal_file_path)
-            .unwrap();
-        for record in &self.wal_records {
-            let data = bincode::serialize(record).unwrap();
-            file.write_all(&data).unwrap();
-        }
 
+    // Closes the active timeline so a checkpoint can cover everything written
+    // so far, new records go to a fresh timeline. Returns the sealed timeline
+    // and the LSN of its last record.
+    pub fn rotate_timeline(&mut self) -> Result<(u64, u64), String> {
+        self.sync.close_timeline()?;
+        let sealed = self.cur_timeline;
+        info_string(format!("Sealing WAL timeline {}", sealed));
         self.wal_records.clear();
-        self.control_file.set_new_params(checkpoint_id);
+        self.cur_timeline += 1;
+        Ok((sealed, self.next_lsn - 1))
+    }
+
+    // Records a published checkpoint in the control file, after which the
+    // timelines it covers are no longer needed
+    pub fn mark_checkpointed(
+        &mut self,
+        checkpoint_id: u64,
+        sealed_timeline: u64,
+        checkpoint_lsn: u64,
+    ) -> Result<(), String> {
+        self.control_file
+            .set_new_params(checkpoint_id, sealed_timeline, checkpoint_lsn)?;
+
+        for timeline in list_timelines(self.control_file.get_wal_directory_path())? {
+            if timeline > sealed_timeline {
+                continue;
+            }
+            let path = self.timeline_path(timeline);
+            if let Err(e) = fs::remove_file(&path) {
+                warning_string(format!("Failed to remove old WAL file {}: {}", path, e));
+            }
+        }
+        Ok(())
     }
 
-    pub fn replay_records(&self) -> Result<Vec<(String, ValueObject)>, String> {
-        let wal_file_path = format!(
+    fn timeline_path(&self, timeline: u64) -> String {
+        format!(
             "{}/{}.wal",
             self.control_file.get_wal_directory_path(),
-            self.cur_timeline.to_string()
-        );
-        let file = OpenOptions::new().read(true).open(wal_file_path).unwrap();
-        let mut reader = BufReader::new(file);
+            timeline
+        )
+    }
 
-        let mut records: Vec<(String, ValueObject)> = Vec::new();
+    fn scan_timeline(&self, timeline: u64) -> Result<TimelineScan, String> {
+        let wal_file_path = self.timeline_path(timeline);
+        let bytes = fs::read(&wal_file_path)
+            .map_err(|e| format!("Failed to read WAL file {}: {}", wal_file_path, e))?;
+        scan_frames(&bytes).map_err(|e| format!("Failed to read WAL file {}: {}", wal_file_path, e))
+    }
 
-        loop {
-            match bincode::deserialize_from::<_, (String, ValueObject)>(&mut reader) {
-                Ok(record) => records.push(record),
-                Err(e) => match *e {
-                    ErrorKind::Io(ref io_error)
-                        if io_error.kind() == std::io::ErrorKind::UnexpectedEof =>
-                    {
-                        break;
+    // Reads every intact record of a single timeline. A corrupt tail is cut
+    // off the file when `truncate_tail` is set, otherwise it is an error.
+    pub fn read_timeline(
+        &self,
+        timeline: u64,
+        truncate_tail: bool,
+    ) -> Result<Vec<(u64, WALRecord)>, String> {
+        let scan = self.scan_timeline(timeline)?;
+        if let Some(reason) = scan.corruption {
+            let wal_file_path = self.timeline_path(timeline);
+            if !truncate_tail {
+                return Err(format!(
+                    "Corrupt WAL record in {} after {} intact records: {}",
+                    wal_file_path,
+                    scan.records.len(),
+                    reason
+                ));
+            }
+
+            warning_string(format!(
+                "Truncating corrupt WAL tail of {} to {} bytes ({})",
+                wal_file_path, scan.valid_len, reason
+            ));
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&wal_file_path)
+                .map_err(|e| format!("Failed to open {}: {}", wal_file_path, e))?;
+            file.set_len(scan.valid_len as u64)
+                .and_then(|_| file.sync_all())
+                .map_err(|e| format!("Failed to truncate {}: {}", wal_file_path, e))?;
+        }
+        Ok(scan.records)
+    }
+
+    // Returns all records written since the last checkpoint, oldest timeline
+    // first. Only the newest timeline can have a torn tail, anything broken in
+    // an older one means records after it would be lost and is an error.
+    pub fn replay_records(&mut self) -> Result<Vec<(u64, WALRecord)>, String> {
+        let sealed = self.control_file.get_last_wal_timeline();
+        let timelines: Vec<u64> = list_timelines(self.control_file.get_wal_directory_path())?
+            .into_iter()
+            .filter(|timeline| *timeline > sealed)
+            .collect();
+
+        let mut records: Vec<(u64, WALRecord)> = Vec::new();
+        for (idx, timeline) in timelines.iter().enumerate() {
+            info_string(format!("Replaying WAL timeline {}", timeline));
+            let is_newest = idx == timelines.len() - 1;
+            for (lsn, record) in self.read_timeline(*timeline, is_newest)? {
+                if let Some((prev_lsn, _)) = records.last() {
+                    if lsn <= *prev_lsn {
+                        return Err(format!(
+                            "WAL timeline {} goes back to lsn {} after {}",
+                            timeline, lsn, prev_lsn
+                        ));
                     }
-                    _ => return Err(format!("Failed to deserialize wal records: {}", e)),
-                },
+                }
+                records.push((lsn, record));
             }
         }
+
+        if let Some((lsn, _)) = records.last() {
+            self.next_lsn = self.next_lsn.max(lsn + 1);
+        }
         Ok(records)
     }
 
     pub fn display_wal(&self) -> String {
-        let wal_file_path = format!(
-            "{}/{}.wal",
-            self.control_file.get_wal_directory_path(),
-            self.cur_timeline.to_string()
-        );
-        let file = OpenOptions::new().read(true).open(wal_file_path).unwrap();
+        let scan = match self.scan_timeline(self.cur_timeline) {
+            Ok(scan) => scan,
+            Err(e) => return e,
+        };
 
-        let mut reader = BufReader::new(file);
         let mut decoded_wal = String::new();
-        let mut record_count = 0;
-        loop {
-            match bincode::deserialize_from::<_, WALRecord>(&mut reader) {
-                Ok(record) => {
-                    record_count += 1;
-                    decoded_wal.push_str(&format!("Record {}: {:?}\n", record_count, record));
-                }
-                Err(e) => match *e {
-                    ErrorKind::Io(ref io_err)
-                        if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
-                    {
-                        break
-                    }
-                    _ => panic!("Error reading WAL: {}", e),
-                },
+        for (lsn, record) in scan.records.iter() {
+            decoded_wal.push_str(&format!("LSN {}: {:?}\n", lsn, record));
+        }
+        if let Some(reason) = scan.corruption {
+            decoded_wal.push_str(&format!("Corrupt WAL tail: {}\n", reason));
+        }
+        decoded_wal
+    }
+}
+
+// Lists the numeric timeline ids present in the WAL directory in ascending order
+pub fn list_timelines(wal_directory_path: &str) -> Result<Vec<u64>, String> {
+    let dir = match fs::read_dir(wal_directory_path) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("Failed to read WAL directory: {}", e)),
+    };
+
+    let mut timelines: Vec<u64> = Vec::new();
+    for entry in dir {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("wal") {
+            continue;
+        }
+        if let Some(timeline) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            timelines.push(timeline);
+        }
+    }
+    timelines.sort();
+    Ok(timelines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::control::write_test_control_file;
+    use tempfile::TempDir;
+
+    fn put_record(key: &str, value: ValueObject) -> WALRecord {
+        WALRecord::Put {
+            collection: "default".to_string(),
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    // Byte offsets at which each frame of the file ends
+    fn frame_ends(bytes: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        let mut offset = FILE_HEADER_LEN;
+        while offset < bytes.len() {
+            let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            offset += FRAME_HEADER_LEN + len;
+            ends.push(offset);
+        }
+        ends
+    }
+
+    fn write_records(control_file_path: &str, count: isize) {
+        let mut manager = WALManager::new(control_file_path.to_string()).unwrap();
+        for i in 0..count {
+            manager
+                .append_record(put_record(&format!("key{}", i), ValueObject::IntData(i)))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replay_after_cut_at_every_offset() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        write_records(&control_file_path, 5);
+
+        let wal_path = dir.path().join("wal").join("1.wal");
+        let full = fs::read(&wal_path).unwrap();
+        let ends = frame_ends(&full);
+        assert_eq!(ends.len(), 5);
+
+        for cut in 0..=full.len() {
+            fs::write(&wal_path, &full[..cut]).unwrap();
+            let intact = ends.iter().filter(|end| **end <= cut).count();
+
+            let mut manager = WALManager::new(control_file_path.clone()).unwrap();
+            let records = manager.replay_records().unwrap();
+            assert_eq!(records.len(), intact, "cut at {}", cut);
+            for (i, (lsn, record)) in records.iter().enumerate() {
+                assert_eq!(*lsn, i as u64 + 1);
+                assert!(
+                    matches!(record, WALRecord::Put { key, .. } if *key == format!("key{}", i))
+                );
             }
+
+            let expected_len = match intact {
+                // A torn header is dropped, a complete one is kept
+                0 if cut < FILE_HEADER_LEN => 0,
+                0 => FILE_HEADER_LEN,
+                _ => ends[intact - 1],
+            };
+            assert_eq!(
+                fs::metadata(&wal_path).unwrap().len() as usize,
+                expected_len,
+                "cut at {}",
+                cut
+            );
+
+            // Appends continue right after the intact prefix
+            manager
+                .append_record(put_record("after_cut", ValueObject::BoolData(true)))
+                .unwrap();
+            let records = WALManager::new(control_file_path.clone())
+                .unwrap()
+                .replay_records()
+                .unwrap();
+            assert_eq!(records.len(), intact + 1, "cut at {}", cut);
+            assert_eq!(records.last().unwrap().0, intact as u64 + 1);
+            assert!(matches!(
+                &records.last().unwrap().1,
+                WALRecord::Put { key, .. } if key == "after_cut"
+            ));
         }
+    }
 
-        decoded_wal
+    #[test]
+    fn test_replay_truncates_at_checksum_mismatch() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        write_records(&control_file_path, 4);
+
+        let wal_path = dir.path().join("wal").join("1.wal");
+        let mut bytes = fs::read(&wal_path).unwrap();
+        let ends = frame_ends(&bytes);
+        // Flip a payload byte of the third record
+        bytes[ends[1] + FRAME_HEADER_LEN + 2] ^= 0x40;
+        fs::write(&wal_path, &bytes).unwrap();
+
+        let mut manager = WALManager::new(control_file_path).unwrap();
+        let records = manager.replay_records().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(fs::metadata(&wal_path).unwrap().len() as usize, ends[1]);
+    }
+
+    #[test]
+    fn test_corruption_in_older_timeline_is_an_error() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        write_records(&control_file_path, 3);
+
+        let wal_dir = dir.path().join("wal");
+        let bytes = fs::read(wal_dir.join("1.wal")).unwrap();
+        fs::write(wal_dir.join("1.wal"), &bytes[..bytes.len() - 3]).unwrap();
+        fs::write(wal_dir.join("2.wal"), Vec::<u8>::new()).unwrap();
+
+        let mut manager = WALManager::new(control_file_path).unwrap();
+        let err = manager.replay_records().unwrap_err();
+        assert!(err.contains("Corrupt WAL record"), "{}", err);
+    }
+
+    #[test]
+    fn test_appends_skip_timelines_in_older_format() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        write_records(&control_file_path, 3);
+
+        // Same frames under a version 1 header
+        let wal_dir = dir.path().join("wal");
+        let mut bytes = fs::read(wal_dir.join("1.wal")).unwrap();
+        bytes[4..8].copy_from_slice(&1u32.to_le_bytes());
+        fs::write(wal_dir.join("1.wal"), &bytes).unwrap();
+
+        write_records(&control_file_path, 1);
+        assert_eq!(fs::read(wal_dir.join("1.wal")).unwrap(), bytes);
+        let (version, _) =
+            decode_file_header(FileKind::Wal, &fs::read(wal_dir.join("2.wal")).unwrap()).unwrap();
+        assert_eq!(version, CURRENT_FORMAT_VERSION);
+
+        let mut manager = WALManager::new(control_file_path).unwrap();
+        let lsns: Vec<u64> = manager
+            .replay_records()
+            .unwrap()
+            .iter()
+            .map(|(lsn, _)| *lsn)
+            .collect();
+        assert_eq!(lsns, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_failed_appends_are_cut_off_before_the_next_record() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let mut control_file = ControlFile::read_from_file_path(control_file_path.clone()).unwrap();
+        control_file.set_wal_sync_mode(WALSyncMode::Always).unwrap();
+
+        let mut manager = WALManager::new(control_file_path.clone()).unwrap();
+        let sync = manager.get_sync();
+        let mut append = |key: &str, fail_after: Option<usize>| {
+            *sync.fail_next_append.lock().unwrap() = fail_after;
+            manager.append_record(put_record(key, ValueObject::BoolData(true)))
+        };
+        assert!(append("first", None).is_ok());
+        // A torn frame, then a complete one whose sync failed
+        assert!(append("torn", Some(5)).is_err());
+        assert!(append("second", None).is_ok());
+        assert!(append("unsynced", Some(usize::MAX)).is_err());
+        assert!(append("third", None).is_ok());
+
+        let records = WALManager::new(control_file_path)
+            .unwrap()
+            .replay_records()
+            .unwrap();
+        let keys: Vec<&str> = records
+            .iter()
+            .map(|(_, record)| match record {
+                WALRecord::Put { key, .. } => key.as_str(),
+                other => panic!("unexpected record {:?}", other),
+            })
+            .collect();
+        assert_eq!(keys, ["first", "second", "third"]);
+        // The failed appends used up their LSNs
+        let lsns: Vec<u64> = records.iter().map(|(lsn, _)| *lsn).collect();
+        assert_eq!(lsns, [1, 3, 5]);
+    }
+
+    fn writer_threads(
+        control_file_path: &str,
+        mode: WALSyncMode,
+        threads: usize,
+        records_per_thread: usize,
+    ) {
+        let mut control_file =
+            ControlFile::read_from_file_path(control_file_path.to_string()).unwrap();
+        control_file.set_wal_sync_mode(mode).unwrap();
+        // Stand-in for the database lock every writer goes through
+        let manager = Arc::new(Mutex::new(
+            WALManager::new(control_file_path.to_string()).unwrap(),
+        ));
+        let sync = manager.lock().unwrap().get_sync();
+
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let manager = manager.clone();
+                let sync = sync.clone();
+                thread::spawn(move || {
+                    for i in 0..records_per_thread {
+                        let lsn = manager
+                            .lock()
+                            .unwrap()
+                            .append_record(put_record(
+                                &format!("key{}_{}", t, i),
+                                ValueObject::IntData(i as isize),
+                            ))
+                            .unwrap();
+                        sync.wait_durable(lsn).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sync_mode_is_read_from_control_file() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        let control_file = ControlFile::read_from_file_path(control_file_path.clone()).unwrap();
+        assert_eq!(control_file.get_wal_sync_mode(), WALSyncMode::GroupCommit);
+
+        let contents = fs::read_to_string(&control_file_path).unwrap();
+        fs::write(
+            &control_file_path,
+            format!("{}\nwal_sync_mode = \"none\"\n", contents),
+        )
+        .unwrap();
+        let manager = WALManager::new(control_file_path).unwrap();
+        assert_eq!(manager.get_sync().get_mode(), WALSyncMode::Disabled);
+    }
+
+    #[test]
+    fn test_group_commit_acknowledges_every_writer() {
+        let dir = TempDir::new().unwrap();
+        let control_file_path = write_test_control_file(dir.path());
+        writer_threads(&control_file_path, WALSyncMode::GroupCommit, 8, 25);
+
+        let mut manager = WALManager::new(control_file_path).unwrap();
+        let records = manager.replay_records().unwrap();
+        assert_eq!(records.len(), 200);
+        for (i, (lsn, _)) in records.iter().enumerate() {
+            assert_eq!(*lsn, i as u64 + 1);
+        }
     }
 }
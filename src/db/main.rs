@@ -1,12 +1,76 @@
-mod loki_kv;
-mod parser;
-mod server_multithread;
-mod utils;
+use std::env;
+use std::process;
 
-use crate::server_multithread::server::LokiServer;
+use clap::{Parser, Subcommand};
+
+use lokikv::loki_kv::config::DEFAULT_DATA_DIR;
+use lokikv::loki_kv::control::ControlFile;
+use lokikv::loki_kv::upgrade::upgrade_data_directories;
+use lokikv::server_multithread::server::LokiServer;
+use lokikv::utils::{error_string, success_string};
+use lokikv::Config;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Directory holding the control file, WAL and checkpoints, created with
+    /// default settings on first start
+    #[arg(long, global = true, default_value = DEFAULT_DATA_DIR)]
+    data_dir: String,
+    /// Existing control file to use instead of the one in the data directory,
+    /// defaults to CONTROL_FILE_PATH when that is set
+    #[arg(long, global = true)]
+    control_file: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrite WAL, checkpoint and persisted files in the current on-disk
+    /// format. Run it while the server is stopped.
+    Upgrade,
+}
+
+impl Args {
+    fn config(&self) -> Config {
+        let config = Config::new(&self.data_dir);
+        match self
+            .control_file
+            .clone()
+            .or_else(|| env::var("CONTROL_FILE_PATH").ok())
+        {
+            Some(path) => config.with_control_file(path),
+            None => config,
+        }
+    }
+}
+
+fn upgrade(config: &Config) -> Result<(), String> {
+    let control_file =
+        ControlFile::read_from_file_path(config.get_control_file_path().display().to_string())?;
+    let report = upgrade_data_directories(&control_file)?;
+    success_string(format!(
+        "Upgraded {} WAL files and {} pages",
+        report.wal_files, report.pages
+    ));
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() {
-    let serv = LokiServer::new(16);
-    serv.await.start_event_loop().await;
+    let args = Args::parse();
+    let config = args.config();
+    match args.command {
+        Some(Command::Upgrade) => {
+            if let Err(e) = upgrade(&config) {
+                error_string(format!("Upgrade failed: {}", e));
+                process::exit(1);
+            }
+        }
+        None => {
+            let serv = LokiServer::new(config, 16);
+            serv.await.start_event_loop().await;
+        }
+    }
 }
@@ -1,13 +1,14 @@
+use std::ops::Bound;
 use std::process;
 use std::sync::{Arc, RwLock};
-use std::time::SystemTime;
 
-use crate::loki_kv::data_structures::hyperloglog::HLL;
-use crate::loki_kv::loki_kv::{get_control_file_path, get_data_directory};
+use crate::error::LokiError;
+use crate::loki_kv::data_structures::set::SetAlgebra;
+use crate::loki_kv::loki_kv::get_current_timestamp_as_millis;
 use crate::loki_kv::persist::Persistor;
-use crate::utils::{
-    error, error_string, info, info_string, success, success_string, warning, warning_string,
-};
+use crate::loki_kv::wal::WALSync;
+use crate::server_multithread::session::Session;
+use crate::utils::{error_string, info_string};
 use crate::{
     loki_kv::loki_kv::{LokiKV, ValueObject},
     parser::parser::QLCommands,
@@ -15,691 +16,1597 @@ use crate::{
 
 use super::parser::{QLValues, AST};
 
-pub enum OpMode {
-    Read,
-    Write,
-    Phantom,
-    Append,
-    AppendHLL,
-}
-
 pub struct Executor {
     database: Arc<RwLock<LokiKV>>,
     asts: Vec<Option<AST>>,
     persistor: Persistor,
+    wal_sync: Arc<WALSync>,
+}
+
+fn convert_to_value_object(list_data: Vec<QLValues>) -> Result<Vec<ValueObject>, LokiError> {
+    list_data.into_iter().map(to_value_object).collect()
+}
+
+// Converts a literal from the query into the value stored in the database
+fn to_value_object(val: QLValues) -> Result<ValueObject, LokiError> {
+    match val {
+        QLValues::QLInt(a) => Ok(ValueObject::IntData(a)),
+        QLValues::QLBool(a) => Ok(ValueObject::BoolData(a)),
+        QLValues::QLFloat(a) => Ok(ValueObject::DecimalData(a)),
+        QLValues::QLString(a) => Ok(ValueObject::StringData(a)),
+        QLValues::QLBlob(a) => Ok(ValueObject::BlobData(a)),
+        QLValues::QLList(items) => Ok(ValueObject::ListData(convert_to_value_object(items)?)),
+        QLValues::QLMap(entries) => Ok(ValueObject::MapData(
+            entries
+                .into_iter()
+                .map(|(field, value)| Ok((field, to_value_object(value)?)))
+                .collect::<Result<_, LokiError>>()?,
+        )),
+        other => Err(LokiError::ParseError(format!(
+            "{:?} can not be stored as a value",
+            other
+        ))),
+    }
+}
+
+// The key or collection name a command operates on
+fn get_key(node: &AST) -> Result<String, LokiError> {
+    match node.get_left_child().map(|child| child.get_value()) {
+        Some(QLValues::QLId(key)) => Ok(key),
+        _ => Err(LokiError::ParseError("missing key".to_string())),
+    }
+}
+
+// Every key given to a command, in order
+fn get_keys(node: &AST) -> Result<Vec<String>, LokiError> {
+    node.get_children()
+        .iter()
+        .map(|child| match child.get_value() {
+            QLValues::QLId(key) => Ok(key),
+            other => Err(LokiError::ParseError(format!(
+                "expected a key, found {:?}",
+                other
+            ))),
+        })
+        .collect()
+}
+
+fn get_value(node: &AST) -> Result<ValueObject, LokiError> {
+    match node.get_right_child() {
+        Some(child) => to_value_object(child.get_value()),
+        None => Err(LokiError::ParseError("missing value".to_string())),
+    }
+}
+
+// Milliseconds from `SET ... EX n`, if given
+fn get_expiry(node: &AST) -> Option<u64> {
+    match node.get_children().get(2).map(|child| child.get_value()) {
+        Some(QLValues::QLExpiry(millis)) => Some(millis),
+        _ => None,
+    }
+}
+
+fn reject_expiry(node: &AST, command: &str) -> Result<(), LokiError> {
+    match get_expiry(node) {
+        Some(_) => Err(LokiError::ParseError(format!(
+            "{} does not take EX",
+            command
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Turns a relative EXPIRE/PEXPIRE argument into a deadline, a negative
+// one makes the key expire right away
+fn get_deadline(node: &AST, unit_millis: i64) -> Result<u64, LokiError> {
+    match get_value(node)? {
+        ValueObject::IntData(n) => {
+            let delta = (n as i64).saturating_mul(unit_millis);
+            let now = get_current_timestamp_as_millis() as i64;
+            Ok(now.saturating_add(delta).max(0) as u64)
+        }
+        _ => Err(LokiError::ParseError(
+            "expire time must be an integer".to_string(),
+        )),
+    }
+}
+
+fn get_limit(node: &AST) -> Option<usize> {
+    node.get_children()
+        .iter()
+        .find_map(|child| match child.get_value() {
+            QLValues::QLLimit(limit) => Some(limit),
+            _ => None,
+        })
+}
+
+// Arguments after the key of a list command, checked against the number of
+// arguments the command takes
+fn get_list_args(node: &AST, command: &str, expected: usize) -> Result<Vec<QLValues>, LokiError> {
+    let args: Vec<QLValues> = node
+        .get_children()
+        .iter()
+        .skip(1)
+        .map(|child| child.get_value())
+        .collect();
+    if args.len() != expected {
+        return Err(LokiError::ParseError(format!(
+            "{} takes {} arguments after the key, got {}",
+            command,
+            expected,
+            args.len()
+        )));
+    }
+    Ok(args)
+}
+
+fn to_index(val: &QLValues) -> Result<isize, LokiError> {
+    match val {
+        QLValues::QLInt(index) => Ok(*index),
+        other => Err(LokiError::ParseError(format!(
+            "expected an integer, found {:?}",
+            other
+        ))),
+    }
+}
+
+fn set_to_list(members: impl IntoIterator<Item = String>) -> ValueObject {
+    ValueObject::ListData(members.into_iter().map(ValueObject::StringData).collect())
 }
 
-fn convert_to_value_object(list_data: Vec<QLValues>) -> Vec<ValueObject> {
-    let mut data: Vec<ValueObject> = vec![];
-    for item in list_data {
-        match item {
-            QLValues::QLInt(a) => data.push(ValueObject::IntData(a)),
-            QLValues::QLBool(a) => data.push(ValueObject::BoolData(a)),
-            QLValues::QLFloat(a) => data.push(ValueObject::DecimalData(a)),
-            QLValues::QLString(a) => data.push(ValueObject::StringData(a)),
-            QLValues::QLBlob(a) => data.push(ValueObject::BlobData(a)),
-            _ => error("no conversions available"),
+// Number of keys KEYS looks at when no COUNT is given
+const DEFAULT_KEYS_COUNT: usize = 10;
+
+// Cursor, MATCH pattern and COUNT of a KEYS command
+fn get_keys_options(node: &AST) -> Result<(u64, Option<String>, usize), LokiError> {
+    let mut cursor = None;
+    let mut pattern = None;
+    let mut count = DEFAULT_KEYS_COUNT;
+    for child in node.get_children() {
+        match child.get_value() {
+            QLValues::QLInt(c) if c >= 0 => cursor = Some(c as u64),
+            QLValues::QLMatch(p) => pattern = Some(p),
+            QLValues::QLCount(c) => count = c,
+            other => {
+                return Err(LokiError::ParseError(format!(
+                    "invalid KEYS argument {:?}",
+                    other
+                )))
+            }
         }
     }
-    data
+    match cursor {
+        Some(cursor) => Ok((cursor, pattern, count)),
+        None => Err(LokiError::ParseError("missing cursor".to_string())),
+    }
+}
+
+// `-` and `+` leave the start or the end of a scan open
+fn scan_bound(key: &str, open: &str) -> Bound<String> {
+    if key == open {
+        Bound::Unbounded
+    } else {
+        Bound::Included(key.to_string())
+    }
+}
+
+// Scan results are returned as a list of [key, value] lists
+fn pairs_to_list(pairs: Vec<(String, ValueObject)>) -> ValueObject {
+    ValueObject::ListData(
+        pairs
+            .into_iter()
+            .map(|(key, value)| ValueObject::ListData(vec![ValueObject::StringData(key), value]))
+            .collect(),
+    )
+}
+
+// Scores can be written as integers or floats
+fn to_score(val: &QLValues) -> Result<f64, LokiError> {
+    match val {
+        QLValues::QLInt(score) => Ok(*score as f64),
+        QLValues::QLFloat(score) => Ok(*score),
+        other => Err(LokiError::ParseError(format!(
+            "expected a score, found {:?}",
+            other
+        ))),
+    }
+}
+
+fn has_with_scores(node: &AST) -> bool {
+    node.get_children()
+        .iter()
+        .any(|child| matches!(child.get_value(), QLValues::QLWithScores))
+}
+
+// Sorted set ranges are lists of members, or of [member, score] lists
+// WITHSCORES
+fn scored_to_list(entries: Vec<(String, f64)>, with_scores: bool) -> ValueObject {
+    if with_scores {
+        pairs_to_list(
+            entries
+                .into_iter()
+                .map(|(member, score)| (member, ValueObject::DecimalData(score)))
+                .collect(),
+        )
+    } else {
+        set_to_list(entries.into_iter().map(|(member, _)| member))
+    }
 }
 
 impl Executor {
     // Generates a new executor
     pub fn new(db: Arc<RwLock<LokiKV>>, asts: Vec<Option<AST>>) -> Self {
+        let (wal_sync, control_file) = {
+            let ins = db.read().unwrap();
+            (ins.get_wal_sync(), ins.get_control_file().clone())
+        };
         Executor {
             database: db,
             asts,
-            persistor: Persistor::new(get_control_file_path()),
+            persistor: Persistor::from_control_file(control_file),
+            wal_sync,
         }
     }
 
-    // Execute AST
-    pub fn execute(&mut self) -> Vec<ValueObject> {
-        let db = &self.database;
-        let mut responses: Vec<ValueObject> = vec![];
-        for ast in self.asts.iter_mut() {
-            let vc = match ast {
-                Some(v) => v,
-                None => panic!("Empty node"),
-            };
-
-            // Since we already know that we are going to get a phantom value
-            if let Some(left_node) = vc.get_left_child() {
-                let response_d =
-                    execute_rec(left_node, db, OpMode::Phantom, None, self.persistor.clone());
-                match response_d {
-                    Some(res) => {
-                        responses.push(res);
-                    }
-                    None => {}
-                }
+    // Execute AST, returns one result for every command. Keys are looked up
+    // in the collection selected by the session.
+    pub fn execute(&mut self, session: &mut Session) -> Vec<Result<ValueObject, LokiError>> {
+        let mut responses: Vec<Result<ValueObject, LokiError>> = vec![];
+        // Commands that appended to the WAL and the last record they wrote
+        let mut writes: Vec<usize> = vec![];
+        let mut last_write_lsn = 0;
+        for (idx, ast) in self.asts.iter().enumerate() {
+            let before = self.wal_sync.get_written_lsn();
+            // The root of every command is a phantom node holding the command
+            let response = match ast.as_ref().and_then(|root| root.get_left_child()) {
+                Some(command) => execute_rec(command, &self.database, &self.persistor, session),
+                None => Err(LokiError::ParseError("empty command".to_string())),
             };
+            let after = self.wal_sync.get_written_lsn();
+            let read_only = ast.as_ref().is_some_and(AST::is_read_only);
+            if !read_only && response.is_ok() && after > before {
+                writes.push(idx);
+                last_write_lsn = after;
+            }
+            responses.push(response);
+        }
 
-            if let Some(right_node) = vc.get_right_child() {
-                let response_d = execute_rec(
-                    right_node,
-                    db,
-                    OpMode::Phantom,
-                    None,
-                    self.persistor.clone(),
-                );
-                match response_d {
-                    Some(res) => {
-                        responses.push(res);
-                    }
-                    None => {}
-                }
+        // Writes are only acknowledged once the WAL says they are durable,
+        // this happens after every lock on the database has been released
+        if writes.is_empty() {
+            return responses;
+        }
+        if let Err(e) = self.wal_sync.wait_durable(last_write_lsn) {
+            error_string(format!("WAL sync failed: {}", e));
+            let error = LokiError::IoError(format!("write may not be durable: {}", e));
+            for idx in writes {
+                responses[idx] = Err(error.clone());
             }
         }
-        return responses;
+        responses
     }
 }
 
 fn execute_rec(
     node: &AST,
     db: &Arc<RwLock<LokiKV>>,
-    mode: OpMode,
-    key: Option<String>,
-    persistor: Persistor,
-) -> Option<ValueObject> {
-    let val = node.get_value();
-    let mut local_key = String::new();
+    persistor: &Persistor,
+    session: &mut Session,
+) -> Result<ValueObject, LokiError> {
+    let collection = session.get_current_collection().to_string();
+    let cmd = match node.get_value() {
+        QLValues::QLCommand(cmd) => cmd,
+        other => {
+            return Err(LokiError::ParseError(format!(
+                "expected a command, found {:?}",
+                other
+            )))
+        }
+    };
 
-    match val {
-        QLValues::QLCommand(cmd) => {
-            // println!("Command is -> {:?}", cmd);
-            match cmd {
-                QLCommands::SET => {
-                    let key_node = node.get_left_child();
-                    let value_node = node.get_right_child();
-
-                    info_string(format!("Set {:?} {:?}", key_node, value_node));
-
-                    if let Some(node) = key_node {
-                        let v = execute_rec(node, db, OpMode::Phantom, None, persistor.clone());
-                        match v {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(val) = vc {
-                                    local_key = val;
-                                }
-                            }
-                            None => error("No Key!"),
-                        }
-                    };
-
-                    if let Some(node) = value_node {
-                        execute_rec(node, db, OpMode::Write, Some(local_key), persistor.clone());
-                    };
-                    Some(ValueObject::OutputString("SET".to_string()))
-                }
-                QLCommands::ADDHLL => {
-                    let key_node = node.get_left_child();
-                    let value_node = node.get_right_child();
-
-                    info_string(format!("Set {:?} {:?}", key_node, value_node));
-
-                    if let Some(node) = key_node {
-                        let v = execute_rec(node, db, OpMode::Phantom, None, persistor.clone());
-                        match v {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(val) = vc {
-                                    local_key = val;
-                                }
-                            }
-                            None => error("No Key!"),
-                        }
-                    };
-
-                    if let Some(node) = value_node {
-                        execute_rec(
-                            node,
-                            db,
-                            OpMode::AppendHLL,
-                            Some(local_key),
-                            persistor.clone(),
-                        );
-                    };
-                    Some(ValueObject::OutputString("SET".to_string()))
-                }
-                QLCommands::COUNTHLL => {
-                    let key_node = node.get_left_child();
-
-                    let mut val: ValueObject = ValueObject::Phantom;
-
-                    if let Some(node) = key_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let ins = db.read().unwrap();
-                        if let Some(vd) = ins.get(&local_key) {
-                            match vd {
-                                ValueObject::HLLPointer(hll_obj) => {
-                                    val = ValueObject::DecimalData(hll_obj.calculate_cardinality());
-                                }
-                                _ => {
-                                    val = ValueObject::OutputString(
-                                        "ERROR: Value is not of type HLL".to_string(),
-                                    )
-                                }
-                            }
-                        }
-                    };
-                    Some(val)
+    match cmd {
+        QLCommands::SET => {
+            let key = get_key(node)?;
+            let value = get_value(node)?;
+            info_string(format!("Set {} {:?}", key, value));
+            match get_expiry(node) {
+                Some(millis) => {
+                    let expire_at = get_current_timestamp_as_millis().saturating_add(millis);
+                    db.write()
+                        .unwrap()
+                        .put_with_expiry(&collection, &key, value, expire_at)?
                 }
-                QLCommands::GET => {
-                    let key_node = node.get_left_child();
-                    let mut val: ValueObject = ValueObject::Phantom;
-
-                    if let Some(node) = key_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let ins = db.read().unwrap();
-                        val = ins.get(&local_key).unwrap().clone();
-                    };
-                    Some(val)
-                }
-                QLCommands::CREATEBCOL => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.create_bmap_collection(local_key);
-                    };
-                    Some(ValueObject::OutputString(
-                        "CREATE B-TREE MAP COLLECTION".to_string(),
-                    ))
-                }
-                QLCommands::CREATEBCUST => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.create_custom_bcol(local_key);
-                    };
-                    Some(ValueObject::OutputString(
-                        "CREATE CUSTOM B-TREE MAP COLLECTION".to_string(),
+                None => db.write().unwrap().put(&collection, &key, value)?,
+            };
+            Ok(ValueObject::OutputString("SET".to_string()))
+        }
+        QLCommands::ADDHLL => {
+            let key = get_key(node)?;
+            let value = get_value(node)?;
+            reject_expiry(node, "ADDHLL")?;
+            info_string(format!("Add to HLL {} {:?}", key, value));
+            db.write().unwrap().hll_add(&collection, &key, value)?;
+            Ok(ValueObject::OutputString("SET".to_string()))
+        }
+        QLCommands::COUNTHLL => {
+            let ins = db.read().unwrap();
+            Ok(ValueObject::DecimalData(
+                ins.hll_count(&collection, &get_keys(node)?)?,
+            ))
+        }
+        QLCommands::MERGEHLL => {
+            let mut keys = get_keys(node)?;
+            let destination = keys.remove(0);
+            db.write()
+                .unwrap()
+                .hll_merge(&collection, &destination, &keys)?;
+            Ok(ValueObject::OutputString("HLLMERGE".to_string()))
+        }
+        QLCommands::BFRESERVE => {
+            let children = node.get_children();
+            let (Some(QLValues::QLFloat(error_rate)), Some(QLValues::QLInt(capacity))) = (
+                children.get(1).map(|c| c.get_value()),
+                children.get(2).map(|c| c.get_value()),
+            ) else {
+                return Err(LokiError::ParseError(
+                    "BFRESERVE takes an error rate and a capacity".to_string(),
+                ));
+            };
+            let capacity = u64::try_from(capacity)
+                .map_err(|_| LokiError::OutOfRange("capacity must be positive".to_string()))?;
+            let created = db.write().unwrap().bloom_reserve(
+                &collection,
+                &get_key(node)?,
+                error_rate,
+                capacity,
+            )?;
+            Ok(ValueObject::BoolData(created))
+        }
+        QLCommands::BFADD | QLCommands::BFEXISTS | QLCommands::CMSQUERY => {
+            let key = get_key(node)?;
+            let item = get_value(node)?;
+            reject_expiry(node, &format!("{:?}", cmd))?;
+            match cmd {
+                QLCommands::BFADD => Ok(ValueObject::BoolData(db.write().unwrap().bloom_add(
+                    &collection,
+                    &key,
+                    item,
+                )?)),
+                QLCommands::BFEXISTS => Ok(ValueObject::BoolData(
+                    db.read().unwrap().bloom_exists(&collection, &key, &item)?,
+                )),
+                _ => {
+                    let estimate = db.read().unwrap().sketch_query(&collection, &key, &item)?;
+                    Ok(ValueObject::IntData(
+                        estimate.min(isize::MAX as u64) as isize
                     ))
                 }
-                QLCommands::CREATEHCOL => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.create_hmap_collection(local_key);
-                    };
-                    Some(ValueObject::OutputString(
-                        "CREATE CUSTOM H-MAP COLLECTION".to_string(),
+            }
+        }
+        QLCommands::CMSINCR => {
+            let count = match node.get_children().get(2).map(|c| c.get_value()) {
+                None => 1,
+                Some(QLValues::QLInt(count)) if count > 0 => count as u64,
+                Some(_) => {
+                    return Err(LokiError::ParseError(
+                        "CMSINCR takes a positive count".to_string(),
                     ))
                 }
-                QLCommands::LOAD_HMAP => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                    };
-
-                    let mut ins = db.write().unwrap();
-                    let vc = persistor.load_to_hmap(local_key.to_string());
-                    ins.append_hmap(vc.0, vc.1);
-
-                    Some(ValueObject::OutputString(format!(
-                        "LOADED HMAP {} FROM DISK",
-                        local_key
-                    )))
-                }
-                QLCommands::LOAD_BCUST => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                    };
-
-                    let mut ins = db.write().unwrap();
-                    let vc = persistor.load_to_btree(local_key.to_string());
-                    ins.append_custom_bcol(vc.0, vc.1);
-
-                    Some(ValueObject::OutputString(format!(
-                        "LOADED BCUST {} FROM DISK",
-                        local_key
-                    )))
-                }
-                QLCommands::LOAD_BDEF => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                    };
-
-                    let mut ins = db.write().unwrap();
-                    let vc = persistor.load_to_btree_def(local_key.to_string());
-                    ins.append_bcol(vc.0, vc.1);
-
-                    Some(ValueObject::OutputString(format!(
-                        "LOADED BDEF {} FROM DISK",
-                        local_key
-                    )))
-                }
-                QLCommands::DELCOL => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.remove_collection(local_key.clone());
-                    };
-
-                    Some(ValueObject::OutputString(format!(
-                        "Removed Collection {}",
-                        local_key
-                    )))
-                }
-                QLCommands::PERSIST => {
-                    let table_node = node.get_left_child();
-
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.select_collection(local_key.as_str());
-                        persistor.persist(
-                            ins.get_current_collection().generate_pairs(),
-                            local_key.to_string(),
-                        );
-                    };
-                    Some(ValueObject::OutputString(format!(
-                        "PERSISTING {} TO DISK",
-                        local_key
-                    )))
-                }
-                QLCommands::SELCOL => {
-                    let table_node = node.get_left_child();
-                    if let Some(node) = table_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.select_collection(&local_key);
-                    };
-                    Some(ValueObject::OutputString("SELECT COLUMN".to_string()))
-                }
-                QLCommands::INCR => {
-                    let key_node = node.get_left_child();
-
-                    if let Some(node) = key_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.incr(&local_key);
-                    };
-                    Some(ValueObject::OutputString("INCR".to_string()))
-                }
-                QLCommands::DECR => {
-                    let key_node = node.get_left_child();
-
-                    if let Some(node) = key_node {
-                        let _ = match execute_rec(node, db, OpMode::Read, None, persistor.clone()) {
-                            Some(vc) => {
-                                if let ValueObject::OutputString(data) = vc {
-                                    local_key = data
-                                }
-                            }
-                            None => error("Unable to parse key"),
-                        };
-                        let mut ins = db.write().unwrap();
-                        ins.decr(&local_key);
-                    };
-                    Some(ValueObject::OutputString("DECR".to_string()))
-                }
-                QLCommands::DISPLAY => {
-                    let ins = db.read().unwrap();
-                    let data = ins.display_collection();
-                    Some(ValueObject::OutputString(data))
-                }
-                QLCommands::CURCOLNAME => {
-                    let ins = db.read().unwrap();
-                    let data = ins.get_current_collection_name();
-                    Some(ValueObject::OutputString(data))
-                }
-                QLCommands::LISTCOLNAMES => {
-                    let ins = db.read().unwrap();
-                    let data = ins.get_all_collection_names();
-                    Some(ValueObject::OutputString(data))
-                }
-                QLCommands::SHUTDOWN => {
-                    process::exit(1);
-                }
-                QLCommands::DISPLAY_WAL => {
-                    let ins = db.read().unwrap();
-                    let data = ins.display_wal();
-                    Some(ValueObject::OutputString(data))
+            };
+            let estimate = db.write().unwrap().sketch_incr(
+                &collection,
+                &get_key(node)?,
+                get_value(node)?,
+                count,
+            )?;
+            Ok(ValueObject::IntData(
+                estimate.min(isize::MAX as u64) as isize
+            ))
+        }
+        QLCommands::CMSMERGE => {
+            let mut keys = get_keys(node)?;
+            let destination = keys.remove(0);
+            db.write()
+                .unwrap()
+                .sketch_merge(&collection, &destination, &keys)?;
+            Ok(ValueObject::OutputString("CMSMERGE".to_string()))
+        }
+        QLCommands::GET => {
+            let key = get_key(node)?;
+            let ins = db.read().unwrap();
+            Ok(ins.get(&collection, &key)?.clone())
+        }
+        QLCommands::CREATEBCOL => {
+            db.write().unwrap().create_bmap_collection(get_key(node)?)?;
+            Ok(ValueObject::OutputString(
+                "CREATE B-TREE MAP COLLECTION".to_string(),
+            ))
+        }
+        QLCommands::CREATEBCUST => {
+            db.write().unwrap().create_custom_bcol(get_key(node)?)?;
+            Ok(ValueObject::OutputString(
+                "CREATE CUSTOM B-TREE MAP COLLECTION".to_string(),
+            ))
+        }
+        QLCommands::CREATEHCOL => {
+            db.write().unwrap().create_hmap_collection(get_key(node)?)?;
+            Ok(ValueObject::OutputString(
+                "CREATE CUSTOM H-MAP COLLECTION".to_string(),
+            ))
+        }
+        QLCommands::LOAD_HMAP => {
+            let key = get_key(node)?;
+            let (name, col) = persistor
+                .load_to_hmap(key.clone())
+                .map_err(LokiError::IoError)?;
+            db.write().unwrap().append_hmap(name, col)?;
+            Ok(ValueObject::OutputString(format!(
+                "LOADED HMAP {} FROM DISK",
+                key
+            )))
+        }
+        QLCommands::LOAD_BCUST => {
+            let key = get_key(node)?;
+            let (name, col) = persistor
+                .load_to_btree(key.clone())
+                .map_err(LokiError::IoError)?;
+            db.write().unwrap().append_custom_bcol(name, col)?;
+            Ok(ValueObject::OutputString(format!(
+                "LOADED BCUST {} FROM DISK",
+                key
+            )))
+        }
+        QLCommands::LOAD_BDEF => {
+            let key = get_key(node)?;
+            let (name, col) = persistor
+                .load_to_btree_def(key.clone())
+                .map_err(LokiError::IoError)?;
+            db.write().unwrap().append_bcol(name, col)?;
+            Ok(ValueObject::OutputString(format!(
+                "LOADED BDEF {} FROM DISK",
+                key
+            )))
+        }
+        QLCommands::DEL => {
+            let mut ins = db.write().unwrap();
+            let mut removed = 0;
+            for key in get_keys(node)? {
+                if ins.delete(&collection, &key)? {
+                    removed += 1;
                 }
             }
+            Ok(ValueObject::IntData(removed))
         }
-        QLValues::QLId(key_val) => Some(ValueObject::OutputString(key_val)),
-        QLValues::QLList(list_value) => match mode {
-            OpMode::Write => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        ins.put(
-                            &kv,
-                            ValueObject::ListData(convert_to_value_object(list_value)),
-                        );
-                        None
-                    }
-                    _ => None,
-                }
+        QLCommands::EXISTS => {
+            let ins = db.read().unwrap();
+            Ok(ValueObject::BoolData(
+                ins.exists(&collection, &get_key(node)?)?,
+            ))
+        }
+        QLCommands::RENAME => {
+            let keys = get_keys(node)?;
+            if keys.len() != 2 {
+                return Err(LokiError::ParseError(
+                    "RENAME takes a key and its new name".to_string(),
+                ));
             }
-            // OpMode::AppendHLL => {
-            //     let mut ins = db.write().unwrap();
-            //     match key{
-            //         Some(kv) => {
-            //             // get value at key
-            //             println!("HLL here -> {}", kv);
-            //             if let ValueObject::HLLPointer(hll_obj) = ins.get(&kv).unwrap(){
-            //                 hll_obj.add_item(entry);
-            //                 ins.put(&kv, hll_obj);
-            //             }else{
-            //                 // Since hll pointer not found at key, we will change the value at that key
-            //                 let mut hll = HLL::new();
-            //                 hll.add_item(val);
-            //                 ins.put(&kv, ValueObject::HLLPointer(hll));
-            //             }
-            //         }
-            //     }
-            //     None
-            // }
-            OpMode::Append => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        if let Some(cur_list) = ins.get(&local_key) {
-                            if let ValueObject::ListData(new_vec) = cur_list.clone() {}
-                        }
-                        None
-                    }
-                    _ => None,
-                }
+            db.write()
+                .unwrap()
+                .rename(&collection, &keys[0], &keys[1])?;
+            Ok(ValueObject::OutputString("RENAME".to_string()))
+        }
+        QLCommands::EXPIRE | QLCommands::PEXPIRE => {
+            let (name, unit_millis) = match cmd {
+                QLCommands::EXPIRE => ("EXPIRE", 1000),
+                _ => ("PEXPIRE", 1),
+            };
+            reject_expiry(node, name)?;
+            let expire_at = get_deadline(node, unit_millis)?;
+            let updated =
+                db.write()
+                    .unwrap()
+                    .expire(&collection, &get_key(node)?, Some(expire_at))?;
+            Ok(ValueObject::BoolData(updated))
+        }
+        QLCommands::TTL => {
+            let ins = db.read().unwrap();
+            // Whole seconds left rounded up, -1 when the key never expires
+            match ins.ttl(&collection, &get_key(node)?)? {
+                Some(millis) => Ok(ValueObject::IntData(millis.div_ceil(1000) as isize)),
+                None => Ok(ValueObject::IntData(-1)),
             }
-            _ => None,
-        },
-        QLValues::QLBool(bool_val) => match mode {
-            OpMode::Write => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        ins.put(&kv, ValueObject::BoolData(bool_val));
-                    }
-                    None => {}
-                }
-                None
+        }
+        QLCommands::PERSISTKEY => {
+            let removed = db
+                .write()
+                .unwrap()
+                .expire(&collection, &get_key(node)?, None)?;
+            Ok(ValueObject::BoolData(removed))
+        }
+        QLCommands::SCAN | QLCommands::RSCAN => {
+            let start = scan_bound(&get_key(node)?, "-");
+            let end = match node.get_right_child().map(|child| child.get_value()) {
+                Some(QLValues::QLId(end)) => scan_bound(&end, "+"),
+                _ => return Err(LokiError::ParseError("missing end of range".to_string())),
+            };
+            let pairs = db.read().unwrap().scan(
+                &collection,
+                start.as_ref().map(String::as_str),
+                end.as_ref().map(String::as_str),
+                matches!(cmd, QLCommands::RSCAN),
+                get_limit(node),
+            )?;
+            Ok(pairs_to_list(pairs))
+        }
+        QLCommands::PREFIX | QLCommands::RPREFIX => {
+            let pairs = db.read().unwrap().scan_prefix(
+                &collection,
+                &get_key(node)?,
+                matches!(cmd, QLCommands::RPREFIX),
+                get_limit(node),
+            )?;
+            Ok(pairs_to_list(pairs))
+        }
+        QLCommands::KEYS => {
+            let (cursor, pattern, count) = get_keys_options(node)?;
+            let (next_cursor, keys) =
+                session.scan_keys(&db.read().unwrap(), cursor, pattern.as_deref(), count)?;
+            // [next cursor, [keys]], the cursor is 0 once the scan is done
+            Ok(ValueObject::ListData(vec![
+                ValueObject::IntData(next_cursor as isize),
+                ValueObject::ListData(keys.into_iter().map(ValueObject::StringData).collect()),
+            ]))
+        }
+        QLCommands::LPUSH | QLCommands::RPUSH => {
+            let key = get_key(node)?;
+            let values: Vec<ValueObject> = node
+                .get_children()
+                .iter()
+                .skip(1)
+                .map(|child| to_value_object(child.get_value()))
+                .collect::<Result<_, _>>()?;
+            if values.is_empty() {
+                return Err(LokiError::ParseError(format!("{:?} needs a value", cmd)));
             }
-            OpMode::AppendHLL => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        // get value at key
-                        if let Some(vb) = ins.get(&kv) {
-                            match vb {
-                                ValueObject::HLLPointer(hll_obj) => {
-                                    let mut mhll_obj = hll_obj.clone();
-                                    mhll_obj.add_item(bool_val);
-                                    ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            // Since hll pointer not found at key, we will change the value at that key
-                            let mut mhll_obj = HLL::new();
-                            mhll_obj.add_item(bool_val);
-                            ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-                        }
+            let front = matches!(cmd, QLCommands::LPUSH);
+            let len = db
+                .write()
+                .unwrap()
+                .list_push(&collection, &key, front, values)?;
+            Ok(ValueObject::IntData(len as isize))
+        }
+        QLCommands::LPOP | QLCommands::RPOP => {
+            let key = get_key(node)?;
+            let front = matches!(cmd, QLCommands::LPOP);
+            // Without a count the value itself is returned, with one a list
+            let count = match node.get_children().len() {
+                1 => None,
+                _ => match to_index(&get_list_args(node, &format!("{:?}", cmd), 1)?[0])? {
+                    count if count >= 0 => Some(count as usize),
+                    count => {
+                        return Err(LokiError::OutOfRange(format!(
+                            "count {} is negative",
+                            count
+                        )))
                     }
-                    _ => {}
-                }
-                None
+                },
+            };
+            let popped =
+                db.write()
+                    .unwrap()
+                    .list_pop(&collection, &key, front, count.unwrap_or(1))?;
+            match count {
+                Some(_) => Ok(ValueObject::ListData(popped)),
+                // An empty list answers like a missing key
+                None => popped.into_iter().next().ok_or(LokiError::KeyNotFound(key)),
             }
-            _ => None,
-        },
-        QLValues::QLInt(int_v) => match mode {
-            OpMode::Write => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        ins.put(&kv, ValueObject::IntData(int_v));
+        }
+        QLCommands::LRANGE => {
+            let args = get_list_args(node, "LRANGE", 2)?;
+            let values = db.read().unwrap().list_range(
+                &collection,
+                &get_key(node)?,
+                to_index(&args[0])?,
+                to_index(&args[1])?,
+            )?;
+            Ok(ValueObject::ListData(values))
+        }
+        QLCommands::LINDEX => {
+            let args = get_list_args(node, "LINDEX", 1)?;
+            let ins = db.read().unwrap();
+            ins.list_index(&collection, &get_key(node)?, to_index(&args[0])?)
+        }
+        QLCommands::LSET => {
+            let mut args = get_list_args(node, "LSET", 2)?;
+            let value = to_value_object(args.remove(1))?;
+            db.write().unwrap().list_set(
+                &collection,
+                &get_key(node)?,
+                to_index(&args[0])?,
+                value,
+            )?;
+            Ok(ValueObject::OutputString("LSET".to_string()))
+        }
+        QLCommands::LLEN => {
+            get_list_args(node, "LLEN", 0)?;
+            let len = db.read().unwrap().list_len(&collection, &get_key(node)?)?;
+            Ok(ValueObject::IntData(len as isize))
+        }
+        QLCommands::LTRIM => {
+            let args = get_list_args(node, "LTRIM", 2)?;
+            db.write().unwrap().list_trim(
+                &collection,
+                &get_key(node)?,
+                to_index(&args[0])?,
+                to_index(&args[1])?,
+            )?;
+            Ok(ValueObject::OutputString("LTRIM".to_string()))
+        }
+        QLCommands::HSET => {
+            let key = get_key(node)?;
+            let mut fields = Vec::new();
+            for pair in node.get_children()[1..].chunks(2) {
+                match (pair[0].get_value(), pair.get(1)) {
+                    (QLValues::QLId(field), Some(value)) => {
+                        fields.push((field, to_value_object(value.get_value())?))
                     }
-                    _ => {}
-                }
-                None
-            }
-            OpMode::AppendHLL => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        // get value at key
-                        if let Some(vb) = ins.get(&kv) {
-                            match vb {
-                                ValueObject::HLLPointer(hll_obj) => {
-                                    let mut mhll_obj = hll_obj.clone();
-                                    mhll_obj.add_item(int_v);
-                                    ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            // Since hll pointer not found at key, we will change the value at that key
-                            let mut mhll_obj = HLL::new();
-                            mhll_obj.add_item(int_v);
-                            ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-                        }
+                    _ => {
+                        return Err(LokiError::ParseError(
+                            "HSET takes field value pairs".to_string(),
+                        ))
                     }
-                    _ => {}
                 }
-                None
             }
-            _ => None,
-        },
-        QLValues::QLFloat(fl_v) => match mode {
-            OpMode::Write => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        ins.put(&kv, ValueObject::DecimalData(fl_v));
-                    }
-                    _ => {}
+            let added = db.write().unwrap().hash_set(&collection, &key, fields)?;
+            Ok(ValueObject::IntData(added as isize))
+        }
+        QLCommands::HINCRBY => {
+            let children = node.get_children();
+            let (field, delta) = match (children.get(1), children.get(2), children.len()) {
+                (Some(field), Some(delta), 3) => (field.get_value(), delta.get_value()),
+                _ => {
+                    return Err(LokiError::ParseError(
+                        "HINCRBY takes a field and an integer".to_string(),
+                    ))
                 }
-                None
+            };
+            let (QLValues::QLId(field), QLValues::QLInt(delta)) = (field, delta) else {
+                return Err(LokiError::ParseError(
+                    "HINCRBY takes a field and an integer".to_string(),
+                ));
+            };
+            let value =
+                db.write()
+                    .unwrap()
+                    .hash_incr_by(&collection, &get_key(node)?, &field, delta)?;
+            Ok(ValueObject::IntData(value))
+        }
+        QLCommands::HGET | QLCommands::HEXISTS => {
+            let keys = get_keys(node)?;
+            if keys.len() != 2 {
+                return Err(LokiError::ParseError(format!(
+                    "{:?} takes a key and a field",
+                    cmd
+                )));
             }
-            // OpMode::AppendHLL => {
-            //     let mut ins = db.write().unwrap();
-            //     match key{
-            //         Some(kv) => {
-            //             // get value at key
-            //             if let Some(vb) = ins.get(&kv){
-            //                 match vb{
-            //                     ValueObject::HLLPointer(hll_obj) => {
-            //                         let mut mhll_obj = hll_obj.clone();
-            //                         mhll_obj.add_item(fl_v);
-            //                         ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-            //                     }
-            //                     _ => {}
-            //                 }
-            //             }else{
-            //                 // Since hll pointer not found at key, we will change the value at that key
-            //                 let mut mhll_obj = HLL::new();
-            //                 mhll_obj.add_item(fl_v);
-            //                 ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-            //             }
-
-            //         }
-            //         _ => {}
-            //     }
-            //     None
-            // }
-            _ => None,
-        },
-        QLValues::QLString(st_v) => match mode {
-            OpMode::Write => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        ins.put(&kv, ValueObject::StringData(st_v));
-                    }
-                    _ => {}
-                }
-                None
+            let ins = db.read().unwrap();
+            match cmd {
+                QLCommands::HGET => ins.hash_get(&collection, &keys[0], &keys[1]),
+                _ => Ok(ValueObject::BoolData(ins.hash_exists(
+                    &collection,
+                    &keys[0],
+                    &keys[1],
+                )?)),
+            }
+        }
+        QLCommands::HDEL => {
+            let mut keys = get_keys(node)?;
+            if keys.len() < 2 {
+                return Err(LokiError::ParseError(
+                    "HDEL takes a key and at least one field".to_string(),
+                ));
+            }
+            let key = keys.remove(0);
+            let removed = db.write().unwrap().hash_delete(&collection, &key, keys)?;
+            Ok(ValueObject::IntData(removed as isize))
+        }
+        QLCommands::HGETALL => {
+            let ins = db.read().unwrap();
+            Ok(ValueObject::MapData(
+                ins.hash_get_all(&collection, &get_key(node)?)?,
+            ))
+        }
+        QLCommands::SADD | QLCommands::SREM => {
+            let mut keys = get_keys(node)?;
+            if keys.len() < 2 {
+                return Err(LokiError::ParseError(format!(
+                    "{:?} takes a key and at least one member",
+                    cmd
+                )));
+            }
+            let key = keys.remove(0);
+            let mut ins = db.write().unwrap();
+            let changed = match cmd {
+                QLCommands::SADD => ins.set_add(&collection, &key, keys)?,
+                _ => ins.set_remove(&collection, &key, keys)?,
+            };
+            Ok(ValueObject::IntData(changed as isize))
+        }
+        QLCommands::SISMEMBER => {
+            let keys = get_keys(node)?;
+            if keys.len() != 2 {
+                return Err(LokiError::ParseError(
+                    "SISMEMBER takes a key and a member".to_string(),
+                ));
+            }
+            let ins = db.read().unwrap();
+            Ok(ValueObject::BoolData(ins.set_is_member(
+                &collection,
+                &keys[0],
+                &keys[1],
+            )?))
+        }
+        QLCommands::SCARD | QLCommands::SMEMBERS => {
+            let members = db
+                .read()
+                .unwrap()
+                .set_members(&collection, &get_key(node)?)?;
+            match cmd {
+                QLCommands::SCARD => Ok(ValueObject::IntData(members.len() as isize)),
+                _ => Ok(set_to_list(members)),
+            }
+        }
+        QLCommands::SUNION | QLCommands::SINTER | QLCommands::SDIFF => {
+            let algebra = match cmd {
+                QLCommands::SUNION => SetAlgebra::Union,
+                QLCommands::SINTER => SetAlgebra::Inter,
+                _ => SetAlgebra::Diff,
+            };
+            let result = db
+                .read()
+                .unwrap()
+                .set_combine(&collection, algebra, &get_keys(node)?)?;
+            Ok(set_to_list(result))
+        }
+        QLCommands::SUNIONSTORE | QLCommands::SINTERSTORE | QLCommands::SDIFFSTORE => {
+            let algebra = match cmd {
+                QLCommands::SUNIONSTORE => SetAlgebra::Union,
+                QLCommands::SINTERSTORE => SetAlgebra::Inter,
+                _ => SetAlgebra::Diff,
+            };
+            let mut keys = get_keys(node)?;
+            if keys.len() < 2 {
+                return Err(LokiError::ParseError(format!(
+                    "{:?} takes a destination and at least one key",
+                    cmd
+                )));
             }
-            OpMode::AppendHLL => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        // get value at key
-                        if let Some(vb) = ins.get(&kv) {
-                            match vb {
-                                ValueObject::HLLPointer(hll_obj) => {
-                                    let mut mhll_obj = hll_obj.clone();
-                                    mhll_obj.add_item(st_v);
-                                    ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-                                }
-                                _ => {}
-                            }
-                        } else {
-                            // Since hll pointer not found at key, we will change the value at that key
-                            let mut mhll_obj = HLL::new();
-                            mhll_obj.add_item(st_v);
-                            ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-                        }
+            let destination = keys.remove(0);
+            let len =
+                db.write()
+                    .unwrap()
+                    .set_combine_store(&collection, algebra, &destination, &keys)?;
+            Ok(ValueObject::IntData(len as isize))
+        }
+        QLCommands::ZADD | QLCommands::ZINCRBY => {
+            let key = get_key(node)?;
+            let mut members = Vec::new();
+            for pair in node.get_children()[1..].chunks(2) {
+                match (
+                    to_score(&pair[0].get_value()),
+                    pair.get(1).map(|m| m.get_value()),
+                ) {
+                    (Ok(score), Some(QLValues::QLId(member))) => members.push((member, score)),
+                    _ => {
+                        return Err(LokiError::ParseError(format!(
+                            "{:?} takes score member pairs",
+                            cmd
+                        )))
                     }
-                    _ => {}
                 }
-                None
             }
-            _ => None,
-        },
-        QLValues::QLBlob(data) => match mode {
-            OpMode::Write => {
-                let mut ins = db.write().unwrap();
-                match key {
-                    Some(kv) => {
-                        ins.put(&kv, ValueObject::BlobData(data));
-                    }
-                    _ => {}
+            let mut ins = db.write().unwrap();
+            match (cmd, members.as_slice()) {
+                (QLCommands::ZADD, _) => {
+                    Ok(ValueObject::IntData(
+                        ins.sorted_set_add(&collection, &key, members)? as isize,
+                    ))
                 }
-                None
+                (_, [(member, delta)]) => Ok(ValueObject::DecimalData(ins.sorted_set_incr_by(
+                    &collection,
+                    &key,
+                    member,
+                    *delta,
+                )?)),
+                _ => Err(LokiError::ParseError(
+                    "ZINCRBY takes a single increment and member".to_string(),
+                )),
             }
-            // OpMode::AppendHLL => {
-            //     let mut ins = db.write().unwrap();
-            //     match key{
-            //         Some(kv) => {
-            //             // get value at key
-            //             println!("HLL here -> {}", kv);
-            //             if let Some(vb) = ins.get(&kv){
-            //                 match vb{
-            //                     ValueObject::HLLPointer(hll_obj) => {
-            //                         let mut mhll_obj = hll_obj.clone();
-            //                         mhll_obj.add_item(data);
-            //                         ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-            //                     }
-            //                     _ => {}
-            //                 }
-            //             }else{
-            //                 // Since hll pointer not found at key, we will change the value at that key
-            //                 let mut mhll_obj = HLL::new();
-            //                 mhll_obj.add_item(data);
-            //                 ins.put(&kv, ValueObject::HLLPointer(mhll_obj));
-            //             }
-
-            //         }
-            //         _ => {}
-            //     }
-            //     None
-            // }
-            _ => None,
-        },
-        _ => {
-            // println!("Value is -> {:?}", val);
-            None
         }
+        QLCommands::ZREM => {
+            let mut keys = get_keys(node)?;
+            if keys.len() < 2 {
+                return Err(LokiError::ParseError(
+                    "ZREM takes a key and at least one member".to_string(),
+                ));
+            }
+            let key = keys.remove(0);
+            let removed = db
+                .write()
+                .unwrap()
+                .sorted_set_remove(&collection, &key, keys)?;
+            Ok(ValueObject::IntData(removed as isize))
+        }
+        QLCommands::ZSCORE | QLCommands::ZRANK => {
+            let keys = get_keys(node)?;
+            if keys.len() != 2 {
+                return Err(LokiError::ParseError(format!(
+                    "{:?} takes a key and a member",
+                    cmd
+                )));
+            }
+            let ins = db.read().unwrap();
+            match cmd {
+                QLCommands::ZSCORE => Ok(ValueObject::DecimalData(ins.sorted_set_score(
+                    &collection,
+                    &keys[0],
+                    &keys[1],
+                )?)),
+                _ => Ok(ValueObject::IntData(
+                    ins.sorted_set_rank(&collection, &keys[0], &keys[1])? as isize,
+                )),
+            }
+        }
+        QLCommands::ZCARD => {
+            let len = db
+                .read()
+                .unwrap()
+                .sorted_set_len(&collection, &get_key(node)?)?;
+            Ok(ValueObject::IntData(len as isize))
+        }
+        QLCommands::ZRANGE => {
+            let children = node.get_children();
+            let (Some(start), Some(stop)) = (children.get(1), children.get(2)) else {
+                return Err(LokiError::ParseError(
+                    "ZRANGE takes a start and a stop rank".to_string(),
+                ));
+            };
+            let entries = db.read().unwrap().sorted_set_range(
+                &collection,
+                &get_key(node)?,
+                to_index(&start.get_value())?,
+                to_index(&stop.get_value())?,
+            )?;
+            Ok(scored_to_list(entries, has_with_scores(node)))
+        }
+        QLCommands::ZRANGEBYSCORE => {
+            let children = node.get_children();
+            let (Some(min), Some(max)) = (children.get(1), children.get(2)) else {
+                return Err(LokiError::ParseError(
+                    "ZRANGEBYSCORE takes a min and a max score".to_string(),
+                ));
+            };
+            let (QLValues::QLScoreBound(min), QLValues::QLScoreBound(max)) =
+                (min.get_value(), max.get_value())
+            else {
+                return Err(LokiError::ParseError(
+                    "ZRANGEBYSCORE takes a min and a max score".to_string(),
+                ));
+            };
+            let entries = db.read().unwrap().sorted_set_range_by_score(
+                &collection,
+                &get_key(node)?,
+                min,
+                max,
+                get_limit(node),
+            )?;
+            Ok(scored_to_list(entries, has_with_scores(node)))
+        }
+        QLCommands::DELCOL => {
+            let key = get_key(node)?;
+            db.write().unwrap().remove_collection(key.clone())?;
+            Ok(ValueObject::OutputString(format!(
+                "Removed Collection {}",
+                key
+            )))
+        }
+        QLCommands::PERSIST => {
+            let key = get_key(node)?;
+            let (kind, pairs) = {
+                let ins = db.read().unwrap();
+                let kind = ins
+                    .get_collection_kind(&key)
+                    .ok_or_else(|| LokiError::CollectionNotFound(key.clone()))?;
+                (kind, ins.get_collection_by_name(&key)?.generate_pairs())
+            };
+            if let Err(e) = persistor.persist(pairs, key.clone(), kind) {
+                error_string(format!("Failed to persist {}: {}", key, e));
+                return Err(LokiError::IoError(e));
+            }
+            Ok(ValueObject::OutputString(format!(
+                "PERSISTING {} TO DISK",
+                key
+            )))
+        }
+        QLCommands::LOAD => {
+            let key = get_key(node)?;
+            let (header, pairs) = persistor.load(&key).map_err(LokiError::IoError)?;
+            db.write()
+                .unwrap()
+                .load_collection(key.clone(), header.get_kind(), pairs)?;
+            Ok(ValueObject::OutputString(format!(
+                "LOADED {:?} {} FROM DISK ({} keys)",
+                header.get_kind(),
+                key,
+                header.get_key_count()
+            )))
+        }
+        QLCommands::SELCOL => {
+            session.select_collection(&db.read().unwrap(), &get_key(node)?)?;
+            Ok(ValueObject::OutputString("SELECT COLUMN".to_string()))
+        }
+        QLCommands::INCR => {
+            let value = db.write().unwrap().incr(&collection, &get_key(node)?)?;
+            Ok(ValueObject::IntData(value))
+        }
+        QLCommands::DECR => {
+            let value = db.write().unwrap().decr(&collection, &get_key(node)?)?;
+            Ok(ValueObject::IntData(value))
+        }
+        QLCommands::DISPLAY => {
+            let ins = db.read().unwrap();
+            Ok(ValueObject::OutputString(
+                ins.display_collection(&collection)?,
+            ))
+        }
+        QLCommands::CURCOLNAME => Ok(ValueObject::OutputString(collection)),
+        QLCommands::LISTCOLNAMES => {
+            let ins = db.read().unwrap();
+            Ok(ValueObject::OutputString(ins.get_all_collection_names()))
+        }
+        QLCommands::SHUTDOWN => {
+            process::exit(1);
+        }
+        QLCommands::DISPLAY_WAL => {
+            let ins = db.read().unwrap();
+            Ok(ValueObject::OutputString(ins.display_wal()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::config::Config;
+    use crate::loki_kv::control::write_test_control_file;
+    use crate::loki_kv::loki_kv::DEFAULT_COLLECTION;
+    use crate::loki_kv::wal::WALSyncMode;
+    use crate::parser::parser::parse_lokiql;
+    use tempfile::TempDir;
+
+    fn test_db() -> (TempDir, Arc<RwLock<LokiKV>>) {
+        let dir = TempDir::new().unwrap();
+        let db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        (dir, Arc::new(RwLock::new(db)))
+    }
+
+    // Opens the database of test_db again, replaying what was written to it
+    fn reopen(dir: &TempDir) -> LokiKV {
+        LokiKV::with_control_file(dir.path().join("control.toml").display().to_string()).unwrap()
+    }
+
+    fn run(
+        db: &Arc<RwLock<LokiKV>>,
+        session: &mut Session,
+        query: &str,
+    ) -> Vec<Result<ValueObject, LokiError>> {
+        Executor::new(db.clone(), parse_lokiql(query).unwrap()).execute(session)
+    }
+
+    #[test]
+    fn test_failed_sync_turns_only_the_writes_into_errors() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::new(dir.path()).with_wal_sync_mode(WALSyncMode::GroupCommit);
+        let db = Arc::new(RwLock::new(LokiKV::open(config).unwrap()));
+        let mut session = Session::new();
+        let sync = db.read().unwrap().get_wal_sync();
+
+        // Nothing to wait for when the batch did not write
+        sync.fail_next_sync();
+        assert!(run(&db, &mut session, "GET missing")[0].is_err());
+        assert!(run(&db, &mut session, "SET a 1")[0].is_err());
+
+        sync.fail_next_sync();
+        let responses = run(&db, &mut session, "SET b 2; GET a; INCR missing; SET c 3");
+        assert_eq!(responses.len(), 4);
+        assert!(matches!(&responses[0], Err(LokiError::IoError(e)) if e.contains("durable")));
+        assert!(matches!(responses[1], Ok(ValueObject::IntData(1))));
+        assert!(matches!(responses[2], Err(LokiError::KeyNotFound(_))));
+        assert!(matches!(&responses[3], Err(LokiError::IoError(e)) if e.contains("durable")));
+    }
+
+    #[test]
+    fn test_failed_commands_return_errors_and_keep_the_database_usable() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+
+        assert_eq!(
+            run(&db, &mut session, "GET missing")[0]
+                .as_ref()
+                .err()
+                .map(|e| e.code()),
+            Some("KEY_NOT_FOUND")
+        );
+
+        assert!(run(&db, &mut session, "SET name 'loki'")[0].is_ok());
+        assert!(matches!(
+            run(&db, &mut session, "INCR name")[0],
+            Err(LokiError::WrongType(_))
+        ));
+
+        assert!(!db.is_poisoned());
+        assert!(matches!(
+            db.read().unwrap().get("default", "name"),
+            Ok(ValueObject::StringData(s)) if s == "'loki'"
+        ));
+    }
+
+    #[test]
+    fn test_selected_collection_is_per_session() {
+        let (_dir, db) = test_db();
+        let mut first = Session::new();
+        let mut second = Session::new();
+
+        run(
+            &db,
+            &mut first,
+            "/c_hcol users ; /selectcol users ; SET k 1",
+        );
+        assert!(run(&db, &mut second, "GET k")[0].is_err());
+        assert!(matches!(
+            run(&db, &mut first, "GET k")[0],
+            Ok(ValueObject::IntData(1))
+        ));
+        assert!(matches!(
+            &run(&db, &mut second, "/getcur_colname")[0],
+            Ok(ValueObject::OutputString(name)) if name == "default"
+        ));
+    }
+
+    #[test]
+    fn test_del_exists_and_rename() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+
+        run(&db, &mut session, "SET a 1 ; SET b 2 ; SET c 3");
+        assert!(matches!(
+            run(&db, &mut session, "DEL a c missing")[0],
+            Ok(ValueObject::IntData(2))
+        ));
+        assert!(matches!(
+            run(&db, &mut session, "EXISTS a")[0],
+            Ok(ValueObject::BoolData(false))
+        ));
+
+        assert!(run(&db, &mut session, "RENAME b d")[0].is_ok());
+        assert!(matches!(
+            run(&db, &mut session, "GET d")[0],
+            Ok(ValueObject::IntData(2))
+        ));
+        assert!(matches!(
+            run(&db, &mut session, "RENAME b e")[0],
+            Err(LokiError::KeyNotFound(_))
+        ));
+
+        // DELCOL must not be read as DEL with the key COL
+        run(&db, &mut session, "/c_hcol users");
+        assert!(run(&db, &mut session, "DELCOL users")[0].is_ok());
+        assert!(!db.read().unwrap().collection_exists("users"));
+    }
+
+    #[test]
+    fn test_unsupported_command_is_a_parse_error() {
+        assert!(matches!(
+            parse_lokiql("CHECKPOINT now"),
+            Err(LokiError::ParseError(_))
+        ));
+        assert!(matches!(
+            parse_lokiql("NOT A COMMAND"),
+            Err(LokiError::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_key_expiry_commands() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+
+        let responses = run(
+            &db,
+            &mut session,
+            "SET a 'x' EX 100 ; SET b 1 ; TTL a ; TTL b ; EXPIRE b 50 ; TTL b ; PEXPIRE missing 10",
+        );
+        assert!(matches!(responses[2], Ok(ValueObject::IntData(100))));
+        assert!(matches!(responses[3], Ok(ValueObject::IntData(-1))));
+        assert!(matches!(responses[4], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[5], Ok(ValueObject::IntData(50))));
+        assert!(matches!(responses[6], Ok(ValueObject::BoolData(false))));
+
+        let responses = run(
+            &db,
+            &mut session,
+            "PERSIST_KEY a ; PERSIST_KEY a ; TTL a ; EXPIRE b -1 ; GET b",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[1], Ok(ValueObject::BoolData(false))));
+        assert!(matches!(responses[2], Ok(ValueObject::IntData(-1))));
+        assert_eq!(
+            responses[4].as_ref().err().map(|e| e.code()),
+            Some("KEY_NOT_FOUND")
+        );
+
+        assert!(parse_lokiql("SET c 1 EX 0").is_err());
+        let responses = run(&db, &mut session, "EXPIRE a 'soon' ; ADDHLL h 1 EX 5");
+        assert!(responses.iter().all(|r| r.is_err()));
+    }
+
+    #[test]
+    fn test_scans_on_ordered_collections() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+        let keys = |response: &Result<ValueObject, LokiError>| -> Vec<String> {
+            match response {
+                Ok(ValueObject::ListData(pairs)) => pairs
+                    .iter()
+                    .map(|pair| match pair {
+                        ValueObject::ListData(kv) => match &kv[0] {
+                            ValueObject::StringData(key) => key.clone(),
+                            other => panic!("unexpected key {:?}", other),
+                        },
+                        other => panic!("unexpected pair {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("unexpected response {:?}", other),
+            }
+        };
+
+        assert_eq!(
+            run(&db, &mut session, "SCAN - +")[0]
+                .as_ref()
+                .err()
+                .map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+        for create in ["/c_bcol", "/c_bcust"] {
+            let responses = run(
+                &db,
+                &mut session,
+                &format!(
+                    "{} ordered ; /selectcol ordered ; SET user:1 1 ; SET user:2 2 ; SET user:3 3 ; SET video:1 4",
+                    create
+                ),
+            );
+            assert!(responses.iter().all(|r| r.is_ok()));
+
+            let responses = run(
+                &db,
+                &mut session,
+                "SCAN user:2 + ; RSCAN - user:2 ; SCAN - + LIMIT 2 ; PREFIX user: ; RPREFIX user: LIMIT 1 ; PREFIX zzz",
+            );
+            assert_eq!(keys(&responses[0]), ["user:2", "user:3", "video:1"]);
+            assert_eq!(keys(&responses[1]), ["user:2", "user:1"]);
+            assert_eq!(keys(&responses[2]), ["user:1", "user:2"]);
+            assert_eq!(keys(&responses[3]), ["user:1", "user:2", "user:3"]);
+            assert_eq!(keys(&responses[4]), ["user:3"]);
+            assert!(keys(&responses[5]).is_empty());
+
+            run(&db, &mut session, "/selectcol default ; DELCOL ordered");
+        }
+    }
+
+    #[test]
+    fn test_keys_command_returns_cursor_and_keys() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+
+        run(&db, &mut session, "SET a 1 ; SET b 2 ; SET c 3");
+        let responses = run(&db, &mut session, "KEYS 0 MATCH * COUNT 2");
+        let cursor = match &responses[0] {
+            Ok(ValueObject::ListData(reply)) => match (&reply[0], &reply[1]) {
+                (ValueObject::IntData(cursor), ValueObject::ListData(keys)) => {
+                    assert_eq!(keys.len(), 2);
+                    *cursor
+                }
+                other => panic!("unexpected reply {:?}", other),
+            },
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_ne!(cursor, 0);
+
+        let responses = run(&db, &mut session, &format!("KEYS {} COUNT 10", cursor));
+        assert!(matches!(
+            &responses[0],
+            Ok(ValueObject::ListData(reply))
+                if matches!(reply[0], ValueObject::IntData(0))
+                && matches!(&reply[1], ValueObject::ListData(keys) if keys.len() == 1)
+        ));
+        assert_eq!(
+            run(&db, &mut session, "KEYS 99")[0]
+                .as_ref()
+                .err()
+                .map(|e| e.code()),
+            Some("PARSE_ERROR")
+        );
+    }
+
+    #[test]
+    fn test_list_commands() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+        let ints = |response: &Result<ValueObject, LokiError>| -> Vec<isize> {
+            match response {
+                Ok(ValueObject::ListData(values)) => values
+                    .iter()
+                    .map(|v| match v {
+                        ValueObject::IntData(i) => *i,
+                        other => panic!("unexpected value {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("unexpected response {:?}", other),
+            }
+        };
+
+        let responses = run(
+            &db,
+            &mut session,
+            "RPUSH l 3 4 5 ; LPUSH l 2 1 ; LRANGE l 0 -1 ; LLEN l ; LINDEX l -2 ; LSET l 0 10 ; LPOP l",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::IntData(3))));
+        assert!(matches!(responses[1], Ok(ValueObject::IntData(5))));
+        assert_eq!(ints(&responses[2]), [1, 2, 3, 4, 5]);
+        assert!(matches!(responses[3], Ok(ValueObject::IntData(5))));
+        assert!(matches!(responses[4], Ok(ValueObject::IntData(4))));
+        assert!(matches!(responses[6], Ok(ValueObject::IntData(10))));
+
+        let responses = run(
+            &db,
+            &mut session,
+            "RPOP l 2 ; LTRIM l 1 5 ; LRANGE l 0 -1 ; LPOP l ; LLEN l ; EXISTS l",
+        );
+        assert_eq!(ints(&responses[0]), [5, 4]);
+        assert_eq!(ints(&responses[2]), [3]);
+        assert!(matches!(responses[3], Ok(ValueObject::IntData(3))));
+        assert!(matches!(responses[4], Ok(ValueObject::IntData(0))));
+        assert!(matches!(responses[5], Ok(ValueObject::BoolData(false))));
+
+        let responses = run(
+            &db,
+            &mut session,
+            "SET s 'x' ; LPUSH s 1 ; LLEN s ; RPUSH l 1 ; LSET l 3 1 ; LINDEX l 1 ; LPOP missing ; LRANGE l 0",
+        );
+        let codes: Vec<Option<&str>> = responses
+            .iter()
+            .map(|r| r.as_ref().err().map(|e| e.code()))
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                None,
+                Some("WRONG_TYPE"),
+                Some("WRONG_TYPE"),
+                None,
+                Some("OUT_OF_RANGE"),
+                Some("OUT_OF_RANGE"),
+                Some("KEY_NOT_FOUND"),
+                Some("PARSE_ERROR"),
+            ]
+        );
+
+        // An empty list can still come in through SET or LOAD
+        db.write()
+            .unwrap()
+            .put(DEFAULT_COLLECTION, "empty", ValueObject::ListData(vec![]))
+            .unwrap();
+        let responses = run(&db, &mut session, "LPOP empty ; RPOP empty 1");
+        assert!(matches!(responses[0], Err(LokiError::KeyNotFound(_))));
+        assert_eq!(ints(&responses[1]), Vec::<isize>::new());
+    }
+
+    #[test]
+    fn test_map_values_and_field_commands() {
+        let (dir, db) = test_db();
+        let mut session = Session::new();
+
+        let responses = run(
+            &db,
+            &mut session,
+            "SET u1 {'name': 'Ann', 'tags': [1, 2], 'address': {'city': 'Oslo'}} ; HSET u1 age 30 name 'Bo' ; HGET u1 name ; HINCRBY u1 age 2 ; HEXISTS u1 tags ; HDEL u1 tags missing ; HGETALL u1",
+        );
+        assert!(responses[0].is_ok());
+        assert!(matches!(responses[1], Ok(ValueObject::IntData(1))));
+        assert!(matches!(&responses[2], Ok(ValueObject::StringData(name)) if name == "'Bo'"));
+        assert!(matches!(responses[3], Ok(ValueObject::IntData(32))));
+        assert!(matches!(responses[4], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[5], Ok(ValueObject::IntData(1))));
+        let fields = match &responses[6] {
+            Ok(ValueObject::MapData(map)) => map.keys().cloned().collect::<Vec<_>>(),
+            other => panic!("unexpected response {:?}", other),
+        };
+        assert_eq!(fields, ["address", "age", "name"]);
+
+        let responses = run(
+            &db,
+            &mut session,
+            "HGET u1 missing ; HINCRBY u1 name 1 ; SET s 1 ; HSET s f 1 ; HGETALL nobody",
+        );
+        assert_eq!(
+            responses[0].as_ref().err().map(|e| e.code()),
+            Some("KEY_NOT_FOUND")
+        );
+        assert_eq!(
+            responses[1].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+        assert_eq!(
+            responses[3].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+        assert!(matches!(&responses[4], Ok(ValueObject::MapData(map)) if map.is_empty()));
+
+        // Maps survive both the Persistor and WAL replay
+        let responses = run(
+            &db,
+            &mut session,
+            "PERSIST default ; HDEL u1 address age name ; EXISTS u1",
+        );
+        assert!(responses[0].is_ok());
+        assert!(matches!(responses[2], Ok(ValueObject::BoolData(false))));
+        assert!(run(&db, &mut session, "LOAD default")[0].is_ok());
+        drop(db);
+        let db = reopen(&dir);
+        assert!(matches!(
+            db.hash_get(DEFAULT_COLLECTION, "u1", "address"),
+            Ok(ValueObject::MapData(address)) if address.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_set_commands() {
+        let (dir, db) = test_db();
+        let mut session = Session::new();
+        let members = |response: &Result<ValueObject, LokiError>| -> Vec<String> {
+            match response {
+                Ok(ValueObject::ListData(values)) => values
+                    .iter()
+                    .map(|v| match v {
+                        ValueObject::StringData(member) => member.clone(),
+                        other => panic!("unexpected member {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("unexpected response {:?}", other),
+            }
+        };
+
+        let responses = run(
+            &db,
+            &mut session,
+            "SADD a x y z x ; SADD b y w ; SREM a z missing ; SISMEMBER a x ; SCARD a ; SMEMBERS a",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::IntData(3))));
+        assert!(matches!(responses[2], Ok(ValueObject::IntData(1))));
+        assert!(matches!(responses[3], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[4], Ok(ValueObject::IntData(2))));
+        assert_eq!(members(&responses[5]), ["x", "y"]);
+
+        let responses = run(
+            &db,
+            &mut session,
+            "SUNION a b ; SINTER a b ; SDIFF a b ; SINTER a missing ; SDIFFSTORE d a b ; SMEMBERS d",
+        );
+        assert_eq!(members(&responses[0]), ["w", "x", "y"]);
+        assert_eq!(members(&responses[1]), ["y"]);
+        assert_eq!(members(&responses[2]), ["x"]);
+        assert!(members(&responses[3]).is_empty());
+        assert!(matches!(responses[4], Ok(ValueObject::IntData(1))));
+        assert_eq!(members(&responses[5]), ["x"]);
+
+        // An empty result removes the destination
+        let responses = run(
+            &db,
+            &mut session,
+            "SINTERSTORE d a missing ; EXISTS d ; SET s 1 ; SADD s x ; SUNION a s",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::IntData(0))));
+        assert!(matches!(responses[1], Ok(ValueObject::BoolData(false))));
+        assert_eq!(
+            responses[3].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+        assert_eq!(
+            responses[4].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+
+        run(&db, &mut session, "SUNIONSTORE u a b ; SREM b y w");
+        let db = reopen(&dir);
+        assert_eq!(db.set_members(DEFAULT_COLLECTION, "u").unwrap().len(), 3);
+        assert!(!db.exists(DEFAULT_COLLECTION, "b").unwrap());
+    }
+
+    #[test]
+    fn test_sorted_set_commands() {
+        let (dir, db) = test_db();
+        let mut session = Session::new();
+        let members = |response: &Result<ValueObject, LokiError>| -> Vec<String> {
+            match response {
+                Ok(ValueObject::ListData(values)) => values
+                    .iter()
+                    .map(|v| match v {
+                        ValueObject::StringData(member) => member.clone(),
+                        ValueObject::ListData(pair) => format!("{:?}", pair),
+                        other => panic!("unexpected member {:?}", other),
+                    })
+                    .collect(),
+                other => panic!("unexpected response {:?}", other),
+            }
+        };
+
+        let responses = run(
+            &db,
+            &mut session,
+            "ZADD board 30 carol 10 alice 20.5 bob ; ZADD board 15 alice 40 dave ; ZSCORE board alice ; ZRANK board carol ; ZCARD board ; ZRANGE board 0 -1",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::IntData(3))));
+        assert!(matches!(responses[1], Ok(ValueObject::IntData(1))));
+        assert!(matches!(responses[2], Ok(ValueObject::DecimalData(s)) if s == 15.0));
+        assert!(matches!(responses[3], Ok(ValueObject::IntData(2))));
+        assert!(matches!(responses[4], Ok(ValueObject::IntData(4))));
+        assert_eq!(members(&responses[5]), ["alice", "bob", "carol", "dave"]);
+
+        let responses = run(
+            &db,
+            &mut session,
+            "ZRANGE board -2 -1 ; ZRANGEBYSCORE board (15 30 ; ZRANGEBYSCORE board -inf +inf LIMIT 2 ; ZRANGE board 0 0 WITHSCORES ; ZRANGEBYSCORE board 100 +inf",
+        );
+        assert_eq!(members(&responses[0]), ["carol", "dave"]);
+        assert_eq!(members(&responses[1]), ["bob", "carol"]);
+        assert_eq!(members(&responses[2]), ["alice", "bob"]);
+        assert_eq!(
+            members(&responses[3]),
+            [format!(
+                "{:?}",
+                [
+                    ValueObject::StringData("alice".to_string()),
+                    ValueObject::DecimalData(15.0)
+                ]
+            )]
+        );
+        assert!(members(&responses[4]).is_empty());
+
+        let responses = run(
+            &db,
+            &mut session,
+            "ZINCRBY board 100 alice ; ZRANK board alice ; ZREM board bob missing ; ZSCORE board bob ; SET s 1 ; ZADD s 1 x",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::DecimalData(s)) if s == 115.0));
+        assert!(matches!(responses[1], Ok(ValueObject::IntData(3))));
+        assert!(matches!(responses[2], Ok(ValueObject::IntData(1))));
+        assert!(matches!(responses[3], Err(LokiError::KeyNotFound(_))));
+        assert_eq!(
+            responses[5].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+
+        // Scores come back from both the checkpoint and the WAL written after it
+        db.write().unwrap().checkpoint().unwrap();
+        run(&db, &mut session, "ZADD board -5 erin ; ZREM board dave");
+        let db = reopen(&dir);
+        assert_eq!(
+            db.sorted_set_range(DEFAULT_COLLECTION, "board", 0, -1)
+                .unwrap(),
+            [
+                ("erin".to_string(), -5.0),
+                ("carol".to_string(), 30.0),
+                ("alice".to_string(), 115.0)
+            ]
+        );
+        assert_eq!(
+            db.sorted_set_rank(DEFAULT_COLLECTION, "board", "alice")
+                .map_err(|e| e.code()),
+            Ok(2)
+        );
+    }
+
+    #[test]
+    fn test_hll_count_and_merge() {
+        let (_dir, db) = test_db();
+        let mut session = Session::new();
+        let count = |response: &Result<ValueObject, LokiError>| match response {
+            Ok(ValueObject::DecimalData(count)) => count.round() as isize,
+            other => panic!("unexpected response {:?}", other),
+        };
+
+        let responses = run(
+            &db,
+            &mut session,
+            "ADDHLL a 'x' ; ADDHLL a 'y' ; ADDHLL a 'x' ; ADDHLL b 'y' ; ADDHLL b 'z' ; HLLCOUNT a ; HLLCOUNT a b missing ; HLLCOUNT missing",
+        );
+        assert_eq!(count(&responses[5]), 2);
+        assert_eq!(count(&responses[6]), 3);
+        assert_eq!(count(&responses[7]), 0);
+
+        let responses = run(
+            &db,
+            &mut session,
+            "HLLMERGE c a b ; HLLCOUNT c ; ADDHLL c 'w' ; HLLMERGE c a ; HLLCOUNT c ; SET s 1 ; HLLCOUNT a s ; HLLMERGE s a",
+        );
+        assert!(responses[0].is_ok());
+        assert_eq!(count(&responses[1]), 3);
+        assert_eq!(count(&responses[4]), 4);
+        assert_eq!(
+            responses[6].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+        assert_eq!(
+            responses[7].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+    }
+
+    #[test]
+    fn test_bloom_filter_and_sketch_commands() {
+        let (dir, db) = test_db();
+        let mut session = Session::new();
+
+        let responses = run(
+            &db,
+            &mut session,
+            "BFRESERVE seen 0.001 500 ; BFRESERVE seen 0.1 10 ; BFADD seen 'a' ; BFADD seen 'a' ; BFEXISTS seen 'a' ; BFEXISTS seen 'b' ; BFEXISTS missing 'a' ; BFADD auto 7",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[1], Ok(ValueObject::BoolData(false))));
+        assert!(matches!(responses[2], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[3], Ok(ValueObject::BoolData(false))));
+        assert!(matches!(responses[4], Ok(ValueObject::BoolData(true))));
+        assert!(matches!(responses[5], Ok(ValueObject::BoolData(false))));
+        assert!(matches!(responses[6], Ok(ValueObject::BoolData(false))));
+        assert!(matches!(responses[7], Ok(ValueObject::BoolData(true))));
+
+        let responses = run(
+            &db,
+            &mut session,
+            "BFRESERVE bad 1.5 10 ; BFADD seen [1, 2] ; SET s 1 ; BFEXISTS s 'a'",
+        );
+        assert_eq!(
+            responses[0].as_ref().err().map(|e| e.code()),
+            Some("OUT_OF_RANGE")
+        );
+        assert_eq!(
+            responses[1].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+        assert_eq!(
+            responses[3].as_ref().err().map(|e| e.code()),
+            Some("WRONG_TYPE")
+        );
+
+        let responses = run(
+            &db,
+            &mut session,
+            "CMSINCR views 'home' ; CMSINCR views 'home' 4 ; CMSQUERY views 'home' ; CMSQUERY views 'about' ; CMSINCR other 'home' 10 ; CMSMERGE total views other ; CMSQUERY total 'home' ; CMSINCR views 'home' 0",
+        );
+        assert!(matches!(responses[0], Ok(ValueObject::IntData(1))));
+        assert!(matches!(responses[1], Ok(ValueObject::IntData(5))));
+        assert!(matches!(responses[2], Ok(ValueObject::IntData(5))));
+        assert!(matches!(responses[3], Ok(ValueObject::IntData(0))));
+        assert!(matches!(responses[6], Ok(ValueObject::IntData(15))));
+        assert!(matches!(responses[7], Err(LokiError::ParseError(_))));
+
+        // Filters and sketches come back from the WAL
+        let db = reopen(&dir);
+        let item = ValueObject::StringData("'a'".to_string());
+        assert!(db.bloom_exists(DEFAULT_COLLECTION, "seen", &item).unwrap());
+        let home = ValueObject::StringData("'home'".to_string());
+        assert_eq!(db.sketch_query(DEFAULT_COLLECTION, "total", &home), Ok(15));
     }
 }
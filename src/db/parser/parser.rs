@@ -1,5 +1,6 @@
+use crate::error::LokiError;
 use crate::utils::error;
-use std::ops::Deref;
+use std::ops::Bound;
 
 use pest::iterators::Pair;
 use pest::Parser;
@@ -31,8 +32,103 @@ pub enum QLCommands {
     LOAD_BCUST,
     LOAD_BDEF,
     LOAD_HMAP,
+    LOAD,
     DELCOL,
     DISPLAY_WAL,
+    DEL,
+    EXISTS,
+    RENAME,
+    EXPIRE,
+    PEXPIRE,
+    TTL,
+    PERSISTKEY,
+    SCAN,
+    RSCAN,
+    PREFIX,
+    RPREFIX,
+    KEYS,
+    LPUSH,
+    RPUSH,
+    LPOP,
+    RPOP,
+    LRANGE,
+    LINDEX,
+    LSET,
+    LLEN,
+    LTRIM,
+    HSET,
+    HGET,
+    HDEL,
+    HGETALL,
+    HINCRBY,
+    HEXISTS,
+    SADD,
+    SREM,
+    SISMEMBER,
+    SCARD,
+    SMEMBERS,
+    SUNION,
+    SINTER,
+    SDIFF,
+    SUNIONSTORE,
+    SINTERSTORE,
+    SDIFFSTORE,
+    ZADD,
+    ZREM,
+    ZSCORE,
+    ZRANK,
+    ZCARD,
+    ZRANGE,
+    ZRANGEBYSCORE,
+    ZINCRBY,
+    MERGEHLL,
+    BFRESERVE,
+    BFADD,
+    BFEXISTS,
+    CMSINCR,
+    CMSQUERY,
+    CMSMERGE,
+}
+
+impl QLCommands {
+    // Commands that change neither the database nor the session, a
+    // connection can run several of them at the same time
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            QLCommands::GET
+                | QLCommands::EXISTS
+                | QLCommands::TTL
+                | QLCommands::DISPLAY
+                | QLCommands::CURCOLNAME
+                | QLCommands::LISTCOLNAMES
+                | QLCommands::DISPLAY_WAL
+                | QLCommands::COUNTHLL
+                | QLCommands::SCAN
+                | QLCommands::RSCAN
+                | QLCommands::PREFIX
+                | QLCommands::RPREFIX
+                | QLCommands::LRANGE
+                | QLCommands::LINDEX
+                | QLCommands::LLEN
+                | QLCommands::HGET
+                | QLCommands::HGETALL
+                | QLCommands::HEXISTS
+                | QLCommands::SISMEMBER
+                | QLCommands::SCARD
+                | QLCommands::SMEMBERS
+                | QLCommands::SUNION
+                | QLCommands::SINTER
+                | QLCommands::SDIFF
+                | QLCommands::ZSCORE
+                | QLCommands::ZRANK
+                | QLCommands::ZCARD
+                | QLCommands::ZRANGE
+                | QLCommands::ZRANGEBYSCORE
+                | QLCommands::BFEXISTS
+                | QLCommands::CMSQUERY
+        )
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -46,13 +142,20 @@ pub enum QLValues {
     QLPhantom,
     QLBlob(Vec<u8>),
     QLList(Vec<QLValues>),
+    QLMap(Vec<(String, QLValues)>),
     QLHLL(HLL),
+    QLExpiry(u64), // Milliseconds until the key expires
+    QLLimit(usize),
+    QLMatch(String),
+    QLCount(usize),
+    QLScoreBound(Bound<f64>),
+    QLWithScores,
 }
 
 #[derive(Debug)]
 pub struct AST {
     val: QLValues,
-    children: Vec<Box<AST>>, // Can only have 2 children at max
+    children: Vec<Box<AST>>, // DEL has one child per key, SET ... EX adds the expiry as a third
 }
 
 impl AST {
@@ -66,6 +169,25 @@ impl AST {
         self.children.push(new_node);
     }
 
+    // A command built the way the parser would build it, for requests that
+    // do not arrive as LokiQL
+    pub fn command(command: QLCommands, args: Vec<QLValues>) -> Self {
+        let mut node = AST::new(QLValues::QLCommand(command));
+        for arg in args {
+            node.add_child(arg);
+        }
+        let mut root = AST::new(QLValues::QLPhantom);
+        root.children.push(Box::new(node));
+        root
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        match self.get_left_child().map(|command| command.get_value()) {
+            Some(QLValues::QLCommand(command)) => command.is_read_only(),
+            _ => false,
+        }
+    }
+
     pub fn get_value(&self) -> QLValues {
         self.val.clone()
     }
@@ -84,6 +206,10 @@ impl AST {
         return self.children.get(1);
     }
 
+    pub fn get_children(&self) -> &[Box<AST>] {
+        &self.children
+    }
+
     pub fn get_left_child_mut(&mut self) -> Option<&mut Box<AST>> {
         if self.children.len() == 0 {
             return None;
@@ -99,7 +225,7 @@ impl AST {
     }
 }
 
-pub fn parse_lokiql(ql: &str) -> Vec<Option<AST>> {
+pub fn parse_lokiql(ql: &str) -> Result<Vec<Option<AST>>, LokiError> {
     let result = LokiQLParser::parse(Rule::LOKIQL_FILE, ql);
     match result {
         Ok(pairs) => {
@@ -108,38 +234,70 @@ pub fn parse_lokiql(ql: &str) -> Vec<Option<AST>> {
                 match pair.as_rule() {
                     // Parse Each command
                     Rule::COMMAND => {
-                        let ast = parse_vals(pair, None);
+                        let ast = parse_vals(pair, None)?;
                         asts.push(ast);
                     }
                     _ => {}
                 }
             }
-            asts
+            Ok(asts)
         }
         Err(e) => {
             error(&format!("Error parsing LokiQL: {}", e.to_string()));
-            vec![]
+            Err(LokiError::ParseError(e.to_string()))
         }
     }
 }
 
-pub fn parse_individual_item_asql(pair: Pair<Rule>) -> QLValues {
+fn parse_literal<T: std::str::FromStr>(pair: &Pair<Rule>) -> Result<T, LokiError> {
+    pair.as_str()
+        .parse()
+        .map_err(|_| LokiError::ParseError(format!("invalid literal {}", pair.as_str())))
+}
+
+fn unsupported(command: &str) -> LokiError {
+    LokiError::ParseError(format!("{} is not supported", command))
+}
+
+pub fn parse_individual_item_asql(pair: Pair<Rule>) -> Result<QLValues, LokiError> {
     match pair.as_rule() {
-        Rule::FLOAT => QLValues::QLFloat(pair.as_str().parse().unwrap()),
-        Rule::INT => QLValues::QLInt(pair.as_str().parse().unwrap()),
-        Rule::STRING => QLValues::QLString(pair.as_str().to_string()),
-        Rule::BOOL => QLValues::QLBool(pair.as_str().parse().unwrap()),
+        Rule::FLOAT => Ok(QLValues::QLFloat(parse_literal(&pair)?)),
+        Rule::INT => Ok(QLValues::QLInt(parse_literal(&pair)?)),
+        Rule::STRING => Ok(QLValues::QLString(pair.as_str().to_string())),
+        Rule::BOOL => Ok(QLValues::QLBool(parse_literal(&pair)?)),
         Rule::BLOB => {
-            let mut val: String = pair.as_str().parse().unwrap();
+            let mut val = pair.as_str().to_string();
             val = val.replace("<BLOB_BEINGS>", "");
             val = val.replace("<BLOB_ENDS>", "");
-            QLValues::QLBlob(val.as_bytes().to_vec())
+            Ok(QLValues::QLBlob(val.as_bytes().to_vec()))
+        }
+        Rule::LIST => Ok(QLValues::QLList(
+            pair.into_inner()
+                .map(parse_individual_item_asql)
+                .collect::<Result<_, _>>()?,
+        )),
+        Rule::MAP => {
+            let mut entries = Vec::new();
+            for entry in pair.into_inner() {
+                let mut parts = entry.into_inner();
+                let (Some(field), Some(value)) = (parts.next(), parts.next()) else {
+                    return Err(LokiError::ParseError("incomplete map entry".to_string()));
+                };
+                // The field name is stored without its quotes
+                let field = field.as_str();
+                let field = field[1..field.len() - 1].to_string();
+                entries.push((field, parse_individual_item_asql(value)?));
+            }
+            Ok(QLValues::QLMap(entries))
         }
-        _ => panic!("primitive not added"),
+        _ => Err(unsupported(pair.as_str())),
     }
 }
 
-pub fn parse_vals(pair: Pair<Rule>, ast_node: Option<&mut Box<AST>>) -> Option<AST> {
+pub fn parse_vals(
+    pair: Pair<Rule>,
+    ast_node: Option<&mut Box<AST>>,
+) -> Result<Option<AST>, LokiError> {
     match pair.as_rule() {
         Rule::DUO_COMMAND => {
             let mut node = QLValues::QLPhantom;
@@ -147,14 +305,39 @@ pub fn parse_vals(pair: Pair<Rule>, ast_node: Option<&mut Box<AST>>) -> Option<A
                 "SET" => {
                     node = QLValues::QLCommand(QLCommands::SET);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "ADDHLL" => {
                     node = QLValues::QLCommand(QLCommands::ADDHLL);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
+                }
+                "EXPIRE" => {
+                    node = QLValues::QLCommand(QLCommands::EXPIRE);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "PEXPIRE" => {
+                    node = QLValues::QLCommand(QLCommands::PEXPIRE);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
                 }
-                _ => panic!("Command not supported yet!"),
+                "BFADD" => {
+                    node = QLValues::QLCommand(QLCommands::BFADD);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "BFEXISTS" => {
+                    node = QLValues::QLCommand(QLCommands::BFEXISTS);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "CMSQUERY" => {
+                    node = QLValues::QLCommand(QLCommands::CMSQUERY);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                _ => Err(unsupported(pair.as_str())),
             }
         }
         Rule::UNI_COMMAND => {
@@ -163,154 +346,369 @@ pub fn parse_vals(pair: Pair<Rule>, ast_node: Option<&mut Box<AST>>) -> Option<A
                 "GET" => {
                     node = QLValues::QLCommand(QLCommands::GET);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "INCR" => {
                     node = QLValues::QLCommand(QLCommands::INCR);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "DECR" => {
                     node = QLValues::QLCommand(QLCommands::DECR);
                     ast_node.unwrap().add_child(node);
-                    None
-                }
-                "HLLCOUNT" => {
-                    node = QLValues::QLCommand(QLCommands::COUNTHLL);
-                    ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "/c_hcol" => {
                     node = QLValues::QLCommand(QLCommands::CREATEHCOL);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "/c_bcol" => {
                     node = QLValues::QLCommand(QLCommands::CREATEBCOL);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "/c_bcust" => {
                     node = QLValues::QLCommand(QLCommands::CREATEBCUST);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "/selectcol" => {
                     node = QLValues::QLCommand(QLCommands::SELCOL);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "PERSIST" => {
                     node = QLValues::QLCommand(QLCommands::PERSIST);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "LOAD_BCUST" => {
                     node = QLValues::QLCommand(QLCommands::LOAD_BCUST);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "LOAD_BDEF" => {
                     node = QLValues::QLCommand(QLCommands::LOAD_BDEF);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
                 }
                 "LOAD_HMAP" => {
                     node = QLValues::QLCommand(QLCommands::LOAD_HMAP);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
+                }
+                "LOAD" => {
+                    node = QLValues::QLCommand(QLCommands::LOAD);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
                 }
                 "DELCOL" => {
                     node = QLValues::QLCommand(QLCommands::DELCOL);
                     ast_node.unwrap().add_child(node);
-                    None
+                    Ok(None)
+                }
+                "EXISTS" => {
+                    node = QLValues::QLCommand(QLCommands::EXISTS);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "TTL" => {
+                    node = QLValues::QLCommand(QLCommands::TTL);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "PERSIST_KEY" => {
+                    node = QLValues::QLCommand(QLCommands::PERSISTKEY);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "HGETALL" => {
+                    node = QLValues::QLCommand(QLCommands::HGETALL);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "SCARD" => {
+                    node = QLValues::QLCommand(QLCommands::SCARD);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
                 }
-                _ => panic!("Command not supported yet!"),
+                "SMEMBERS" => {
+                    node = QLValues::QLCommand(QLCommands::SMEMBERS);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                "ZCARD" => {
+                    node = QLValues::QLCommand(QLCommands::ZCARD);
+                    ast_node.unwrap().add_child(node);
+                    Ok(None)
+                }
+                _ => Err(unsupported(pair.as_str())),
             }
         }
+        Rule::MULTI_COMMAND => {
+            let command = match pair.as_str() {
+                "DEL" => QLCommands::DEL,
+                "HDEL" => QLCommands::HDEL,
+                "SADD" => QLCommands::SADD,
+                "SREM" => QLCommands::SREM,
+                "SUNION" => QLCommands::SUNION,
+                "SINTER" => QLCommands::SINTER,
+                "SDIFF" => QLCommands::SDIFF,
+                "SUNIONSTORE" => QLCommands::SUNIONSTORE,
+                "SINTERSTORE" => QLCommands::SINTERSTORE,
+                "SDIFFSTORE" => QLCommands::SDIFFSTORE,
+                "ZREM" => QLCommands::ZREM,
+                "HLLCOUNT" => QLCommands::COUNTHLL,
+                "HLLMERGE" => QLCommands::MERGEHLL,
+                "CMSMERGE" => QLCommands::CMSMERGE,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::PAIR_COMMAND => {
+            let command = match pair.as_str() {
+                "RENAME" => QLCommands::RENAME,
+                "HGET" => QLCommands::HGET,
+                "HEXISTS" => QLCommands::HEXISTS,
+                "SISMEMBER" => QLCommands::SISMEMBER,
+                "ZSCORE" => QLCommands::ZSCORE,
+                "ZRANK" => QLCommands::ZRANK,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::RANGE_COMMAND => {
+            let command = match pair.as_str() {
+                "SCAN" => QLCommands::SCAN,
+                "RSCAN" => QLCommands::RSCAN,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::PREFIX_COMMAND => {
+            let command = match pair.as_str() {
+                "PREFIX" => QLCommands::PREFIX,
+                "RPREFIX" => QLCommands::RPREFIX,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::KEYS_COMMAND => {
+            ast_node
+                .unwrap()
+                .add_child(QLValues::QLCommand(QLCommands::KEYS));
+            Ok(None)
+        }
+        Rule::LIST_COMMAND => {
+            let command = match pair.as_str() {
+                "LPUSH" => QLCommands::LPUSH,
+                "RPUSH" => QLCommands::RPUSH,
+                "LPOP" => QLCommands::LPOP,
+                "RPOP" => QLCommands::RPOP,
+                "LRANGE" => QLCommands::LRANGE,
+                "LINDEX" => QLCommands::LINDEX,
+                "LSET" => QLCommands::LSET,
+                "LLEN" => QLCommands::LLEN,
+                "LTRIM" => QLCommands::LTRIM,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::FIELD_COMMAND => {
+            let command = match pair.as_str() {
+                "HSET" => QLCommands::HSET,
+                "HINCRBY" => QLCommands::HINCRBY,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::SCORE_COMMAND => {
+            let command = match pair.as_str() {
+                "ZADD" => QLCommands::ZADD,
+                "ZINCRBY" => QLCommands::ZINCRBY,
+                _ => return Err(unsupported(pair.as_str())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCommand(command));
+            Ok(None)
+        }
+        Rule::ZRANGE_COMMAND => {
+            ast_node
+                .unwrap()
+                .add_child(QLValues::QLCommand(QLCommands::ZRANGE));
+            Ok(None)
+        }
+        Rule::ZRANGEBYSCORE_COMMAND => {
+            ast_node
+                .unwrap()
+                .add_child(QLValues::QLCommand(QLCommands::ZRANGEBYSCORE));
+            Ok(None)
+        }
+        Rule::BFRESERVE_COMMAND => {
+            ast_node
+                .unwrap()
+                .add_child(QLValues::QLCommand(QLCommands::BFRESERVE));
+            Ok(None)
+        }
+        Rule::CMSINCR_COMMAND => {
+            ast_node
+                .unwrap()
+                .add_child(QLValues::QLCommand(QLCommands::CMSINCR));
+            Ok(None)
+        }
         Rule::SOLO_COMMAND => match pair.as_str() {
             "DISPLAY" => {
                 let node = QLValues::QLCommand(QLCommands::DISPLAY);
                 ast_node.unwrap().add_child(node);
-                None
+                Ok(None)
             }
             "/getcur_colname" => {
                 let node = QLValues::QLCommand(QLCommands::CURCOLNAME);
                 ast_node.unwrap().add_child(node);
-                None
+                Ok(None)
             }
             "/listcolnames" => {
                 let node = QLValues::QLCommand(QLCommands::LISTCOLNAMES);
                 ast_node.unwrap().add_child(node);
-                None
+                Ok(None)
             }
             "SHUTDOWN" => {
                 let node = QLValues::QLCommand(QLCommands::SHUTDOWN);
                 ast_node.unwrap().add_child(node);
-                None
+                Ok(None)
             }
             "DISPLAY_WAL" => {
                 let node = QLValues::QLCommand(QLCommands::DISPLAY_WAL);
                 ast_node.unwrap().add_child(node);
-                None
+                Ok(None)
             }
-            _ => panic!("Support for command not added"),
+            _ => Err(unsupported(pair.as_str())),
         },
         Rule::FLOAT => {
-            let node_val = QLValues::QLFloat(pair.as_str().parse().unwrap());
+            let node_val = QLValues::QLFloat(parse_literal(&pair)?);
             ast_node.unwrap().add_child(node_val);
-            None
+            Ok(None)
         }
         Rule::INT => {
-            let node_val = QLValues::QLInt(pair.as_str().parse().unwrap());
+            let node_val = QLValues::QLInt(parse_literal(&pair)?);
             ast_node.unwrap().add_child(node_val);
-            None
+            Ok(None)
         }
         Rule::STRING => {
             let node_val = QLValues::QLString(pair.as_str().to_string());
             ast_node.unwrap().add_child(node_val);
-            None
+            Ok(None)
         }
         Rule::BOOL => {
-            let node_val = QLValues::QLBool(pair.as_str().parse().unwrap());
+            let node_val = QLValues::QLBool(parse_literal(&pair)?);
             ast_node.unwrap().add_child(node_val);
-            None
+            Ok(None)
         }
         Rule::BLOB => {
-            let mut val: String = pair.as_str().parse().unwrap();
+            let mut val = pair.as_str().to_string();
             val = val.replace("<BLOB_BEINGS>", "");
             val = val.replace("<BLOB_ENDS>", "");
             let node_val = QLValues::QLBlob(val.as_bytes().to_vec());
             ast_node.unwrap().add_child(node_val);
-            None
+            Ok(None)
         }
         Rule::ID => {
             let node_val = QLValues::QLId(pair.as_str().to_string());
             ast_node.unwrap().add_child(node_val);
-            None
+            Ok(None)
+        }
+        Rule::LIST | Rule::MAP => {
+            let value = parse_individual_item_asql(pair)?;
+            ast_node.unwrap().add_child(value);
+            Ok(None)
+        }
+        Rule::EXPIRY => {
+            let secs: isize = match pair.clone().into_inner().next() {
+                Some(secs) => parse_literal(&secs)?,
+                None => return Err(LokiError::ParseError("missing expiry".to_string())),
+            };
+            if secs <= 0 {
+                return Err(LokiError::ParseError(format!(
+                    "invalid expire time {}",
+                    secs
+                )));
+            }
+            ast_node
+                .unwrap()
+                .add_child(QLValues::QLExpiry(secs as u64 * 1000));
+            Ok(None)
+        }
+        Rule::LIMIT => {
+            let limit = match pair.clone().into_inner().next() {
+                Some(limit) => parse_literal(&limit)?,
+                None => return Err(LokiError::ParseError("missing limit".to_string())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLLimit(limit));
+            Ok(None)
+        }
+        Rule::MATCH => {
+            let pattern = match pair.clone().into_inner().next() {
+                Some(pattern) => pattern.as_str().to_string(),
+                None => return Err(LokiError::ParseError("missing pattern".to_string())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLMatch(pattern));
+            Ok(None)
         }
-        Rule::LIST => {
-            let values: Vec<QLValues> = pair.into_inner().map(parse_individual_item_asql).collect();
-            ast_node.unwrap().add_child(QLValues::QLList(values));
-            None
+        Rule::COUNT => {
+            let count = match pair.clone().into_inner().next() {
+                Some(count) => parse_literal(&count)?,
+                None => return Err(LokiError::ParseError("missing count".to_string())),
+            };
+            ast_node.unwrap().add_child(QLValues::QLCount(count));
+            Ok(None)
+        }
+        Rule::SCORE_BOUND => {
+            let mut exclusive = false;
+            let mut score = None;
+            for part in pair.clone().into_inner() {
+                match part.as_rule() {
+                    Rule::EXCLUSIVE => exclusive = true,
+                    Rule::INF if part.as_str().starts_with('-') => score = Some(f64::NEG_INFINITY),
+                    Rule::INF => score = Some(f64::INFINITY),
+                    _ => score = Some(parse_literal(&part)?),
+                }
+            }
+            let Some(score) = score else {
+                return Err(LokiError::ParseError("missing score".to_string()));
+            };
+            let bound = if exclusive {
+                Bound::Excluded(score)
+            } else {
+                Bound::Included(score)
+            };
+            ast_node.unwrap().add_child(QLValues::QLScoreBound(bound));
+            Ok(None)
+        }
+        Rule::WITHSCORES => {
+            ast_node.unwrap().add_child(QLValues::QLWithScores);
+            Ok(None)
         }
-        Rule::EOI => None,
+        Rule::EOI => Ok(None),
         Rule::COMMAND => {
             let mut pair_in = pair.clone().into_inner();
             let mut root = Box::new(AST::new(QLValues::QLPhantom));
             let mut root_ast = &mut root;
             if let Some(command) = pair_in.next() {
-                parse_vals(command, Some(&mut root_ast));
+                parse_vals(command, Some(&mut root_ast))?;
                 root_ast = root_ast.get_left_child_mut().unwrap();
             };
-            if let Some(key) = pair_in.next() {
-                parse_vals(key, Some(root_ast));
-            };
-            if let Some(value) = pair_in.next() {
-                parse_vals(value, Some(root_ast));
-            };
-            return Some(*root);
+            // Key and value, or every key for commands taking several
+            for arg in pair_in {
+                parse_vals(arg, Some(&mut *root_ast))?;
+            }
+            Ok(Some(*root))
         }
-        _ => panic!("Something..."),
+        _ => Err(unsupported(pair.as_str())),
     }
 }
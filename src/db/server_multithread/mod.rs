@@ -1,2 +1,5 @@
 pub mod paxos;
+pub mod protocol;
+pub mod resp;
 pub mod server;
+pub mod session;
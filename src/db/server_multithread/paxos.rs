@@ -7,7 +7,6 @@ use tokio::sync::RwLock;
 use tokio::net::UdpSocket;
 use crate::loki_kv::loki_kv::ValueObject;
 use crate::loki_kv::control::ControlFile;
-use crate::loki_kv::loki_kv::get_control_file_path;
 use crate::utils::info_string;
 
 pub struct ServiceManager {
@@ -18,8 +17,7 @@ pub struct ServiceManager {
 }
 
 impl ServiceManager {
-    pub fn new() -> Self {
-        let control_file = ControlFile::read_from_file_path(get_control_file_path()).unwrap();
+    pub fn new(control_file: &ControlFile) -> Self {
         let listen_addr: SocketAddr = control_file.get_send_addr().parse().unwrap();
         let soc2_listen_socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
         soc2_listen_socket.set_broadcast(true).unwrap();
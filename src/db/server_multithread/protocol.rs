@@ -0,0 +1,135 @@
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::LokiError;
+use crate::loki_kv::loki_kv::ValueObject;
+
+// Binary protocol, picked by sending `HELLO BINARY <version>` as the first
+// line of a connection. Once the server answers `OK BINARY <version>` both
+// sides exchange frames: a u32 big endian length followed by the payload.
+// Requests hold the query text, responses the bincode encoded results of its
+// commands, so values never need escaping and keep their type.
+//
+// From version 2 on every payload starts with a u64 big endian request ID
+// chosen by the client, and the response to a request carries its ID.
+// Clients can send requests without waiting, answers to reads may arrive
+// out of order. Version 1 answers requests one at a time, in order.
+pub const BINARY_HELLO: &str = "HELLO BINARY";
+pub const BINARY_VERSION: u32 = 2;
+const MIN_BINARY_VERSION: u32 = 1;
+pub const REQUEST_ID_VERSION: u32 = 2;
+// Larger frames are refused instead of allocated
+const MAX_FRAME_LEN: u32 = 512 << 20;
+
+pub type Responses = Vec<Result<ValueObject, LokiError>>;
+
+// None if line is not a binary hello, otherwise the version it asks for if
+// this server speaks it
+pub fn parse_hello(line: &str) -> Option<Result<u32, LokiError>> {
+    let version = line.strip_prefix(BINARY_HELLO)?.trim();
+    Some(match version.parse::<u32>() {
+        Ok(version) if (MIN_BINARY_VERSION..=BINARY_VERSION).contains(&version) => Ok(version),
+        _ => Err(LokiError::ParseError(format!(
+            "unsupported binary protocol version {}, this server speaks {} to {}",
+            version, MIN_BINARY_VERSION, BINARY_VERSION
+        ))),
+    })
+}
+
+pub fn accepted_line(version: u32) -> String {
+    format!("OK BINARY {}\n", version)
+}
+
+// Returns None when the peer closed the connection between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is larger than {}", len, MAX_FRAME_LEN),
+        ));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(payload))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes is too large", payload.len()),
+            )
+        })?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+// Request ID and query of a version 2 request, None if the frame is too
+// short to hold an ID
+pub fn split_request_id(payload: &[u8]) -> Option<(u64, &[u8])> {
+    let (id, query) = payload.split_first_chunk::<8>()?;
+    Some((u64::from_be_bytes(*id), query))
+}
+
+pub fn with_request_id(id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(8 + payload.len());
+    framed.extend(id.to_be_bytes());
+    framed.extend(payload);
+    framed
+}
+
+pub fn encode_responses(responses: &Responses) -> Result<Vec<u8>, LokiError> {
+    bincode::serialize(responses)
+        .map_err(|e| LokiError::IoError(format!("Failed to encode response: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_versions() {
+        assert!(parse_hello("SET key 1").is_none());
+        assert_eq!(parse_hello("HELLO BINARY 1"), Some(Ok(1)));
+        assert_eq!(parse_hello("HELLO BINARY 2"), Some(Ok(2)));
+        assert_eq!(
+            parse_hello("HELLO BINARY 7").map(|r| r.map_err(|e| e.code())),
+            Some(Err("PARSE_ERROR"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"GET key").await.unwrap();
+        write_frame(&mut buf, b"").await.unwrap();
+        let mut reader = &buf[..];
+        assert_eq!(read_frame(&mut reader).await.unwrap().unwrap(), b"GET key");
+        assert_eq!(read_frame(&mut reader).await.unwrap().unwrap(), b"");
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+
+        // Cut off in the middle of the payload
+        let mut reader = &buf[..6];
+        assert!(read_frame(&mut reader).await.is_err());
+        let mut reader = &(u32::MAX.to_be_bytes())[..];
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let framed = with_request_id(42, b"GET key");
+        assert_eq!(split_request_id(&framed), Some((42, &b"GET key"[..])));
+        assert_eq!(split_request_id(&framed[..8]), Some((42, &b""[..])));
+        assert_eq!(split_request_id(&framed[..7]), None);
+    }
+}
@@ -0,0 +1,956 @@
+use std::sync::{Arc, RwLock};
+
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::error::LokiError;
+use crate::loki_kv::loki_kv::{LokiKV, ValueObject, DEFAULT_COLLECTION};
+use crate::parser::executor::Executor;
+use crate::parser::parser::{QLCommands, QLValues, AST};
+use crate::server_multithread::session::Session;
+use crate::utils::{info, warning};
+
+// RESP front end for Redis clients. Every request is translated into the
+// matching LokiQL command and run by the executor, so it sees the same data,
+// expiry and durability as the text and binary protocols. Strings are stored
+// in their LokiQL form, `SET k hello` here and `SET k 'hello'` in LokiQL
+// store the same value.
+
+// Same limit as Redis' default proto-max-bulk-len
+const MAX_BULK_LEN: usize = 512 << 20;
+const MAX_ARGS: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    // Sent as a bulk string in RESP2
+    Double(f64),
+    Null,
+    Array(Vec<RespValue>),
+    // Sent as a flat array of keys and values in RESP2
+    Map(Vec<(RespValue, RespValue)>),
+}
+
+impl RespValue {
+    fn ok() -> Self {
+        RespValue::Simple("OK".to_string())
+    }
+
+    fn bulk(s: impl Into<String>) -> Self {
+        RespValue::Bulk(s.into().into_bytes())
+    }
+
+    fn error(msg: impl Into<String>) -> Self {
+        RespValue::Error(format!("ERR {}", msg.into()))
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>, version: u8) {
+        match self {
+            RespValue::Simple(s) => out.extend(format!("+{}\r\n", s).as_bytes()),
+            RespValue::Error(e) => {
+                // Line breaks would end the error early
+                out.extend(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes())
+            }
+            RespValue::Integer(n) => out.extend(format!(":{}\r\n", n).as_bytes()),
+            RespValue::Bulk(bytes) => {
+                out.extend(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend(bytes);
+                out.extend(b"\r\n");
+            }
+            RespValue::Double(f) if version >= 3 => out.extend(format!(",{}\r\n", f).as_bytes()),
+            RespValue::Double(f) => RespValue::bulk(f.to_string()).encode(out, version),
+            RespValue::Null if version >= 3 => out.extend(b"_\r\n"),
+            RespValue::Null => out.extend(b"$-1\r\n"),
+            RespValue::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out, version);
+                }
+            }
+            RespValue::Map(entries) => {
+                match version {
+                    3.. => out.extend(format!("%{}\r\n", entries.len()).as_bytes()),
+                    _ => out.extend(format!("*{}\r\n", entries.len() * 2).as_bytes()),
+                }
+                for (key, value) in entries {
+                    key.encode(out, version);
+                    value.encode(out, version);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Reads a line without its \r\n, None at the end of the stream
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // A line longer than any header is cut off here and rejected below
+    let n = (&mut *reader)
+        .take(MAX_BULK_LEN as u64)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line is too long".to_string()));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(line: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n <= max)
+        .ok_or_else(|| protocol_error(format!("invalid length {}", String::from_utf8_lossy(line))))
+}
+
+// Next request as its arguments, either an array of bulk strings or an
+// inline command split on whitespace. None once the client hung up.
+pub async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> io::Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader).await? else {
+            return Ok(None);
+        };
+        let Some(count) = line.strip_prefix(b"*") else {
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        };
+
+        let count = parse_len(count, MAX_ARGS)?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = read_line(reader)
+                .await?
+                .ok_or_else(|| protocol_error("request ended early".to_string()))?;
+            let Some(len) = header.strip_prefix(b"$") else {
+                return Err(protocol_error("expected a bulk string".to_string()));
+            };
+            let mut arg = vec![0; parse_len(len, MAX_BULK_LEN)? + 2];
+            reader.read_exact(&mut arg).await?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string is not terminated".to_string()));
+            }
+            arg.truncate(arg.len() - 2);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+// How an argument is handed to the executor
+#[derive(Clone, Copy)]
+enum Arg {
+    // Keys, fields and members
+    Key,
+    // Stored values and HLL items
+    Value,
+    Int,
+    Score,
+}
+
+// How the result of a command is sent back
+#[derive(Clone, Copy)]
+enum Reply {
+    Ok,
+    // Counts, new values and booleans
+    Integer,
+    // Stored values, nil if the key does not exist
+    Value,
+    // Scores, nil if the key or member does not exist
+    Double,
+    // Ranks, nil if the key or member does not exist
+    Rank,
+    // HLL estimates, rounded
+    Estimate,
+    // -2 if the key does not exist
+    Ttl,
+}
+
+// A Redis command that maps onto a single LokiQL command. After `args` the
+// `rest` arguments can repeat any number of times.
+struct Spec {
+    name: &'static str,
+    command: QLCommands,
+    args: &'static [Arg],
+    rest: &'static [Arg],
+    reply: Reply,
+}
+
+macro_rules! spec {
+    ($name:literal, $command:ident, [$($arg:ident),*], [$($rest:ident),*], $reply:ident) => {
+        Spec {
+            name: $name,
+            command: QLCommands::$command,
+            args: &[$(Arg::$arg),*],
+            rest: &[$(Arg::$rest),*],
+            reply: Reply::$reply,
+        }
+    };
+}
+
+const SPECS: &[Spec] = &[
+    spec!("GET", GET, [Key], [], Value),
+    spec!("DEL", DEL, [Key], [Key], Integer),
+    spec!("UNLINK", DEL, [Key], [Key], Integer),
+    spec!("INCR", INCR, [Key], [], Integer),
+    spec!("DECR", DECR, [Key], [], Integer),
+    spec!("RENAME", RENAME, [Key, Key], [], Ok),
+    spec!("EXPIRE", EXPIRE, [Key, Int], [], Integer),
+    spec!("PEXPIRE", PEXPIRE, [Key, Int], [], Integer),
+    spec!("TTL", TTL, [Key], [], Ttl),
+    spec!("PERSIST", PERSISTKEY, [Key], [], Integer),
+    spec!("PFCOUNT", COUNTHLL, [Key], [Key], Estimate),
+    spec!("PFMERGE", MERGEHLL, [Key], [Key], Ok),
+    spec!("LPUSH", LPUSH, [Key, Value], [Value], Integer),
+    spec!("RPUSH", RPUSH, [Key, Value], [Value], Integer),
+    spec!("LPOP", LPOP, [Key], [], Value),
+    spec!("RPOP", RPOP, [Key], [], Value),
+    spec!("LRANGE", LRANGE, [Key, Int, Int], [], Value),
+    spec!("LINDEX", LINDEX, [Key, Int], [], Value),
+    spec!("LSET", LSET, [Key, Int, Value], [], Ok),
+    spec!("LLEN", LLEN, [Key], [], Integer),
+    spec!("LTRIM", LTRIM, [Key, Int, Int], [], Ok),
+    spec!("HSET", HSET, [Key, Key, Value], [Key, Value], Integer),
+    spec!("HGET", HGET, [Key, Key], [], Value),
+    spec!("HDEL", HDEL, [Key, Key], [Key], Integer),
+    spec!("HGETALL", HGETALL, [Key], [], Value),
+    spec!("HEXISTS", HEXISTS, [Key, Key], [], Integer),
+    spec!("HINCRBY", HINCRBY, [Key, Key, Int], [], Integer),
+    spec!("SADD", SADD, [Key, Key], [Key], Integer),
+    spec!("SREM", SREM, [Key, Key], [Key], Integer),
+    spec!("SISMEMBER", SISMEMBER, [Key, Key], [], Integer),
+    spec!("SCARD", SCARD, [Key], [], Integer),
+    spec!("SMEMBERS", SMEMBERS, [Key], [], Value),
+    spec!("SUNION", SUNION, [Key], [Key], Value),
+    spec!("SINTER", SINTER, [Key], [Key], Value),
+    spec!("SDIFF", SDIFF, [Key], [Key], Value),
+    spec!("SUNIONSTORE", SUNIONSTORE, [Key, Key], [Key], Integer),
+    spec!("SINTERSTORE", SINTERSTORE, [Key, Key], [Key], Integer),
+    spec!("SDIFFSTORE", SDIFFSTORE, [Key, Key], [Key], Integer),
+    spec!("ZADD", ZADD, [Key, Score, Key], [Score, Key], Integer),
+    spec!("ZREM", ZREM, [Key, Key], [Key], Integer),
+    spec!("ZSCORE", ZSCORE, [Key, Key], [], Double),
+    spec!("ZRANK", ZRANK, [Key, Key], [], Rank),
+    spec!("ZCARD", ZCARD, [Key], [], Integer),
+    spec!("ZINCRBY", ZINCRBY, [Key, Score, Key], [], Double),
+];
+
+fn wrong_arity(name: &str) -> RespValue {
+    RespValue::error(format!(
+        "wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+fn to_key(arg: Vec<u8>) -> Result<String, RespValue> {
+    String::from_utf8(arg).map_err(|_| RespValue::error("keys must be valid UTF-8"))
+}
+
+fn to_int(arg: &[u8]) -> Result<isize, RespValue> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| RespValue::error("value is not an integer or out of range"))
+}
+
+// Integers are stored as integers so INCR works on them, other text as a
+// LokiQL string and anything else as a blob
+fn to_value(arg: Vec<u8>) -> QLValues {
+    match String::from_utf8(arg) {
+        Ok(s) => match s.parse::<isize>() {
+            Ok(n) if n.to_string() == s => QLValues::QLInt(n),
+            _ => QLValues::QLString(format!("'{}'", s)),
+        },
+        Err(e) => QLValues::QLBlob(e.into_bytes()),
+    }
+}
+
+fn to_arg(kind: Arg, arg: Vec<u8>) -> Result<QLValues, RespValue> {
+    match kind {
+        Arg::Key => Ok(QLValues::QLId(to_key(arg)?)),
+        Arg::Value => Ok(to_value(arg)),
+        Arg::Int => Ok(QLValues::QLInt(to_int(&arg)?)),
+        Arg::Score => std::str::from_utf8(&arg)
+            .ok()
+            .and_then(|f| f.parse::<f64>().ok())
+            .filter(|f| !f.is_nan())
+            .map(QLValues::QLFloat)
+            .ok_or_else(|| RespValue::error("value is not a valid float")),
+    }
+}
+
+fn to_args(spec: &Spec, args: Vec<Vec<u8>>) -> Result<Vec<QLValues>, RespValue> {
+    let extra = args.len().checked_sub(spec.args.len());
+    let fits = match (extra, spec.rest.len()) {
+        (Some(extra), 0) => extra == 0,
+        (Some(extra), rest) => extra % rest == 0,
+        (None, _) => false,
+    };
+    if !fits {
+        return Err(wrong_arity(spec.name));
+    }
+    let kinds = spec.args.iter().chain(spec.rest.iter().cycle());
+    kinds
+        .zip(args)
+        .map(|(kind, arg)| to_arg(*kind, arg))
+        .collect()
+}
+
+// The text LokiQL shows, without the quotes of a string literal
+fn unquote(s: String) -> Vec<u8> {
+    match s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+        Some(inner) => inner.as_bytes().to_vec(),
+        None => s.into_bytes(),
+    }
+}
+
+fn to_resp(value: ValueObject) -> RespValue {
+    match value {
+        ValueObject::StringData(s) => RespValue::Bulk(unquote(s)),
+        ValueObject::OutputString(s) => RespValue::bulk(s),
+        ValueObject::IntData(n) => RespValue::bulk(n.to_string()),
+        ValueObject::BoolData(b) => RespValue::bulk(b.to_string()),
+        ValueObject::DecimalData(f) => RespValue::Double(f),
+        ValueObject::BlobData(bytes) => RespValue::Bulk(bytes),
+        ValueObject::Phantom => RespValue::Null,
+        ValueObject::ListData(items) => RespValue::Array(items.into_iter().map(to_resp).collect()),
+        ValueObject::MapData(fields) => RespValue::Map(
+            fields
+                .into_iter()
+                .map(|(field, value)| (RespValue::bulk(field), to_resp(value)))
+                .collect(),
+        ),
+        ValueObject::SetData(members) => {
+            RespValue::Array(members.into_iter().map(RespValue::bulk).collect())
+        }
+        ValueObject::HLLPointer(_)
+        | ValueObject::SortedSetData(_)
+        | ValueObject::BloomFilterData(_)
+        | ValueObject::CountMinSketchData(_) => {
+            RespValue::Error("WRONGTYPE this value can not be read with GET".to_string())
+        }
+    }
+}
+
+fn to_resp_error(e: LokiError) -> RespValue {
+    match e {
+        LokiError::WrongType(msg) => RespValue::Error(format!("WRONGTYPE {}", msg)),
+        e => RespValue::error(e.to_string()),
+    }
+}
+
+fn to_reply(reply: Reply, result: Result<ValueObject, LokiError>) -> RespValue {
+    match (reply, result) {
+        (Reply::Value | Reply::Double | Reply::Rank, Err(LokiError::KeyNotFound(_))) => {
+            RespValue::Null
+        }
+        (Reply::Ttl, Err(LokiError::KeyNotFound(_))) => RespValue::Integer(-2),
+        (_, Err(e)) => to_resp_error(e),
+        (Reply::Ok, Ok(_)) => RespValue::ok(),
+        (Reply::Integer | Reply::Rank | Reply::Ttl, Ok(ValueObject::IntData(n))) => {
+            RespValue::Integer(n as i64)
+        }
+        (Reply::Integer, Ok(ValueObject::BoolData(b))) => RespValue::Integer(b as i64),
+        (Reply::Estimate, Ok(ValueObject::DecimalData(f))) => RespValue::Integer(f.round() as i64),
+        (_, Ok(value)) => to_resp(value),
+    }
+}
+
+// State of one RESP connection
+pub struct RespConnection {
+    db: Arc<RwLock<LokiKV>>,
+    session: Session,
+    // 2 until the client asks for RESP3 with HELLO
+    version: u8,
+}
+
+impl RespConnection {
+    pub fn new(db: Arc<RwLock<LokiKV>>) -> Self {
+        RespConnection {
+            db,
+            session: Session::new(),
+            version: 2,
+        }
+    }
+
+    pub fn get_version(&self) -> u8 {
+        self.version
+    }
+
+    // Runs the commands through the executor, one result each
+    fn run(&mut self, asts: Vec<AST>) -> Vec<Result<ValueObject, LokiError>> {
+        let asts = asts.into_iter().map(Some).collect();
+        Executor::new(self.db.clone(), asts).execute(&mut self.session)
+    }
+
+    fn run_one(&mut self, ast: AST) -> Result<ValueObject, LokiError> {
+        self.run(vec![ast]).remove(0)
+    }
+
+    // Reply to a request and whether the connection should be closed after it
+    pub fn handle(&mut self, mut args: Vec<Vec<u8>>) -> (RespValue, bool) {
+        let name = String::from_utf8_lossy(&args.remove(0)).to_uppercase();
+        if name == "QUIT" {
+            return (RespValue::ok(), true);
+        }
+        let reply = match SPECS.iter().find(|spec| spec.name == name) {
+            Some(spec) => match to_args(spec, args) {
+                Ok(args) => to_reply(spec.reply, self.run_one(AST::command(spec.command, args))),
+                Err(e) => e,
+            },
+            None => self.handle_special(&name, args).unwrap_or_else(|e| e),
+        };
+        (reply, false)
+    }
+
+    // Commands that need more than a single LokiQL command
+    fn handle_special(&mut self, name: &str, args: Vec<Vec<u8>>) -> Result<RespValue, RespValue> {
+        match name {
+            "PING" => match args.len() {
+                0 => Ok(RespValue::Simple("PONG".to_string())),
+                1 => Ok(RespValue::Bulk(args.into_iter().next().unwrap())),
+                _ => Err(wrong_arity(name)),
+            },
+            "ECHO" => match <[Vec<u8>; 1]>::try_from(args) {
+                Ok([message]) => Ok(RespValue::Bulk(message)),
+                Err(_) => Err(wrong_arity(name)),
+            },
+            "HELLO" => self.hello(args),
+            // Sent by redis-cli and client libraries while connecting
+            "COMMAND" => Ok(RespValue::Array(Vec::new())),
+            "CLIENT" => Ok(RespValue::ok()),
+            "SELECT" => {
+                let [collection] = <[Vec<u8>; 1]>::try_from(args).map_err(|_| wrong_arity(name))?;
+                let collection = match to_key(collection)? {
+                    c if c == "0" => DEFAULT_COLLECTION.to_string(),
+                    c => c,
+                };
+                let result = self.run_one(AST::command(
+                    QLCommands::SELCOL,
+                    vec![QLValues::QLId(collection)],
+                ));
+                Ok(to_reply(Reply::Ok, result))
+            }
+            "SET" => self.set(args),
+            "EXISTS" => {
+                if args.is_empty() {
+                    return Err(wrong_arity(name));
+                }
+                let asts = args
+                    .into_iter()
+                    .map(|key| {
+                        Ok(AST::command(
+                            QLCommands::EXISTS,
+                            vec![QLValues::QLId(to_key(key)?)],
+                        ))
+                    })
+                    .collect::<Result<_, RespValue>>()?;
+                let mut existing = 0;
+                for result in self.run(asts) {
+                    match result {
+                        Ok(ValueObject::BoolData(true)) => existing += 1,
+                        Ok(_) => {}
+                        Err(e) => return Err(to_resp_error(e)),
+                    }
+                }
+                Ok(RespValue::Integer(existing))
+            }
+            "PFADD" => {
+                if args.is_empty() {
+                    return Err(wrong_arity(name));
+                }
+                let mut args = args.into_iter();
+                let key = to_key(args.next().unwrap())?;
+                let asts: Vec<AST> = args
+                    .map(|item| {
+                        AST::command(
+                            QLCommands::ADDHLL,
+                            vec![QLValues::QLId(key.clone()), to_value(item)],
+                        )
+                    })
+                    .collect();
+                // Whether a register changed is not tracked, any item counts
+                let added = !asts.is_empty();
+                for result in self.run(asts) {
+                    result.map_err(to_resp_error)?;
+                }
+                Ok(RespValue::Integer(added as i64))
+            }
+            "ZRANGE" => {
+                let with_scores = args
+                    .last()
+                    .is_some_and(|arg| arg.eq_ignore_ascii_case(b"WITHSCORES"));
+                let mut args = args;
+                if with_scores {
+                    args.pop();
+                }
+                let spec = spec!("ZRANGE", ZRANGE, [Key, Int, Int], [], Value);
+                let mut values = to_args(&spec, args)?;
+                if with_scores {
+                    values.push(QLValues::QLWithScores);
+                }
+                let result = self.run_one(AST::command(QLCommands::ZRANGE, values));
+                match result.map_err(to_resp_error)? {
+                    // RESP2 clients expect member, score, member, ...
+                    ValueObject::ListData(pairs) if with_scores && self.version < 3 => {
+                        Ok(RespValue::Array(
+                            pairs
+                                .into_iter()
+                                .flat_map(|pair| match to_resp(pair) {
+                                    RespValue::Array(pair) => pair,
+                                    other => vec![other],
+                                })
+                                .collect(),
+                        ))
+                    }
+                    value => Ok(to_resp(value)),
+                }
+            }
+            "SCAN" => self.scan(args),
+            _ => Err(RespValue::error(format!("unknown command '{}'", name))),
+        }
+    }
+
+    // HELLO [protover ...], switches the connection to RESP3 when asked to
+    fn hello(&mut self, args: Vec<Vec<u8>>) -> Result<RespValue, RespValue> {
+        if let Some(version) = args.first() {
+            match to_int(version) {
+                Ok(version @ 2..=3) => self.version = version as u8,
+                _ => {
+                    return Err(RespValue::Error(
+                        "NOPROTO unsupported protocol version".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(RespValue::Map(vec![
+            (RespValue::bulk("server"), RespValue::bulk("lokikv")),
+            (
+                RespValue::bulk("version"),
+                RespValue::bulk(env!("CARGO_PKG_VERSION")),
+            ),
+            (
+                RespValue::bulk("proto"),
+                RespValue::Integer(self.version as i64),
+            ),
+            (RespValue::bulk("mode"), RespValue::bulk("standalone")),
+            (RespValue::bulk("role"), RespValue::bulk("master")),
+            (RespValue::bulk("modules"), RespValue::Array(Vec::new())),
+        ]))
+    }
+
+    // SET key value [EX seconds | PX milliseconds]
+    fn set(&mut self, args: Vec<Vec<u8>>) -> Result<RespValue, RespValue> {
+        let mut args = args.into_iter();
+        let (Some(key), Some(value)) = (args.next(), args.next()) else {
+            return Err(wrong_arity("SET"));
+        };
+        let mut values = vec![QLValues::QLId(to_key(key)?), to_value(value)];
+        let options: Vec<Vec<u8>> = args.collect();
+        match options.as_slice() {
+            [] => {}
+            [unit, amount] => {
+                let unit_millis = match unit.to_ascii_uppercase().as_slice() {
+                    b"EX" => 1000,
+                    b"PX" => 1,
+                    _ => return Err(RespValue::error("syntax error")),
+                };
+                let amount = to_int(amount)?;
+                if amount <= 0 {
+                    return Err(RespValue::error("invalid expire time in 'set' command"));
+                }
+                values.push(QLValues::QLExpiry(
+                    (amount as u64).saturating_mul(unit_millis),
+                ));
+            }
+            _ => return Err(RespValue::error("syntax error")),
+        }
+        let result = self.run_one(AST::command(QLCommands::SET, values));
+        Ok(to_reply(Reply::Ok, result))
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count], backed by KEYS
+    fn scan(&mut self, args: Vec<Vec<u8>>) -> Result<RespValue, RespValue> {
+        let mut args = args.into_iter();
+        let cursor = args.next().ok_or_else(|| wrong_arity("SCAN"))?;
+        let mut values = vec![QLValues::QLInt(to_int(&cursor)?)];
+        while let Some(option) = args.next() {
+            let argument = args
+                .next()
+                .ok_or_else(|| RespValue::error("syntax error"))?;
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => values.push(QLValues::QLMatch(to_key(argument)?)),
+                b"COUNT" => match to_int(&argument)? {
+                    count @ 1.. => values.push(QLValues::QLCount(count as usize)),
+                    _ => return Err(RespValue::error("syntax error")),
+                },
+                _ => return Err(RespValue::error("syntax error")),
+            }
+        }
+        let result = self.run_one(AST::command(QLCommands::KEYS, values));
+        Ok(to_reply(Reply::Value, result))
+    }
+}
+
+pub async fn handle_resp_connection(
+    stream: TcpStream,
+    db_instance: Arc<RwLock<LokiKV>>,
+) -> Result<(), String> {
+    info("Starting RESP handle....");
+    let (rd, mut wr) = stream.into_split();
+    let mut reader = BufReader::new(rd);
+    let mut connection = RespConnection::new(db_instance);
+    let mut out = Vec::new();
+
+    loop {
+        out.clear();
+        let close = match read_request(&mut reader).await {
+            Ok(Some(args)) => {
+                // Handling takes the database lock and may wait for the WAL
+                // to be synced, so it runs on the blocking thread pool
+                let (returned, (reply, close)) = tokio::task::spawn_blocking(move || {
+                    let handled = connection.handle(args);
+                    (connection, handled)
+                })
+                .await
+                .map_err(|e| format!("Request failed: {}", e))?;
+                connection = returned;
+                reply.encode(&mut out, connection.get_version());
+                close
+            }
+            Ok(None) => {
+                warning("RESP connection closed!");
+                return Ok(());
+            }
+            // The rest of the stream can't be trusted after this
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                RespValue::Error(format!("ERR Protocol error: {}", e))
+                    .encode(&mut out, connection.get_version());
+                true
+            }
+            Err(e) => return Err(format!("Failed to read request: {}", e)),
+        };
+        wr.write_all(&out)
+            .await
+            .map_err(|e| format!("Failed to write response: {}", e))?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::control::write_test_control_file;
+    use crate::parser::parser::parse_lokiql;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+    use tokio::time::timeout;
+
+    // Client side of RESP, requests are always arrays of bulk strings
+    fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+        let mut out = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            out.extend(format!("${}\r\n", arg.len()).as_bytes());
+            out.extend(*arg);
+            out.extend(b"\r\n");
+        }
+        out
+    }
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        async fn send(&mut self, request: &[u8], expected: &[u8]) {
+            self.stream.write_all(request).await.unwrap();
+            let mut reply = vec![0; expected.len()];
+            timeout(Duration::from_secs(5), self.stream.read_exact(&mut reply))
+                .await
+                .expect("no reply")
+                .unwrap();
+            assert_eq!(
+                String::from_utf8_lossy(&reply),
+                String::from_utf8_lossy(expected)
+            );
+        }
+
+        async fn call(&mut self, args: &[&str], expected: &str) {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            self.send(&encode_command(&args), expected.as_bytes()).await;
+        }
+
+        // For errors whose message comes from deeper down
+        async fn call_error(&mut self, args: &[&str], prefix: &str) {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+            self.stream.write_all(&encode_command(&args)).await.unwrap();
+            let mut reply = Vec::new();
+            while !reply.ends_with(b"\r\n") {
+                let byte = timeout(Duration::from_secs(5), self.stream.read_u8())
+                    .await
+                    .expect("no reply")
+                    .unwrap();
+                reply.push(byte);
+            }
+            let reply = String::from_utf8(reply).unwrap();
+            assert!(reply.starts_with(prefix), "{}", reply);
+        }
+    }
+
+    async fn connect(dir: &TempDir) -> (Client, Arc<RwLock<LokiKV>>) {
+        let db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_db = db.clone();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = handle_resp_connection(socket, server_db).await;
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        (Client { stream }, db)
+    }
+
+    fn run_lokiql(db: &Arc<RwLock<LokiKV>>, query: &str) -> Vec<Result<ValueObject, LokiError>> {
+        let asts = parse_lokiql(query).unwrap();
+        Executor::new(db.clone(), asts).execute(&mut Session::new())
+    }
+
+    #[tokio::test]
+    async fn test_string_and_key_commands() {
+        let dir = TempDir::new().unwrap();
+        let (mut client, db) = connect(&dir).await;
+
+        client.call(&["PING"], "+PONG\r\n").await;
+        client.call(&["SET", "name", "loki kv"], "+OK\r\n").await;
+        client.call(&["GET", "name"], "$7\r\nloki kv\r\n").await;
+        client.call(&["GET", "missing"], "$-1\r\n").await;
+        client.call(&["SET", "hits", "10"], "+OK\r\n").await;
+        client.call(&["INCR", "hits"], ":11\r\n").await;
+        client.call(&["DECR", "hits"], ":10\r\n").await;
+        client.call(&["GET", "hits"], "$2\r\n10\r\n").await;
+        client.call_error(&["INCR", "name"], "-WRONGTYPE ").await;
+        client
+            .call(&["EXISTS", "name", "hits", "missing"], ":2\r\n")
+            .await;
+        client.call(&["TTL", "name"], ":-1\r\n").await;
+        client.call(&["TTL", "missing"], ":-2\r\n").await;
+        client.call(&["EXPIRE", "name", "100"], ":1\r\n").await;
+        client.call(&["TTL", "name"], ":100\r\n").await;
+        client
+            .call(&["SET", "temp", "x", "PX", "1"], "+OK\r\n")
+            .await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        client.call(&["GET", "temp"], "$-1\r\n").await;
+        client
+            .call(&["DEL", "hits", "temp", "missing"], ":1\r\n")
+            .await;
+        client
+            .call(&["SET", "a", "b", "NX"], "-ERR syntax error\r\n")
+            .await;
+        client
+            .call(
+                &["GET"],
+                "-ERR wrong number of arguments for 'get' command\r\n",
+            )
+            .await;
+        client
+            .call(&["FLUSHALL"], "-ERR unknown command 'FLUSHALL'\r\n")
+            .await;
+
+        // Arbitrary bytes round trip as blobs
+        let blob: &[u8] = b"\x00\xff\r\n";
+        client
+            .send(&encode_command(&[b"SET", b"raw", blob]), b"+OK\r\n")
+            .await;
+        client
+            .send(
+                &encode_command(&[b"GET", b"raw"]),
+                b"$4\r\n\x00\xff\r\n\r\n",
+            )
+            .await;
+
+        // Both protocols see the same values
+        let responses = run_lokiql(&db, "GET name ; SET greeting 'hi'");
+        assert!(matches!(&responses[0], Ok(ValueObject::StringData(s)) if s == "'loki kv'"));
+        client.call(&["GET", "greeting"], "$2\r\nhi\r\n").await;
+
+        assert!(run_lokiql(&db, "/c_hcol users")[0].is_ok());
+        client.call(&["SELECT", "users"], "+OK\r\n").await;
+        client.call(&["GET", "greeting"], "$-1\r\n").await;
+        client.call(&["SELECT", "0"], "+OK\r\n").await;
+        client.call(&["GET", "greeting"], "$2\r\nhi\r\n").await;
+        client
+            .call(
+                &["SELECT", "nope"],
+                "-ERR collection nope does not exist\r\n",
+            )
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_data_type_commands() {
+        let dir = TempDir::new().unwrap();
+        let (mut client, _db) = connect(&dir).await;
+
+        client
+            .call(&["PFADD", "visitors", "ann", "bo", "ann"], ":1\r\n")
+            .await;
+        client.call(&["PFADD", "other", "cy"], ":1\r\n").await;
+        client.call(&["PFCOUNT", "visitors"], ":2\r\n").await;
+        client
+            .call(&["PFCOUNT", "visitors", "other", "missing"], ":3\r\n")
+            .await;
+        client
+            .call(&["PFMERGE", "all", "visitors", "other"], "+OK\r\n")
+            .await;
+        client.call(&["PFCOUNT", "all"], ":3\r\n").await;
+
+        client
+            .call(&["HSET", "user", "name", "ann", "age", "3"], ":2\r\n")
+            .await;
+        client
+            .call(&["HINCRBY", "user", "age", "1"], ":4\r\n")
+            .await;
+        client
+            .call(&["HGET", "user", "name"], "$3\r\nann\r\n")
+            .await;
+        client.call(&["HGET", "user", "email"], "$-1\r\n").await;
+        client
+            .call(
+                &["HGETALL", "user"],
+                "*4\r\n$3\r\nage\r\n$1\r\n4\r\n$4\r\nname\r\n$3\r\nann\r\n",
+            )
+            .await;
+        client
+            .call(
+                &["HSET", "user", "name"],
+                "-ERR wrong number of arguments for 'hset' command\r\n",
+            )
+            .await;
+
+        client
+            .call(&["RPUSH", "queue", "a", "b", "c"], ":3\r\n")
+            .await;
+        client.call(&["LPOP", "queue"], "$1\r\na\r\n").await;
+        client
+            .call(
+                &["LRANGE", "queue", "0", "-1"],
+                "*2\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            )
+            .await;
+        client.call(&["LPOP", "empty"], "$-1\r\n").await;
+
+        client
+            .call(&["SADD", "tags", "x", "y", "x"], ":2\r\n")
+            .await;
+        client.call(&["SISMEMBER", "tags", "y"], ":1\r\n").await;
+        client
+            .call(&["SMEMBERS", "tags"], "*2\r\n$1\r\nx\r\n$1\r\ny\r\n")
+            .await;
+
+        client
+            .call(&["ZADD", "board", "10", "ann", "2.5", "bo"], ":2\r\n")
+            .await;
+        client
+            .call(&["ZSCORE", "board", "bo"], "$3\r\n2.5\r\n")
+            .await;
+        client.call(&["ZRANK", "board", "ann"], ":1\r\n").await;
+        client.call(&["ZRANK", "board", "cy"], "$-1\r\n").await;
+        client
+            .call(
+                &["ZRANGE", "board", "0", "-1", "WITHSCORES"],
+                "*4\r\n$2\r\nbo\r\n$3\r\n2.5\r\n$3\r\nann\r\n$2\r\n10\r\n",
+            )
+            .await;
+        client
+            .call(
+                &["ZADD", "board", "high", "cy"],
+                "-ERR value is not a valid float\r\n",
+            )
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_hello_switches_to_resp3() {
+        let dir = TempDir::new().unwrap();
+        let (mut client, _db) = connect(&dir).await;
+
+        client
+            .call(&["HELLO", "4"], "-NOPROTO unsupported protocol version\r\n")
+            .await;
+        client
+            .call(&["HELLO", "3"], "%6\r\n$6\r\nserver\r\n$6\r\nlokikv\r\n")
+            .await;
+        // Rest of the HELLO reply
+        let version = env!("CARGO_PKG_VERSION");
+        let rest = format!(
+            "$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            version.len(),
+            version
+        );
+        client.send(b"", rest.as_bytes()).await;
+
+        client.call(&["GET", "missing"], "_\r\n").await;
+        client
+            .call(&["ZADD", "board", "1.5", "ann"], ":1\r\n")
+            .await;
+        client.call(&["ZSCORE", "board", "ann"], ",1.5\r\n").await;
+        client
+            .call(
+                &["ZRANGE", "board", "0", "0", "WITHSCORES"],
+                "*1\r\n*2\r\n$3\r\nann\r\n,1.5\r\n",
+            )
+            .await;
+        client.call(&["HSET", "m", "f", "v"], ":1\r\n").await;
+        client
+            .call(&["HGETALL", "m"], "%1\r\n$1\r\nf\r\n$1\r\nv\r\n")
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_inline_commands_and_protocol_errors() {
+        let dir = TempDir::new().unwrap();
+        let (mut client, _db) = connect(&dir).await;
+
+        client
+            .send(b"SET k v\r\n\r\nGET k\r\n", b"+OK\r\n$1\r\nv\r\n")
+            .await;
+        client
+            .send(
+                b"*1\r\n:1\r\n",
+                b"-ERR Protocol error: expected a bulk string\r\n",
+            )
+            .await;
+        // The server hangs up after a protocol error
+        let mut rest = Vec::new();
+        client.stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
@@ -1,12 +1,19 @@
+use crate::error::LokiError;
+use crate::loki_kv::config::Config;
 use crate::loki_kv::control::ControlFile;
-use crate::loki_kv::loki_kv::{get_control_file_path, LokiKV, ValueObject};
+use crate::loki_kv::loki_kv::LokiKV;
 use crate::parser::executor::Executor;
-use crate::parser::parser::parse_lokiql;
+use crate::parser::parser::{parse_lokiql, AST};
 use crate::server_multithread::paxos::MultiPaxos;
+use crate::server_multithread::protocol::{self, Responses};
+use crate::server_multithread::resp::handle_resp_connection;
+use crate::server_multithread::session::Session;
 use crate::utils::{error_string, info, info_string, warning};
 use rand;
 use std::collections::HashSet;
 use std::env;
+use std::net::SocketAddr;
+use std::process;
 use std::time::{Duration, Instant};
 use std::{
     ops::{Deref, DerefMut},
@@ -14,15 +21,22 @@ use std::{
 };
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::time::{interval, sleep};
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{self, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
 };
 
 // Server Logic
 pub struct LokiServer {
     tcp_listener: TcpListener,
+    // Redis clients connect here when `resp_addr` is set
+    resp_listener: Option<TcpListener>,
     host: String,
     port: u16,
     thread_count: usize,
@@ -32,11 +46,76 @@ pub struct LokiServer {
 
 fn decide_random_bool() -> bool {
     let x: u8 = rand::random();
-    x % 2 == 0
+    x.is_multiple_of(2)
 }
 
-//
-async fn handle_connection(
+// Takes the database lock only to snapshot the collections and to publish
+// the result, the checkpoint itself is written on the blocking thread pool
+async fn run_checkpoint(db: Arc<RwLock<LokiKV>>) -> Result<(), String> {
+    let started = Instant::now();
+    let pending = Arc::new(db.write().unwrap().begin_checkpoint()?);
+    let locked_for = started.elapsed();
+
+    let writer = pending.clone();
+    let written = tokio::task::spawn_blocking(move || writer.write())
+        .await
+        .unwrap_or_else(|e| Err(format!("Checkpoint writer panicked: {}", e)));
+
+    let checkpoint_id = db.write().unwrap().finish_checkpoint(&pending, written)?;
+    info_string(format!(
+        "Checkpoint {} took {:?} (writers blocked for {:?})",
+        checkpoint_id,
+        started.elapsed(),
+        locked_for
+    ));
+    Ok(())
+}
+
+// Errors are sent as a single line so clients can tell them apart from values
+fn format_error(e: &LokiError) -> String {
+    format!("ERROR {}: {}\n", e.code(), e)
+}
+
+// Executes on the blocking thread pool, since executing takes the database
+// lock and waits for the WAL to be synced. The session goes along and comes
+// back afterwards.
+async fn execute_blocking(
+    db_instance: Arc<RwLock<LokiKV>>,
+    asts: Vec<Option<AST>>,
+    session: &mut Session,
+) -> Responses {
+    let mut owned = std::mem::take(session);
+    let executed = tokio::task::spawn_blocking(move || {
+        let responses = Executor::new(db_instance, asts).execute(&mut owned);
+        (owned, responses)
+    })
+    .await;
+    match executed {
+        Ok((owned, responses)) => {
+            *session = owned;
+            responses
+        }
+        // The session starts over in the default collection
+        Err(e) => vec![Err(LokiError::IoError(format!("request failed: {}", e)))],
+    }
+}
+
+// Runs every command of a request, a query that does not parse gives a
+// single error
+async fn run_request(
+    request: &str,
+    db_instance: &Arc<RwLock<LokiKV>>,
+    session: &mut Session,
+) -> Responses {
+    match parse_lokiql(request) {
+        Ok(asts) => execute_blocking(db_instance.clone(), asts, session).await,
+        Err(e) => vec![Err(e)],
+    }
+}
+
+// Line based protocol, the default. A connection whose first line is a
+// binary hello continues with `serve_binary` instead.
+pub async fn handle_connection(
     stream: TcpStream,
     db_instance: Arc<RwLock<LokiKV>>,
 ) -> Result<(), String> {
@@ -44,35 +123,45 @@ async fn handle_connection(
     let (rd, mut wr) = stream.into_split();
     let mut reader = BufReader::new(rd);
     let mut buf = String::new();
+    let mut session = Session::new();
+    let mut first_request = true;
 
     loop {
         buf.clear();
-        let n = reader.read_line(&mut buf).await.unwrap();
+        let n = reader
+            .read_line(&mut buf)
+            .await
+            .map_err(|e| format!("Failed to read request: {}", e))?;
         if n == 0 {
             warning("Connection closed!");
             return Err(String::from("connection closed"));
         }
 
         let request_line = buf.trim().to_string();
-        // let request_line = String::from_utf8(buf[..n].to_vec())
-        //     .map_err(|e| format!("Invalid UTF-8 data: {}", e))
-        //     .unwrap();
-
         let mut resp_str = String::new();
 
-        let asts = parse_lokiql(&request_line);
-        if asts.len() == 0 {
-            // Query was wrong.. lets tell it to the user
-            resp_str += "Invalid command.. Pls try again\n";
+        let hello = if first_request {
+            protocol::parse_hello(&request_line)
         } else {
-            let mut ast_exector = Executor::new(db_instance.clone(), asts);
-            let responses = ast_exector.execute();
-
-            // Improve output result
-            for response in responses.iter() {
-                if let val = response {
-                    resp_str += &format!("{:?}\n", val);
-                };
+            None
+        };
+        first_request = false;
+        match hello {
+            Some(Ok(version)) => {
+                wr.write_all(protocol::accepted_line(version).as_bytes())
+                    .await
+                    .map_err(|e| format!("Failed to write response: {}", e))?;
+                return serve_binary(reader, wr, db_instance, session, version).await;
+            }
+            // Unknown version, the client can carry on with text
+            Some(Err(e)) => resp_str += &format_error(&e),
+            None => {
+                for response in run_request(&request_line, &db_instance, &mut session).await {
+                    match response {
+                        Ok(val) => resp_str += &format!("{:?}\n", val),
+                        Err(e) => resp_str += &format_error(&e),
+                    }
+                }
             }
         }
 
@@ -90,9 +179,138 @@ async fn handle_connection(
     }
 }
 
+// Response frames waiting for the writer, and read only requests running at
+// once, per binary connection. Reading more requests waits beyond these.
+const MAX_QUEUED_FRAMES: usize = 64;
+const MAX_PIPELINED_READS: usize = 16;
+
+fn response_frame(id: Option<u64>, responses: &Responses) -> Vec<u8> {
+    // Values that cannot be encoded still get an answer
+    let payload = protocol::encode_responses(responses).unwrap_or_else(|e| {
+        protocol::encode_responses(&vec![Err(e); responses.len()]).expect("errors always encode")
+    });
+    match id {
+        Some(id) => protocol::with_request_id(id, &payload),
+        None => payload,
+    }
+}
+
+// One response frame per request frame, see `protocol`. With request IDs
+// read only requests run concurrently, every other request waits for the
+// ones before it so writes and session changes happen in the order sent.
+async fn serve_binary(
+    mut reader: BufReader<OwnedReadHalf>,
+    mut wr: OwnedWriteHalf,
+    db_instance: Arc<RwLock<LokiKV>>,
+    mut session: Session,
+    version: u32,
+) -> Result<(), String> {
+    info_string(format!(
+        "Switched connection to binary protocol version {}",
+        version
+    ));
+    let with_ids = version >= protocol::REQUEST_ID_VERSION;
+    // A single task writes the frames in the order they are queued
+    let (frames, mut queued) = mpsc::channel::<Vec<u8>>(MAX_QUEUED_FRAMES);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = queued.recv().await {
+            protocol::write_frame(&mut wr, &frame)
+                .await
+                .map_err(|e| format!("Failed to write response: {}", e))?;
+        }
+        Ok::<(), String>(())
+    });
+    let mut reads = JoinSet::new();
+
+    let result = loop {
+        let frame = match protocol::read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                warning("Connection closed!");
+                break Err(String::from("connection closed"));
+            }
+            Err(e) => break Err(format!("Failed to read request: {}", e)),
+        };
+        let (id, query) = match with_ids {
+            true => match protocol::split_request_id(&frame) {
+                Some((id, query)) => (Some(id), query),
+                None => break Err(String::from("request frame has no request ID")),
+            },
+            false => (None, &frame[..]),
+        };
+
+        let parsed = std::str::from_utf8(query)
+            .map_err(|e| LokiError::ParseError(format!("request is not valid UTF-8: {}", e)))
+            .and_then(parse_lokiql);
+        let asts = match parsed {
+            Ok(asts) => asts,
+            Err(e) => {
+                if frames
+                    .send(response_frame(id, &vec![Err(e)]))
+                    .await
+                    .is_err()
+                {
+                    break Err(String::from("response writer stopped"));
+                }
+                continue;
+            }
+        };
+
+        let read_only = !asts.is_empty()
+            && asts
+                .iter()
+                .all(|ast| ast.as_ref().is_some_and(AST::is_read_only));
+        if with_ids && read_only {
+            if reads.len() >= MAX_PIPELINED_READS {
+                reads.join_next().await;
+            }
+            let db = db_instance.clone();
+            let mut detached = session.detached();
+            let frames = frames.clone();
+            reads.spawn_blocking(move || {
+                let responses = Executor::new(db, asts).execute(&mut detached);
+                // Only fails once the connection is going away
+                let _ = frames.blocking_send(response_frame(id, &responses));
+            });
+        } else {
+            while reads.join_next().await.is_some() {}
+            let responses = execute_blocking(db_instance.clone(), asts, &mut session).await;
+            if frames.send(response_frame(id, &responses)).await.is_err() {
+                break Err(String::from("response writer stopped"));
+            }
+        }
+    };
+
+    // Answers to reads already running are still sent
+    while reads.join_next().await.is_some() {}
+    drop(frames);
+    match writer.await {
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(format!("Response writer panicked: {}", e)),
+        Ok(Ok(())) => result,
+    }
+}
+
+// Never resolves when the RESP listener is off
+async fn accept_resp(listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
 impl LokiServer {
-    pub async fn new(thread_count: usize) -> Self {
-        let control_file = ControlFile::read_from_file_path(get_control_file_path()).unwrap();
+    // Opens the database described by config, then starts listening on the
+    // address from its control file
+    pub async fn new(config: Config, thread_count: usize) -> Self {
+        let db_instance = match LokiKV::open(config) {
+            Ok(db) => db,
+            Err(e) => {
+                error_string(format!("Recovery failed, refusing to start: {}", e));
+                process::exit(1);
+            }
+        };
+        let control_file = db_instance.get_control_file().clone();
         let host: String = control_file.get_hostname();
         let port: u16 = control_file.get_port();
         let addr = format!(
@@ -106,14 +324,24 @@ impl LokiServer {
         match tcp_listener {
             Ok(tcp_list) => {
                 info_string(format!("Started Sevrer at {}:{}", host, port));
-                let db_instance = LokiKV::new();
+                let resp_listener = match control_file.get_resp_addr() {
+                    Some(resp_addr) => match TcpListener::bind(resp_addr).await {
+                        Ok(listener) => {
+                            info_string(format!("Started RESP listener at {}", resp_addr));
+                            Some(listener)
+                        }
+                        Err(e) => panic!("Unable to start RESP listener at {}: {}", resp_addr, e),
+                    },
+                    None => None,
+                };
                 LokiServer {
                     tcp_listener: tcp_list,
+                    resp_listener,
                     host,
                     port,
                     thread_count,
                     db_instance: Arc::new(RwLock::new(db_instance)),
-                    control_file: control_file,
+                    control_file,
                 }
             }
             Err(_) => {
@@ -128,7 +356,10 @@ impl LokiServer {
 
         let mut checkpoint_timer = interval(Duration::from_secs(checkpoint_itr * 60));
         let mut paxos_gossip_broadcast_timer = interval(Duration::from_secs(paxos_itr * 30));
-        
+        let mut expiry_sweep_timer = interval(Duration::from_millis(
+            self.control_file.get_expiry_sweep_interval_ms(),
+        ));
+
         let node_id = self.control_file.get_self_identifier().unwrap_or(1);
         let peers: HashSet<u64> = vec![1, 2, 3].into_iter().collect();
         let paxos_node: Arc<tokio::sync::RwLock<MultiPaxos>> =
@@ -155,12 +386,42 @@ impl LokiServer {
                 }
 
 
+                accept_result = accept_resp(&self.resp_listener) => {
+                    match accept_result {
+                        Ok((socket, _)) => {
+                            let db = self.db_instance.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = handle_resp_connection(socket, db).await {
+                                    error_string(format!("Error handling RESP connection: {}", e));
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            error_string(format!("Error accepting RESP connection: {}", e));
+                        }
+                    }
+                }
+
                 _ = checkpoint_timer.tick() => {
                     info("Checkpointing...");
                     let ins = self.db_instance.clone();
                     tokio::spawn(async move {
-                        let mut db = ins.write().unwrap();
-                        db.checkpoint();
+                        if let Err(e) = run_checkpoint(ins).await {
+                            error_string(format!("Checkpoint failed: {}", e));
+                        }
+                    });
+                }
+
+                // Expired keys are also dropped lazily, this catches the ones
+                // nobody reads again
+                _ = expiry_sweep_timer.tick() => {
+                    let ins = self.db_instance.clone();
+                    tokio::task::spawn_blocking(move || {
+                        match ins.write().unwrap().remove_expired_keys() {
+                            Ok(0) => {}
+                            Ok(removed) => info_string(format!("Removed {} expired keys", removed)),
+                            Err(e) => error_string(format!("Expiry sweep failed: {}", e)),
+                        }
                     });
                 }
 
@@ -179,8 +440,8 @@ impl LokiServer {
                         }else{
                             info("Consuming gossip from strangers..(for now)");
                             let state = node.read().await;
-                            info_string(format!("Paxos state - Leader: {:?}, Peers: {:?}", 
-                                state.get_leader().await, 
+                            info_string(format!("Paxos state - Leader: {:?}, Peers: {:?}",
+                                state.get_leader().await,
                                 state.get_state().await.peers));
                         }
 
@@ -191,3 +452,129 @@ impl LokiServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::control::write_test_control_file;
+    use crate::loki_kv::loki_kv::ValueObject;
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    async fn serve_one(dir: &TempDir) -> TcpStream {
+        let db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = handle_connection(socket, db).await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_binary_protocol_keeps_value_types() {
+        let dir = TempDir::new().unwrap();
+        let mut stream = serve_one(&dir).await;
+        stream.write_all(b"HELLO BINARY 1\n").await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "OK BINARY 1\n");
+
+        // A value holding the text protocol's sentinel and a newline
+        let query = "SET note '<END_OF_RESPONSE>\n' ; GET note ; GET missing";
+        protocol::write_frame(&mut reader, query.as_bytes())
+            .await
+            .unwrap();
+        let payload = protocol::read_frame(&mut reader).await.unwrap().unwrap();
+        let responses: Responses = bincode::deserialize(&payload).unwrap();
+        assert_eq!(responses.len(), 3);
+        assert!(matches!(
+            &responses[1],
+            Ok(ValueObject::StringData(note)) if note == "'<END_OF_RESPONSE>\n'"
+        ));
+        assert_eq!(
+            responses[2].as_ref().err().map(|e| e.code()),
+            Some("KEY_NOT_FOUND")
+        );
+
+        protocol::write_frame(&mut reader, &[0xff, 0xfe])
+            .await
+            .unwrap();
+        let payload = protocol::read_frame(&mut reader).await.unwrap().unwrap();
+        let responses: Responses = bincode::deserialize(&payload).unwrap();
+        assert!(matches!(responses[..], [Err(LokiError::ParseError(_))]));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_keep_write_order() {
+        let dir = TempDir::new().unwrap();
+        let mut stream = serve_one(&dir).await;
+        stream.write_all(b"HELLO BINARY 2\n").await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "OK BINARY 2\n");
+
+        // Everything is sent before the first answer is read
+        let mut requests = vec![(1, "SET counter 1".to_string())];
+        requests.extend((2..=31).map(|id| (id, "GET counter ; EXISTS counter".to_string())));
+        requests.push((32, "INCR counter".to_string()));
+        requests.push((33, "GET counter".to_string()));
+        requests.push((34, "GET counter 1".to_string()));
+        for (id, query) in &requests {
+            let frame = protocol::with_request_id(*id, query.as_bytes());
+            protocol::write_frame(&mut reader, &frame).await.unwrap();
+        }
+
+        let mut answers = BTreeMap::new();
+        let mut order = Vec::new();
+        for _ in 0..requests.len() {
+            let frame = protocol::read_frame(&mut reader).await.unwrap().unwrap();
+            let (id, payload) = protocol::split_request_id(&frame).unwrap();
+            let responses: Responses = bincode::deserialize(payload).unwrap();
+            order.push(id);
+            assert!(answers.insert(id, responses).is_none());
+        }
+        assert_eq!(
+            answers.keys().copied().collect::<Vec<_>>(),
+            (1..=34).collect::<Vec<_>>()
+        );
+        // Writes are answered in the order they were sent, after the reads
+        // sent before them
+        assert_eq!(order[0], 1);
+        assert_eq!(order[31], 32);
+        for id in 2..=31 {
+            assert!(matches!(answers[&id][0], Ok(ValueObject::IntData(1))));
+            assert!(matches!(answers[&id][1], Ok(ValueObject::BoolData(true))));
+        }
+        assert!(matches!(answers[&32][0], Ok(ValueObject::IntData(2))));
+        assert!(matches!(answers[&33][0], Ok(ValueObject::IntData(2))));
+        assert!(matches!(answers[&34][..], [Err(LokiError::ParseError(_))]));
+    }
+
+    #[tokio::test]
+    async fn test_text_protocol_is_the_default() {
+        let dir = TempDir::new().unwrap();
+        let mut stream = serve_one(&dir).await;
+        stream
+            .write_all(b"HELLO BINARY 9\nSET a 1 ; GET a\nHELLO BINARY 1\n")
+            .await
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut lines = Vec::new();
+        while lines.len() < 7 {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.unwrap();
+            lines.push(line.trim_end().to_string());
+        }
+        assert!(lines[0].starts_with("ERROR PARSE_ERROR: unsupported binary protocol version 9"));
+        assert_eq!(lines[1], "<END_OF_RESPONSE>");
+        assert_eq!(lines[3], "IntData(1)");
+        assert_eq!(lines[4], "<END_OF_RESPONSE>");
+        // A hello after the first request is just a bad query
+        assert!(lines[5].starts_with("ERROR PARSE_ERROR"));
+    }
+}
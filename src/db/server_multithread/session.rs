@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+
+use crate::error::LokiError;
+use crate::loki_kv::loki_kv::{KeyIter, LokiKV, DEFAULT_COLLECTION};
+use crate::utils::glob_match;
+
+// Open KEYS cursors per connection, the oldest one is dropped beyond this
+const MAX_CURSORS: usize = 16;
+
+// A KEYS scan in progress. It walks a snapshot taken when the scan started,
+// so every key that stays in the collection is returned exactly once.
+struct KeyCursor {
+    collection: String,
+    keys: KeyIter,
+}
+
+// State that belongs to a single client connection. Commands that don't name
+// a collection run against the one selected here.
+pub struct Session {
+    current_collection: String,
+    cursors: BTreeMap<u64, KeyCursor>,
+    last_cursor_id: u64,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            current_collection: DEFAULT_COLLECTION.to_string(),
+            cursors: BTreeMap::new(),
+            last_cursor_id: 0,
+        }
+    }
+
+    // Same collection without the KEYS cursors, for read only requests that
+    // run next to the ones using this session
+    pub fn detached(&self) -> Session {
+        Session {
+            current_collection: self.current_collection.clone(),
+            ..Session::new()
+        }
+    }
+
+    pub fn get_current_collection(&self) -> &str {
+        &self.current_collection
+    }
+
+    pub fn select_collection(
+        &mut self,
+        db: &LokiKV,
+        collection_name: &str,
+    ) -> Result<(), LokiError> {
+        if !db.collection_exists(collection_name) {
+            return Err(LokiError::CollectionNotFound(collection_name.to_string()));
+        }
+        self.current_collection = collection_name.to_string();
+        Ok(())
+    }
+
+    // Looks at the next `count` keys of a scan and returns the ones still in
+    // the collection that match `pattern`, with the cursor to continue from.
+    // Cursor 0 starts a scan of the current collection and is returned once
+    // the scan is complete.
+    pub fn scan_keys(
+        &mut self,
+        db: &LokiKV,
+        cursor_id: u64,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> Result<(u64, Vec<String>), LokiError> {
+        let (cursor_id, mut cursor) = if cursor_id == 0 {
+            let keys = db.snapshot_keys(&self.current_collection)?;
+            self.last_cursor_id += 1;
+            let cursor = KeyCursor {
+                collection: self.current_collection.clone(),
+                keys,
+            };
+            (self.last_cursor_id, cursor)
+        } else {
+            let cursor = self
+                .cursors
+                .remove(&cursor_id)
+                .ok_or_else(|| LokiError::ParseError(format!("unknown cursor {}", cursor_id)))?;
+            (cursor_id, cursor)
+        };
+
+        let count = count.max(1);
+        let mut keys = Vec::new();
+        let mut examined = 0;
+        for key in cursor.keys.by_ref().take(count) {
+            examined += 1;
+            if pattern.is_some_and(|pattern| !glob_match(pattern, &key)) {
+                continue;
+            }
+            // Keys deleted or expired since the snapshot are skipped
+            if db.exists(&cursor.collection, &key)? {
+                keys.push(key);
+            }
+        }
+        if examined < count {
+            return Ok((0, keys));
+        }
+
+        if self.cursors.len() >= MAX_CURSORS {
+            self.cursors.pop_first();
+        }
+        self.cursors.insert(cursor_id, cursor);
+        Ok((cursor_id, keys))
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loki_kv::control::write_test_control_file;
+    use crate::loki_kv::loki_kv::ValueObject;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sessions_select_collections_independently() {
+        let dir = TempDir::new().unwrap();
+        let mut db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        db.create_hmap_collection("users".to_string()).unwrap();
+
+        let mut first = Session::new();
+        let second = Session::new();
+        first.select_collection(&db, "users").unwrap();
+        db.put(first.get_current_collection(), "k", ValueObject::IntData(1))
+            .unwrap();
+
+        assert_eq!(second.get_current_collection(), DEFAULT_COLLECTION);
+        assert!(db.get(second.get_current_collection(), "k").is_err());
+        assert!(db.get(first.get_current_collection(), "k").is_ok());
+
+        // A failed select keeps the previous collection
+        assert!(first.select_collection(&db, "missing").is_err());
+        assert_eq!(first.get_current_collection(), "users");
+    }
+
+    #[test]
+    fn test_key_scans_survive_concurrent_writes() {
+        let dir = TempDir::new().unwrap();
+        let mut db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        db.create_bmap_collection("ordered".to_string()).unwrap();
+        db.create_custom_bcol("custom".to_string()).unwrap();
+
+        for collection in [DEFAULT_COLLECTION, "ordered", "custom"] {
+            for i in 0..100 {
+                db.put(collection, &format!("k{:03}", i), ValueObject::IntData(i))
+                    .unwrap();
+            }
+            let mut session = Session::new();
+            session.select_collection(&db, collection).unwrap();
+
+            let mut seen: Vec<String> = Vec::new();
+            let mut cursor = 0;
+            let mut round = 0;
+            loop {
+                let (next, keys) = session.scan_keys(&db, cursor, None, 7).unwrap();
+                seen.extend(keys);
+                // Writes between pages: new keys and removals of odd keys
+                db.put(
+                    collection,
+                    &format!("new{}", round),
+                    ValueObject::IntData(0),
+                )
+                .unwrap();
+                db.delete(collection, &format!("k{:03}", round * 2 + 1))
+                    .unwrap();
+                round += 1;
+                cursor = next;
+                if cursor == 0 {
+                    break;
+                }
+            }
+
+            // Every key that was never removed shows up exactly once
+            for i in (0..100).step_by(2) {
+                let key = format!("k{:03}", i);
+                assert_eq!(seen.iter().filter(|k| **k == key).count(), 1, "{}", key);
+            }
+            assert!(seen.iter().all(|k| !k.starts_with("new")));
+            assert!(session.cursors.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_key_scan_match_and_unknown_cursor() {
+        let dir = TempDir::new().unwrap();
+        let mut db = LokiKV::with_control_file(write_test_control_file(dir.path())).unwrap();
+        for key in ["user:1", "user:2", "video:1"] {
+            db.put(DEFAULT_COLLECTION, key, ValueObject::IntData(1))
+                .unwrap();
+        }
+        let mut session = Session::new();
+        let (cursor, mut keys) = session.scan_keys(&db, 0, Some("user:*"), 100).unwrap();
+        keys.sort();
+        assert_eq!(cursor, 0);
+        assert_eq!(keys, ["user:1", "user:2"]);
+        assert!(session.scan_keys(&db, 42, None, 10).is_err());
+    }
+}
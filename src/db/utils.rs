@@ -39,3 +39,93 @@ pub fn success_string(msg: String) {
     let mut logger = Logger::new();
     logger.success(msg.as_str());
 }
+
+// Glob matching as used by KEYS ... MATCH: `*`, `?`, `[abc]`, `[a-z]`, `[^a]`
+// and `\` to escape the next character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Pattern position after the last `*` and the text position it stopped at
+    let mut star: Option<(usize, usize)> = None;
+    while ti < text.len() {
+        if pi < pattern.len() && pattern[pi] == '*' {
+            pi += 1;
+            star = Some((pi, ti));
+            continue;
+        }
+        if pi < pattern.len() {
+            if let Some(next) = match_one(&pattern, pi, text[ti]) {
+                pi = next;
+                ti += 1;
+                continue;
+            }
+        }
+        // Let the last `*` swallow one more character
+        match star {
+            Some((star_pi, star_ti)) => {
+                pi = star_pi;
+                ti = star_ti + 1;
+                star = Some((star_pi, ti));
+            }
+            None => return false,
+        }
+    }
+    pattern[pi..].iter().all(|c| *c == '*')
+}
+
+// Matches one character against the pattern token at `pi`, returns where
+// the next token starts
+fn match_one(pattern: &[char], pi: usize, c: char) -> Option<usize> {
+    match pattern[pi] {
+        '?' => Some(pi + 1),
+        '\\' if pi + 1 < pattern.len() => (pattern[pi + 1] == c).then_some(pi + 2),
+        '[' => {
+            let mut i = pi + 1;
+            let negate = pattern.get(i) == Some(&'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != ']' {
+                if pattern[i] == '\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+                    matched |= pattern[i] <= c && c <= pattern[i + 2];
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            if i == pattern.len() {
+                // Unterminated class, the `[` is taken literally
+                return (c == '[').then_some(pi + 1);
+            }
+            (matched != negate).then_some(i + 1)
+        }
+        other => (other == c).then_some(pi + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:42"));
+        assert!(!glob_match("user:*", "video:1"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(glob_match("h*l*o", "heeeelllo"));
+        assert!(!glob_match("h*l*o", "heeeelllx"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("k[0-9][0-9]", "k42"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+        assert!(glob_match("[abc", "[abc"));
+    }
+}